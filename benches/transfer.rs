@@ -0,0 +1,221 @@
+//! Hashing, block reads, packet coding and a loopback transfer between two nodes.
+//!
+//! Run with `cargo bench`, or `cargo bench --features with-mmap` to compare block serving.
+use actix::System;
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use futures::prelude::*;
+use hyperg::codec::{Ask, GetBlock, StCodec, StCommand};
+use hyperg::database::{self, Access, RegisterHash};
+use hyperg::filemap::{self, BundleFormat, FileMap, HashAlgorithm, BLOCK_SIZE};
+use hyperg::user_report::UserReportHandle;
+use hyperg::{connection, download, hasher, server};
+use std::fs;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use tokio_util::codec::{Decoder, Encoder};
+
+const MIB: u64 = 1024 * 1024;
+
+fn bench_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("hyperg-bench-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// File of `size` pseudo random bytes, so hashing can't take shortcuts.
+fn write_file(dir: &Path, size: u64) -> PathBuf {
+    let path = dir.join(format!("{}.bin", size));
+    let mut file = fs::File::create(&path).unwrap();
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut chunk = vec![0u8; MIB as usize];
+    for _ in 0..size.div_ceil(MIB) {
+        for b in chunk.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *b = state as u8;
+        }
+        file.write_all(&chunk).unwrap();
+    }
+    file.set_len(size).unwrap();
+    path
+}
+
+fn file_map(size: u64) -> FileMap {
+    FileMap {
+        file_name: "bench.bin".into(),
+        file_size: size,
+        blocks: Vec::new(),
+        hash_algorithm: HashAlgorithm::default(),
+        mode: None,
+        mtime: None,
+        link: None,
+    }
+}
+
+fn hash_file(c: &mut Criterion) {
+    let dir = bench_dir("hash");
+    let mut group = c.benchmark_group("hash_file");
+    group.sample_size(10);
+    for &size in &[MIB, 16 * MIB, 64 * MIB] {
+        let path = write_file(&dir, size);
+        group.throughput(Throughput::Bytes(size));
+        for &hash_algorithm in &[HashAlgorithm::Sha224, HashAlgorithm::Blake3] {
+            let id = BenchmarkId::new(hash_algorithm.to_string(), format!("{}MiB", size / MIB));
+            group.bench_with_input(id, &path, |b, path| {
+                b.iter(|| {
+                    (0..filemap::block_count(size))
+                        .map(|block_no| {
+                            filemap::hash_file_block(path, block_no, size, hash_algorithm).unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            });
+        }
+    }
+    group.finish();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn read_block(c: &mut Criterion) {
+    let dir = bench_dir("read");
+    let size = 64 * MIB;
+    let path = write_file(&dir, size);
+    let file_map = file_map(size);
+
+    let mut group = c.benchmark_group("read_block");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(size));
+    group.bench_function("64MiB", |b| {
+        b.iter(|| {
+            for block_no in 0..filemap::block_count(size) {
+                connection::read_block(&path, &file_map, block_no as u32).unwrap();
+            }
+        })
+    });
+    group.finish();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn codec(c: &mut Criterion) {
+    let payload = bytes::Bytes::from(vec![7u8; BLOCK_SIZE]);
+    let mut encoded = BytesMut::new();
+    StCodec::default()
+        .encode(StCommand::block(1, 0, 0, payload.clone()), &mut encoded)
+        .unwrap();
+
+    let mut group = c.benchmark_group("codec");
+    group.throughput(Throughput::Bytes(BLOCK_SIZE as u64));
+    group.bench_function("encode_block", |b| {
+        let mut codec = StCodec::default();
+        b.iter_batched_ref(
+            || BytesMut::with_capacity(encoded.len()),
+            |buf| {
+                codec
+                    .encode(StCommand::block(1, 0, 0, payload.clone()), buf)
+                    .unwrap()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("decode_block", |b| {
+        let mut codec = StCodec::default();
+        b.iter_batched_ref(
+            || encoded.clone(),
+            |buf| codec.decode(buf).unwrap().unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Address on the loopback interface nothing listens on.
+fn free_addr() -> SocketAddr {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Downloads all blocks of a shared file from a node in the same process over TCP.
+fn loopback_transfer(c: &mut Criterion) {
+    let dir = bench_dir("loopback");
+    let size = 64 * MIB;
+    let path = write_file(&dir, size);
+    let server_dir = dir.join("server");
+    let client_dir = dir.join("client");
+    fs::create_dir_all(&server_dir).unwrap();
+    fs::create_dir_all(&client_dir).unwrap();
+
+    let sys = System::new();
+    let (connection, hash, blocks) = sys.block_on(async {
+        let server_db = database::database_manager(&Some(server_dir)).unwrap();
+        let hasher = hasher::start(1);
+        let files = hasher::hash_files(&hasher, vec![(path, "bench.bin")], HashAlgorithm::Blake3)
+            .await
+            .unwrap();
+        let blocks = files[0].0.blocks.len();
+        let hash = database::call(
+            &server_db,
+            RegisterHash {
+                files,
+                valid_to: None,
+                inline_data: Vec::new(),
+                inline_files: Vec::new(),
+                hash_algorithm: HashAlgorithm::Blake3,
+                access: Access::default(),
+                reporter: UserReportHandle::empty(),
+                bundle_format: BundleFormat::Merkle,
+                namespace: None,
+                metadata: Default::default(),
+            },
+        )
+        .await
+        .unwrap()
+        .hash;
+
+        let addr = free_addr();
+        actix::spawn(server::new(server_db, addr).unwrap());
+        let client_db = database::database_manager(&Some(client_dir)).unwrap();
+        let (connection, _) = download::connect(client_db, vec![addr], UserReportHandle::empty())
+            .await
+            .map_err(|errors| errors[0].1.to_string())
+            .unwrap();
+        connection
+            .send(Ask::new(hash, None))
+            .await
+            .unwrap()
+            .unwrap();
+        (connection, hash, blocks)
+    });
+
+    let mut group = c.benchmark_group("loopback_transfer");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(size));
+    group.bench_function("64MiB", |b| {
+        b.iter(|| {
+            sys.block_on(
+                stream::iter(0..blocks)
+                    .map(|block_nr| {
+                        connection.send(GetBlock {
+                            hash,
+                            file_nr: 0,
+                            block_nr: block_nr as u32,
+                        })
+                    })
+                    .buffer_unordered(download::MAX_BLOCKS_IN_FLIGHT)
+                    .for_each(|block| {
+                        block.unwrap().unwrap();
+                        future::ready(())
+                    }),
+            )
+        })
+    });
+    group.finish();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, hash_file, read_block, codec, loopback_transfer);
+criterion_main!(benches);
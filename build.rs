@@ -0,0 +1,13 @@
+fn main() {
+    // The gRPC service is generated only for `with-grpc` builds.
+    #[cfg(feature = "with-grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/hyperg.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/hyperg.proto"], &["proto"])
+            .expect("compiling proto/hyperg.proto");
+    }
+}
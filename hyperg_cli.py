@@ -2,8 +2,10 @@
 from typing import List, Optional, Dict, Any, Tuple
 from pathlib import Path
 from enum import Enum
-from json import dumps
+from json import dumps, loads
+import hashlib
 import os
+import subprocess
 import argparse
 import requests
 
@@ -93,10 +95,19 @@ class HypergClient:
             json = json.copy()
             json["user"] = self._log_context.to_json()
 
-        print(dumps(json))
-        response = requests.post(self._url, json=json)
+        body = dumps(json).encode()
+        print(body.decode())
+        # Checked by nodes run with --rpc_check_body, which send one back.
+        response = requests.post(self._url, data=body, headers={
+            'Content-Type': 'application/json',
+            'X-Content-Sha256': hashlib.sha256(body).hexdigest(),
+        })
         if response.status_code == 400 or response.status_code == 500:
             print('t=', response.text)
+        checksum = response.headers.get('X-Content-Sha256')
+        if checksum is not None \
+                and checksum != hashlib.sha256(response.content).hexdigest():
+            raise IOError('rpc response doesn\'t match its checksum')
         return response.json()
 
     def server_id(self) -> Id:
@@ -130,6 +141,42 @@ class HypergClient:
         return result['files']
 
 
+# Exit codes of failed daemon starts, other failures exit with 1.
+EXIT_CODES = {2: 'config', 3: 'bind', 4: 'database'}
+
+
+class DaemonError(Exception):
+    """The daemon exited before it was ready."""
+
+    def __init__(self, exit_code: int, error: str) -> None:
+        super().__init__('%s (exit code %d, %s)' %
+                         (error, exit_code, EXIT_CODES.get(exit_code, 'other')))
+        self.exit_code = exit_code
+        self.error = error
+
+
+def start_daemon(args: List[str], binary: str = 'hyperg'
+                 ) -> Tuple[subprocess.Popen, Dict[str, Any]]:
+    """
+    Starts the daemon and waits for its handshake on stdout.
+
+    :return: the process and the ready event with nodeId and the transfer, rpc and grpc
+        addresses listened on.
+    """
+    process = subprocess.Popen([binary] + args,
+                               stdout=subprocess.PIPE,
+                               universal_newlines=True)
+    for line in process.stdout:
+        event = loads(line)
+        if event['event'] == 'ready':
+            return process, event
+        if event['event'] == 'failed':
+            process.wait()
+            raise DaemonError(event['exitCode'], event['error'])
+    exit_code = process.wait()
+    raise DaemonError(exit_code, 'exited without handshake')
+
+
 def parse_addr(addr: str) -> Tuple[str, int]:
     """Parsers addres from <ip>[:<port>] format to tuple with (ip, port)"""
     addr_parts = addr.split(':')
@@ -0,0 +1,361 @@
+use crate::encryption::{self, StoredFile};
+use crate::file_name::FileName;
+use crate::filemap::{
+    create_link, has_linked_parent, link_resolves_within, link_stays_within, FileMap, HashAlgorithm,
+};
+use bytes::{Bytes, BytesMut};
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::SinkExt;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Size of chunks sent to the client while exporting.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What sharing an archive does with the symbolic links in it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum SymlinkPolicy {
+    /// Links are shared as the files they lead to, which have to be in the archive.
+    #[default]
+    Follow,
+    /// Links are shared as links, their targets have to be in the archive.
+    Preserve,
+    /// Archives with links are refused.
+    Reject,
+}
+
+/// Symbolic link shared as a link.
+pub struct Link {
+    pub path: PathBuf,
+    pub name: FileName,
+    pub target: String,
+}
+
+impl Link {
+    pub fn file_map(self, hash_algorithm: HashAlgorithm) -> (FileMap, PathBuf) {
+        let file_map = FileMap {
+            file_name: self.name,
+            file_size: 0,
+            blocks: Vec::new(),
+            hash_algorithm,
+            mode: None,
+            mtime: None,
+            link: Some(self.target),
+        };
+        (file_map, self.path)
+    }
+}
+
+/// Files and links of an unpacked archive, with their names relative to its root.
+#[derive(Default)]
+pub struct Unpacked {
+    pub files: Vec<(PathBuf, FileName)>,
+    pub links: Vec<Link>,
+    /// Empty directories
+    pub dirs: Vec<(PathBuf, FileName)>,
+}
+
+/// `Write` adapter sending data to a response body stream.
+struct ChannelWriter {
+    tx: mpsc::Sender<Bytes>,
+    buf: BytesMut,
+}
+
+impl ChannelWriter {
+    fn send_buf(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = self.buf.split().freeze();
+        block_on(self.tx.send(chunk)).map_err(|_| receiver_gone())
+    }
+}
+
+fn receiver_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "archive receiver gone")
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_buf()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buf()
+    }
+}
+
+/// Streams a tar archive of `files` (source path, name in archive).
+///
+/// The archive is built on a separate thread, one chunk ahead of the reader.
+pub fn export_tar(files: Vec<(PathBuf, FileName)>) -> mpsc::Receiver<Bytes> {
+    let (tx, rx) = mpsc::channel(1);
+
+    std::thread::spawn(move || {
+        let writer = ChannelWriter {
+            tx,
+            buf: BytesMut::with_capacity(CHUNK_SIZE),
+        };
+        let mut builder = tar::Builder::new(writer);
+        let result = files
+            .iter()
+            .try_for_each(|(path, name)| append_file(&mut builder, path, name))
+            .and_then(|()| builder.into_inner())
+            .and_then(|mut writer| writer.flush());
+        if let Err(e) = result {
+            log::error!("archive export failed: {}", e);
+        }
+    });
+
+    rx
+}
+
+/// Appends the plain content of a possibly encrypted file.
+fn append_file<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &FileName,
+) -> io::Result<()> {
+    if name.is_dir() {
+        return builder.append_dir(name.as_path(), path);
+    }
+    let file = StoredFile::open(path)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&fs::metadata(path)?);
+    header.set_size(file.len()?);
+    builder.append_data(&mut header, name.as_path(), file)
+}
+
+/// Unpacks a tar or zip archive into `dest`, returning the unpacked files
+/// with their names relative to `dest`.
+pub fn unpack(archive: &Path, dest: &Path, symlinks: SymlinkPolicy) -> io::Result<Unpacked> {
+    let mut file = fs::File::open(archive)?;
+    let mut magic = [0u8; 4];
+    let is_zip = file.read_exact(&mut magic).is_ok() && magic == *b"PK\x03\x04";
+    file.seek(SeekFrom::Start(0))?;
+
+    fs::create_dir_all(dest)?;
+    if is_zip {
+        unpack_zip(file, dest)?;
+    } else if encryption::is_enabled() {
+        unpack_tar_encrypted(file, dest)?;
+    } else {
+        tar::Archive::new(file).unpack(dest)?;
+    }
+
+    let real_root = fs::canonicalize(dest)?;
+    let mut collector = Collector {
+        root: dest,
+        visited: vec![real_root.clone()],
+        real_root,
+        symlinks,
+        unpacked: Unpacked::default(),
+    };
+    collector.collect(dest)?;
+    let mut unpacked = collector.unpacked;
+    unpacked.files.sort_by(|a, b| a.1.cmp(&b.1));
+    unpacked.links.sort_by(|a, b| a.name.cmp(&b.name));
+    unpacked.dirs.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(unpacked)
+}
+
+fn unpack_zip(file: fs::File, dest: &Path) -> io::Result<()> {
+    let mut zip = zip::ZipArchive::new(file)?;
+    let mut links = Vec::new();
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let out_path = match entry.enclosed_name() {
+            Some(name) => dest.join(name),
+            None => {
+                log::warn!("skipping unsafe zip entry {}", entry.name());
+                continue;
+            }
+        };
+        check_parent(dest, &out_path)?;
+        if entry.is_dir() {
+            fs::create_dir_all(&out_path)?;
+        } else if entry.unix_mode().map(|mode| mode & 0o170000) == Some(0o120000) {
+            let mut target = String::new();
+            entry.read_to_string(&mut target)?;
+            links.push((out_path, target));
+        } else {
+            if let Some(parent) = out_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            io::copy(&mut entry, &mut StoredFile::create(&out_path)?)?;
+        }
+    }
+    create_links(dest, links)
+}
+
+/// Unpacks regular files, directories and symbolic links, so that every file is written
+/// encrypted.
+fn unpack_tar_encrypted(file: fs::File, dest: &Path) -> io::Result<()> {
+    let mut archive = tar::Archive::new(file);
+    let mut links = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        if !name
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            log::warn!("skipping unsafe tar entry {}", name.display());
+            continue;
+        }
+        let out_path = dest.join(&name);
+        check_parent(dest, &out_path)?;
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => fs::create_dir_all(&out_path)?,
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                if let Some(parent) = out_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                io::copy(&mut entry, &mut StoredFile::create(&out_path)?)?;
+            }
+            tar::EntryType::Symlink => {
+                let target = entry.link_name()?.ok_or_else(|| {
+                    invalid_data(format!("tar link {} has no target", name.display()))
+                })?;
+                links.push((out_path, target.to_string_lossy().into_owned()));
+            }
+            _ => log::warn!("skipping tar entry {} of unsupported type", name.display()),
+        }
+    }
+    create_links(dest, links)
+}
+
+/// Refuses entries in directories that are symbolic links, which would be written outside
+/// of the directory the archive is unpacked to.
+fn check_parent(dest: &Path, path: &Path) -> io::Result<()> {
+    if has_linked_parent(dest, path) {
+        return Err(invalid_data(format!(
+            "archive entry {} is under a symbolic link",
+            path.strip_prefix(dest).unwrap_or(path).display()
+        )));
+    }
+    Ok(())
+}
+
+/// Creates the symbolic links of an archive once its other entries are unpacked, so that
+/// none is written through them. Links leading outside `dest`, also through other links,
+/// are refused.
+fn create_links(dest: &Path, links: Vec<(PathBuf, String)>) -> io::Result<()> {
+    let outside = |path: &Path| {
+        invalid_data(format!(
+            "symbolic link {} leads outside the archive",
+            path.strip_prefix(dest).unwrap_or(path).display()
+        ))
+    };
+    for (path, target) in &links {
+        let name = FileName::from_relative_path(path.strip_prefix(dest).unwrap_or(path));
+        if !link_stays_within(&name, target) {
+            return Err(outside(path));
+        }
+        check_parent(dest, path)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        create_link(path, target)?;
+    }
+    for (path, _) in &links {
+        if !link_resolves_within(dest, path)? {
+            return Err(outside(path));
+        }
+    }
+    Ok(())
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct Collector<'a> {
+    root: &'a Path,
+    real_root: PathBuf,
+    /// Directories being collected, to detect links leading back to them
+    visited: Vec<PathBuf>,
+    symlinks: SymlinkPolicy,
+    unpacked: Unpacked,
+}
+
+impl Collector<'_> {
+    fn collect(&mut self, dir: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let name = FileName::from_relative_path(path.strip_prefix(self.root).unwrap_or(&path));
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                self.collect_link(path, name)?;
+            } else if file_type.is_dir() {
+                self.collect_dir(path, name)?;
+            } else if file_type.is_file() {
+                self.unpacked.files.push((path, name));
+            }
+        }
+        Ok(())
+    }
+
+    fn collect_dir(&mut self, path: PathBuf, name: FileName) -> io::Result<()> {
+        let real_path = fs::canonicalize(&path)?;
+        if self.visited.contains(&real_path) {
+            return Err(invalid_data(format!("link loop at {}", name)));
+        }
+        if fs::read_dir(&path)?.next().is_none() {
+            self.unpacked.dirs.push((path, name));
+            return Ok(());
+        }
+        self.visited.push(real_path);
+        let result = self.collect(&path);
+        self.visited.pop();
+        result
+    }
+
+    fn collect_link(&mut self, path: PathBuf, name: FileName) -> io::Result<()> {
+        match self.symlinks {
+            SymlinkPolicy::Reject => {
+                Err(invalid_data(format!("symbolic link {} not allowed", name)))
+            }
+            SymlinkPolicy::Preserve => {
+                let target = fs::read_link(&path)?.to_string_lossy().into_owned();
+                if !link_stays_within(&name, &target) || !link_resolves_within(self.root, &path)? {
+                    return Err(invalid_data(format!(
+                        "symbolic link {} leads outside the archive",
+                        name
+                    )));
+                }
+                self.unpacked.links.push(Link { path, name, target });
+                Ok(())
+            }
+            SymlinkPolicy::Follow => {
+                let target = match fs::canonicalize(&path) {
+                    Ok(target) if target.starts_with(&self.real_root) => target,
+                    _ => {
+                        return Err(invalid_data(format!(
+                            "symbolic link {} leads outside the archive",
+                            name
+                        )))
+                    }
+                };
+                if target.is_dir() {
+                    self.collect_dir(path, name)
+                } else {
+                    if target.is_file() {
+                        self.unpacked.files.push((target, name));
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+}
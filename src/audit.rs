@@ -0,0 +1,163 @@
+//! Audit log of blocks served to peers.
+//!
+//! With `--audit_log <file>` every block sent is recorded as a JSON line with the peer,
+//! the block and the time. The file is rotated once it reaches `--audit_log_max_mb`,
+//! keeping `AUDIT_LOG_FILES` old files, and `--audit_sample <n>` records only every n-th
+//! block.
+use crate::codec::{hash_to_hex, GetBlock};
+use crate::error::Error;
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Rotated files kept next to the current one, as `<file>.1` (newest) to `<file>.5`.
+const AUDIT_LOG_FILES: usize = 5;
+
+struct AuditLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    sample: u64,
+    /// Blocks served since the last recorded one.
+    skipped: u64,
+}
+
+static AUDIT_LOG: OnceLock<Mutex<AuditLog>> = OnceLock::new();
+
+/// Starts recording blocks served to `path`, rotating it at `max_size` bytes (0 never)
+/// and recording every `sample`-th block.
+pub fn enable(path: PathBuf, max_size: u64, sample: u64) -> Result<(), Error> {
+    let log = AuditLog::open(path, max_size, sample)?;
+    let _ = AUDIT_LOG.set(Mutex::new(log));
+    Ok(())
+}
+
+/// Records a block sent to the peer at `peer_addr`.
+pub fn served(peer_id: Option<u128>, peer_addr: SocketAddr, get_block: &GetBlock, len: usize) {
+    let log = match AUDIT_LOG.get() {
+        Some(log) => log,
+        None => return,
+    };
+    let mut log = match log.lock() {
+        Ok(log) => log,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if let Err(e) = log.record(peer_id, peer_addr, get_block, len) {
+        log::error!("unable to write audit log {}: {}", log.path.display(), e);
+    }
+}
+
+impl AuditLog {
+    fn open(path: PathBuf, max_size: u64, sample: u64) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            path,
+            file,
+            size,
+            max_size,
+            sample: sample.max(1),
+            skipped: 0,
+        })
+    }
+
+    fn record(
+        &mut self,
+        peer_id: Option<u128>,
+        peer_addr: SocketAddr,
+        get_block: &GetBlock,
+        len: usize,
+    ) -> Result<(), Error> {
+        self.skipped += 1;
+        if self.skipped < self.sample {
+            return Ok(());
+        }
+        self.skipped = 0;
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut line = serde_json::to_vec(&json!({
+            "ts": ts,
+            "peerId": peer_id.map(hash_to_hex),
+            "peerAddr": peer_addr.to_string(),
+            "hash": hash_to_hex(get_block.hash),
+            "fileNr": get_block.file_nr,
+            "blockNr": get_block.block_nr,
+            "size": len,
+        }))?;
+        line.push(b'\n');
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        // A single write keeps lines whole when the daemon is killed.
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        for n in (1..AUDIT_LOG_FILES).rev() {
+            let from = rotated(&self.path, n);
+            if from.exists() {
+                fs::rename(from, rotated(&self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(&self.path, 1))?;
+        *self = AuditLog::open(self.path.clone(), self.max_size, self.sample)?;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_audit_log() {
+        let dir = std::env::temp_dir().join(format!("hyperg-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let peer_addr = "127.0.0.1:3282".parse().unwrap();
+        let mut log = AuditLog::open(path.clone(), 1000, 2).unwrap();
+        for block_nr in 0..100 {
+            let get_block = GetBlock {
+                hash: 1,
+                file_nr: 0,
+                block_nr,
+            };
+            log.record(Some(2), peer_addr, &get_block, 10).unwrap();
+        }
+
+        let current = fs::read_to_string(&path).unwrap();
+        let first: serde_json::Value =
+            serde_json::from_str(current.lines().next().unwrap()).unwrap();
+        assert_eq!(first["peerId"], format!("{:032x}", 2));
+        assert_eq!(first["peerAddr"], "127.0.0.1:3282");
+        assert_eq!(first["blockNr"].as_u64().unwrap() % 2, 1);
+        assert!(current.len() <= 1000);
+
+        let rotated_lines: usize = (1..=AUDIT_LOG_FILES)
+            .map(|n| {
+                fs::read_to_string(rotated(&path, n))
+                    .unwrap()
+                    .lines()
+                    .count()
+            })
+            .sum();
+        assert!(rotated_lines + current.lines().count() < 50);
+        assert!(!rotated(&path, AUDIT_LOG_FILES + 1).exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
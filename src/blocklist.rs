@@ -0,0 +1,110 @@
+//! Hashes of resources the node refuses to serve.
+//!
+//! Asks and block requests for a listed hash are refused with `blocked` even when the
+//! resource is shared, so operators can stop distributing it at once. The list is kept
+//! in the file given with `--blocklist` and changed with the `blocklist` command.
+use crate::codec::hash_to_hex;
+use crate::error::Error;
+use crate::hash_encoding;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+#[derive(Default)]
+struct Blocklist {
+    path: Option<PathBuf>,
+    hashes: BTreeSet<u128>,
+}
+
+static BLOCKLIST: OnceLock<Mutex<Blocklist>> = OnceLock::new();
+
+fn blocklist() -> std::sync::MutexGuard<'static, Blocklist> {
+    match BLOCKLIST.get_or_init(Default::default).lock() {
+        Ok(blocklist) => blocklist,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Loads the hashes listed in the JSON array at `path`, a missing file lists none.
+/// Changes are saved to it.
+pub fn load(path: PathBuf) -> Result<(), Error> {
+    let hashes: Vec<String> = match fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let hashes = hashes
+        .iter()
+        .map(|hash| hash_encoding::parse(hash))
+        .collect::<Result<_, _>>()?;
+    *blocklist() = Blocklist {
+        path: Some(path),
+        hashes,
+    };
+    Ok(())
+}
+
+pub fn is_blocked(hash: u128) -> bool {
+    blocklist().hashes.contains(&hash)
+}
+
+/// Adds and removes hashes, returning the ones listed after.
+pub fn update(add: &[u128], remove: &[u128]) -> Result<Vec<u128>, Error> {
+    let mut blocklist = blocklist();
+    let before = blocklist.hashes.clone();
+    for hash in add {
+        if blocklist.hashes.insert(*hash) {
+            log::warn!("blocked {:032x}", hash);
+        }
+    }
+    for hash in remove {
+        if blocklist.hashes.remove(hash) {
+            log::info!("unblocked {:032x}", hash);
+        }
+    }
+    if blocklist.hashes != before {
+        if let Err(e) = blocklist.save() {
+            blocklist.hashes = before;
+            return Err(e);
+        }
+    }
+    Ok(blocklist.hashes.iter().copied().collect())
+}
+
+impl Blocklist {
+    fn save(&self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let hashes: Vec<String> = self.hashes.iter().map(|&hash| hash_to_hex(hash)).collect();
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&hashes)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blocklist() {
+        let path =
+            std::env::temp_dir().join(format!("hyperg-blocklist-{}.json", std::process::id()));
+        assert_eq!(update(&[1], &[]).unwrap(), vec![1]);
+        assert!(is_blocked(1));
+
+        load(path.clone()).unwrap();
+        assert!(!is_blocked(1));
+        assert_eq!(update(&[3, 2], &[]).unwrap(), vec![2, 3]);
+        assert_eq!(update(&[], &[3, 4]).unwrap(), vec![2]);
+
+        // The list survives a restart.
+        load(path.clone()).unwrap();
+        assert!(is_blocked(2) && !is_blocked(3));
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,645 @@
+use crate::client::RpcClient;
+use crate::command::{
+    AddressSpec, AvailabilityResult, BatchResource, BatchStatus, BlocklistResult,
+    CaseCollisionReport, Command, DownloadBatchResult, DownloadResult, HashResult, ModeResult,
+    PeerInfo, PrivacyResult, ReplicateResult, StatusResult, UploadResult,
+};
+use crate::download::{FsyncPolicy, LocalPolicy, VerifyPolicy};
+use crate::error::Error;
+use crate::file_name::{CasePolicy, NamePolicy};
+use crate::hash_encoding;
+use crate::mode::NodeMode;
+use crate::user_report::Privacy;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use structopt::StructOpt;
+
+const DEFAULT_TRANSFER_PORT: u16 = 3282;
+
+/// Client commands executed against an already running daemon.
+#[derive(StructOpt, Clone, Debug)]
+pub enum ClientCommand {
+    /// Shares files and prints the resource hash
+    #[structopt(name = "share")]
+    Share {
+        /// Files to share
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        paths: Vec<PathBuf>,
+
+        /// Share lifetime in seconds
+        #[structopt(long)]
+        timeout: Option<f64>,
+
+        /// Access token downloaders have to present
+        #[structopt(long)]
+        token: Option<String>,
+
+        /// Node id of a peer allowed to download
+        #[structopt(long = "allow_peer")]
+        allowed_peers: Vec<String>,
+
+        /// Random key of 64 hex digits to encrypt the files with, downloaders need the same
+        /// one
+        #[structopt(long)]
+        encryption_key: Option<String>,
+
+        /// Namespace the resource is listed and managed in
+        #[structopt(long)]
+        namespace: Option<String>,
+
+        /// keep, error or rename file names differing only in case, defaults to error on
+        /// Windows and macOS and keep elsewhere
+        #[structopt(long)]
+        case_policy: Option<CasePolicy>,
+
+        /// Metadata of the resource in <key>=<value> format
+        #[structopt(long = "meta", parse(try_from_str = "parse_meta"))]
+        metadata: Vec<(String, String)>,
+
+        /// Expiry in seconds since the unix epoch, instead of the one of --timeout
+        #[structopt(long)]
+        valid_to: Option<u64>,
+    },
+
+    /// Prints the resource hash sharing the files would give, without sharing them
+    #[structopt(name = "hash")]
+    Hash {
+        /// Files to hash
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        paths: Vec<PathBuf>,
+
+        /// sha224 or blake3, defaults to the daemon's --hash_algorithm
+        #[structopt(long)]
+        hash_algorithm: Option<String>,
+    },
+
+    /// Downloads a resource from peers
+    #[structopt(name = "fetch")]
+    Fetch {
+        /// Resource hashes, fetched into <dest>/<hash> over shared connections when more
+        /// than one is given
+        #[structopt(raw(required = "true"))]
+        hashes: Vec<String>,
+
+        /// Peer address in <host>[:<port>] format, <host>[:<port>]#<node id> of a peer
+        /// that has to present the node id, <node id>@<host>[:<port>] of a relay,
+        /// or http://<host>/<path> of a server providing blocks
+        #[structopt(long = "peer")]
+        peers: Vec<String>,
+
+        /// Destination directory
+        #[structopt(long, parse(from_os_str))]
+        dest: PathBuf,
+
+        /// Access token of the resource
+        #[structopt(long)]
+        token: Option<String>,
+
+        /// Key of 64 hex digits the resource was encrypted with
+        #[structopt(long)]
+        encryption_key: Option<String>,
+
+        /// Node id that has to sign the file maps
+        #[structopt(long)]
+        signer: Option<String>,
+
+        /// Keep sharing the resource once downloaded
+        #[structopt(long)]
+        share: bool,
+
+        /// Accept peers presenting node ids other than the pinned ones
+        #[structopt(long)]
+        repin: bool,
+
+        /// Fail unless the destination directory exists
+        #[structopt(long)]
+        no_create_dest: bool,
+
+        /// Leave the modification times of downloaded files at the download time
+        #[structopt(long)]
+        no_restore_mtime: bool,
+
+        /// Store files under ASCII names valid on all systems
+        #[structopt(long)]
+        portable_names: bool,
+
+        /// keep, error or rename file names differing only in case, like in share
+        #[structopt(long)]
+        case_policy: Option<CasePolicy>,
+
+        /// first copies resources and files the node shares already, only copies without
+        /// asking peers, never always fetches from peers
+        #[structopt(long)]
+        local: Option<LocalPolicy>,
+
+        /// full hashes every block, endOfFile whole files once complete, off nothing (for
+        /// trusted peers)
+        #[structopt(long)]
+        verify: Option<VerifyPolicy>,
+
+        /// Bytes of a file buffered before writing them, 0 writes every block as it arrives
+        #[structopt(long)]
+        write_buffer: Option<usize>,
+
+        /// file syncs each file once written, transfer all files once downloaded, off none
+        #[structopt(long)]
+        fsync: Option<FsyncPolicy>,
+    },
+
+    /// Asks peers for a resource without downloading it
+    #[structopt(name = "check")]
+    Check {
+        /// Resource hash
+        hash: String,
+
+        /// Peer address, like in fetch
+        #[structopt(long = "peer", raw(required = "true"))]
+        peers: Vec<String>,
+
+        /// Seconds each peer has to answer
+        #[structopt(long)]
+        timeout: Option<f64>,
+
+        /// Access token of the resource
+        #[structopt(long)]
+        token: Option<String>,
+
+        /// Node id file maps have to be signed by
+        #[structopt(long)]
+        signer: Option<String>,
+    },
+
+    /// Makes other nodes download and share a resource from this node
+    #[structopt(name = "replicate")]
+    Replicate {
+        /// Resource hash
+        hash: String,
+
+        /// RPC address of a node in <ip>:<port> format
+        #[structopt(long = "target", raw(required = "true"))]
+        targets: Vec<String>,
+
+        /// Download directory on the nodes
+        #[structopt(long, parse(from_os_str))]
+        dest: PathBuf,
+    },
+
+    /// Lists shared resources
+    #[structopt(name = "ls")]
+    Ls,
+
+    /// Stops sharing a resource
+    #[structopt(name = "rm")]
+    Rm {
+        /// Resource hash
+        hash: String,
+    },
+
+    /// Prints the runtime mode or switches it
+    #[structopt(name = "mode")]
+    Mode {
+        /// normal, readOnly or maintenance
+        mode: Option<NodeMode>,
+    },
+
+    /// Prints the telemetry privacy level or switches it
+    #[structopt(name = "privacy")]
+    Privacy {
+        /// off, errors, breadcrumbs or full
+        level: Option<Privacy>,
+    },
+
+    /// Prints the hashes the node refuses to serve, changing them first
+    #[structopt(name = "blocklist")]
+    Blocklist {
+        /// Hash to block, can be repeated
+        #[structopt(long)]
+        add: Vec<String>,
+        /// Hash to unblock, can be repeated
+        #[structopt(long)]
+        remove: Vec<String>,
+    },
+}
+
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_owned())
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
+
+/// Absolute paths of `paths` with the names they are shared under.
+fn named_files(paths: Vec<PathBuf>) -> Result<HashMap<PathBuf, String>, Error> {
+    let mut files = HashMap::new();
+    for path in paths {
+        let path = fs::canonicalize(&path)?;
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| Error::InvalidArgument(format!("not a file: {}", path.display())))?;
+        files.insert(path, file_name);
+    }
+    Ok(files)
+}
+
+fn parse_peer(peer: &str) -> Result<PeerInfo, Error> {
+    if peer.contains("://") {
+        return Ok(PeerInfo::Http(peer.to_string()));
+    }
+    if let Some(idx) = peer.rfind('#') {
+        return match parse_peer(&peer[..idx])? {
+            PeerInfo::TCP(host, port) => {
+                Ok(PeerInfo::Node(host, port, peer[idx + 1..].to_string()))
+            }
+            PeerInfo::Node(..) | PeerInfo::Relay(..) | PeerInfo::Http(..) => {
+                Err(Error::InvalidArgument(format!("invalid peer: {}", peer)))
+            }
+        };
+    }
+    if let Some(idx) = peer.find('@') {
+        return match parse_peer(&peer[idx + 1..])? {
+            PeerInfo::TCP(host, port) => Ok(PeerInfo::Relay(host, port, peer[..idx].to_string())),
+            PeerInfo::Node(..) | PeerInfo::Relay(..) | PeerInfo::Http(..) => {
+                Err(Error::InvalidArgument(format!("invalid peer: {}", peer)))
+            }
+        };
+    }
+    let (host, port) = if peer.starts_with('[') {
+        // [ipv6]:port
+        match peer.find("]:") {
+            Some(idx) => (&peer[1..idx], Some(&peer[idx + 2..])),
+            None => (peer.trim_start_matches('[').trim_end_matches(']'), None),
+        }
+    } else if peer.matches(':').count() == 1 {
+        let mut it = peer.splitn(2, ':');
+        (it.next().unwrap_or_default(), it.next())
+    } else {
+        (peer, None)
+    };
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .map_err(|_| Error::InvalidArgument(format!("invalid peer port: {}", peer)))?,
+        None => DEFAULT_TRANSFER_PORT,
+    };
+    Ok(PeerInfo::TCP(host.to_string(), port))
+}
+
+fn parse_meta(src: &str) -> Result<(String, String), String> {
+    match src.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!(
+            "invalid metadata: {} (expected <key>=<value>)",
+            src
+        )),
+    }
+}
+
+/// Names differing only in case go to stderr, stdout stays hashes and paths.
+fn print_case_collisions(collisions: &[CaseCollisionReport]) {
+    for collision in collisions {
+        match &collision.renamed_to {
+            Some(renamed_to) => eprintln!(
+                "{} differs only in case from {}, renamed to {}",
+                collision.file_name, collision.collides_with, renamed_to
+            ),
+            None => eprintln!(
+                "{} differs only in case from {}",
+                collision.file_name, collision.collides_with
+            ),
+        }
+    }
+}
+
+pub fn run(command: ClientCommand, rpc_addr: SocketAddr) -> Result<(), Error> {
+    let client = RpcClient::new(rpc_addr);
+
+    match command {
+        ClientCommand::Share {
+            paths,
+            timeout,
+            token,
+            allowed_peers,
+            encryption_key,
+            namespace,
+            case_policy,
+            metadata,
+            valid_to,
+        } => {
+            let result: UploadResult = client.call(&Command::Upload {
+                files: Some(named_files(paths)?),
+                timeout,
+                hash: None,
+                user: None,
+                token,
+                allowed_peers: Some(allowed_peers).filter(|peers| !peers.is_empty()),
+                encryption_key,
+                namespace,
+                case_policy,
+                metadata: Some(metadata.into_iter().collect())
+                    .filter(|m: &BTreeMap<_, _>| !m.is_empty()),
+                valid_to,
+                file_options: None,
+                id: None,
+            })?;
+            print_case_collisions(&result.case_collisions);
+            println!("{}", hash_encoding::reencode(&result.hash));
+        }
+        ClientCommand::Hash {
+            paths,
+            hash_algorithm,
+        } => {
+            let result: HashResult = client.call(&Command::Hash {
+                files: named_files(paths)?,
+                hash_algorithm,
+            })?;
+            println!("{}", hash_encoding::reencode(&result.hash));
+        }
+        ClientCommand::Fetch {
+            mut hashes,
+            peers,
+            dest,
+            token,
+            encryption_key,
+            signer,
+            share,
+            repin,
+            no_create_dest,
+            no_restore_mtime,
+            portable_names,
+            case_policy,
+            local,
+            verify,
+            write_buffer,
+            fsync,
+        } => {
+            let peers = peers
+                .iter()
+                .map(|peer| parse_peer(peer))
+                .collect::<Result<Vec<_>, _>>()?;
+            if !no_create_dest {
+                fs::create_dir_all(&dest)?;
+            }
+            let file_names = if portable_names {
+                NamePolicy::Portable
+            } else {
+                NamePolicy::Keep
+            };
+            if hashes.len() > 1 {
+                let dest = absolute(&dest)?;
+                let result: DownloadBatchResult = client.call(&Command::DownloadBatch {
+                    resources: hashes
+                        .into_iter()
+                        .map(|hash| BatchResource {
+                            dest: dest.join(&hash),
+                            hash,
+                            token: token.clone(),
+                            encryption_key: encryption_key.clone(),
+                        })
+                        .collect(),
+                    peers,
+                    timeout: None,
+                    user: None,
+                    concurrency: None,
+                    signer,
+                    share_after_download: share,
+                    repin,
+                    create_dest: Some(!no_create_dest),
+                    restore_mtime: Some(!no_restore_mtime),
+                    file_names,
+                    case_policy,
+                    local,
+                    verify,
+                    write_buffer,
+                    fsync,
+                })?;
+                for resource in &result.resources {
+                    match &resource.result {
+                        Some(result) if resource.status == BatchStatus::Done => {
+                            print_case_collisions(&result.case_collisions);
+                            for file in &result.files {
+                                println!("{}", file.display());
+                            }
+                        }
+                        _ => eprintln!(
+                            "{}: {}",
+                            resource.hash,
+                            resource.error.as_deref().unwrap_or("failed")
+                        ),
+                    }
+                }
+                if result.failed > 0 {
+                    return Err(Error::InvalidArgument(format!(
+                        "{} of {} resources failed",
+                        result.failed,
+                        result.resources.len()
+                    )));
+                }
+                return Ok(());
+            }
+            let result: DownloadResult = client.call(&Command::Download {
+                hash: hashes.remove(0),
+                dest: absolute(&dest)?,
+                peers,
+                timeout: None,
+                user: None,
+                token,
+                encryption_key,
+                signer,
+                share_after_download: share,
+                repin,
+                create_dest: Some(!no_create_dest),
+                restore_mtime: Some(!no_restore_mtime),
+                file_names,
+                case_policy,
+                local,
+                verify,
+                write_buffer,
+                fsync,
+                size: None,
+            })?;
+            print_case_collisions(&result.case_collisions);
+            for file in result.files {
+                println!("{}", file.display());
+            }
+        }
+        ClientCommand::Check {
+            hash,
+            peers,
+            timeout,
+            token,
+            signer,
+        } => {
+            let peers = peers
+                .iter()
+                .map(|peer| parse_peer(peer))
+                .collect::<Result<Vec<_>, _>>()?;
+            let result: AvailabilityResult = client.call(&Command::CheckAvailability {
+                hash,
+                peers,
+                timeout,
+                token,
+                signer,
+            })?;
+            for peer in &result.peers {
+                println!(
+                    "{}  ok  {} files  {} bytes",
+                    peer.peer, peer.files, peer.size
+                );
+            }
+            for failure in &result.failures {
+                println!(
+                    "{}  {:?}: {}",
+                    failure.peer, failure.reason, failure.message
+                );
+            }
+            if !result.available {
+                return Err(Error::InvalidArgument(format!(
+                    "resource {} not available",
+                    hash_encoding::reencode(&result.hash)
+                )));
+            }
+        }
+        ClientCommand::Replicate {
+            hash,
+            targets,
+            dest,
+        } => {
+            let result: ReplicateResult = client.call(&Command::Replicate {
+                hash,
+                targets,
+                dest,
+                peers: None,
+            })?;
+            let mut failed = false;
+            for status in result.targets {
+                match status.error {
+                    Some(error) => {
+                        failed = true;
+                        println!("{}  failed: {}", status.target, error)
+                    }
+                    None => println!("{}  ok", status.target),
+                }
+            }
+            if failed {
+                return Err(Error::InvalidArgument("replication failed".into()));
+            }
+        }
+        ClientCommand::Ls => {
+            let resources: Vec<serde_json::Value> = client.get("/resources")?;
+            println!("{:32}  {:>5}  {:>12}  VALID TO", "HASH", "FILES", "SIZE");
+            for resource in resources {
+                println!(
+                    "{:32}  {:>5}  {:>12}  {}",
+                    hash_encoding::reencode(resource["hash"].as_str().unwrap_or("")),
+                    resource["files"].to_string(),
+                    resource["totalSize"].to_string(),
+                    resource["validTo"]
+                );
+            }
+        }
+        ClientCommand::Rm { hash } => {
+            client.delete(&format!("/resources/{}", hash))?;
+        }
+        ClientCommand::Mode { mode } => {
+            let result: ModeResult = client.call(&Command::Mode { mode })?;
+            println!("{}", result.mode);
+        }
+        ClientCommand::Privacy { level } => {
+            let result: PrivacyResult = client.call(&Command::Privacy { level })?;
+            println!("{}", result.level);
+        }
+        ClientCommand::Blocklist { add, remove } => {
+            let result: BlocklistResult = client.call(&Command::Blocklist { add, remove })?;
+            for hash in result.hashes {
+                println!("{}", hash);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn status(rpc_addr: SocketAddr, json: bool) -> Result<(), Error> {
+    let status: StatusResult = RpcClient::new(rpc_addr).get("/status")?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    let AddressSpec::TCP { address, port } = &status.addresses;
+    println!("{:20} {}", "id", hash_encoding::reencode(&status.id));
+    println!("{:20} {}", "version", status.version);
+    println!("{:20} {}:{}", "address", address, port);
+    println!("{:20} {}", "shares", status.shares);
+    println!("{:20} {}", "active downloads", status.active_downloads);
+    println!("{:20} {}", "active connections", status.active_connections);
+    println!("{:20} {}", "cache usage", status.cache_usage);
+    println!("{:20} {}", "db queue", status.db_queue);
+    println!("{:20} {}", "mode", status.mode);
+    println!(
+        "{:20} {}",
+        "refused connections", status.refused_connections
+    );
+    println!("{:20} {}", "banned addresses", status.banned_addresses);
+    for (name, count) in &status.actor_restarts {
+        println!("{:20} {}", format!("{} restarts", name), count);
+    }
+    for quarantined in &status.quarantined {
+        println!(
+            "{:20} {} {}",
+            "quarantined",
+            hash_encoding::reencode(&quarantined.hash),
+            quarantined.reason
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_peer() {
+        match parse_peer("10.30.10.219").unwrap() {
+            PeerInfo::TCP(host, port) => {
+                assert_eq!(host, "10.30.10.219");
+                assert_eq!(port, 3282);
+            }
+            _ => panic!("expected direct peer"),
+        }
+        match parse_peer("[::1]:3000").unwrap() {
+            PeerInfo::TCP(host, port) => {
+                assert_eq!(host, "::1");
+                assert_eq!(port, 3000);
+            }
+            _ => panic!("expected direct peer"),
+        }
+        match parse_peer("0123abcd@10.0.0.1").unwrap() {
+            PeerInfo::Relay(host, port, node_id) => {
+                assert_eq!(host, "10.0.0.1");
+                assert_eq!(port, 3282);
+                assert_eq!(node_id, "0123abcd");
+            }
+            _ => panic!("expected relayed peer"),
+        }
+        match parse_peer("10.0.0.1:3000#0123abcd").unwrap() {
+            PeerInfo::Node(host, port, node_id) => {
+                assert_eq!(host, "10.0.0.1");
+                assert_eq!(port, 3000);
+                assert_eq!(node_id, "0123abcd");
+            }
+            _ => panic!("expected identified peer"),
+        }
+        assert!(parse_peer("0123abcd@10.0.0.1#0123abcd").is_err());
+        match parse_peer("http://user@example.com/data/").unwrap() {
+            PeerInfo::Http(url) => assert_eq!(url, "http://user@example.com/data/"),
+            _ => panic!("expected http source"),
+        }
+        assert!(parse_peer("127.0.0.1:port").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use crate::command::{Command, ErrorResult};
+use crate::error::Error;
+use serde::de::DeserializeOwned;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Minimal blocking HTTP client for the local RPC API.
+pub struct RpcClient {
+    addr: SocketAddr,
+    request_id: Option<String>,
+}
+
+impl RpcClient {
+    pub fn new(addr: SocketAddr) -> Self {
+        RpcClient {
+            addr,
+            request_id: None,
+        }
+    }
+
+    /// Sends `request_id` as the correlation id of the requests.
+    pub fn with_request_id(mut self, request_id: String) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+
+    pub fn call<T: DeserializeOwned>(&self, command: &Command) -> Result<T, Error> {
+        let body = serde_json::to_vec(command)?;
+        let bytes = self.request("POST", "/api", Some(&body))?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        let bytes = self.request("GET", path, None)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    pub fn delete(&self, path: &str) -> Result<(), Error> {
+        self.request("DELETE", path, None).map(|_| ())
+    }
+
+    fn request(&self, method: &str, path: &str, body: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+        let mut stream = TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT)?;
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            method, path, self.addr
+        );
+        if let Some(request_id) = &self.request_id {
+            head.push_str(&format!("X-Request-Id: {}\r\n", request_id));
+        }
+        if let Some(body) = body {
+            head.push_str("Content-Type: application/json\r\n");
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+            head.push_str(&format!(
+                "X-Content-Sha256: {}\r\n",
+                crate::rpc_body::checksum(body)
+            ));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        if let Some(body) = body {
+            stream.write_all(body)?;
+        }
+        stream.flush()?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let (status, body) = parse_response(&response)?;
+        if status >= 300 {
+            return Err(error_of(status, &body));
+        }
+        Ok(body)
+    }
+}
+
+/// Error of an RPC answer with the HTTP `status`, with the stable code when the body is an
+/// `ErrorResult`.
+pub fn error_of(status: u16, body: &[u8]) -> Error {
+    match serde_json::from_slice::<ErrorResult>(body) {
+        Ok(e) => Error::Rpc {
+            status,
+            code: Some(e.code),
+            message: e.error,
+        },
+        Err(_) => Error::Rpc {
+            status,
+            code: None,
+            message: String::from_utf8_lossy(body).into_owned(),
+        },
+    }
+}
+
+fn invalid_response(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn parse_response(response: &[u8]) -> Result<(u16, Vec<u8>), io::Error> {
+    let head_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid_response("truncated http response"))?;
+    let head = std::str::from_utf8(&response[..head_end])
+        .map_err(|_| invalid_response("invalid http header"))?;
+    let body = &response[head_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|status_line| status_line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid_response("invalid http status line"))?;
+
+    let chunked = lines.any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+
+    if chunked {
+        Ok((status, decode_chunked(body)?))
+    } else {
+        Ok((status, body.to_vec()))
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut output = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| invalid_response("truncated chunk"))?;
+        let size_str = std::str::from_utf8(&body[..line_end])
+            .map_err(|_| invalid_response("invalid chunk size"))?;
+        let size = usize::from_str_radix(size_str.split(';').next().unwrap_or("").trim(), 16)
+            .map_err(|_| invalid_response("invalid chunk size"))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(output);
+        }
+        if body.len() < size + 2 {
+            return Err(invalid_response("truncated chunk"));
+        }
+        output.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let (status, body) =
+            parse_response(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}").unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"{}");
+
+        let (status, body) = parse_response(
+            b"HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nres \r\n5\r\nfound\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(status, 404);
+        assert_eq!(body, b"res found");
+    }
+}
@@ -1,17 +1,34 @@
-use crate::filemap::FileMap;
+use crate::filemap::{FileMap, HashAlgorithm};
 use actix::Message;
-use bytes::{BufMut, ByteOrder, BytesMut, LittleEndian};
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::cmp::min;
 use std::convert::{TryFrom, TryInto};
 use std::fmt::Display;
 use std::io;
-use tokio_io::codec::{Decoder, Encoder};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_util::codec::{Decoder, Encoder};
 
-const PROTO_VERSION: u8 = 1;
+pub const PROTO_VERSION: u8 = 1;
 
 const MAX_PACKET_SIZE: usize = 1024 * 1024 * 8;
 
+/// Largest block payload that fits into a single `Block` packet.
+pub const MAX_BLOCK_PAYLOAD: usize = MAX_PACKET_SIZE - BLOCK_HEADER_SIZE;
+
+/// Payload size of `BlockPart` packets used for blocks above `MAX_BLOCK_PAYLOAD`.
+pub const BLOCK_PART_SIZE: usize = 1024 * 1024;
+
+/// Encoded size of the file maps in a page, for ask replies too big for one packet and
+/// `GetFileMaps`.
+pub const FILE_MAPS_PAGE_SIZE: usize = MAX_PACKET_SIZE / 2;
+
 pub fn hash_to_hex(hash: u128) -> String {
     format!("{:032x}", hash)
 }
@@ -25,8 +42,49 @@ pub enum Op {
     GetBlock = 4,
     Block = 5,
     Bye = 6,
+    BlockPart = 7,
+    RelayRegister = 8,
+    RelayConnect = 9,
+    RelayOffer = 10,
+    RelayAccept = 11,
+    RelayReply = 12,
+    AskToken = 13,
+    Error = 14,
+    Challenge = 15,
+    ChallengeReply = 16,
+    GetFileMaps = 17,
+    FileMaps = 18,
+    Identify = 19,
+    Identity = 20,
+    Auth = 21,
 }
 
+/// Packet names, indexed by their `Op`.
+pub const OP_NAMES: &[&str] = &[
+    "nop",
+    "hello",
+    "ask",
+    "askReply",
+    "getBlock",
+    "block",
+    "bye",
+    "blockPart",
+    "relayRegister",
+    "relayConnect",
+    "relayOffer",
+    "relayAccept",
+    "relayReply",
+    "askToken",
+    "error",
+    "challenge",
+    "challengeReply",
+    "getFileMaps",
+    "fileMaps",
+    "identify",
+    "identity",
+    "auth",
+];
+
 pub enum StCommand {
     Nop,
     Hello(Hello),
@@ -35,6 +93,25 @@ pub enum StCommand {
     GetBlock(GetBlock),
     Block(Block),
     Bye,
+    BlockPart(BlockPart),
+    RelayRegister,
+    RelayConnect(u128),
+    RelayOffer(u128),
+    RelayAccept(u128),
+    RelayReply(RelayReply),
+    AskToken(AskToken),
+    /// Code of the error the connection is closed with, see `error::ErrorCode`.
+    Error(u16),
+    Challenge(Challenge),
+    ChallengeReply(ChallengeReply),
+    GetFileMaps(GetFileMaps),
+    FileMaps(FileMaps),
+    /// Nonce and key share the peer signs to prove its node id.
+    Identify(Identify),
+    /// Answer to `Identify`, `None` from nodes without an identity key.
+    Identity(Option<IdentityProof>),
+    /// Tag of the preceding packet, see `PacketAuth`. Only relays get them as packets.
+    Auth([u8; AUTH_TAG_SIZE]),
 }
 
 impl StCommand {
@@ -43,15 +120,55 @@ impl StCommand {
     }
 
     pub fn ask_reply(hash: u128, files: Option<Vec<FileMap>>) -> Self {
-        StCommand::AskReply(AskReply { hash, files })
+        StCommand::AskReply(AskReply {
+            hash,
+            files,
+            peers: Vec::new(),
+            unauthorized: false,
+            signature: None,
+            signed_by: None,
+            inline_files: Vec::new(),
+            inline_data: None,
+            total_files: None,
+        })
+    }
+
+    /// Reply for a hash the peer is not allowed to download.
+    pub fn ask_reply_unauthorized(hash: u128) -> Self {
+        StCommand::AskReply(AskReply {
+            hash,
+            files: None,
+            peers: Vec::new(),
+            unauthorized: true,
+            signature: None,
+            signed_by: None,
+            inline_files: Vec::new(),
+            inline_data: None,
+            total_files: None,
+        })
+    }
+
+    /// Reply for an unknown hash pointing to peers that may have it.
+    pub fn ask_reply_hints(hash: u128, peers: Vec<SocketAddr>) -> Self {
+        StCommand::AskReply(AskReply {
+            hash,
+            files: None,
+            peers,
+            unauthorized: false,
+            signature: None,
+            signed_by: None,
+            inline_files: Vec::new(),
+            inline_data: None,
+            total_files: None,
+        })
     }
 
-    pub fn block(hash: u128, file_nr: u32, block_nr: u32, bytes: Vec<u8>) -> Self {
+    pub fn block(hash: u128, file_nr: u32, block_nr: u32, bytes: impl Into<Bytes>) -> Self {
         StCommand::Block(Block {
             hash,
             block_nr,
             file_nr,
-            bytes,
+            bytes: bytes.into(),
         })
     }
 
@@ -60,6 +177,7 @@ impl StCommand {
             StCommand::Nop => format!("[nop]"),
             StCommand::Hello(h) => format!("[hello id:{}, v:{}", h.node_id, h.proto_version),
             StCommand::Ask(hash) => format!("[ask {}]", hash),
+            StCommand::AskToken(ask) => format!("[ask-token {}]", ask.hash),
             StCommand::AskReply(_hash) => format!("[ask-replay ...]"),
             StCommand::GetBlock(b) => format!(
                 "[get-block hash:{}, file-no:{}, block-no:{}]",
@@ -70,20 +188,59 @@ impl StCommand {
                 b.hash, b.file_nr, b.block_nr
             ),
             StCommand::Bye => format!("[bye]"),
+            StCommand::BlockPart(p) => format!(
+                "[block-part hash:{}, file-no:{}, block-no:{}, offset:{}/{}]",
+                p.hash, p.file_nr, p.block_nr, p.offset, p.block_size
+            ),
+            StCommand::RelayRegister => "[relay-register]".to_string(),
+            StCommand::RelayConnect(node_id) => format!("[relay-connect id:{}]", node_id),
+            StCommand::RelayOffer(token) => format!("[relay-offer token:{}]", token),
+            StCommand::RelayAccept(token) => format!("[relay-accept token:{}]", token),
+            StCommand::RelayReply(r) => {
+                format!("[relay-reply id:{}, status:{}]", r.node_id, r.status)
+            }
+            StCommand::Error(code) => format!("[error code:{}]", code),
+            StCommand::Challenge(c) => format!("[challenge bits:{}]", c.bits),
+            StCommand::ChallengeReply(r) => format!("[challenge-reply solution:{}]", r.solution),
+            StCommand::GetFileMaps(g) => format!("[get-file-maps {} offset:{}]", g.hash, g.offset),
+            StCommand::FileMaps(m) => format!(
+                "[file-maps {} offset:{}, files:{}]",
+                m.hash,
+                m.offset,
+                m.files.len()
+            ),
+            StCommand::Identify(_) => "[identify]".to_string(),
+            StCommand::Identity(proof) => format!("[identity proven:{}]", proof.is_some()),
+            StCommand::Auth(_) => "[auth]".to_string(),
         }
     }
 }
 
 impl StCommand {
-    fn decode(op: Op, buf: &[u8]) -> Result<Self, bincode::Error> {
+    fn decode(op: Op, buf: BytesMut) -> Result<Self, bincode::Error> {
         Ok(match op {
             Op::Nop => StCommand::Nop,
-            Op::Hello => StCommand::Hello(bincode::deserialize(buf)?),
-            Op::Ask => StCommand::Ask(bincode::deserialize(buf)?),
-            Op::AskReply => StCommand::AskReply(bincode::deserialize(buf)?),
-            Op::GetBlock => StCommand::GetBlock(bincode::deserialize(buf)?),
-            Op::Block => StCommand::Block(bincode::deserialize(buf)?),
+            Op::Hello => StCommand::Hello(bincode::deserialize(buf.as_ref())?),
+            Op::Ask => StCommand::Ask(bincode::deserialize(buf.as_ref())?),
+            Op::AskReply => StCommand::AskReply(AskReply::decode(buf.as_ref())?),
+            Op::GetBlock => StCommand::GetBlock(bincode::deserialize(buf.as_ref())?),
+            Op::Block => StCommand::Block(Block::decode(buf.freeze())?),
             Op::Bye => StCommand::Bye,
+            Op::BlockPart => StCommand::BlockPart(BlockPart::decode(buf.freeze())?),
+            Op::RelayRegister => StCommand::RelayRegister,
+            Op::RelayConnect => StCommand::RelayConnect(bincode::deserialize(buf.as_ref())?),
+            Op::RelayOffer => StCommand::RelayOffer(bincode::deserialize(buf.as_ref())?),
+            Op::RelayAccept => StCommand::RelayAccept(bincode::deserialize(buf.as_ref())?),
+            Op::RelayReply => StCommand::RelayReply(bincode::deserialize(buf.as_ref())?),
+            Op::AskToken => StCommand::AskToken(bincode::deserialize(buf.as_ref())?),
+            Op::Error => StCommand::Error(bincode::deserialize(buf.as_ref())?),
+            Op::Challenge => StCommand::Challenge(bincode::deserialize(buf.as_ref())?),
+            Op::ChallengeReply => StCommand::ChallengeReply(bincode::deserialize(buf.as_ref())?),
+            Op::GetFileMaps => StCommand::GetFileMaps(bincode::deserialize(buf.as_ref())?),
+            Op::FileMaps => StCommand::FileMaps(FileMaps::decode(buf.as_ref())?),
+            Op::Identify => StCommand::Identify(bincode::deserialize(buf.as_ref())?),
+            Op::Identity => StCommand::Identity(bincode::deserialize(buf.as_ref())?),
+            Op::Auth => StCommand::Auth(bincode::deserialize(buf.as_ref())?),
         })
     }
 }
@@ -98,6 +255,21 @@ impl Op {
             Op::GetBlock => None,
             Op::Block => None,
             Op::Bye => Some(0),
+            Op::BlockPart => None,
+            Op::RelayRegister => Some(0),
+            Op::RelayConnect => Some(16),
+            Op::RelayOffer => Some(16),
+            Op::RelayAccept => Some(16),
+            Op::RelayReply => Some(17),
+            Op::AskToken => Some(32),
+            Op::Error => Some(2),
+            Op::Challenge => Some(17),
+            Op::ChallengeReply => Some(24),
+            Op::GetFileMaps => Some(20),
+            Op::FileMaps => None,
+            Op::Identify => Some(48),
+            Op::Identity => None,
+            Op::Auth => Some(AUTH_TAG_SIZE as u32),
         }
     }
 }
@@ -105,7 +277,7 @@ impl Op {
 impl TryFrom<u8> for Op {
     type Error = io::Error;
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
+    fn try_from(value: u8) -> Result<Self, io::Error> {
         match value {
             0 => Ok(Op::Nop),
             1 => Ok(Op::Hello),
@@ -114,6 +286,21 @@ impl TryFrom<u8> for Op {
             4 => Ok(Op::GetBlock),
             5 => Ok(Op::Block),
             6 => Ok(Op::Bye),
+            7 => Ok(Op::BlockPart),
+            8 => Ok(Op::RelayRegister),
+            9 => Ok(Op::RelayConnect),
+            10 => Ok(Op::RelayOffer),
+            11 => Ok(Op::RelayAccept),
+            12 => Ok(Op::RelayReply),
+            13 => Ok(Op::AskToken),
+            14 => Ok(Op::Error),
+            15 => Ok(Op::Challenge),
+            16 => Ok(Op::ChallengeReply),
+            17 => Ok(Op::GetFileMaps),
+            18 => Ok(Op::FileMaps),
+            19 => Ok(Op::Identify),
+            20 => Ok(Op::Identity),
+            21 => Ok(Op::Auth),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "unknown packet opcode",
@@ -157,16 +344,117 @@ impl Message for Bye {
     type Result = Result<(), super::error::Error>;
 }
 
+/// Sends `nop`, so the peer does not close the connection as idle.
+pub struct KeepAlive;
+
+impl Message for KeepAlive {
+    type Result = Result<(), super::error::Error>;
+}
+
 #[derive(Default, Serialize, Deserialize)]
 pub struct Ask {
     pub hash: u128,
+    /// Access token of the resource, sent in `AskToken`.
+    #[serde(skip)]
+    pub token: Option<u128>,
+    /// File maps of the resource received earlier. A reply whose first page matches them
+    /// is completed with them instead of fetching the other pages.
+    #[serde(skip)]
+    pub known_files: Option<Arc<Vec<FileMap>>>,
 }
 
 impl Ask {
     #[inline]
-    pub fn new(hash: u128) -> Self {
-        Self { hash }
+    pub fn new(hash: u128, token: Option<u128>) -> Self {
+        Self {
+            hash,
+            token,
+            known_files: None,
+        }
+    }
+
+    pub fn with_known_files(self, known_files: Option<Arc<Vec<FileMap>>>) -> Self {
+        Self {
+            known_files,
+            ..self
+        }
+    }
+}
+
+/// `Ask` presenting an access token.
+#[derive(Default, Serialize, Deserialize)]
+pub struct AskToken {
+    pub hash: u128,
+    pub token: u128,
+}
+
+/// Largest `Challenge::bits` solved, harder challenges close the connection.
+pub const MAX_CHALLENGE_BITS: u8 = 24;
+
+/// Work asked of a peer before its asks are answered, sent after `Hello`.
+#[derive(Default, Serialize, Deserialize, Clone, Copy)]
+pub struct Challenge {
+    pub nonce: u128,
+    /// Leading zero bits of the BLAKE3 hash of nonce and solution, 0 to just echo the nonce.
+    pub bits: u8,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ChallengeReply {
+    pub nonce: u128,
+    pub solution: u64,
+}
+
+impl Challenge {
+    pub fn new(bits: u8) -> Self {
+        Challenge {
+            nonce: rand::random(),
+            bits,
+        }
+    }
+
+    pub fn is_solved_by(&self, reply: &ChallengeReply) -> bool {
+        reply.nonce == self.nonce && self.leading_zeros(reply.solution) >= u32::from(self.bits)
     }
+
+    /// Finds a solution, up to 2^`bits` hashes on average.
+    pub fn solve(&self) -> ChallengeReply {
+        let solution = (0..=u64::MAX)
+            .find(|&solution| self.leading_zeros(solution) >= u32::from(self.bits))
+            .unwrap_or_default();
+        ChallengeReply {
+            nonce: self.nonce,
+            solution,
+        }
+    }
+
+    fn leading_zeros(&self, solution: u64) -> u32 {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.nonce.to_le_bytes());
+        hasher.update(&solution.to_le_bytes());
+        let hash = hasher.finalize();
+        let mut head = [0; 16];
+        head.copy_from_slice(&hash.as_bytes()[..16]);
+        u128::from_be_bytes(head).leading_zeros()
+    }
+}
+
+/// Asks the peer to prove its node id for this connection, see `identity::prove`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Identify {
+    pub nonce: u128,
+    /// X25519 public key of the asking node, for this connection only
+    pub key_share: [u8; 32],
+}
+
+/// Signature of an `Identify` and the key share of the sender with the key its node id is
+/// derived from, see `identity::prove`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdentityProof {
+    pub public_key: [u8; 32],
+    /// X25519 public key of the proving node, for this connection only
+    pub key_share: [u8; 32],
+    pub signature: Vec<u8>,
 }
 
 impl Message for Ask {
@@ -178,6 +466,378 @@ pub struct AskReply {
     pub hash: u128,
     // None if unknown hash
     pub files: Option<Vec<FileMap>>,
+    /// Alternative peers for an unknown hash, sent in `AskReplyExt`.
+    #[serde(skip)]
+    pub peers: Vec<SocketAddr>,
+    /// The resource exists but the peer is not allowed to download it,
+    /// sent after `AskReplyExt`. Older peers see an unknown hash.
+    #[serde(skip)]
+    pub unauthorized: bool,
+    /// Signature of the file maps by the sharing node, sent after `unauthorized`.
+    #[serde(skip)]
+    pub signature: Option<FileMapSignature>,
+    /// Node whose signature was verified, set by the receiving connection.
+    #[serde(skip)]
+    pub signed_by: Option<u128>,
+    /// Files packed into the inline data of the resource, sent after `links`.
+    #[serde(skip)]
+    pub inline_files: Vec<InlineFile>,
+    /// The inline data itself when it holds all files of the resource, sent after
+    /// `inline_files`, so no block needs to be requested.
+    #[serde(skip)]
+    pub inline_data: Option<Vec<u8>>,
+    /// Number of files when `files` is only the first page of them, sent after
+    /// `inline_data`. Older peers see just the first page.
+    #[serde(skip)]
+    pub total_files: Option<u32>,
+}
+
+/// File whose content is kept in the inline data of a resource, at `offset`.
+///
+/// Peers announcing inline files send all of them in one block for `INLINE_FILE_NR`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InlineFile {
+    pub file_nr: u32,
+    pub offset: u32,
+}
+
+/// `file_nr` of the block holding the inline data of a resource.
+pub const INLINE_FILE_NR: u32 = u32::MAX;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileMapSignature {
+    /// Ed25519 key the node id of the signer is derived from
+    pub public_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+/// Appended after the `AskReply` body. Older peers ignore trailing bytes,
+/// replies without it carry legacy SHA-224 maps.
+#[derive(Serialize, Deserialize)]
+struct AskReplyExt {
+    hash_algorithms: Vec<HashAlgorithm>,
+    peers: Vec<SocketAddr>,
+}
+
+impl AskReply {
+    fn ext(&self) -> AskReplyExt {
+        AskReplyExt {
+            hash_algorithms: self
+                .files
+                .iter()
+                .flatten()
+                .map(|file_map| file_map.hash_algorithm)
+                .collect(),
+            peers: self.peers.clone(),
+        }
+    }
+
+    fn encoded_size(&self) -> usize {
+        (bincode::serialized_size(self).unwrap()
+            + bincode::serialized_size(&self.ext()).unwrap()
+            + bincode::serialized_size(&self.unauthorized).unwrap()
+            + bincode::serialized_size(&self.signature).unwrap()
+            + bincode::serialized_size(&self.modes()).unwrap()
+            + bincode::serialized_size(&self.mtimes()).unwrap()
+            + bincode::serialized_size(&self.links()).unwrap()
+            + bincode::serialized_size(&self.inline_files).unwrap()
+            + bincode::serialized_size(&self.inline_data).unwrap()
+            + bincode::serialized_size(&self.total_files).unwrap()) as usize
+    }
+
+    /// Keeps the first page of the file maps when they don't fit into one packet, replies
+    /// that fit stay complete for peers older than paging. Pages follow the signature,
+    /// which covers all file maps, and drop the inline data.
+    pub fn into_first_page(mut self) -> Self {
+        if self.encoded_size() <= MAX_PACKET_SIZE {
+            return self;
+        }
+        if let Some(files) = &mut self.files {
+            let page = page_len(files.iter());
+            if page < files.len() {
+                self.total_files = Some(files.len() as u32);
+                files.truncate(page);
+                self.inline_data = None;
+            }
+        }
+        self
+    }
+
+    /// Permission bits of the files, sent after `signature` and not covered by it.
+    fn modes(&self) -> Vec<Option<u32>> {
+        self.files
+            .iter()
+            .flatten()
+            .map(|file_map| file_map.mode)
+            .collect()
+    }
+
+    /// Modification times of the files, sent after `modes`.
+    fn mtimes(&self) -> Vec<Option<SystemTime>> {
+        self.files
+            .iter()
+            .flatten()
+            .map(|file_map| file_map.mtime)
+            .collect()
+    }
+
+    /// Link targets of the files, sent after `mtimes`.
+    fn links(&self) -> Vec<Option<String>> {
+        self.files
+            .iter()
+            .flatten()
+            .map(|file_map| file_map.link.clone())
+            .collect()
+    }
+
+    /// Data covered by `signature`: the hash and file maps with their hash algorithms,
+    /// followed by the link targets if there are links.
+    pub fn signed_content(&self) -> Vec<u8> {
+        let mut content = bincode::serialize(&(
+            "hyperg file maps",
+            self.hash,
+            &self.files,
+            self.ext().hash_algorithms,
+        ))
+        .unwrap();
+        let links = self.links();
+        if links.iter().any(Option::is_some) {
+            bincode::serialize_into(&mut content, &links).unwrap();
+        }
+        content
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, bincode::Error> {
+        let mut cursor = io::Cursor::new(buf);
+        let mut reply: AskReply = bincode::deserialize_from(&mut cursor)?;
+        if (cursor.position() as usize) < buf.len() {
+            let ext: AskReplyExt = bincode::deserialize_from(&mut cursor)?;
+            if let Some(files) = &mut reply.files {
+                for (file_map, hash_algorithm) in files.iter_mut().zip(ext.hash_algorithms) {
+                    file_map.hash_algorithm = hash_algorithm;
+                }
+            }
+            reply.peers = ext.peers;
+        }
+        if (cursor.position() as usize) < buf.len() {
+            reply.unauthorized = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < buf.len() {
+            reply.signature = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < buf.len() {
+            let modes: Vec<Option<u32>> = bincode::deserialize_from(&mut cursor)?;
+            if let Some(files) = &mut reply.files {
+                for (file_map, mode) in files.iter_mut().zip(modes) {
+                    file_map.mode = mode;
+                }
+            }
+        }
+        if (cursor.position() as usize) < buf.len() {
+            let mtimes: Vec<Option<SystemTime>> = bincode::deserialize_from(&mut cursor)?;
+            if let Some(files) = &mut reply.files {
+                for (file_map, mtime) in files.iter_mut().zip(mtimes) {
+                    file_map.mtime = mtime;
+                }
+            }
+        }
+        if (cursor.position() as usize) < buf.len() {
+            let links: Vec<Option<String>> = bincode::deserialize_from(&mut cursor)?;
+            if let Some(files) = &mut reply.files {
+                for (file_map, link) in files.iter_mut().zip(links) {
+                    file_map.link = link;
+                }
+            }
+        }
+        if (cursor.position() as usize) < buf.len() {
+            reply.inline_files = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < buf.len() {
+            reply.inline_data = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < buf.len() {
+            reply.total_files = bincode::deserialize_from(&mut cursor)?;
+        }
+        Ok(reply)
+    }
+}
+
+/// Fields of a file map sent apart from it, in the order of `AskReply`.
+type FileMapExt = (
+    HashAlgorithm,
+    Option<u32>,
+    Option<SystemTime>,
+    Option<String>,
+);
+
+fn file_map_ext(file_map: &FileMap) -> FileMapExt {
+    (
+        file_map.hash_algorithm,
+        file_map.mode,
+        file_map.mtime,
+        file_map.link.clone(),
+    )
+}
+
+/// Number of file maps from the start of `files` fitting into `FILE_MAPS_PAGE_SIZE`, at
+/// least one.
+pub fn page_len<'a>(files: impl IntoIterator<Item = &'a FileMap>) -> usize {
+    let mut size = 0;
+    files
+        .into_iter()
+        .take_while(|file_map| {
+            size += bincode::serialized_size(file_map).unwrap() as usize
+                + bincode::serialized_size(&file_map_ext(file_map)).unwrap() as usize;
+            size <= FILE_MAPS_PAGE_SIZE
+        })
+        .count()
+        .max(1)
+}
+
+/// Requests the file maps of an ask reply from `offset` on.
+#[derive(Default, Serialize, Deserialize)]
+pub struct GetFileMaps {
+    pub hash: u128,
+    pub offset: u32,
+}
+
+/// Page of file maps with the fields sent apart in `AskReply`.
+#[derive(Default)]
+pub struct FileMaps {
+    pub hash: u128,
+    pub offset: u32,
+    pub files: Vec<FileMap>,
+}
+
+impl FileMaps {
+    fn encoded(&self) -> (u128, u32, &Vec<FileMap>, Vec<FileMapExt>) {
+        let ext = self.files.iter().map(file_map_ext).collect();
+        (self.hash, self.offset, &self.files, ext)
+    }
+
+    fn encoded_size(&self) -> usize {
+        bincode::serialized_size(&self.encoded()).unwrap() as usize
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, bincode::Error> {
+        let (hash, offset, mut files, ext): (u128, u32, Vec<FileMap>, Vec<FileMapExt>) =
+            bincode::deserialize(buf)?;
+        for (file_map, (hash_algorithm, mode, mtime, link)) in files.iter_mut().zip(ext) {
+            file_map.hash_algorithm = hash_algorithm;
+            file_map.mode = mode;
+            file_map.mtime = mtime;
+            file_map.link = link;
+        }
+        Ok(FileMaps {
+            hash,
+            offset,
+            files,
+        })
+    }
+}
+
+/// Asks a relay to open a session with a node registered there.
+pub struct RelayConnect {
+    pub node_id: u128,
+}
+
+impl Message for RelayConnect {
+    type Result = Result<(), crate::error::Error>;
+}
+
+/// Registers this node at a relay over the connection.
+pub struct RelayRegister;
+
+impl Message for RelayRegister {
+    type Result = Result<(), crate::error::Error>;
+}
+
+/// Binds a new connection to the relay session offered with the token.
+pub struct RelayAccept {
+    pub token: u128,
+}
+
+impl Message for RelayAccept {
+    type Result = Result<(), crate::error::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayStatus {
+    Ok,
+    Disabled,
+    UnknownPeer,
+    QuotaExceeded,
+    Timeout,
+    /// The node did not prove its node id, see `identity::prove`.
+    Unproven,
+    /// The node id is registered over another connection still open.
+    Registered,
+    Other(u8),
+}
+
+impl From<u8> for RelayStatus {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => RelayStatus::Ok,
+            1 => RelayStatus::Disabled,
+            2 => RelayStatus::UnknownPeer,
+            3 => RelayStatus::QuotaExceeded,
+            4 => RelayStatus::Timeout,
+            5 => RelayStatus::Unproven,
+            6 => RelayStatus::Registered,
+            code => RelayStatus::Other(code),
+        }
+    }
+}
+
+impl From<RelayStatus> for u8 {
+    fn from(status: RelayStatus) -> Self {
+        match status {
+            RelayStatus::Ok => 0,
+            RelayStatus::Disabled => 1,
+            RelayStatus::UnknownPeer => 2,
+            RelayStatus::QuotaExceeded => 3,
+            RelayStatus::Timeout => 4,
+            RelayStatus::Unproven => 5,
+            RelayStatus::Registered => 6,
+            RelayStatus::Other(code) => code,
+        }
+    }
+}
+
+impl std::fmt::Display for RelayStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RelayStatus::Ok => f.write_str("ok"),
+            RelayStatus::Disabled => f.write_str("relaying disabled"),
+            RelayStatus::UnknownPeer => f.write_str("peer not registered"),
+            RelayStatus::QuotaExceeded => f.write_str("quota exceeded"),
+            RelayStatus::Timeout => f.write_str("peer did not accept"),
+            RelayStatus::Unproven => f.write_str("node id not proven"),
+            RelayStatus::Registered => f.write_str("registered over another connection"),
+            RelayStatus::Other(code) => write!(f, "status {}", code),
+        }
+    }
+}
+
+/// Answer of a relay to `RelayRegister` and `RelayConnect`.
+#[derive(Default, Serialize, Deserialize)]
+pub struct RelayReply {
+    pub node_id: u128,
+    pub status: u8,
+}
+
+impl RelayReply {
+    pub fn new(node_id: u128, status: RelayStatus) -> Self {
+        RelayReply {
+            node_id,
+            status: status.into(),
+        }
+    }
+
+    pub fn status(&self) -> RelayStatus {
+        self.status.into()
+    }
 }
 
 #[derive(Default, Serialize, Deserialize, Hash, PartialEq, Eq, Clone)]
@@ -191,16 +851,173 @@ impl Message for GetBlock {
     type Result = Result<Block, crate::error::Error>;
 }
 
-#[derive(Default, Serialize, Deserialize, Clone)]
+/// Block payload.
+///
+/// Encoded by hand (same layout as bincode with `bytes` as `Vec<u8>`) so the payload
+/// is shared with the receive buffer instead of being copied.
+#[derive(Default, Clone)]
 pub struct Block {
     pub hash: u128,
     pub block_nr: u32,
     pub file_nr: u32,
-    pub bytes: Vec<u8>,
+    pub bytes: Bytes,
+}
+
+/// hash + block_nr + file_nr + payload length
+const BLOCK_HEADER_SIZE: usize = 16 + 4 + 4 + 8;
+
+impl Block {
+    fn encoded_size(&self) -> usize {
+        BLOCK_HEADER_SIZE + self.bytes.len()
+    }
+
+    fn encode(&self, dst: &mut BytesMut) {
+        let mut header = [0u8; BLOCK_HEADER_SIZE];
+        LittleEndian::write_u128(&mut header[0..16], self.hash);
+        LittleEndian::write_u32(&mut header[16..20], self.block_nr);
+        LittleEndian::write_u32(&mut header[20..24], self.file_nr);
+        LittleEndian::write_u64(&mut header[24..32], self.bytes.len() as u64);
+        dst.put_slice(&header);
+        dst.put_slice(self.bytes.as_ref());
+    }
+
+    fn decode(buf: Bytes) -> Result<Self, bincode::Error> {
+        if buf.len() < BLOCK_HEADER_SIZE {
+            return Err(Box::new(bincode::ErrorKind::SizeLimit));
+        }
+        let len = LittleEndian::read_u64(&buf[24..32]) as usize;
+        if buf.len() != BLOCK_HEADER_SIZE + len {
+            return Err(Box::new(bincode::ErrorKind::SizeLimit));
+        }
+        Ok(Block {
+            hash: LittleEndian::read_u128(&buf[0..16]),
+            block_nr: LittleEndian::read_u32(&buf[16..20]),
+            file_nr: LittleEndian::read_u32(&buf[20..24]),
+            bytes: buf.slice(BLOCK_HEADER_SIZE..),
+        })
+    }
+}
+
+/// Fragment of a block too big for a single packet.
+///
+/// Parts of a block are sent in order; `offset` is the position of `bytes`
+/// within the block and `block_size` the size of the whole block.
+#[derive(Default, Clone)]
+pub struct BlockPart {
+    pub hash: u128,
+    pub block_nr: u32,
+    pub file_nr: u32,
+    pub offset: u64,
+    pub block_size: u64,
+    pub bytes: Bytes,
+}
+
+/// hash + block_nr + file_nr + offset + block_size + payload length
+const BLOCK_PART_HEADER_SIZE: usize = 16 + 4 + 4 + 8 + 8 + 8;
+
+impl BlockPart {
+    /// Splits block payload into parts of at most `BLOCK_PART_SIZE` bytes.
+    pub fn split(hash: u128, file_nr: u32, block_nr: u32, bytes: Bytes) -> Vec<BlockPart> {
+        let block_size = bytes.len();
+        (0..block_size)
+            .step_by(BLOCK_PART_SIZE)
+            .map(|offset| BlockPart {
+                hash,
+                block_nr,
+                file_nr,
+                offset: offset as u64,
+                block_size: block_size as u64,
+                bytes: bytes.slice(offset..min(offset + BLOCK_PART_SIZE, block_size)),
+            })
+            .collect()
+    }
+
+    fn encoded_size(&self) -> usize {
+        BLOCK_PART_HEADER_SIZE + self.bytes.len()
+    }
+
+    fn encode(&self, dst: &mut BytesMut) {
+        let mut header = [0u8; BLOCK_PART_HEADER_SIZE];
+        LittleEndian::write_u128(&mut header[0..16], self.hash);
+        LittleEndian::write_u32(&mut header[16..20], self.block_nr);
+        LittleEndian::write_u32(&mut header[20..24], self.file_nr);
+        LittleEndian::write_u64(&mut header[24..32], self.offset);
+        LittleEndian::write_u64(&mut header[32..40], self.block_size);
+        LittleEndian::write_u64(&mut header[40..48], self.bytes.len() as u64);
+        dst.put_slice(&header);
+        dst.put_slice(self.bytes.as_ref());
+    }
+
+    fn decode(buf: Bytes) -> Result<Self, bincode::Error> {
+        if buf.len() < BLOCK_PART_HEADER_SIZE {
+            return Err(Box::new(bincode::ErrorKind::SizeLimit));
+        }
+        let len = LittleEndian::read_u64(&buf[40..48]) as usize;
+        if buf.len() != BLOCK_PART_HEADER_SIZE + len {
+            return Err(Box::new(bincode::ErrorKind::SizeLimit));
+        }
+        Ok(BlockPart {
+            hash: LittleEndian::read_u128(&buf[0..16]),
+            block_nr: LittleEndian::read_u32(&buf[16..20]),
+            file_nr: LittleEndian::read_u32(&buf[20..24]),
+            offset: LittleEndian::read_u64(&buf[24..32]),
+            block_size: LittleEndian::read_u64(&buf[32..40]),
+            bytes: buf.slice(BLOCK_PART_HEADER_SIZE..),
+        })
+    }
+}
+
+/// Size of the tag sent in an `Auth` packet after every authenticated packet.
+pub const AUTH_TAG_SIZE: usize = 32;
+
+/// Key authenticating the packets sent in one direction of a connection once the sender
+/// proved its node id, shared by the connection and its codec.
+///
+/// Every packet is followed by an `Auth` packet with the keyed BLAKE3 hash of the packet
+/// count and the packet, so packets of nodes not knowing the key of the session are
+/// refused.
+#[derive(Clone, Default)]
+pub struct PacketAuth(Rc<RefCell<Option<AuthState>>>);
+
+struct AuthState {
+    key: [u8; 32],
+    packets: u64,
+}
+
+impl PacketAuth {
+    /// Authenticates packets from now on.
+    pub fn set_key(&self, key: [u8; 32]) {
+        *self.0.borrow_mut() = Some(AuthState { key, packets: 0 });
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.borrow().is_some()
+    }
+
+    /// Tag of the next packet, `None` while packets are not authenticated.
+    fn tag(&self, packet: &[u8]) -> Option<blake3::Hash> {
+        let mut state = self.0.borrow_mut();
+        let state = state.as_mut()?;
+        let mut hasher = blake3::Hasher::new_keyed(&state.key);
+        hasher.update(&state.packets.to_le_bytes());
+        hasher.update(packet);
+        state.packets += 1;
+        Some(hasher.finalize())
+    }
 }
 
 #[derive(Default)]
-pub struct StCodec {}
+pub struct StCodec {
+    auth: PacketAuth,
+}
+
+impl StCodec {
+    /// Codec tagging encoded packets, or checking the tags of decoded ones, once `auth`
+    /// has a key.
+    pub fn with_auth(auth: PacketAuth) -> Self {
+        StCodec { auth }
+    }
+}
 
 impl Decoder for StCodec {
     type Item = StCommand;
@@ -226,12 +1043,38 @@ impl Decoder for StCodec {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "packet too big"));
         }
 
-        if src.len() >= size + prefix_size + 1 {
-            src.split_to(prefix_size + 1);
+        let packet_size = size + prefix_size + 1;
+        if self.auth.is_set() {
+            let auth_size = packet_size + 1 + AUTH_TAG_SIZE;
+            if src.len() < auth_size {
+                src.reserve(auth_size - src.len());
+                return Ok(None);
+            }
+            let tag = self.auth.tag(&src[..packet_size]);
+            let mut sent = [0; AUTH_TAG_SIZE];
+            sent.copy_from_slice(&src[packet_size + 1..auth_size]);
+            // Hashes compare in constant time.
+            if src[packet_size] != Op::Auth as u8 || tag != Some(blake3::Hash::from(sent)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "packet not authenticated",
+                ));
+            }
+            let mut packet = src.split_to(auth_size);
+            packet.truncate(packet_size);
+            packet.advance(prefix_size + 1);
+            return Ok(Some(
+                StCommand::decode(op_code, packet)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            ));
+        }
+
+        if src.len() >= packet_size {
+            src.advance(prefix_size + 1);
             let buf = src.split_to(size);
-            Ok(Some(StCommand::decode(op_code, buf.as_ref()).map_err(
-                |e| io::Error::new(io::ErrorKind::InvalidData, e),
-            )?))
+            Ok(Some(StCommand::decode(op_code, buf).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, e)
+            })?))
         } else {
             if src.capacity() < size + prefix_size + 1 {
                 src.reserve(size + prefix_size + 1 - src.len())
@@ -252,8 +1095,7 @@ fn put_into_buf<T: Serialize>(_size: usize, buf: &mut BytesMut, item: &T) -> io:
     })
 }
 
-impl Encoder for StCodec {
-    type Item = StCommand;
+impl Encoder<StCommand> for StCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: StCommand, dst: &mut BytesMut) -> Result<(), Self::Error> {
@@ -262,24 +1104,36 @@ impl Encoder for StCodec {
             StCommand::Bye => (Op::Bye, 0usize, 0usize),
             StCommand::Hello(..) => (Op::Hello, 0, 17),
             StCommand::Ask(..) => (Op::Ask, 0, 16),
-            StCommand::AskReply(reply) => (
-                Op::AskReply,
-                4,
-                bincode::serialized_size(reply).unwrap() as usize,
-            ),
+            StCommand::AskReply(reply) => (Op::AskReply, 4, reply.encoded_size()),
             StCommand::GetBlock(get_block) => (
                 Op::GetBlock,
                 4,
                 bincode::serialized_size(get_block).unwrap() as usize,
             ),
-            StCommand::Block(block) => (
-                Op::Block,
+            StCommand::Block(block) => (Op::Block, 4, block.encoded_size()),
+            StCommand::BlockPart(part) => (Op::BlockPart, 4, part.encoded_size()),
+            StCommand::RelayRegister => (Op::RelayRegister, 0, 0),
+            StCommand::RelayConnect(..) => (Op::RelayConnect, 0, 16),
+            StCommand::RelayOffer(..) => (Op::RelayOffer, 0, 16),
+            StCommand::RelayAccept(..) => (Op::RelayAccept, 0, 16),
+            StCommand::RelayReply(..) => (Op::RelayReply, 0, 17),
+            StCommand::AskToken(..) => (Op::AskToken, 0, 32),
+            StCommand::Error(..) => (Op::Error, 0, 2),
+            StCommand::Challenge(..) => (Op::Challenge, 0, 17),
+            StCommand::ChallengeReply(..) => (Op::ChallengeReply, 0, 24),
+            StCommand::GetFileMaps(..) => (Op::GetFileMaps, 0, 20),
+            StCommand::FileMaps(maps) => (Op::FileMaps, 4, maps.encoded_size()),
+            StCommand::Identify(..) => (Op::Identify, 0, 48),
+            StCommand::Identity(proof) => (
+                Op::Identity,
                 4,
-                bincode::serialized_size(block).unwrap() as usize,
+                bincode::serialized_size(proof).unwrap() as usize,
             ),
+            StCommand::Auth(..) => (Op::Auth, 0, AUTH_TAG_SIZE),
         };
         dst.reserve(1 + prefix_size + size);
 
+        let start = dst.len();
         dst.put_u8(op as u8);
         if prefix_size == 4 {
             dst.put_u32_le(size as u32);
@@ -291,10 +1145,47 @@ impl Encoder for StCodec {
             StCommand::Bye => Ok(()),
             StCommand::Hello(hello) => put_into_buf(size, dst, &hello),
             StCommand::Ask(ask) => put_into_buf(size, dst, &ask),
-            StCommand::AskReply(ask_reply) => put_into_buf(size, dst, &ask_reply),
+            StCommand::AskReply(ask_reply) => {
+                put_into_buf(size, dst, &ask_reply)?;
+                put_into_buf(size, dst, &ask_reply.ext())?;
+                put_into_buf(size, dst, &ask_reply.unauthorized)?;
+                put_into_buf(size, dst, &ask_reply.signature)?;
+                put_into_buf(size, dst, &ask_reply.modes())?;
+                put_into_buf(size, dst, &ask_reply.mtimes())?;
+                put_into_buf(size, dst, &ask_reply.links())?;
+                put_into_buf(size, dst, &ask_reply.inline_files)?;
+                put_into_buf(size, dst, &ask_reply.inline_data)?;
+                put_into_buf(size, dst, &ask_reply.total_files)
+            }
             StCommand::GetBlock(get_block) => put_into_buf(size, dst, &get_block),
-            StCommand::Block(block) => put_into_buf(size, dst, &block),
+            StCommand::Block(block) => {
+                block.encode(dst);
+                Ok(())
+            }
+            StCommand::BlockPart(part) => {
+                part.encode(dst);
+                Ok(())
+            }
+            StCommand::RelayRegister => Ok(()),
+            StCommand::RelayConnect(node_id) => put_into_buf(size, dst, &node_id),
+            StCommand::RelayOffer(token) => put_into_buf(size, dst, &token),
+            StCommand::RelayAccept(token) => put_into_buf(size, dst, &token),
+            StCommand::RelayReply(reply) => put_into_buf(size, dst, &reply),
+            StCommand::AskToken(ask) => put_into_buf(size, dst, &ask),
+            StCommand::Error(code) => put_into_buf(size, dst, &code),
+            StCommand::Challenge(challenge) => put_into_buf(size, dst, &challenge),
+            StCommand::ChallengeReply(reply) => put_into_buf(size, dst, &reply),
+            StCommand::GetFileMaps(get) => put_into_buf(size, dst, &get),
+            StCommand::FileMaps(maps) => put_into_buf(size, dst, &maps.encoded()),
+            StCommand::Identify(identify) => put_into_buf(size, dst, &identify),
+            StCommand::Identity(proof) => put_into_buf(size, dst, &proof),
+            StCommand::Auth(tag) => put_into_buf(size, dst, &tag),
+        }?;
+        if let Some(tag) = self.auth.tag(&dst[start..]) {
+            dst.put_u8(Op::Auth as u8);
+            dst.extend_from_slice(tag.as_bytes());
         }
+        Ok(())
     }
 }
 
@@ -302,6 +1193,13 @@ impl Encoder for StCodec {
 mod test {
 
     use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_op_names() {
+        assert_eq!(OP_NAMES.len(), Op::Auth as usize + 1);
+        assert_eq!(OP_NAMES[Op::AskToken as usize], "askToken");
+    }
 
     #[test]
     fn test_size() {
@@ -330,7 +1228,7 @@ mod test {
             )
             .unwrap();
 
-        let mut r = buf.take();
+        let mut r = buf.split();
         let v = codec.decode(&mut r).unwrap().unwrap();
         match v {
             StCommand::Hello(Hello {
@@ -344,6 +1242,37 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_relay_packets() {
+        assert_eq!(
+            bincode::serialized_size(&RelayReply::default()).unwrap(),
+            Op::RelayReply.size().unwrap() as u64
+        );
+
+        let mut codec = StCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(StCommand::RelayConnect(42), &mut buf).unwrap();
+        codec
+            .encode(
+                StCommand::RelayReply(RelayReply::new(42, RelayStatus::QuotaExceeded)),
+                &mut buf,
+            )
+            .unwrap();
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(StCommand::RelayConnect(node_id)) => assert_eq!(node_id, 42),
+            _ => panic!("expected relay connect"),
+        }
+        match codec.decode(&mut buf).unwrap() {
+            Some(StCommand::RelayReply(reply)) => {
+                assert_eq!(reply.node_id, 42);
+                assert_eq!(reply.status(), RelayStatus::QuotaExceeded);
+            }
+            _ => panic!("expected relay reply"),
+        }
+        assert!(buf.is_empty());
+    }
+
     #[test]
     fn test_block() {
         let mut codec = StCodec::default();
@@ -351,7 +1280,7 @@ mod test {
             hash: 0x1212deadbeef1212,
             file_nr: 0,
             block_nr: 0,
-            bytes: vec![1, 2, 3, 4, 5, 6],
+            bytes: vec![1, 2, 3, 4, 5, 6].into(),
         };
 
         let mut buf = BytesMut::new();
@@ -359,7 +1288,7 @@ mod test {
             .encode(StCommand::Block(block.clone()), &mut buf)
             .unwrap();
 
-        let mut r = buf.take();
+        let mut r = buf.split();
         let v = codec.decode(&mut r).unwrap().unwrap();
         match v {
             StCommand::Block(Block {
@@ -376,4 +1305,644 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_block_parts() {
+        let mut codec = StCodec::default();
+        let payload: Vec<u8> = (0..BLOCK_PART_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let parts = BlockPart::split(7, 1, 2, payload.clone().into());
+        assert_eq!(parts.len(), 3);
+
+        let mut buf = BytesMut::new();
+        for part in parts {
+            codec.encode(StCommand::BlockPart(part), &mut buf).unwrap();
+        }
+
+        let mut reassembled = Vec::new();
+        while let Some(cmd) = codec.decode(&mut buf).unwrap() {
+            match cmd {
+                StCommand::BlockPart(part) => {
+                    assert_eq!(part.offset as usize, reassembled.len());
+                    assert_eq!(part.block_size as usize, payload.len());
+                    reassembled.extend_from_slice(part.bytes.as_ref());
+                }
+                _ => panic!("unexpected packet"),
+            }
+        }
+        assert_eq!(reassembled, payload);
+    }
+
+    #[test]
+    fn test_block_bincode_compat() {
+        #[derive(Serialize)]
+        struct VecBlock {
+            hash: u128,
+            block_nr: u32,
+            file_nr: u32,
+            bytes: Vec<u8>,
+        }
+        let block = Block {
+            hash: 0x1212deadbeef1212,
+            file_nr: 3,
+            block_nr: 7,
+            bytes: vec![1, 2, 3].into(),
+        };
+        let mut buf = BytesMut::with_capacity(block.encoded_size());
+        block.encode(&mut buf);
+
+        let expected = bincode::serialize(&VecBlock {
+            hash: block.hash,
+            block_nr: block.block_nr,
+            file_nr: block.file_nr,
+            bytes: vec![1, 2, 3],
+        })
+        .unwrap();
+        assert_eq!(buf.as_ref(), expected.as_slice());
+        assert_eq!(buf.len(), block.encoded_size());
+    }
+
+    #[test]
+    fn test_ask_reply_hash_algorithm() {
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+        let file_map = FileMap {
+            file_name: "a".into(),
+            file_size: 1,
+            blocks: vec![7],
+            hash_algorithm: HashAlgorithm::Blake3,
+            mode: Some(0o755),
+            mtime: Some(mtime),
+            link: None,
+        };
+        let mut buf = BytesMut::new();
+        StCodec::default()
+            .encode(StCommand::ask_reply(1, Some(vec![file_map])), &mut buf)
+            .unwrap();
+        let body = &buf[5..];
+
+        // older peers decode the legacy layout and ignore the rest
+        let legacy: AskReply = bincode::deserialize(body).unwrap();
+        assert_eq!(legacy.files.unwrap()[0].blocks, vec![7]);
+
+        let reply = AskReply::decode(body).unwrap();
+        let files = reply.files.unwrap();
+        assert_eq!(files[0].hash_algorithm, HashAlgorithm::Blake3);
+        assert_eq!(files[0].mode, Some(0o755));
+        assert_eq!(files[0].mtime, Some(mtime));
+
+        let legacy_body = bincode::serialize(&AskReply::decode(body).unwrap()).unwrap();
+        let reply = AskReply::decode(&legacy_body).unwrap();
+        assert_eq!(
+            reply.files.unwrap()[0].hash_algorithm,
+            HashAlgorithm::Sha224
+        );
+    }
+
+    #[test]
+    fn test_file_maps_pages() {
+        assert_eq!(
+            bincode::serialized_size(&GetFileMaps::default()).unwrap(),
+            Op::GetFileMaps.size().unwrap() as u64
+        );
+
+        let files: Vec<FileMap> = (0..200_000)
+            .map(|nr| FileMap {
+                file_name: format!("dir/file-{:06}", nr).as_str().into(),
+                file_size: 100,
+                blocks: vec![nr],
+                hash_algorithm: HashAlgorithm::Blake3,
+                mode: Some(0o644),
+                mtime: None,
+                link: None,
+            })
+            .collect();
+        let small = match StCommand::ask_reply(1, Some(files[..10].to_vec())) {
+            StCommand::AskReply(reply) => reply.into_first_page(),
+            _ => unreachable!(),
+        };
+        assert_eq!((small.files.unwrap().len(), small.total_files), (10, None));
+
+        let reply = match StCommand::ask_reply(1, Some(files.clone())) {
+            StCommand::AskReply(reply) => reply.into_first_page(),
+            _ => unreachable!(),
+        };
+        let first = reply.files.as_ref().unwrap().len();
+        assert!(first < files.len());
+        assert_eq!(reply.total_files, Some(200_000));
+        assert!(reply.encoded_size() < MAX_PACKET_SIZE);
+        let second = page_len(&files[first..]);
+        assert!(second < files.len() - first);
+
+        let mut buf = encode(StCommand::FileMaps(FileMaps {
+            hash: 1,
+            offset: first as u32,
+            files: files[first..first + second].to_vec(),
+        }));
+        match StCodec::default().decode(&mut buf).unwrap() {
+            Some(StCommand::FileMaps(maps)) => {
+                assert_eq!(maps.offset as usize, first);
+                assert_eq!(maps.files.len(), second);
+                assert_eq!(maps.files[0].mode, Some(0o644));
+                assert_eq!(maps.files[0].hash_algorithm, HashAlgorithm::Blake3);
+            }
+            _ => panic!("expected file maps"),
+        }
+    }
+
+    #[test]
+    fn test_ask_reply_below_packet_limit() {
+        let files: Vec<FileMap> = (0..70_000)
+            .map(|nr| FileMap {
+                file_name: format!("dir/file-{:06}", nr).as_str().into(),
+                file_size: 100,
+                blocks: vec![nr],
+                hash_algorithm: HashAlgorithm::Sha224,
+                mode: None,
+                mtime: None,
+                link: None,
+            })
+            .collect();
+        let reply = match StCommand::ask_reply(1, Some(files)) {
+            StCommand::AskReply(reply) => reply.into_first_page(),
+            _ => unreachable!(),
+        };
+        let size = reply.encoded_size();
+        assert!(size > FILE_MAPS_PAGE_SIZE && size <= MAX_PACKET_SIZE);
+        assert_eq!(reply.total_files, None);
+
+        // peers older than paging get all file maps from a plain ask
+        let buf = encode(StCommand::AskReply(reply));
+        let legacy: AskReply = bincode::deserialize(&buf[5..]).unwrap();
+        assert_eq!(legacy.files.unwrap().len(), 70_000);
+    }
+
+    #[test]
+    fn test_error() {
+        let code = crate::error::ProtocolError::TooManyRequests.code();
+        let mut codec = StCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(StCommand::Error(code), &mut buf).unwrap();
+        assert_eq!(buf.len(), 1 + Op::Error.size().unwrap() as usize);
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(StCommand::Error(decoded)) => assert_eq!(decoded, 106),
+            _ => panic!("expected error"),
+        }
+    }
+
+    #[test]
+    fn test_challenge() {
+        assert_eq!(
+            bincode::serialized_size(&Challenge::default()).unwrap(),
+            Op::Challenge.size().unwrap() as u64
+        );
+        assert_eq!(
+            bincode::serialized_size(&ChallengeReply::default()).unwrap(),
+            Op::ChallengeReply.size().unwrap() as u64
+        );
+
+        let challenge = Challenge::new(8);
+        let reply = challenge.solve();
+        assert!(challenge.is_solved_by(&reply));
+        assert!(!Challenge::new(8).is_solved_by(&reply));
+
+        let cookie = Challenge::new(0);
+        assert!(cookie.is_solved_by(&ChallengeReply {
+            nonce: cookie.nonce,
+            solution: 0,
+        }));
+    }
+
+    #[test]
+    fn test_identity() {
+        let mut codec = StCodec::default();
+        let mut buf = BytesMut::new();
+        let identify = Identify {
+            nonce: 7,
+            key_share: [3; 32],
+        };
+        codec
+            .encode(StCommand::Identify(identify), &mut buf)
+            .unwrap();
+        assert_eq!(buf.len(), 1 + Op::Identify.size().unwrap() as usize);
+        let proof = IdentityProof {
+            public_key: [1; 32],
+            key_share: [4; 32],
+            signature: vec![2; 64],
+        };
+        codec
+            .encode(StCommand::Identity(Some(proof)), &mut buf)
+            .unwrap();
+        codec.encode(StCommand::Identity(None), &mut buf).unwrap();
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(StCommand::Identify(decoded)) => assert_eq!(decoded, identify),
+            _ => panic!("expected identify"),
+        }
+        match codec.decode(&mut buf).unwrap() {
+            Some(StCommand::Identity(Some(proof))) => {
+                assert_eq!(proof.public_key, [1; 32]);
+                assert_eq!(proof.key_share, [4; 32]);
+                assert_eq!(proof.signature, vec![2; 64]);
+            }
+            _ => panic!("expected identity"),
+        }
+        match codec.decode(&mut buf).unwrap() {
+            Some(StCommand::Identity(None)) => (),
+            _ => panic!("expected identity without proof"),
+        }
+    }
+
+    #[test]
+    fn test_packet_auth() {
+        let auth = PacketAuth::default();
+        let mut encoder = StCodec::with_auth(auth.clone());
+        let decoder_auth = PacketAuth::default();
+        let mut decoder = StCodec::with_auth(decoder_auth.clone());
+        let mut buf = BytesMut::new();
+        encoder.encode(StCommand::hello(1), &mut buf).unwrap();
+        auth.set_key([5; 32]);
+        encoder.encode(StCommand::Ask(2), &mut buf).unwrap();
+        let block = StCommand::block(2, 0, 0, vec![7; 100]);
+        encoder.encode(block, &mut buf).unwrap();
+
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Ok(Some(StCommand::Hello(_)))
+        ));
+        decoder_auth.set_key([5; 32]);
+        // The tag follows the packet, which waits for it.
+        let mut partial = buf.split_to(1 + 16 + 1);
+        assert!(matches!(decoder.decode(&mut partial), Ok(None)));
+        partial.unsplit(buf);
+        let mut buf = partial;
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Ok(Some(StCommand::Ask(_)))
+        ));
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Ok(Some(StCommand::Block(_)))
+        ));
+        assert!(buf.is_empty());
+
+        // Packets without the tag of the session, e.g. of a node that passed a proof on.
+        let mut relay = StCodec::with_auth(PacketAuth::default());
+        relay.encode(StCommand::Ask(3), &mut buf).unwrap();
+        assert!(matches!(decoder.decode(&mut buf), Ok(None)));
+        relay.encode(StCommand::Ask(3), &mut buf).unwrap();
+        relay.encode(StCommand::Ask(3), &mut buf).unwrap();
+        assert!(decoder.decode(&mut buf).is_err());
+        let relay_auth = PacketAuth::default();
+        relay_auth.set_key([6; 32]);
+        let mut relay = StCodec::with_auth(relay_auth);
+        let mut buf = BytesMut::new();
+        relay.encode(StCommand::Ask(3), &mut buf).unwrap();
+        assert!(decoder.decode(&mut buf).is_err());
+
+        // Replayed packets carry the tag of an earlier count.
+        let replay_auth = PacketAuth::default();
+        replay_auth.set_key([5; 32]);
+        let mut replay = StCodec::with_auth(replay_auth);
+        let mut buf = BytesMut::new();
+        replay.encode(StCommand::Ask(2), &mut buf).unwrap();
+        let decoder_auth = PacketAuth::default();
+        decoder_auth.set_key([5; 32]);
+        let mut decoder = StCodec::with_auth(decoder_auth);
+        let mut replayed = buf.clone();
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert!(decoder.decode(&mut replayed).is_err());
+    }
+
+    #[test]
+    fn test_ask_token() {
+        assert_eq!(
+            bincode::serialized_size(&AskToken::default()).unwrap(),
+            Op::AskToken.size().unwrap() as u64
+        );
+
+        let mut codec = StCodec::default();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(
+                StCommand::AskToken(AskToken { hash: 1, token: 2 }),
+                &mut buf,
+            )
+            .unwrap();
+        codec
+            .encode(StCommand::ask_reply_unauthorized(1), &mut buf)
+            .unwrap();
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(StCommand::AskToken(ask)) => assert_eq!((ask.hash, ask.token), (1, 2)),
+            _ => panic!("expected ask token"),
+        }
+        match codec.decode(&mut buf).unwrap() {
+            Some(StCommand::AskReply(reply)) => {
+                assert!(reply.files.is_none());
+                assert!(reply.unauthorized);
+            }
+            _ => panic!("expected ask reply"),
+        }
+        assert!(buf.is_empty());
+    }
+
+    /// `StCommand` printed with `display`, as proptest needs `Debug` values.
+    struct Cmd(StCommand);
+
+    impl std::fmt::Debug for Cmd {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}", self.0.display())
+        }
+    }
+
+    /// Name, size, blocks, algorithm, mode, mtime and link target of a `FileMap`.
+    type FileMapParts = (
+        String,
+        u64,
+        Vec<u128>,
+        bool,
+        Option<u32>,
+        Option<(u64, u32)>,
+        Option<String>,
+    );
+
+    fn arb_file_map() -> impl Strategy<Value = FileMapParts> {
+        (
+            "[a-z/.]{1,12}",
+            any::<u64>(),
+            prop::collection::vec(any::<u128>(), 0..4),
+            any::<bool>(),
+            prop::option::of(any::<u32>()),
+            prop::option::of((0u64..1 << 40, 0u32..1_000_000_000)),
+            prop::option::of("[a-z/.]{0,12}"),
+        )
+    }
+
+    fn file_map(parts: FileMapParts) -> FileMap {
+        let (name, file_size, blocks, blake3, mode, mtime, link) = parts;
+        FileMap {
+            file_name: name.as_str().into(),
+            file_size,
+            blocks,
+            hash_algorithm: if blake3 {
+                HashAlgorithm::Blake3
+            } else {
+                HashAlgorithm::Sha224
+            },
+            mode,
+            mtime: mtime.map(|(secs, nanos)| {
+                SystemTime::UNIX_EPOCH + std::time::Duration::new(secs, nanos)
+            }),
+            link,
+        }
+    }
+
+    fn arb_ask_reply() -> impl Strategy<Value = Cmd> {
+        (
+            any::<u128>(),
+            prop::option::of(prop::collection::vec(arb_file_map(), 0..4)),
+            prop::collection::vec(any::<SocketAddr>(), 0..3),
+            any::<bool>(),
+            prop::option::of((any::<[u8; 32]>(), prop::collection::vec(any::<u8>(), 0..80))),
+            prop::collection::vec((any::<u32>(), any::<u32>()), 0..3),
+            prop::option::of(prop::collection::vec(any::<u8>(), 0..200)),
+            prop::option::of(any::<u32>()),
+        )
+            .prop_map(
+                |(
+                    hash,
+                    files,
+                    peers,
+                    unauthorized,
+                    signature,
+                    inline_files,
+                    inline_data,
+                    total_files,
+                )| {
+                    Cmd(StCommand::AskReply(AskReply {
+                        hash,
+                        files: files.map(|files| files.into_iter().map(file_map).collect()),
+                        peers,
+                        unauthorized,
+                        signature: signature.map(|(public_key, signature)| FileMapSignature {
+                            public_key,
+                            signature,
+                        }),
+                        signed_by: None,
+                        inline_files: inline_files
+                            .into_iter()
+                            .map(|(file_nr, offset)| InlineFile { file_nr, offset })
+                            .collect(),
+                        inline_data,
+                        total_files,
+                    }))
+                },
+            )
+    }
+
+    fn arb_command() -> impl Strategy<Value = Cmd> {
+        let bytes = || prop::collection::vec(any::<u8>(), 0..2048).prop_map(Bytes::from);
+        let empty = |cmd: fn() -> StCommand| Just(()).prop_map(move |()| Cmd(cmd()));
+        prop_oneof![
+            empty(|| StCommand::Nop),
+            (any::<u8>(), any::<u128>()).prop_map(|(proto_version, node_id)| {
+                Cmd(StCommand::Hello(Hello {
+                    proto_version,
+                    node_id,
+                }))
+            }),
+            any::<u128>().prop_map(|hash| Cmd(StCommand::Ask(hash))),
+            arb_ask_reply(),
+            (any::<u128>(), any::<u32>(), any::<u32>()).prop_map(|(hash, file_nr, block_nr)| {
+                Cmd(StCommand::GetBlock(GetBlock {
+                    hash,
+                    file_nr,
+                    block_nr,
+                }))
+            }),
+            (any::<u128>(), any::<u32>(), any::<u32>(), bytes()).prop_map(
+                |(hash, file_nr, block_nr, bytes)| {
+                    Cmd(StCommand::block(hash, file_nr, block_nr, bytes))
+                }
+            ),
+            empty(|| StCommand::Bye),
+            (
+                any::<u128>(),
+                any::<u32>(),
+                any::<u32>(),
+                any::<u64>(),
+                any::<u64>(),
+                bytes()
+            )
+                .prop_map(|(hash, file_nr, block_nr, offset, block_size, bytes)| {
+                    Cmd(StCommand::BlockPart(BlockPart {
+                        hash,
+                        block_nr,
+                        file_nr,
+                        offset,
+                        block_size,
+                        bytes,
+                    }))
+                }),
+            empty(|| StCommand::RelayRegister),
+            any::<u128>().prop_map(|node_id| Cmd(StCommand::RelayConnect(node_id))),
+            any::<u128>().prop_map(|token| Cmd(StCommand::RelayOffer(token))),
+            any::<u128>().prop_map(|token| Cmd(StCommand::RelayAccept(token))),
+            (any::<u128>(), any::<u8>()).prop_map(|(node_id, status)| {
+                Cmd(StCommand::RelayReply(RelayReply { node_id, status }))
+            }),
+            (any::<u128>(), any::<u128>())
+                .prop_map(|(hash, token)| { Cmd(StCommand::AskToken(AskToken { hash, token })) }),
+            any::<u16>().prop_map(|code| Cmd(StCommand::Error(code))),
+            (any::<u128>(), any::<u8>())
+                .prop_map(|(nonce, bits)| Cmd(StCommand::Challenge(Challenge { nonce, bits }))),
+            (any::<u128>(), any::<u64>()).prop_map(|(nonce, solution)| {
+                Cmd(StCommand::ChallengeReply(ChallengeReply {
+                    nonce,
+                    solution,
+                }))
+            }),
+            (any::<u128>(), any::<u32>()).prop_map(|(hash, offset)| {
+                Cmd(StCommand::GetFileMaps(GetFileMaps { hash, offset }))
+            }),
+            (
+                any::<u128>(),
+                any::<u32>(),
+                prop::collection::vec(arb_file_map(), 0..4)
+            )
+                .prop_map(|(hash, offset, files)| {
+                    Cmd(StCommand::FileMaps(FileMaps {
+                        hash,
+                        offset,
+                        files: files.into_iter().map(file_map).collect(),
+                    }))
+                }),
+        ]
+    }
+
+    fn encode(cmd: StCommand) -> BytesMut {
+        let mut buf = BytesMut::new();
+        StCodec::default().encode(cmd, &mut buf).unwrap();
+        buf
+    }
+
+    proptest! {
+        /// Decoded packets encode to the same bytes, covering every field on the wire.
+        #[test]
+        fn prop_round_trip(cmds in prop::collection::vec(arb_command(), 1..8)) {
+            let encoded: Vec<BytesMut> = cmds.into_iter().map(|cmd| encode(cmd.0)).collect();
+            let mut buf = BytesMut::new();
+            for packet in &encoded {
+                buf.extend_from_slice(packet);
+            }
+
+            let mut codec = StCodec::default();
+            for packet in &encoded {
+                let decoded = codec.decode(&mut buf).unwrap().unwrap();
+                prop_assert_eq!(&encode(decoded)[..], &packet[..]);
+            }
+            prop_assert!(buf.is_empty());
+            prop_assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+
+        /// Packets delivered a byte at a time are decoded once complete, and only then.
+        #[test]
+        fn prop_split_delivery(cmds in prop::collection::vec(arb_command(), 1..4)) {
+            let encoded: Vec<BytesMut> = cmds.into_iter().map(|cmd| encode(cmd.0)).collect();
+            let stream: Vec<u8> = encoded.iter().flat_map(|packet| packet.to_vec()).collect();
+            let mut ends = encoded.iter().scan(0, |end, packet| {
+                *end += packet.len();
+                Some(*end)
+            });
+
+            let mut codec = StCodec::default();
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            let mut next_end = ends.next();
+            for (pos, b) in stream.iter().enumerate() {
+                buf.put_u8(*b);
+                let cmd = codec.decode(&mut buf).unwrap();
+                prop_assert_eq!(cmd.is_some(), next_end == Some(pos + 1));
+                if let Some(cmd) = cmd {
+                    decoded.push(encode(cmd));
+                    next_end = ends.next();
+                }
+            }
+            prop_assert_eq!(decoded, encoded);
+        }
+
+        /// Length prefixes above `MAX_PACKET_SIZE` fail before the body arrives.
+        #[test]
+        fn prop_oversized_packet(
+            op in prop::sample::select(vec![
+                Op::AskReply as u8,
+                Op::GetBlock as u8,
+                Op::Block as u8,
+                Op::BlockPart as u8,
+            ]),
+            size in (MAX_PACKET_SIZE as u32 + 1)..=u32::MAX,
+        ) {
+            let mut buf = BytesMut::new();
+            buf.put_u8(op);
+            buf.put_u32_le(size);
+            let e = StCodec::default().decode(&mut buf).err().unwrap();
+            prop_assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+
+        /// Inner lengths disagreeing with the packet size are rejected, not trusted.
+        #[test]
+        fn prop_block_length_mismatch(
+            len in any::<u64>(),
+            payload in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            prop_assume!(len != payload.len() as u64);
+            let mut body = BytesMut::new();
+            Block {
+                hash: 1,
+                block_nr: 2,
+                file_nr: 3,
+                bytes: payload.into(),
+            }
+            .encode(&mut body);
+            LittleEndian::write_u64(&mut body[24..32], len);
+
+            let mut buf = BytesMut::new();
+            buf.put_u8(Op::Block as u8);
+            buf.put_u32_le(body.len() as u32);
+            buf.extend_from_slice(&body);
+            let e = StCodec::default().decode(&mut buf).err().unwrap();
+            prop_assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+
+        /// Garbage never panics the decoder.
+        #[test]
+        fn prop_arbitrary_input(data in prop::collection::vec(any::<u8>(), 0..256)) {
+            let mut codec = StCodec::default();
+            let mut buf = BytesMut::from(&data[..]);
+            while let Ok(Some(_)) = codec.decode(&mut buf) {}
+        }
+
+        #[test]
+        fn prop_unknown_opcode(op in (Op::Auth as u8 + 1)..=u8::MAX, rest in any::<[u8; 8]>()) {
+            let mut buf = BytesMut::new();
+            buf.put_u8(op);
+            buf.extend_from_slice(&rest);
+            let e = StCodec::default().decode(&mut buf).err().unwrap();
+            prop_assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_max_packet_size() {
+        let mut codec = StCodec::default();
+        let block = StCommand::block(1, 0, 0, vec![7u8; MAX_BLOCK_PAYLOAD]);
+        let mut buf = encode(block);
+        assert_eq!(buf.len(), 1 + 4 + MAX_PACKET_SIZE);
+        match codec.decode(&mut buf).unwrap() {
+            Some(StCommand::Block(block)) => assert_eq!(block.bytes.len(), MAX_BLOCK_PAYLOAD),
+            _ => panic!("expected block"),
+        }
+
+        let mut buf = encode(StCommand::block(1, 0, 0, vec![7u8; MAX_BLOCK_PAYLOAD + 1]));
+        assert!(codec.decode(&mut buf).is_err());
+    }
 }
@@ -1,10 +1,18 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use crate::archive::SymlinkPolicy;
+use crate::download::{CopyStrategy, FsyncPolicy, LocalPolicy, VerifyPolicy};
+use crate::error::{Error, PeerFailure};
+use crate::file_name::{CaseCollision, CasePolicy, NamePolicy};
+use crate::mode::NodeMode;
+use crate::user_report::Privacy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Fields not listed are refused, see `parse`.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 #[serde(tag = "command")]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub enum Command {
     Id,
     Addresses,
@@ -14,6 +22,36 @@ pub enum Command {
         hash: Option<String>,
         #[serde(default)]
         user: Option<User>,
+        /// Hex access token required from downloaders
+        #[serde(default)]
+        token: Option<String>,
+        /// Hex node ids of peers allowed to download
+        #[serde(default)]
+        allowed_peers: Option<Vec<String>>,
+        /// Random key of 64 hex digits the files are encrypted with before sharing
+        #[serde(default)]
+        encryption_key: Option<String>,
+        /// Namespace the resource is listed and managed in
+        #[serde(default)]
+        namespace: Option<String>,
+        /// What is done with file names differing only in case, `keep`, `error` or
+        /// `rename`, defaults to `error` on Windows and macOS and `keep` elsewhere
+        #[serde(default)]
+        case_policy: Option<CasePolicy>,
+        /// Key/value pairs of the resource, listed by `GET /resources` and searchable with
+        /// its `meta` filter
+        #[serde(default)]
+        metadata: Option<BTreeMap<String, String>>,
+        /// Expiry in seconds since the unix epoch, instead of the one given by `timeout`
+        #[serde(default)]
+        valid_to: Option<u64>,
+        /// Metadata and expiries of single files, by their paths in `files`
+        #[serde(default)]
+        file_options: Option<HashMap<PathBuf, FileOptions>>,
+        /// Sent by Golem clients, ignored
+        #[serde(default, skip_serializing)]
+        #[schemars(skip)]
+        id: Option<serde_json::Value>,
     },
     Download {
         hash: String,
@@ -22,25 +60,210 @@ pub enum Command {
         timeout: Option<f64>,
         #[serde(default)]
         user: Option<User>,
+        /// Hex access token of the resource
+        #[serde(default)]
+        token: Option<String>,
+        /// Key of 64 hex digits the resource was encrypted with when shared
+        #[serde(default)]
+        encryption_key: Option<String>,
+        /// Hex node id file maps have to be signed by
+        #[serde(default)]
+        signer: Option<String>,
+        /// Share the downloaded files under the same hash
+        #[serde(default)]
+        share_after_download: bool,
+        /// Accept peers presenting node ids other than the pinned ones and pin them
+        #[serde(default)]
+        repin: bool,
+        /// Create missing destination directories, defaults to true
+        #[serde(default)]
+        create_dest: Option<bool>,
+        /// Set the modification times of the shared files, defaults to true
+        #[serde(default)]
+        restore_mtime: Option<bool>,
+        /// How file names are stored, `keep` or `portable`
+        #[serde(default)]
+        file_names: NamePolicy,
+        /// What is done with file names differing only in case, like in `upload`
+        #[serde(default)]
+        case_policy: Option<CasePolicy>,
+        /// `first` (default) copies the resource, or files of it, this node shares already,
+        /// `only` copies without dialing peers, `never` fetches from peers
+        #[serde(default)]
+        local: Option<LocalPolicy>,
+        /// `full` (default) hashes every block when it arrives, `endOfFile` whole files once
+        /// complete, `off` nothing
+        #[serde(default)]
+        verify: Option<VerifyPolicy>,
+        /// Bytes of a file buffered before writing them, consecutive blocks in one write;
+        /// defaults to 4 blocks, 0 writes every block as it arrives
+        #[serde(default)]
+        write_buffer: Option<usize>,
+        /// `file` (default) syncs each file before moving it in place, `transfer` all
+        /// files once the resource is downloaded, `off` none
+        #[serde(default)]
+        fsync: Option<FsyncPolicy>,
+        /// Sent by Golem clients, ignored
+        #[serde(default, skip_serializing)]
+        #[schemars(skip)]
+        size: Option<serde_json::Value>,
+    },
+    /// Downloads several resources from the same peers at once, sharing connections to
+    /// them. Failing resources don't stop the others, see `DownloadBatchResult`.
+    #[serde(rename = "download_batch")]
+    DownloadBatch {
+        resources: Vec<BatchResource>,
+        peers: Vec<PeerInfo>,
+        timeout: Option<f64>,
+        #[serde(default)]
+        user: Option<User>,
+        /// Resources downloaded at the same time, defaults to 4
+        #[serde(default)]
+        concurrency: Option<usize>,
+        /// Options applying to every resource, like in `download`
+        #[serde(default)]
+        signer: Option<String>,
+        #[serde(default)]
+        share_after_download: bool,
+        #[serde(default)]
+        repin: bool,
+        #[serde(default)]
+        create_dest: Option<bool>,
+        #[serde(default)]
+        restore_mtime: Option<bool>,
+        #[serde(default)]
+        file_names: NamePolicy,
+        #[serde(default)]
+        case_policy: Option<CasePolicy>,
+        #[serde(default)]
+        local: Option<LocalPolicy>,
+        #[serde(default)]
+        verify: Option<VerifyPolicy>,
+        #[serde(default)]
+        write_buffer: Option<usize>,
+        #[serde(default)]
+        fsync: Option<FsyncPolicy>,
+    },
+    /// Makes other nodes download and share a resource of this node.
+    Replicate {
+        hash: String,
+        /// RPC addresses of the nodes, <ip>:<port>
+        targets: Vec<String>,
+        /// Download directory on the nodes
+        dest: PathBuf,
+        /// Addresses the nodes download from, defaults to this node's transfer address
+        #[serde(default)]
+        peers: Option<Vec<PeerInfo>>,
+    },
+    /// Returns the resource hash `upload` would give the files, without sharing them.
+    Hash {
+        files: HashMap<PathBuf, String>,
+        /// `sha224` or `blake3`, defaults to `--hash_algorithm`
+        #[serde(default)]
+        hash_algorithm: Option<String>,
+    },
+    /// Asks peers for a resource without downloading any blocks.
+    #[serde(rename = "check_availability")]
+    CheckAvailability {
+        hash: String,
+        peers: Vec<PeerInfo>,
+        /// Seconds each peer has to answer
+        timeout: Option<f64>,
+        /// Hex access token of the resource
+        #[serde(default)]
+        token: Option<String>,
+        /// Hex node id file maps have to be signed by
+        #[serde(default)]
+        signer: Option<String>,
+    },
+    /// Returns the runtime mode, switching to `mode` first if given.
+    Mode {
+        #[serde(default)]
+        mode: Option<NodeMode>,
+    },
+    /// Returns the telemetry privacy level, switching to `level` first if given.
+    Privacy {
+        #[serde(default)]
+        level: Option<Privacy>,
+    },
+    /// Returns the blocked hashes, adding and removing the given ones first.
+    Blocklist {
+        #[serde(default)]
+        add: Vec<String>,
+        #[serde(default)]
+        remove: Vec<String>,
     },
 }
 
 impl Command {
-    pub fn log_start(&self) {
+    /// Parses a body of `/api`. Bodies not fitting their command, e.g. with unknown or
+    /// missing fields, fail with `Error::InvalidCommand` naming the command.
+    pub fn parse(body: &[u8]) -> Result<Command, Error> {
+        #[derive(Deserialize)]
+        struct Name {
+            command: String,
+        }
+
+        serde_json::from_slice(body).map_err(|e| match serde_json::from_slice::<Name>(body) {
+            Ok(Name { command }) if e.is_data() => Error::InvalidCommand {
+                command,
+                message: e.to_string(),
+            },
+            _ => Error::InvalidJsonFormat(e),
+        })
+    }
+
+    /// Parses the fields of the command `name`, the body of its `/api/v2` route. An empty
+    /// body has no fields.
+    pub fn parse_fields(name: &str, body: &[u8]) -> Result<Command, Error> {
+        let mut fields = if body.iter().all(u8::is_ascii_whitespace) {
+            serde_json::Map::new()
+        } else {
+            serde_json::from_slice(body)?
+        };
+        if fields.contains_key("command") {
+            return Err(Error::InvalidArgument(format!(
+                "`command` is given by the route of {}",
+                name
+            )));
+        }
+        fields.insert("command".into(), name.into());
+        Command::parse(&serde_json::to_vec(&fields)?)
+    }
+
+    pub fn log_start(&self, request_id: &str) {
         match self {
-            Command::Id => log::info!("command st ID"),
-            Command::Addresses => log::info!("command st ADDRESSES"),
+            Command::Id => log::info!("[{}] command st ID", request_id),
+            Command::Addresses => log::info!("[{}] command st ADDRESSES", request_id),
             Command::Upload {
                 files,
                 timeout,
                 hash,
                 user,
+                token,
+                allowed_peers,
+                encryption_key,
+                namespace,
+                case_policy,
+                metadata,
+                valid_to,
+                file_options,
+                id: _,
             } => log::info!(
-                "command UPLOAD files={:?} timeout={:?} hash={:?} user={:?}",
+                "[{}] command UPLOAD files={:?} timeout={:?} hash={:?} user={:?} token={} allowed_peers={:?} encrypted={} namespace={:?} case_policy={:?} metadata={:?} valid_to={:?} file_options={:?}",
+                request_id,
                 files,
                 timeout,
                 hash,
-                user
+                user,
+                token.is_some(),
+                allowed_peers,
+                encryption_key.is_some(),
+                namespace,
+                case_policy,
+                metadata,
+                valid_to,
+                file_options
             ),
             Command::Download {
                 hash,
@@ -48,19 +271,160 @@ impl Command {
                 peers,
                 timeout,
                 user,
+                token,
+                encryption_key,
+                signer,
+                share_after_download,
+                repin,
+                create_dest,
+                restore_mtime,
+                file_names,
+                case_policy,
+                local,
+                verify,
+                write_buffer,
+                fsync,
+                size: _,
             } => log::info!(
-                "command DOWNLOAD hash={}, dest={} peers={:?} timeout={:?} user={:?}",
+                "[{}] command DOWNLOAD hash={}, dest={} peers={:?} timeout={:?} user={:?} token={} encrypted={} signer={:?} share_after_download={} repin={} create_dest={:?} restore_mtime={:?} file_names={:?} case_policy={:?} local={:?} verify={:?} write_buffer={:?} fsync={:?}",
+                request_id,
                 hash,
                 dest.display(),
                 peers,
                 timeout,
-                user
+                user,
+                token.is_some(),
+                encryption_key.is_some(),
+                signer,
+                share_after_download,
+                repin,
+                create_dest,
+                restore_mtime,
+                file_names,
+                case_policy,
+                local,
+                verify,
+                write_buffer,
+                fsync
+            ),
+            Command::DownloadBatch {
+                resources,
+                peers,
+                timeout,
+                user,
+                concurrency,
+                signer,
+                share_after_download,
+                repin,
+                create_dest,
+                restore_mtime,
+                file_names,
+                case_policy,
+                local,
+                verify,
+                write_buffer,
+                fsync,
+            } => log::info!(
+                "[{}] command DOWNLOAD_BATCH resources={:?} peers={:?} timeout={:?} user={:?} concurrency={:?} signer={:?} share_after_download={} repin={} create_dest={:?} restore_mtime={:?} file_names={:?} case_policy={:?} local={:?} verify={:?} write_buffer={:?} fsync={:?}",
+                request_id,
+                resources
+                    .iter()
+                    .map(|resource| (&resource.hash, resource.dest.display()))
+                    .collect::<Vec<_>>(),
+                peers,
+                timeout,
+                user,
+                concurrency,
+                signer,
+                share_after_download,
+                repin,
+                create_dest,
+                restore_mtime,
+                file_names,
+                case_policy,
+                local,
+                verify,
+                write_buffer,
+                fsync
+            ),
+            Command::Replicate {
+                hash,
+                targets,
+                dest,
+                peers,
+            } => log::info!(
+                "[{}] command REPLICATE hash={} targets={:?} dest={} peers={:?}",
+                request_id,
+                hash,
+                targets,
+                dest.display(),
+                peers
+            ),
+            Command::Hash {
+                files,
+                hash_algorithm,
+            } => log::info!(
+                "[{}] command HASH files={:?} hash_algorithm={:?}",
+                request_id,
+                files,
+                hash_algorithm
+            ),
+            Command::CheckAvailability {
+                hash,
+                peers,
+                timeout,
+                token,
+                signer,
+            } => log::info!(
+                "[{}] command CHECK_AVAILABILITY hash={} peers={:?} timeout={:?} token={} signer={:?}",
+                request_id,
+                hash,
+                peers,
+                timeout,
+                token.is_some(),
+                signer
+            ),
+            Command::Mode { mode } => log::info!("[{}] command MODE mode={:?}", request_id, mode),
+            Command::Privacy { level } => {
+                log::info!("[{}] command PRIVACY level={:?}", request_id, level)
+            }
+            Command::Blocklist { add, remove } => log::info!(
+                "[{}] command BLOCKLIST add={:?} remove={:?}",
+                request_id,
+                add,
+                remove
             ),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Metadata and expiry of a file of `upload`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct FileOptions {
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// Expiry in seconds since the unix epoch, the resource is only served until the
+    /// first of its files expires
+    #[serde(default)]
+    pub valid_to: Option<u64>,
+}
+
+/// A resource of `download_batch` and where it goes.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BatchResource {
+    pub hash: String,
+    pub dest: PathBuf,
+    /// Hex access token of the resource
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Key of 64 hex digits the resource was encrypted with when shared
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum AppEnv {
     TestNet,
@@ -77,7 +441,7 @@ impl AppEnv {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
     pub id: String,
@@ -88,35 +452,437 @@ pub struct User {
     pub golem_version: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub enum PeerInfo {
     TCP(String, u16),
+    /// Peer that has to present the node id, the download fails otherwise.
+    Node(String, u16, String),
+    /// Node id of a peer registered at the relay listening on the address.
+    Relay(String, u16, String),
+    /// Url of a plain HTTP server serving the files, used for blocks only.
+    Http(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub struct IdResult {
     pub id: String,
     pub version: String,
+    /// Version of resource hashes, see `filemap::BUNDLE_FORMAT`
+    #[serde(default)]
+    pub bundle_format: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub struct AddressesResult {
     pub addresses: AddressSpec,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub enum AddressSpec {
     TCP { address: String, port: u16 },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct UploadResult {
     pub hash: String,
+    /// Missing in answers of older nodes and for `check`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<ShareStatus>,
+    /// Expiry of the earlier share in seconds since the unix epoch, missing for new
+    /// resources and ones shared without expiry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_valid_to: Option<u64>,
+    /// Files named like an earlier file apart from case
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub case_collisions: Vec<CaseCollisionReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CaseCollisionReport {
+    pub file_name: String,
+    /// Earlier file name differing only in case
+    pub collides_with: String,
+    /// Name the file was stored under with the `rename` case policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renamed_to: Option<String>,
+}
+
+impl From<CaseCollision> for CaseCollisionReport {
+    fn from(collision: CaseCollision) -> Self {
+        CaseCollisionReport {
+            file_name: collision.name.to_string(),
+            collides_with: collision.collides_with.to_string(),
+            renamed_to: collision.renamed_to.map(|name| name.to_string()),
+        }
+    }
+}
+
+/// What an upload did to the resource.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ShareStatus {
+    /// Not shared before
+    New,
+    /// Already shared at least as long, kept with the access of the upload
+    Present,
+    /// Already shared, now kept until the expiry of the upload
+    Extended,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub struct DownloadResult {
+    #[serde(serialize_with = "serialize_paths_lossy")]
     pub files: Vec<PathBuf>,
+    /// Missing in answers of older nodes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<DownloadReport>,
+    /// Files named like an earlier file apart from case
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub case_collisions: Vec<CaseCollisionReport>,
+}
+
+/// Outcome of each resource of `download_batch`, in the order they were given.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadBatchResult {
+    pub resources: Vec<BatchResourceResult>,
+    /// Number of resources that failed
+    pub failed: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum BatchStatus {
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchResourceResult {
+    pub hash: String,
+    pub status: BatchStatus,
+    /// Files and report of a resource that is done
+    #[serde(default, flatten, skip_serializing_if = "Option::is_none")]
+    pub result: Option<DownloadResult>,
+    /// Why the resource failed, with the stable code of the error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    /// Failures of the peers when none provided the resource
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<PeerFailure>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadReport {
+    pub files: Vec<FileReport>,
+    /// Blocks received from each peer and HTTP source
+    pub sources: Vec<SourceReport>,
+    /// Seconds from the command until the last file was in place
+    pub duration: f64,
+    /// Size of all files
+    pub bytes: u64,
+    /// Average bytes per second
+    pub throughput: f64,
+    /// Node id the file maps had to be signed by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signer: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FileReport {
+    #[serde(serialize_with = "serialize_path_lossy")]
+    pub path: PathBuf,
+    pub size: u64,
+    /// Blocks of the file, directories and links have none
+    pub blocks: usize,
+    pub verification: Verification,
+    /// How a file copied from a share of this node was placed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copy: Option<CopyStrategy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Verification {
+    /// Every block matched its hash in the file maps, which hash to the resource hash in
+    /// one of the bundle formats, see `filemap::BundleFormat`. Maps that don't fail the
+    /// download.
+    Verified,
+    /// Copied from a share of this node, every block matched its hash in the file maps
+    /// of the share
+    Copied,
+    /// Downloaded without checking, see `VerifyPolicy::Off`
+    Unverified,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceReport {
+    /// Peer address or HTTP source url
+    pub source: String,
+    pub blocks: usize,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HashResult {
+    pub hash: String,
+    pub hash_algorithm: String,
+    /// File maps in the order they are hashed in
+    pub files: Vec<HashedFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HashedFile {
+    pub path: PathBuf,
+    pub file_name: String,
+    pub file_size: u64,
+    /// Hex hashes of the blocks
+    pub blocks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AvailabilityResult {
+    pub hash: String,
+    /// True when at least one peer has the resource
+    pub available: bool,
+    /// Size of all files as told by the first peer having the resource
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<usize>,
+    /// Peers having the resource
+    pub peers: Vec<PeerAvailability>,
+    /// Peers that don't, with the reasons given in download errors
+    pub failures: Vec<PeerFailure>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerAvailability {
+    pub peer: String,
+    pub size: u64,
+    pub files: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct ModeResult {
+    pub mode: NodeMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct PrivacyResult {
+    pub level: Privacy,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct BlocklistResult {
+    pub hashes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct ReplicateResult {
+    pub targets: Vec<ReplicaStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct ReplicaStatus {
+    pub target: String,
+    /// Files stored by the node, empty on error
+    #[serde(default, serialize_with = "serialize_paths_lossy")]
+    pub files: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Paths of files with names that aren't UTF-8 can't be sent as they are.
+fn serialize_paths_lossy<S: Serializer>(
+    paths: &[PathBuf],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(paths.iter().map(|path| path.to_string_lossy()))
+}
+
+fn serialize_path_lossy<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+/// Body of RPC error responses.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct ErrorResult {
+    pub error: String,
+    /// Stable error code, see `error::ErrorCode`
+    pub code: u16,
+    /// Command a body didn't fit, with its `schema`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionResult {
+    pub version: String,
+    pub build: BuildInfo,
+    pub protocol: ProtocolInfo,
+    /// Build features, e.g. `mmap`
+    pub features: Vec<String>,
+}
+
+/// Travis build of the binary, missing for local builds.
+#[derive(Serialize, Deserialize, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub commit: Option<String>,
+    pub build_number: Option<String>,
+    pub tag: Option<String>,
+    pub os: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtocolInfo {
+    /// Version sent in `hello`
+    pub version: u8,
+    /// Packet names indexed by opcode
+    pub opcodes: Vec<String>,
+    pub bundle_format: u32,
+    pub hash_algorithms: Vec<String>,
+    pub hash_encodings: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusResult {
+    pub id: String,
+    pub version: String,
+    pub addresses: AddressSpec,
+    pub shares: usize,
+    pub active_downloads: usize,
+    pub active_connections: usize,
+    /// Total size in bytes of all shared files
+    pub cache_usage: u64,
+    /// Database requests waiting for an answer
+    #[serde(default)]
+    pub db_queue: usize,
+    #[serde(default)]
+    pub mode: NodeMode,
+    /// Transfer connections refused by the per address limits since start
+    #[serde(default)]
+    pub refused_connections: u64,
+    /// Addresses refused for exceeding `--ip_conn_rate`
+    #[serde(default)]
+    pub banned_addresses: usize,
+    /// Restarts of the database and hashing workers after panics, by worker
+    #[serde(default)]
+    pub actor_restarts: BTreeMap<String, u64>,
+    /// Resources no longer served because a copy found their files corrupt
+    #[serde(default)]
+    pub quarantined: Vec<QuarantineReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct QuarantineReport {
+    pub hash: String,
+    pub reason: String,
+    /// Seconds since the unix epoch
+    pub since: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CleanupQuery {
+    /// Seconds, defaults to `--artifact_max_age`
+    pub max_age: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoveQuery {
+    /// Seconds the resource stays restorable, removed at once without it
+    pub grace: Option<u64>,
+    pub namespace: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceQuery {
+    /// Limits the request to resources uploaded in the namespace
+    pub namespace: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ListQuery {
+    /// Limits the request to resources uploaded in the namespace
+    pub namespace: Option<String>,
+    /// `<key>` or `<key>=<value>`, lists only resources with the metadata key and value
+    /// on the resource or one of its files
+    pub meta: Option<String>,
+    /// Lists only resources with a file name containing the text, ignoring case
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProofQuery {
+    /// Name of the file in the resource
+    pub file: String,
+    /// Number of the block to prove as well
+    pub block: Option<u32>,
+    pub namespace: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GcQuery {
+    /// Seconds, resources with an expiry shared longer ago are removed as well
+    pub lifetime_override: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchiveQuery {
+    /// What to do with symbolic links in the archive
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    pub namespace: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamQuery {
+    /// File name of the share, defaults to `stream`
+    pub name: Option<String>,
+    /// Share lifetime in seconds
+    pub timeout: Option<f64>,
+    /// Caller chosen id making the upload resumable
+    pub upload: Option<String>,
+    /// Offset the body starts at when continuing an upload
+    #[serde(default)]
+    pub offset: u64,
+    pub namespace: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadStatus {
+    pub upload: String,
+    pub name: String,
+    /// Bytes stored, the upload continues from here
+    pub offset: u64,
 }
 
 #[cfg(test)]
@@ -137,4 +903,54 @@ mod test {
         eprintln!("upload_cmd={:?}", download_cmd);
     }
 
+    #[test]
+    fn test_parse() {
+        let download = br#"{"command": "download", "hash": "00", "dests": "/tmp", "peers": []}"#;
+        match Command::parse(download) {
+            Err(Error::InvalidCommand { command, message }) => {
+                assert_eq!(command, "download");
+                assert!(message.contains("unknown field `dests`"), "{}", message);
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        let download = br#"{"command": "download", "hash": "00", "peers": []}"#;
+        match Command::parse(download) {
+            Err(Error::InvalidCommand { message, .. }) => {
+                assert!(message.contains("missing field `dest`"), "{}", message);
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        // Commands without fields take any, clients may add `user` to every command.
+        assert!(Command::parse(br#"{"command": "id", "user": null}"#).is_ok());
+        assert!(matches!(
+            Command::parse(br#"{"command": "id""#),
+            Err(Error::InvalidJsonFormat(_))
+        ));
+        let fields = br#"{"hash": "00", "dest": "/tmp", "peers": []}"#;
+        assert!(matches!(
+            Command::parse_fields("download", fields),
+            Ok(Command::Download { .. })
+        ));
+        assert!(matches!(Command::parse_fields("id", b""), Ok(Command::Id)));
+        assert!(Command::parse_fields("download", br#"{"command": "id"}"#).is_err());
+        // Fields sent by Golem are still accepted.
+        let download = br#"{"command": "download", "hash": "00", "dest": "/tmp", "peers": [], "size": null, "timeout": null}"#;
+        assert!(Command::parse(download).is_ok());
+    }
+
+    #[test]
+    fn test_upload_result() {
+        let legacy: UploadResult = serde_json::from_str(r#"{"hash":"00"}"#).unwrap();
+        assert_eq!(legacy.status, None);
+        let result = UploadResult {
+            hash: "00".into(),
+            status: Some(ShareStatus::Extended),
+            previous_valid_to: Some(1_560_000_000),
+            case_collisions: Vec::new(),
+        };
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"{"hash":"00","status":"extended","previousValidTo":1560000000}"#
+        );
+    }
 }
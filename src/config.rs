@@ -0,0 +1,83 @@
+//! Defaults for command line flags read from a TOML file.
+//!
+//! Keys are long flag names, e.g. `idle_timeout = 300` or `watch = ["/a", "/b"]`.
+//! Flags given on the command line take precedence.
+use crate::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+/// Inserts flags from the config file that are not given in `args`.
+pub fn merge_args(args: Vec<OsString>, path: &Path) -> Result<Vec<OsString>, Error> {
+    let content = fs::read_to_string(path)?;
+    let table: toml::value::Table = toml::from_str(&content)
+        .map_err(|e| Error::InvalidArgument(format!("{}: {}", path.display(), e)))?;
+
+    let mut config_args = Vec::new();
+    for (key, value) in table {
+        let flag = format!("--{}", key);
+        let given = args.iter().any(|arg| {
+            let arg = arg.to_string_lossy();
+            arg == flag || arg.starts_with(&format!("{}=", flag))
+        });
+        if given {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => config_args.push(flag.clone()),
+                toml::Value::Boolean(false) => (),
+                toml::Value::String(s) => config_args.push(format!("{}={}", flag, s)),
+                toml::Value::Integer(_) | toml::Value::Float(_) => {
+                    config_args.push(format!("{}={}", flag, value))
+                }
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "{}: unsupported value of {}",
+                        path.display(),
+                        key
+                    )))
+                }
+            }
+        }
+    }
+
+    // Before any subcommand.
+    let mut merged = Vec::with_capacity(args.len() + config_args.len());
+    let mut args = args.into_iter();
+    merged.extend(args.next());
+    merged.extend(config_args.into_iter().map(OsString::from));
+    merged.extend(args);
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge_args() {
+        let path = std::env::temp_dir().join(format!("hyperg-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "idle_timeout = 30\nport = 4000\nlan_discovery = true\nwatch = [\"/a\", \"/b\"]\n",
+        )
+        .unwrap();
+        let args = vec!["hyperg".into(), "--port".into(), "5000".into(), "ls".into()];
+        let merged = merge_args(args, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let merged: Vec<_> = merged.iter().map(|arg| arg.to_string_lossy()).collect();
+        assert_eq!(merged[0], "hyperg");
+        assert!(merged.contains(&"--idle_timeout=30".into()));
+        assert!(merged.contains(&"--lan_discovery".into()));
+        assert!(merged.contains(&"--watch=/a".into()));
+        assert!(merged.contains(&"--watch=/b".into()));
+        assert!(!merged.contains(&"--port=4000".into()));
+        assert_eq!(&merged[merged.len() - 3..], &["--port", "5000", "ls"]);
+    }
+}
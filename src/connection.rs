@@ -1,43 +1,181 @@
-use crate::codec::{AskReply, Block, GetBlock, StCodec, StCommand};
+use crate::codec::{
+    hash_to_hex, page_len, AskReply, AskToken, Block, BlockPart, Challenge, ChallengeReply,
+    FileMaps, GetBlock, GetFileMaps, Identify, IdentityProof, PacketAuth, RelayReply, RelayStatus,
+    StCodec, StCommand, INLINE_FILE_NR, MAX_BLOCK_PAYLOAD, MAX_CHALLENGE_BITS,
+};
 
-use crate::database;
-use crate::database::{DatabaseManager, FileDesc};
+use crate::connection_registry::{
+    self, CloseIdle, ConnectionDebugInfo, ConnectionInfo, GetDebugInfo, GetInfo,
+};
+use crate::database::{Access, DatabaseManager, FileDesc};
+use crate::encryption::{self, StoredFile};
 use crate::error::{Error, ProtocolError};
 use crate::filemap::{FileMap, BLOCK_SIZE};
+use crate::mode::{self, NodeMode};
+use crate::serve_queue::{self, ServeQueue};
+use crate::write_queue::{CountingRead, CountingWrite, QueuedEncoder, WriteQueue};
+use crate::{audit, blocklist, database, identity, relay};
 use actix::io::WriteHandler;
 use actix::prelude::*;
 use actix::{Actor, Addr, Context};
+use bytes::{Bytes, BytesMut};
+use failure::AsFail;
 
-use futures::unsync::oneshot;
+use futures::channel::oneshot;
+use futures::prelude::*;
+use std::cell::Cell;
 use std::cmp::min;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::io::{ErrorKind, Read, Seek, SeekFrom};
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use std::{io, net};
-use tokio_codec::FramedRead;
-use tokio_io::io::WriteHalf;
-use tokio_io::AsyncRead;
-use tokio_tcp::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio_util::codec::FramedRead;
 
 static CONNECTION_IDS: AtomicUsize = AtomicUsize::new(0);
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
 
-const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+pub fn active_connections() -> usize {
+    ACTIVE_CONNECTIONS.load(Ordering::SeqCst)
+}
+
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Timeouts {
+    handshake: Duration,
+    /// Connections without traffic and pending requests for this long are closed.
+    idle: Option<Duration>,
+}
+
+static TIMEOUTS: OnceLock<Timeouts> = OnceLock::new();
+
+/// Sets timeouts of connections opened from now on, `idle` of `None` keeps idle connections.
+pub fn set_timeouts(handshake: Duration, idle: Option<Duration>) {
+    let _ = TIMEOUTS.set(Timeouts { handshake, idle });
+}
+
+static CHALLENGE_BITS: OnceLock<u8> = OnceLock::new();
+
+/// Makes peers connecting to the transfer port solve a `Challenge` of `bits` before their
+/// asks are answered.
+pub fn set_challenge(bits: u8) {
+    let _ = CHALLENGE_BITS.set(bits);
+}
+
+static INLINE_REPLIES: AtomicBool = AtomicBool::new(false);
+
+/// Sends the inline data of small resources with the ask reply. `hello` can't tell whether
+/// the peer reads it, so it is off unless all downloaders are known to be updated.
+pub fn set_inline_replies(enabled: bool) {
+    INLINE_REPLIES.store(enabled, Ordering::Relaxed);
+}
+
+fn timeouts() -> &'static Timeouts {
+    TIMEOUTS.get_or_init(|| Timeouts {
+        handshake: DEFAULT_HANDSHAKE_TIMEOUT,
+        idle: None,
+    })
+}
+
+/// Serving of blocks is paused when more than this is waiting for the socket.
+const WRITE_HIGH_WATERMARK: usize = 4 * BLOCK_SIZE;
+/// ... and resumed when the queue drops below this.
+const WRITE_LOW_WATERMARK: usize = BLOCK_SIZE;
+/// Max number of block requests waiting for the write queue to drain.
+const MAX_DEFERRED_BLOCKS: usize = 1024;
+/// Max number of asks waiting for the peer to solve its challenge or prove its node id.
+const MAX_DEFERRED_ASKS: usize = 16;
+/// Max size of a block reassembled from `BlockPart` packets.
+const MAX_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+/// Max number of files in a bundle whose file maps come in pages.
+const MAX_BUNDLE_FILES: u32 = 10_000_000;
+
+/// Whether the peer proved the node id it announced in `Hello`, see `identity::prove`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PeerIdentity {
+    /// Not asked for a proof yet
+    Claimed,
+    /// `Identify` sent with the secret of its key share, waiting for the proof
+    Requested(Identify, [u8; 32]),
+    Proven(u128),
+    /// The peer has no identity key or sent an invalid proof
+    Unproven,
+}
+
+type FramedWrite =
+    actix::io::FramedWrite<StCommand, CountingWrite<OwnedWriteHalf>, QueuedEncoder<StCodec>>;
 
 pub struct Connection {
     connection_id: usize,
     db: Addr<DatabaseManager>,
     peer_addr: net::SocketAddr,
-    framed: actix::io::FramedWrite<WriteHalf<TcpStream>, StCodec>,
+    framed: FramedWrite,
+    write_queue: WriteQueue,
+    /// Block requests of the peer, served in turns given by the `ServeQueue`.
+    deferred_blocks: VecDeque<GetBlock>,
+    drain_scheduled: bool,
+    turn_requested: bool,
+    /// Node id announced by this node in `Hello`.
+    node_id: Option<u128>,
+    /// Node id announced by the peer, not authenticated unless `identity` is proven.
     peer_id: Option<u128>,
+    identity: PeerIdentity,
+    opened: Instant,
+    last_activity: Instant,
+    bytes_in: Rc<Cell<u64>>,
     current_file: Option<Arc<database::FileDesc>>,
+    block_reader: BlockReader,
     block_requests: HashMap<GetBlock, oneshot::Sender<Result<Block, Error>>>,
+    partial_blocks: HashMap<GetBlock, BytesMut>,
     ask_requests: HashMap<u128, oneshot::Sender<Result<AskReply, Error>>>,
+    /// Byte ranges of the inline files announced by the peer, in the block for
+    /// `INLINE_FILE_NR`.
+    inline_files: HashMap<u128, Vec<(u32, Range<usize>)>>,
+    /// Inline data sent by the peer with its ask reply.
+    inline_data: HashMap<u128, Bytes>,
+    /// Ask replies with the first pages of their file maps, until the rest arrives.
+    partial_replies: HashMap<u128, AskReply>,
+    /// File maps received earlier for the asks, see `Ask::known_files`.
+    known_files: HashMap<u128, Arc<Vec<FileMap>>>,
+    relay_requests: HashMap<u128, oneshot::Sender<Result<(), Error>>>,
+    /// Other end of the relay session, all packets are forwarded to it.
+    relay_peer: Option<Addr<Connection>>,
+    /// Block bytes left to forward in the relay session.
+    relay_quota: u64,
+    /// Node registered at this relay over the connection.
+    relay_node: Option<u128>,
+    /// Node the session requested over the connection goes to, and its token until accepted.
+    relay_session: Option<(u128, Option<u128>)>,
+    /// This node registered at the relay over the connection.
+    relay_control: bool,
+    /// The peer asked to register at this relay, it is registered once it proves its node id.
+    relay_register_pending: bool,
+    /// Outstanding requests failed, the actor stops shortly.
+    closing: bool,
+    /// Node id of the peer is checked once known.
+    verify_peer: Option<VerifyPeer>,
+    /// Answers `VerifyPeer` once the node id is checked.
+    verify_reply: Option<oneshot::Sender<Result<(), Error>>>,
+    /// Tags packets sent once this node proved its node id to the peer.
+    send_auth: PacketAuth,
+    /// Checks packets received once the peer proved its node id.
+    recv_auth: PacketAuth,
     reporter: crate::user_report::UserReportHandle,
+    /// Slot of an accepted connection in the per address limits, freed on drop.
+    admission: Option<crate::server::Admission>,
+    /// Challenge sent to the peer and not solved yet, its asks wait in `deferred_asks`.
+    challenge: Option<Challenge>,
+    /// Asks waiting for the peer to solve its challenge or prove its node id.
+    deferred_asks: Vec<(u128, Option<u128>)>,
+    /// Packets of the connection are handled in this span, see `crate::trace`.
+    span: tracing::Span,
 }
 
 impl Drop for Connection {
@@ -54,12 +192,28 @@ impl Actor for Connection {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+        connection_registry::register(self.connection_id, ctx.address());
         log::info!(
             "opened connection [{}] [{}]",
             self.connection_id,
             self.peer_addr
         );
-        ctx.run_later(HANDSHAKE_TIMEOUT, |act, ctx| {
+        let timeouts = timeouts();
+        if let Some(idle_timeout) = timeouts.idle {
+            let check_interval = std::cmp::max(idle_timeout / 4, Duration::from_secs(1));
+            ctx.run_interval(check_interval, move |act, ctx| {
+                if act.is_idle() && act.last_activity.elapsed() >= idle_timeout {
+                    log::info!(
+                        "[{}] closing idle connection {}",
+                        act.connection_id,
+                        act.peer_addr
+                    );
+                    act.close_idle(ctx);
+                }
+            });
+        }
+        ctx.run_later(timeouts.handshake, |act, ctx| {
             if act.peer_id.is_none() {
                 log::error!(
                     "[{}] identification timeout for {}",
@@ -72,6 +226,20 @@ impl Actor for Connection {
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+        connection_registry::unregister(self.connection_id);
+        if let Some(peer) = self.relay_peer.take() {
+            peer.do_send(relay::Closed);
+        }
+        if let Some((node_id, token)) = self.relay_session.take() {
+            if let Some(token) = token {
+                relay::expire(token);
+            }
+            relay::close_session(node_id);
+        }
+        if let Some(node_id) = self.relay_node.take() {
+            relay::unregister(node_id, self.connection_id);
+        }
         log::info!(
             "closed connection [{}] [{}]",
             self.connection_id,
@@ -86,28 +254,87 @@ impl Connection {
         tcp_stream: TcpStream,
         peer_addr: net::SocketAddr,
         reporter: &crate::user_report::UserReportHandle,
+        admission: Option<crate::server::Admission>,
     ) -> Addr<Connection> {
         let connection_id = CONNECTION_IDS.fetch_add(1, Ordering::SeqCst);
         let reporter = reporter.new_context();
+        // Below the transfer span for connections made by downloads.
+        let span = tracing::info_span!(
+            "connection",
+            id = connection_id,
+            peer = %peer_addr,
+            peer_id = tracing::field::Empty,
+        );
         let addr: Addr<Connection> = Connection::create(move |ctx| {
-            let (r, w) = tcp_stream.split();
-            let framed = actix::io::FramedWrite::new(w, StCodec::default(), ctx);
-            log::debug!("opened connection id={}, peer={}", connection_id, peer_addr);
+            let (r, w) = tcp_stream.into_split();
+            let write_queue = WriteQueue::new(WRITE_LOW_WATERMARK, WRITE_HIGH_WATERMARK);
+            let (send_auth, recv_auth) = (PacketAuth::default(), PacketAuth::default());
+            let framed = actix::io::FramedWrite::new(
+                CountingWrite::new(w, write_queue.clone()),
+                QueuedEncoder::new(StCodec::with_auth(send_auth.clone()), write_queue.clone()),
+                ctx,
+            );
+            match reporter.request_id() {
+                Some(request_id) => log::info!(
+                    "[{}] opened connection id={}, peer={}",
+                    request_id,
+                    connection_id,
+                    peer_addr
+                ),
+                None => log::debug!("opened connection id={}, peer={}", connection_id, peer_addr),
+            }
 
-            reporter.annotate("connection_id", &connection_id);
-            reporter.annotate("peer", &peer_addr);
+            annotate_connection(&reporter, connection_id, peer_addr);
 
-            Connection::add_stream(FramedRead::new(r, StCodec::default()), ctx);
+            let bytes_in = Rc::new(Cell::new(0));
+            Connection::add_stream(
+                FramedRead::new(
+                    CountingRead::new(r, bytes_in.clone()),
+                    StCodec::with_auth(recv_auth.clone()),
+                ),
+                ctx,
+            );
             Connection {
                 connection_id,
                 db,
                 framed,
+                write_queue,
+                deferred_blocks: VecDeque::new(),
+                drain_scheduled: false,
+                turn_requested: false,
                 peer_addr,
+                node_id: None,
                 peer_id: None,
+                identity: PeerIdentity::Claimed,
+                opened: Instant::now(),
+                last_activity: Instant::now(),
+                bytes_in,
                 current_file: None,
+                block_reader: BlockReader::default(),
                 block_requests: HashMap::new(),
+                partial_blocks: HashMap::new(),
                 ask_requests: HashMap::new(),
+                inline_files: HashMap::new(),
+                inline_data: HashMap::new(),
+                partial_replies: HashMap::new(),
+                known_files: HashMap::new(),
+                relay_requests: HashMap::new(),
+                relay_peer: None,
+                relay_quota: 0,
+                relay_node: None,
+                relay_session: None,
+                relay_control: false,
+                relay_register_pending: false,
+                closing: false,
+                verify_peer: None,
+                verify_reply: None,
+                send_auth,
+                recv_auth,
                 reporter,
+                admission,
+                challenge: None,
+                deferred_asks: Vec::new(),
+                span,
             }
         });
 
@@ -119,15 +346,38 @@ impl Connection {
         tcp_stream: TcpStream,
         peer_addr: net::SocketAddr,
         reporter: &crate::user_report::UserReportHandle,
-    ) -> impl Future<Item = Addr<Connection>, Error = Error> {
+    ) -> impl Future<Output = Result<Addr<Connection>, Error>> {
         let id_fut = database::id(&db);
-        let addr = Self::new_addr(db, tcp_stream, peer_addr, reporter);
+        let addr = Self::new_addr(db, tcp_stream, peer_addr, reporter, None);
 
-        id_fut.and_then(move |id| {
-            addr.send(crate::codec::Hello::new(id))
-                .flatten()
-                .and_then(move |()| Ok(addr))
-        })
+        async move {
+            let id = id_fut.await?;
+            addr.send(crate::codec::Hello::new(id)).await??;
+            Ok(addr)
+        }
+    }
+
+    /// Connection accepted by the transfer server, its peer may be challenged.
+    pub fn accept(
+        db: Addr<DatabaseManager>,
+        tcp_stream: TcpStream,
+        peer_addr: net::SocketAddr,
+        admission: crate::server::Admission,
+    ) -> impl Future<Output = Result<Addr<Connection>, Error>> {
+        let id_fut = database::id(&db);
+        let addr = Self::new_addr(
+            db,
+            tcp_stream,
+            peer_addr,
+            &crate::user_report::UserReportHandle::empty(),
+            Some(admission),
+        );
+
+        async move {
+            let id = id_fut.await?;
+            addr.send(crate::codec::Hello::new(id)).await??;
+            Ok(addr)
+        }
     }
 
     pub fn new_managed(
@@ -135,19 +385,42 @@ impl Connection {
         tcp_stream: TcpStream,
         peer_addr: net::SocketAddr,
         reporter: &crate::user_report::UserReportHandle,
-    ) -> impl Future<Item = ConnectionRef, Error = Error> {
+    ) -> impl Future<Output = Result<ConnectionRef, Error>> {
         let id_fut = database::id(&db);
-        let addr = ConnectionRef(Self::new_addr(db, tcp_stream, peer_addr, reporter));
+        let addr = ConnectionRef(Rc::new(ManagedConnection(Self::new_addr(
+            db, tcp_stream, peer_addr, reporter, None,
+        ))));
 
-        id_fut.and_then(move |id| {
-            addr.send(crate::codec::Hello::new(id))
-                .flatten()
-                .and_then(move |()| Ok(addr))
-        })
+        async move {
+            let id = id_fut.await?;
+            addr.send(crate::codec::Hello::new(id)).await??;
+            Ok(addr)
+        }
+    }
+
+    /// Reports failure of serving `hash` to the share owner's reporter.
+    fn report_serve_failure(&self, hash: u128, e: &impl AsFail) {
+        log::error!(
+            "[{}] failed to serve {:032x} to {}: {}",
+            self.connection_id,
+            hash,
+            self.peer_addr,
+            e.as_fail()
+        );
+        self.reporter.annotate("hash", &hash_to_hex(hash));
+        self.reporter
+            .add_err(|| format!("serving {:032x} failed: {}", hash, e.as_fail()));
+        self.reporter.emit_fail(e);
     }
 
     fn send_ask_reply(&mut self, file_desc: FileDesc, _ctx: &mut <Self as Actor>::Context) {
-        let reply = StCommand::ask_reply(
+        crate::stats::asked(file_desc.map_hash, self.peer_id);
+        let inline_data = if INLINE_REPLIES.load(Ordering::Relaxed) {
+            self.complete_inline_data(&file_desc)
+        } else {
+            None
+        };
+        let mut reply = StCommand::ask_reply(
             file_desc.map_hash,
             Some(
                 file_desc
@@ -157,79 +430,235 @@ impl Connection {
                     .collect(),
             ),
         );
+        if let StCommand::AskReply(reply) = &mut reply {
+            crate::identity::sign(reply);
+            reply.inline_files = file_desc.inline_files;
+            reply.inline_data = inline_data;
+            // Signed over all file maps, large bundles then send them in pages.
+            *reply = std::mem::take(reply).into_first_page();
+        }
 
         self.framed.write(reply)
     }
 
+    fn handle_get_file_maps(&mut self, get: GetFileMaps, ctx: &mut <Self as Actor>::Context) {
+        if blocklist::is_blocked(get.hash) {
+            return self.close_with_error(ProtocolError::Blocked(get.hash), ctx);
+        }
+        let file_desc = match &self.current_file {
+            Some(v) if v.map_hash == get.hash => v.clone(),
+            Some(_) => return self.close_with_error(ProtocolError::UnexpectedHash(get.hash), ctx),
+            None => return self.close_with_error(ProtocolError::MissingAsk, ctx),
+        };
+        let rest = match file_desc.files.get(get.offset as usize..) {
+            Some(rest) if !rest.is_empty() => rest,
+            _ => return self.close_with_error(ProtocolError::InvalidFileNo(get.offset), ctx),
+        };
+        let page = page_len(rest.iter().map(|(file_map, _)| file_map));
+        self.framed.write(StCommand::FileMaps(FileMaps {
+            hash: get.hash,
+            offset: get.offset,
+            files: rest[..page]
+                .iter()
+                .map(|(file_map, _)| file_map.clone())
+                .collect(),
+        }))
+    }
+
+    /// Inline data sent with the ask reply, when it holds every file with blocks.
+    fn complete_inline_data(&self, file_desc: &FileDesc) -> Option<Vec<u8>> {
+        let complete = !file_desc.inline_files.is_empty()
+            && file_desc
+                .files
+                .iter()
+                .enumerate()
+                .all(|(file_nr, (file_map, _))| {
+                    file_map.blocks.is_empty()
+                        || file_desc
+                            .inline_files
+                            .iter()
+                            .any(|inline_file| inline_file.file_nr == file_nr as u32)
+                });
+        if !complete {
+            return None;
+        }
+        encryption::unseal(&file_desc.inline_data)
+            .map_err(|e| {
+                log::error!(
+                    "unable to decrypt inline data of {:032x}: {}",
+                    file_desc.map_hash,
+                    e
+                )
+            })
+            .ok()
+    }
+
     fn send_ask_reply_not_found(&mut self, hash: u128, _ctx: &mut <Self as Actor>::Context) {
-        self.framed.write(StCommand::ask_reply(hash, None))
+        self.framed.write(StCommand::ask_reply_hints(
+            hash,
+            crate::download::peer_hints(hash),
+        ))
     }
 
-    fn handle_ask(&mut self, hash: u128, ctx: &mut <Self as Actor>::Context) {
+    fn send_ask_reply_unauthorized(&mut self, hash: u128) {
+        self.report_serve_failure(hash, &ProtocolError::Unauthorized(hash));
+        self.framed.write(StCommand::ask_reply_unauthorized(hash))
+    }
+
+    fn handle_ask(&mut self, hash: u128, token: Option<u128>, ctx: &mut <Self as Actor>::Context) {
+        if blocklist::is_blocked(hash) {
+            self.report_serve_failure(hash, &ProtocolError::Blocked(hash));
+            return self.close_with_error(ProtocolError::Blocked(hash), ctx);
+        }
         if let Some(file_desc) = self.current_file.clone() {
-            if file_desc.map_hash == hash {
+            if file_desc.map_hash == hash && file_desc.access.allows(self.proven_peer_id(), token) {
                 return self.send_ask_reply(file_desc.as_ref().clone(), ctx);
             }
         }
 
+        if database::is_overloaded() {
+            return self.close_with_error(ProtocolError::Busy, ctx);
+        }
+        if mode::current() == NodeMode::Maintenance {
+            return self.close_with_error(ProtocolError::Maintenance, ctx);
+        }
+        if crate::stats::quota_exceeded() {
+            return self.close_with_error(ProtocolError::QuotaExceeded, ctx);
+        }
+
         let reply_hash = hash;
 
-        let f = self
-            .db
-            .send(database::GetHash(hash))
-            .then(|v| match v {
-                Err(e) => Err(e.into()),
-                Ok(v) => v,
-            })
+        let f = database::call(&self.db, database::GetHash(hash))
             .into_actor(self)
-            .and_then(move |r, act: &mut Self, ctx| match r {
-                Some((file_desc, reporter)) => {
-                    act.reporter = reporter;
+            .map(move |r, act: &mut Self, ctx| match r {
+                Ok(Some((file_desc, _)))
+                    if !file_desc.access.allows(act.proven_peer_id(), token) =>
+                {
+                    if act.identify_for(&file_desc.access) {
+                        act.defer_ask(reply_hash, token, ctx)
+                    } else {
+                        act.send_ask_reply_unauthorized(reply_hash);
+                    }
+                }
+                Ok(Some((file_desc, reporter))) => {
+                    act.reporter = reporter.new_context();
+                    annotate_connection(&act.reporter, act.connection_id, act.peer_addr);
+                    act.reporter.add_note(|| format!("ask {:032x}", reply_hash));
                     if file_desc.map_hash == reply_hash {
                         act.current_file = Some(file_desc.clone());
+                        act.block_reader.clear();
                         act.send_ask_reply(file_desc.as_ref().clone(), ctx);
-                        fut::ok(())
                     } else {
-                        panic!("unexpected result on db call")
+                        let e = ProtocolError::Internal(format!(
+                            "database answered ask for {:032x} with {:032x}",
+                            reply_hash, file_desc.map_hash
+                        ));
+                        act.report_serve_failure(reply_hash, &e);
+                        act.close_with_error(e, ctx)
                     }
                 }
-                None => {
-                    act.send_ask_reply_not_found(reply_hash, ctx);
-                    fut::ok(())
+                Ok(None) => act.send_ask_reply_not_found(reply_hash, ctx),
+                Err(e) => {
+                    log::error!("fail to handle ask from: {}", &act.peer_addr);
+                    act.report_serve_failure(reply_hash, &e);
+                    act.close_with_error(ProtocolError::Internal(e.to_string()), ctx)
                 }
-            })
-            .map_err(|_e, act, ctx| {
-                log::error!("fail to handle ask from: {}", &act.peer_addr);
-                ctx.stop()
             });
 
         ctx.spawn(f);
     }
 
-    // TODO: return error in proto
+    /// Serves the block now, or defers it while the write queue is above the watermark.
+    fn queue_get_block(&mut self, get_block: GetBlock, ctx: &mut <Self as Actor>::Context) {
+        if self.deferred_blocks.len() >= MAX_DEFERRED_BLOCKS {
+            log::error!(
+                "[{}] too many pending block requests from {}",
+                self.connection_id,
+                self.peer_addr
+            );
+            return self.close_with_error(ProtocolError::TooManyRequests, ctx);
+        }
+        self.deferred_blocks.push_back(get_block);
+        self.request_turn(ctx);
+    }
+
+    /// Asks the `ServeQueue` for a turn once the write queue has room.
+    fn request_turn(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if self.turn_requested || self.deferred_blocks.is_empty() {
+            return;
+        }
+        if self.write_queue.is_full() {
+            log::trace!(
+                "[{}] write queue full ({} bytes), deferring block requests",
+                self.connection_id,
+                self.write_queue.queued()
+            );
+            return self.schedule_drain(ctx);
+        }
+        self.turn_requested = true;
+        ServeQueue::from_registry().do_send(serve_queue::Ready {
+            connection_id: self.connection_id,
+            ip: self.peer_addr.ip(),
+            connection: ctx.address(),
+        });
+    }
+
+    fn schedule_drain(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if self.drain_scheduled {
+            return;
+        }
+        self.drain_scheduled = true;
+        ctx.spawn(
+            self.write_queue
+                .drained()
+                .into_actor(self)
+                .map(|(), act, ctx| {
+                    act.drain_scheduled = false;
+                    act.request_turn(ctx);
+                }),
+        );
+    }
+
     fn handle_get_block(&mut self, get_block: GetBlock, ctx: &mut <Self as Actor>::Context) {
+        // Queued blocks are served outside of the packet handler.
+        let _span = tracing::debug_span!(
+            parent: &self.span,
+            "serve_block",
+            hash = %hash_to_hex(get_block.hash),
+            file_nr = get_block.file_nr,
+            block_nr = get_block.block_nr,
+        )
+        .entered();
+        // Blocking a resource stops the transfers running for it as well.
+        if blocklist::is_blocked(get_block.hash) {
+            self.report_serve_failure(get_block.hash, &ProtocolError::Blocked(get_block.hash));
+            return self.close_with_error(ProtocolError::Blocked(get_block.hash), ctx);
+        }
+        // Only asks passing the access check set the current file.
         let file_map = match &self.current_file {
-            Some(v) if v.map_hash == get_block.hash => v,
+            Some(v) if v.map_hash == get_block.hash => v.clone(),
             Some(_) => {
                 log::error!("wrong hash before get_block");
-                ctx.stop();
-                return;
+                let e = ProtocolError::UnexpectedHash(get_block.hash);
+                self.report_serve_failure(get_block.hash, &e);
+                return self.close_with_error(e, ctx);
             }
             None => {
                 log::error!("get hash before get_block needed");
-                ctx.stop();
-                return;
+                self.report_serve_failure(get_block.hash, &ProtocolError::MissingAsk);
+                return self.close_with_error(ProtocolError::MissingAsk, ctx);
             }
         };
+        self.reporter.add_note(|| {
+            format!(
+                "serving block file_no:{}, block_no:{}",
+                get_block.file_nr, get_block.block_nr
+            )
+        });
 
-        if file_map.inline_data.len() > 0 && get_block.file_nr == 0 && get_block.block_nr == 0 {
-            self.framed.write(StCommand::block(
-                get_block.hash,
-                get_block.file_nr,
-                get_block.block_nr,
-                file_map.inline_data.clone(),
-            ));
-            return;
+        if let Some(bytes) = self.read_inline(&file_map, &get_block) {
+            crate::stats::served(get_block.hash, bytes.len());
+            return self.write_block(get_block, bytes);
         }
 
         let (map, path) = match file_map.files.get(get_block.file_nr as usize) {
@@ -240,25 +669,76 @@ impl Connection {
                     get_block.file_nr,
                     get_block.hash
                 );
-                ctx.stop();
-                return;
+                let e = ProtocolError::InvalidFileNo(get_block.file_nr);
+                self.report_serve_failure(get_block.hash, &e);
+                return self.close_with_error(e, ctx);
             }
         };
-        let bytes = match read_block(path, map, get_block.block_nr) {
+        let bytes = match self.block_reader.read_block(path, map, get_block.block_nr) {
             Err(e) => {
                 log::error!("read fail: {}", e);
-                ctx.stop();
-                return;
+                let e = Error::from(e);
+                self.report_serve_failure(get_block.hash, &e);
+                return self.close_with_error(ProtocolError::Internal(e.to_string()), ctx);
             }
             Ok(bytes) => bytes,
         };
 
-        self.framed.write(StCommand::block(
-            get_block.hash,
-            get_block.file_nr,
-            get_block.block_nr,
-            bytes,
-        ));
+        crate::stats::served(get_block.hash, bytes.len());
+        self.write_block(get_block, bytes.into());
+    }
+
+    /// Block served from the inline data: all inline files for `INLINE_FILE_NR`, or the
+    /// only block of an inline file.
+    fn read_inline(&self, file_desc: &FileDesc, get_block: &GetBlock) -> Option<Bytes> {
+        if file_desc.inline_files.is_empty() {
+            return None;
+        }
+        let range = if get_block.file_nr == INLINE_FILE_NR {
+            None
+        } else {
+            let inline_file = file_desc
+                .inline_files
+                .iter()
+                .find(|inline_file| inline_file.file_nr == get_block.file_nr)
+                .filter(|_| get_block.block_nr == 0)?;
+            let (map, _) = file_desc.files.get(get_block.file_nr as usize)?;
+            let offset = inline_file.offset as usize;
+            Some(offset..offset + map.file_size as usize)
+        };
+        let inline_data = match encryption::unseal(&file_desc.inline_data) {
+            Ok(inline_data) => Bytes::from(inline_data),
+            Err(e) => {
+                log::error!(
+                    "unable to decrypt inline data of {:032x}: {}",
+                    get_block.hash,
+                    e
+                );
+                return None;
+            }
+        };
+        match range {
+            Some(range) if range.end <= inline_data.len() => Some(inline_data.slice(range)),
+            Some(_) => None,
+            None => Some(inline_data),
+        }
+    }
+
+    /// Sends a block, split into `BlockPart` packets if it does not fit into one packet.
+    fn write_block(&mut self, get_block: GetBlock, bytes: Bytes) {
+        audit::served(self.peer_id, self.peer_addr, &get_block, bytes.len());
+        if bytes.len() <= MAX_BLOCK_PAYLOAD {
+            self.framed.write(StCommand::block(
+                get_block.hash,
+                get_block.file_nr,
+                get_block.block_nr,
+                bytes,
+            ));
+            return;
+        }
+        for part in BlockPart::split(get_block.hash, get_block.file_nr, get_block.block_nr, bytes) {
+            self.framed.write(StCommand::BlockPart(part));
+        }
     }
 
     fn handle_block(&mut self, b: Block, _ctx: &mut <Self as Actor>::Context) {
@@ -274,15 +754,284 @@ impl Connection {
         }
     }
 
-    fn handle_ask_reply(&mut self, b: AskReply, _ctx: &mut <Self as Actor>::Context) {
+    fn handle_block_part(&mut self, part: BlockPart, ctx: &mut <Self as Actor>::Context) {
+        let get_block = GetBlock {
+            hash: part.hash,
+            file_nr: part.file_nr,
+            block_nr: part.block_nr,
+        };
+        if !self.block_requests.contains_key(&get_block) {
+            log::error!("response part for not requested block");
+            return;
+        }
+
+        let received = self
+            .partial_blocks
+            .get(&get_block)
+            .map(|buf| buf.len() as u64)
+            .unwrap_or(0);
+        if part.block_size > MAX_BLOCK_SIZE
+            || part.offset != received
+            || part.offset + part.bytes.len() as u64 > part.block_size
+        {
+            log::error!(
+                "invalid block part offset:{} size:{} from {}",
+                part.offset,
+                part.block_size,
+                self.peer_addr
+            );
+            return self.close_with_error(ProtocolError::InvalidBlockPart, ctx);
+        }
+
+        let block_size = part.block_size;
+        let buf = self
+            .partial_blocks
+            .entry(get_block.clone())
+            .or_insert_with(|| BytesMut::with_capacity(block_size as usize));
+        buf.extend_from_slice(part.bytes.as_ref());
+
+        if buf.len() as u64 == block_size {
+            if let Some(buf) = self.partial_blocks.remove(&get_block) {
+                self.handle_block(
+                    Block {
+                        hash: part.hash,
+                        block_nr: part.block_nr,
+                        file_nr: part.file_nr,
+                        bytes: buf.freeze(),
+                    },
+                    ctx,
+                );
+            }
+        }
+    }
+
+    fn handle_ask_reply(&mut self, mut b: AskReply, ctx: &mut <Self as Actor>::Context) {
+        if !self.ask_requests.contains_key(&b.hash) {
+            return log::warn!("unexpected ask reply");
+        }
+        let received = b.files.as_ref().map_or(0, Vec::len);
+        match b.total_files {
+            Some(total) if total > MAX_BUNDLE_FILES => {
+                log::error!("{} files in {:032x}, too many", total, b.hash);
+                self.close_with_error(ProtocolError::InvalidFileMaps(b.hash), ctx)
+            }
+            Some(total) if total as usize > received => {
+                if let Some(known) = self.known_files.get(&b.hash).cloned() {
+                    let files = b.files.get_or_insert_with(Vec::new);
+                    if known.len() == total as usize
+                        && files
+                            .iter()
+                            .zip(known.iter())
+                            .all(|(received, known)| received.same_content(known))
+                    {
+                        // The signature, checked over all maps, still has to match them.
+                        files.extend(known[received..].iter().cloned());
+                        return self.finish_ask_reply(b);
+                    }
+                }
+                self.framed.write(StCommand::GetFileMaps(GetFileMaps {
+                    hash: b.hash,
+                    offset: received as u32,
+                }));
+                self.partial_replies.insert(b.hash, b);
+            }
+            _ => self.finish_ask_reply(b),
+        }
+    }
+
+    fn handle_file_maps(&mut self, maps: FileMaps, ctx: &mut <Self as Actor>::Context) {
+        let reply = match self.partial_replies.get_mut(&maps.hash) {
+            Some(reply) => reply,
+            None => return log::warn!("unexpected file maps"),
+        };
+        let total = reply.total_files.unwrap_or_default() as usize;
+        let files = reply.files.get_or_insert_with(Vec::new);
+        if maps.offset as usize != files.len()
+            || maps.files.is_empty()
+            || files.len() + maps.files.len() > total
+        {
+            log::error!("invalid file maps page of {:032x}", maps.hash);
+            return self.close_with_error(ProtocolError::InvalidFileMaps(maps.hash), ctx);
+        }
+        files.extend(maps.files);
+        if files.len() < total {
+            let offset = files.len() as u32;
+            self.framed.write(StCommand::GetFileMaps(GetFileMaps {
+                hash: maps.hash,
+                offset,
+            }))
+        } else if let Some(reply) = self.partial_replies.remove(&maps.hash) {
+            self.finish_ask_reply(reply)
+        }
+    }
+
+    fn finish_ask_reply(&mut self, mut b: AskReply) {
+        self.known_files.remove(&b.hash);
         if let Some(h) = self.ask_requests.remove(&b.hash) {
-            let _ = h.send(Ok(b));
+            if let Some(files) = &b.files {
+                let ranges: Vec<_> = b
+                    .inline_files
+                    .iter()
+                    .filter_map(|inline_file| {
+                        let file_size = files.get(inline_file.file_nr as usize)?.file_size;
+                        let offset = inline_file.offset as usize;
+                        // Offsets and sizes come from the peer, overflowing ones are dropped.
+                        let end = offset.checked_add(usize::try_from(file_size).ok()?)?;
+                        Some((inline_file.file_nr, offset..end))
+                    })
+                    .collect();
+                if !ranges.is_empty() {
+                    self.inline_files.insert(b.hash, ranges);
+                    if let Some(inline_data) = &b.inline_data {
+                        self.inline_data
+                            .insert(b.hash, Bytes::copy_from_slice(inline_data));
+                    }
+                }
+            }
+            let _ = h.send(if b.unauthorized {
+                Err(ProtocolError::Unauthorized(b.hash).into_err())
+            } else {
+                crate::identity::verify(&b, self.peer_id).map(|signed_by| {
+                    b.signed_by = signed_by;
+                    b
+                })
+            });
+        }
+    }
+
+    /// Registers the peer at this relay, once it proved its node id.
+    fn handle_relay_register(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let node_id = match self.identity {
+            _ if !relay::is_enabled() => self.peer_id.unwrap_or_default(),
+            PeerIdentity::Proven(node_id) => node_id,
+            PeerIdentity::Claimed | PeerIdentity::Requested(..) => {
+                self.relay_register_pending = true;
+                return self.identify();
+            }
+            PeerIdentity::Unproven => {
+                let node_id = self.peer_id.unwrap_or_default();
+                return self.framed.write(StCommand::RelayReply(RelayReply::new(
+                    node_id,
+                    RelayStatus::Unproven,
+                )));
+            }
+        };
+        let status = relay::register(node_id, self.connection_id, ctx.address());
+        if status == RelayStatus::Ok {
+            log::info!("[{}] relaying for {:032x}", self.connection_id, node_id);
+            self.relay_node = Some(node_id);
+        }
+        self.framed
+            .write(StCommand::RelayReply(RelayReply::new(node_id, status)))
+    }
+
+    fn handle_relay_connect(&mut self, node_id: u128, ctx: &mut <Self as Actor>::Context) {
+        if self.relay_session.is_some() {
+            return self.framed.write(StCommand::RelayReply(RelayReply::new(
+                node_id,
+                RelayStatus::QuotaExceeded,
+            )));
+        }
+        let token = match relay::open_session(node_id, ctx.address()) {
+            Ok(token) => token,
+            Err(status) => {
+                return self
+                    .framed
+                    .write(StCommand::RelayReply(RelayReply::new(node_id, status)))
+            }
+        };
+        self.relay_session = Some((node_id, Some(token)));
+        ctx.run_later(relay::RELAY_ACCEPT_TIMEOUT, move |act, _ctx| {
+            if act.relay_session == Some((node_id, Some(token))) && relay::expire(token) {
+                relay::close_session(node_id);
+                act.relay_session = None;
+                act.framed.write(StCommand::RelayReply(RelayReply::new(
+                    node_id,
+                    RelayStatus::Timeout,
+                )));
+            }
+        });
+    }
+
+    fn handle_relay_accept(&mut self, token: u128, ctx: &mut <Self as Actor>::Context) {
+        let node_id = self.peer_id.unwrap_or_default();
+        match relay::accept(token, node_id) {
+            Some(requester) if requester.connected() => {
+                self.relay_quota = relay::quota();
+                self.relay_peer = Some(requester.clone());
+                requester.do_send(relay::Pair {
+                    peer: ctx.address(),
+                    node_id,
+                });
+            }
+            _ => {
+                log::error!(
+                    "[{}] invalid relay token from {}",
+                    self.connection_id,
+                    self.peer_addr
+                );
+                self.close_with_error(ProtocolError::InvalidRelayToken, ctx)
+            }
+        }
+    }
+
+    fn handle_relay_reply(&mut self, reply: RelayReply, ctx: &mut <Self as Actor>::Context) {
+        let status = reply.status();
+        if let Some(tx) = self.relay_requests.remove(&reply.node_id) {
+            let _ = tx.send(match status {
+                RelayStatus::Ok => Ok(()),
+                status => Err(Error::Relay(status)),
+            });
+        } else if self.relay_control && status != RelayStatus::Ok {
+            log::error!("relay {} refused registration: {}", self.peer_addr, status);
+            self.close_with_error(ProtocolError::Disconnect, ctx)
+        } else if self.relay_control {
+            log::info!("registered at relay {}", self.peer_addr);
         } else {
-            log::warn!("unexpected ask reply");
+            log::warn!("unexpected relay reply");
+        }
+    }
+
+    /// Passes a packet to the other end of the relay session.
+    fn forward(&mut self, item: StCommand, ctx: &mut <Self as Actor>::Context) {
+        let size = relay::relayed_bytes(&item);
+        if size > self.relay_quota {
+            log::warn!(
+                "[{}] relay quota exceeded by {}",
+                self.connection_id,
+                self.peer_addr
+            );
+            self.reporter
+                .emit_fail(&ProtocolError::RelayQuotaExceeded.into_err());
+            // Nothing to flush, stop before more packets get here.
+            return ctx.stop();
         }
+        self.relay_quota -= size;
+        if let Some(peer) = &self.relay_peer {
+            peer.do_send(relay::Forward(item));
+        }
+    }
+
+    /// Nothing requested by either side is in progress.
+    fn is_idle(&self) -> bool {
+        self.block_requests.is_empty()
+            && self.ask_requests.is_empty()
+            && self.relay_requests.is_empty()
+            && self.deferred_blocks.is_empty()
+            && self.write_queue.queued() == 0
+    }
+
+    fn close_idle(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.framed.write(StCommand::Bye);
+        self.framed.close();
+        ctx.run_later(Duration::from_millis(10), |_, ctx| ctx.stop());
     }
 
     fn close_with_error(&mut self, e: ProtocolError, ctx: &mut <Self as Actor>::Context) {
+        if self.closing {
+            return;
+        }
+        self.closing = true;
         self.reporter.emit_fail(&e);
         std::mem::replace(&mut self.block_requests, HashMap::new())
             .into_iter()
@@ -294,15 +1043,331 @@ impl Connection {
             .for_each(|(_, sender)| {
                 let _ = sender.send(Err(e.into_err()));
             });
+        std::mem::take(&mut self.relay_requests)
+            .into_iter()
+            .for_each(|(_, sender)| {
+                let _ = sender.send(Err(e.into_err()));
+            });
+        if let Some(sender) = self.verify_reply.take() {
+            let _ = sender.send(Err(e.into_err()));
+        }
+        self.deferred_blocks.clear();
+        self.partial_blocks.clear();
+        self.partial_replies.clear();
+        self.known_files.clear();
+        if e.is_reported_to_peer() {
+            self.framed.write(StCommand::Error(e.code()));
+        }
         self.framed.close();
+        // Leaves time to deliver the errors, stopping drops requests still in the context.
         ctx.run_later(Duration::from_millis(10), |_, ctx| {
             ctx.stop();
         });
         //
     }
+
+    /// Challenges peers of accepted connections after their `hello`, with `set_challenge`.
+    fn send_challenge(&mut self) {
+        if let (Some(&bits), Some(_)) = (CHALLENGE_BITS.get(), &self.admission) {
+            let challenge = Challenge::new(bits);
+            self.challenge = Some(challenge);
+            self.framed.write(StCommand::Challenge(challenge));
+        }
+    }
+
+    fn defer_ask(&mut self, hash: u128, token: Option<u128>, ctx: &mut <Self as Actor>::Context) {
+        if self.deferred_asks.len() >= MAX_DEFERRED_ASKS {
+            return self.close_with_error(ProtocolError::TooManyRequests, ctx);
+        }
+        self.deferred_asks.push((hash, token));
+    }
+
+    /// Asks are deferred until the challenge is solved and the proof of the node id is in.
+    fn defers_asks(&self) -> bool {
+        self.challenge.is_some() || matches!(self.identity, PeerIdentity::Requested(..))
+    }
+
+    fn resume_asks(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if !self.defers_asks() {
+            for (hash, token) in std::mem::take(&mut self.deferred_asks) {
+                self.handle_ask(hash, token, ctx);
+            }
+        }
+    }
+
+    fn handle_challenge(&mut self, challenge: Challenge, ctx: &mut <Self as Actor>::Context) {
+        // Only the side that connected answers, peers can not make a server do the work.
+        if self.admission.is_some() || challenge.bits > MAX_CHALLENGE_BITS {
+            log::error!(
+                "refused challenge of {} bits from {}",
+                challenge.bits,
+                self.peer_addr
+            );
+            return self.close_with_error(ProtocolError::ChallengeFailed, ctx);
+        }
+        ctx.spawn(
+            tokio::task::spawn_blocking(move || challenge.solve())
+                .into_actor(self)
+                .map(|reply, act, _ctx| match reply {
+                    Ok(reply) => act.framed.write(StCommand::ChallengeReply(reply)),
+                    Err(e) => log::error!("solving challenge failed: {}", e),
+                }),
+        );
+    }
+
+    fn handle_challenge_reply(
+        &mut self,
+        reply: ChallengeReply,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        match self.challenge {
+            Some(challenge) if challenge.is_solved_by(&reply) => {
+                self.challenge = None;
+                self.resume_asks(ctx);
+            }
+            Some(_) => {
+                log::warn!("{} failed the challenge", self.peer_addr);
+                self.close_with_error(ProtocolError::ChallengeFailed, ctx)
+            }
+            // Answer to a relay, forwarded once the session started.
+            None => log::debug!("unexpected challenge reply from {}", self.peer_addr),
+        }
+    }
+
+    /// Node id of the peer, once it proved it.
+    fn proven_peer_id(&self) -> Option<u128> {
+        match self.identity {
+            PeerIdentity::Proven(peer_id) => Some(peer_id),
+            _ => None,
+        }
+    }
+
+    /// Asks the peer to prove its node id, unless it was asked already.
+    fn identify(&mut self) {
+        if self.identity == PeerIdentity::Claimed {
+            let (identify, secret) = identity::identify();
+            self.identity = PeerIdentity::Requested(identify, secret);
+            self.framed.write(StCommand::Identify(identify));
+        }
+    }
+
+    /// Asks the peer for the proof when `access` lists the id it claimed. Returns whether
+    /// the proof is on the way.
+    fn identify_for(&mut self, access: &Access) -> bool {
+        match (self.identity, self.peer_id) {
+            (PeerIdentity::Claimed, Some(peer_id))
+            | (PeerIdentity::Requested(..), Some(peer_id))
+                if access.peers.contains(&peer_id) =>
+            {
+                self.identify();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Proves the node id to the peer, packets sent after the proof are authenticated with
+    /// the key of the session.
+    fn handle_identify(&mut self, identify: Identify) {
+        if self.send_auth.is_set() {
+            return log::warn!("repeated identify from {}", self.peer_addr);
+        }
+        match self
+            .peer_id
+            .and_then(|verifier_id| identity::prove(&identify, verifier_id))
+        {
+            Some((proof, key)) => {
+                self.framed.write(StCommand::Identity(Some(proof)));
+                self.send_auth.set_key(key);
+            }
+            None => self.framed.write(StCommand::Identity(None)),
+        }
+    }
+
+    fn handle_identity(
+        &mut self,
+        proof: Option<IdentityProof>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let (identify, secret) = match self.identity {
+            PeerIdentity::Requested(identify, secret) => (identify, secret),
+            _ => return log::warn!("unexpected identity from {}", self.peer_addr),
+        };
+        let key = match (proof, self.node_id, self.peer_id) {
+            (Some(proof), Some(node_id), Some(peer_id)) => {
+                identity::verify_proof(&proof, &identify, secret, node_id, peer_id)
+                    .map(|key| (key, peer_id))
+            }
+            _ => None,
+        };
+        self.identity = match key {
+            // Packets of nodes passing the proof on lack the tags of the session.
+            Some((key, peer_id)) => {
+                self.recv_auth.set_key(key);
+                PeerIdentity::Proven(peer_id)
+            }
+            None => {
+                log::warn!("{} did not prove its node id", self.peer_addr);
+                PeerIdentity::Unproven
+            }
+        };
+        if self.verify_peer(ctx).is_err() {
+            return;
+        }
+        self.resume_asks(ctx);
+        if std::mem::take(&mut self.relay_register_pending) {
+            self.handle_relay_register(ctx);
+        }
+    }
+
+    /// Checks the node id of the peer once known, and proven when pinning, then answers
+    /// `VerifyPeer`.
+    fn verify_peer(&mut self, ctx: &mut <Self as Actor>::Context) -> Result<(), Error> {
+        let (verify, node_id) = match (self.verify_peer, self.peer_id) {
+            (Some(verify), Some(node_id)) => (verify, node_id),
+            _ => return Ok(()),
+        };
+        let proven = match self.identity {
+            PeerIdentity::Proven(peer_id) => Some(peer_id),
+            PeerIdentity::Claimed | PeerIdentity::Requested(..) if crate::pins::is_enabled() => {
+                // Checked again with the proof.
+                self.identify();
+                return Ok(());
+            }
+            _ => None,
+        };
+        let result = match (verify.expected, proven) {
+            (Some(expected), _) if expected != node_id => Err(ProtocolError::UnexpectedPeerId {
+                expected,
+                seen: node_id,
+            }),
+            // The caller knows the id, it replaces a pinned one.
+            (Some(_), Some(node_id)) => crate::pins::check(self.peer_addr, node_id, true),
+            (Some(_), None) => Ok(()),
+            (None, Some(node_id)) => crate::pins::check(self.peer_addr, node_id, verify.repin),
+            (None, None) => crate::pins::check_unproven(self.peer_addr, node_id),
+        };
+        if let Err(e) = result {
+            log::error!("{}: {}", self.peer_addr, e);
+            self.close_with_error(e.clone(), ctx);
+            return Err(e.into_err());
+        }
+        if let Some(sender) = self.verify_reply.take() {
+            let _ = sender.send(Ok(()));
+        }
+        Ok(())
+    }
+
+    /// The peer closed the connection or it broke with `e`.
+    fn connection_lost(&mut self, e: Option<io::Error>, ctx: &mut <Self as Actor>::Context) {
+        if self.closing {
+            return;
+        }
+        let pending = !(self.block_requests.is_empty()
+            && self.ask_requests.is_empty()
+            && self.relay_requests.is_empty()
+            && self.verify_reply.is_none());
+        match e {
+            Some(e) => {
+                log::warn!(
+                    "[{}] connection to {} lost: {}",
+                    self.connection_id,
+                    self.peer_addr,
+                    e
+                );
+                self.close_with_error(ProtocolError::connection_lost(&e), ctx)
+            }
+            None if pending => {
+                let e = io::Error::new(io::ErrorKind::UnexpectedEof, "closed by peer");
+                self.close_with_error(ProtocolError::connection_lost(&e), ctx)
+            }
+            None => ctx.stop(),
+        }
+    }
+}
+
+fn annotate_connection(
+    reporter: &crate::user_report::UserReportHandle,
+    connection_id: usize,
+    peer_addr: net::SocketAddr,
+) {
+    reporter.annotate("connection_id", &connection_id);
+    reporter.annotate("peer", &peer_addr);
+}
+
+#[cfg(feature = "with-mmap")]
+const MAX_MAPPED_FILES: usize = 16;
+
+/// Reads blocks of served files.
+///
+/// With the `with-mmap` feature files are memory-mapped once and blocks are served
+/// as slices of the mapping. Falls back to seek+read if mapping fails.
+#[derive(Default)]
+struct BlockReader {
+    #[cfg(feature = "with-mmap")]
+    mapped: HashMap<std::path::PathBuf, memmap::Mmap>,
+}
+
+impl BlockReader {
+    fn clear(&mut self) {
+        #[cfg(feature = "with-mmap")]
+        self.mapped.clear();
+    }
+
+    #[cfg(feature = "with-mmap")]
+    fn read_mapped(
+        &mut self,
+        path: &Path,
+        file_map: &FileMap,
+        block_no: u32,
+    ) -> Result<Vec<u8>, io::Error> {
+        if !self.mapped.contains_key(path) {
+            let file = StoredFile::open(path)?;
+            if file.is_encrypted() {
+                return Err(io::Error::new(ErrorKind::Other, "file is encrypted"));
+            }
+            let file = file.into_inner();
+            if file.metadata()?.len() != file_map.file_size {
+                return Err(io::Error::new(ErrorKind::InvalidData, "file size changed"));
+            }
+            if self.mapped.len() >= MAX_MAPPED_FILES {
+                self.mapped.clear();
+            }
+            // Safety: shared files are expected not to be modified while shared.
+            let mmap = unsafe { memmap::Mmap::map(&file)? };
+            self.mapped.insert(path.to_owned(), mmap);
+        }
+        let mmap = &self.mapped[path];
+
+        let offset = block_no as usize * BLOCK_SIZE;
+        if mmap.len() < offset {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "invalid offset"));
+        }
+        let end = min(mmap.len(), offset + BLOCK_SIZE);
+        Ok(mmap[offset..end].to_vec())
+    }
+
+    fn read_block(
+        &mut self,
+        path: &Path,
+        file_map: &FileMap,
+        block_no: u32,
+    ) -> Result<Vec<u8>, io::Error> {
+        #[cfg(feature = "with-mmap")]
+        match self.read_mapped(path, file_map, block_no) {
+            Ok(bytes) => return Ok(bytes),
+            Err(e) => log::debug!(
+                "mmap read of [{}] failed, fallback to read: {}",
+                path.display(),
+                e
+            ),
+        }
+        read_block(path, file_map, block_no)
+    }
 }
 
-fn read_block(
+/// Reads block `block_no` of the file, as served without `with-mmap`.
+pub fn read_block(
     path: impl AsRef<Path>,
     file_map: &FileMap,
     block_no: u32,
@@ -318,7 +1383,7 @@ fn read_block(
         return Err(io::Error::new(ErrorKind::Other, "invalid offset"));
     }
     let size = min(file_map.file_size - offset, BLOCK_SIZE as u64) as usize;
-    let mut file = OpenOptions::new().read(true).open(path)?;
+    let mut file = StoredFile::open(path)?;
     file.seek(SeekFrom::Start(offset))?;
 
     let mut bytes_vec = Vec::with_capacity(size);
@@ -338,9 +1403,23 @@ fn read_block(
     Ok(bytes_vec)
 }
 
-impl StreamHandler<StCommand, io::Error> for Connection {
-    fn handle(&mut self, item: StCommand, ctx: &mut Self::Context) {
+impl StreamHandler<Result<StCommand, io::Error>> for Connection {
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        self.connection_lost(None, ctx)
+    }
+
+    fn handle(&mut self, item: Result<StCommand, io::Error>, ctx: &mut Self::Context) {
+        let item = match item {
+            Ok(item) => item,
+            Err(e) => return self.connection_lost(Some(e), ctx),
+        };
         log::debug!("incomming packet={}", item.display());
+        let span = self.span.clone();
+        let _span = span.enter();
+        self.last_activity = Instant::now();
+        if self.relay_peer.is_some() {
+            return self.forward(item, ctx);
+        }
         match item {
             StCommand::Nop => (),
             StCommand::Bye => {
@@ -350,44 +1429,99 @@ impl StreamHandler<StCommand, io::Error> for Connection {
             StCommand::Hello(h) => {
                 if h.is_valid() {
                     self.peer_id = Some(h.node_id);
+                    self.span
+                        .record("peer_id", tracing::field::display(hash_to_hex(h.node_id)));
+                    if self.verify_peer(ctx).is_ok() {
+                        self.send_challenge();
+                    }
                 } else {
                     log::error!("invalid handshake from: {}", self.peer_addr);
                     self.close_with_error(ProtocolError::InvalidHandshake, ctx)
                 }
             }
-            StCommand::Ask(hash) => {
-                if self.peer_id.is_none() {
-                    log::error!("ask without handshake, disconnect");
-                    self.close_with_error(ProtocolError::MissingHandshake, ctx)
-                } else {
-                    self.handle_ask(hash, ctx)
-                }
+            StCommand::Ask(_) | StCommand::AskToken(_) if self.peer_id.is_none() => {
+                log::error!("ask without handshake, disconnect");
+                self.close_with_error(ProtocolError::MissingHandshake, ctx)
             }
+            StCommand::Ask(hash) if self.defers_asks() => self.defer_ask(hash, None, ctx),
+            StCommand::AskToken(ask) if self.defers_asks() => {
+                self.defer_ask(ask.hash, Some(ask.token), ctx)
+            }
+            StCommand::Ask(hash) => self.handle_ask(hash, None, ctx),
+            StCommand::AskToken(ask) => self.handle_ask(ask.hash, Some(ask.token), ctx),
             StCommand::AskReply(r) => self.handle_ask_reply(r, ctx),
-            StCommand::GetBlock(b) => self.handle_get_block(b, ctx),
+            StCommand::GetBlock(b) => self.queue_get_block(b, ctx),
             StCommand::Block(b) => self.handle_block(b, ctx),
+            StCommand::BlockPart(p) => self.handle_block_part(p, ctx),
+            StCommand::RelayReply(r) => self.handle_relay_reply(r, ctx),
+            StCommand::RelayOffer(token) => {
+                if self.relay_control {
+                    relay::accept_offer(self.db.clone(), self.peer_addr, token)
+                } else {
+                    log::warn!("unexpected relay offer from {}", self.peer_addr)
+                }
+            }
+            StCommand::RelayRegister | StCommand::RelayConnect(_) | StCommand::RelayAccept(_)
+                if self.peer_id.is_none() =>
+            {
+                log::error!("relay request without handshake, disconnect");
+                self.close_with_error(ProtocolError::MissingHandshake, ctx)
+            }
+            StCommand::RelayRegister => self.handle_relay_register(ctx),
+            StCommand::RelayConnect(node_id) => self.handle_relay_connect(node_id, ctx),
+            StCommand::RelayAccept(token) => self.handle_relay_accept(token, ctx),
+            StCommand::Challenge(c) => self.handle_challenge(c, ctx),
+            StCommand::ChallengeReply(r) => self.handle_challenge_reply(r, ctx),
+            StCommand::GetFileMaps(g) => self.handle_get_file_maps(g, ctx),
+            StCommand::FileMaps(m) => self.handle_file_maps(m, ctx),
+            StCommand::Identify(identify) => self.handle_identify(identify),
+            StCommand::Identity(proof) => self.handle_identity(proof, ctx),
+            // Tags are checked by the codec once the peer proved its node id.
+            StCommand::Auth(_) => log::warn!("unexpected auth from {}", self.peer_addr),
+            StCommand::Error(code) => {
+                log::warn!("error {} from {}, disconnect", code, self.peer_addr);
+                self.close_with_error(ProtocolError::Remote(code), ctx)
+            }
         }
     }
 }
 
-impl WriteHandler<io::Error> for Connection {}
+impl WriteHandler<io::Error> for Connection {
+    fn error(&mut self, e: io::Error, ctx: &mut Self::Context) -> Running {
+        self.connection_lost(Some(e), ctx);
+        Running::Stop
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        self.connection_lost(None, ctx)
+    }
+}
 
 impl Handler<crate::codec::Ask> for Connection {
-    type Result = ActorResponse<Self, AskReply, Error>;
+    type Result = ResponseFuture<Result<AskReply, Error>>;
 
     fn handle(&mut self, msg: crate::codec::Ask, _ctx: &mut Self::Context) -> Self::Result {
         let (rx, tx) = oneshot::channel();
         if let Some(_prev) = self.ask_requests.insert(msg.hash, rx) {
             log::error!("duplicate ask");
         } else {
-            self.framed.write(StCommand::Ask(msg.hash))
+            if let Some(known_files) = msg.known_files {
+                self.known_files.insert(msg.hash, known_files);
+            }
+            self.framed.write(match msg.token {
+                Some(token) => StCommand::AskToken(AskToken {
+                    hash: msg.hash,
+                    token,
+                }),
+                None => StCommand::Ask(msg.hash),
+            })
         }
-        ActorResponse::r#async(tx.flatten().into_actor(self))
+        Box::pin(async move { tx.await? })
     }
 }
 
 impl Handler<crate::codec::GetBlock> for Connection {
-    type Result = ActorResponse<Self, Block, Error>;
+    type Result = ResponseFuture<Result<Block, Error>>;
 
     fn handle(&mut self, msg: GetBlock, _ctx: &mut Self::Context) -> Self::Result {
         let (rx, tx) = oneshot::channel();
@@ -396,7 +1530,95 @@ impl Handler<crate::codec::GetBlock> for Connection {
         } else {
             self.framed.write(StCommand::GetBlock(msg))
         }
-        ActorResponse::r#async(tx.flatten().into_actor(self))
+        Box::pin(async move { tx.await? })
+    }
+}
+
+impl Handler<crate::codec::RelayConnect> for Connection {
+    type Result = ResponseFuture<Result<(), Error>>;
+
+    fn handle(
+        &mut self,
+        msg: crate::codec::RelayConnect,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let (rx, tx) = oneshot::channel();
+        if let Some(_prev) = self.relay_requests.insert(msg.node_id, rx) {
+            log::error!("duplicate relay connect");
+        } else {
+            self.framed.write(StCommand::RelayConnect(msg.node_id))
+        }
+        Box::pin(async move { tx.await? })
+    }
+}
+
+impl Handler<crate::codec::RelayRegister> for Connection {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        _msg: crate::codec::RelayRegister,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        self.relay_control = true;
+        self.framed.write(StCommand::RelayRegister);
+        Ok(())
+    }
+}
+
+impl Handler<crate::codec::RelayAccept> for Connection {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: crate::codec::RelayAccept, _ctx: &mut Self::Context) -> Self::Result {
+        self.framed.write(StCommand::RelayAccept(msg.token));
+        Ok(())
+    }
+}
+
+impl Handler<relay::Offer> for Connection {
+    type Result = ();
+
+    fn handle(&mut self, msg: relay::Offer, _ctx: &mut Self::Context) -> Self::Result {
+        self.framed.write(StCommand::RelayOffer(msg.0))
+    }
+}
+
+impl Handler<relay::Pair> for Connection {
+    type Result = ();
+
+    fn handle(&mut self, msg: relay::Pair, _ctx: &mut Self::Context) -> Self::Result {
+        log::info!(
+            "[{}] relaying {} to {:032x}",
+            self.connection_id,
+            self.peer_addr,
+            msg.node_id
+        );
+        if let Some((node_id, _token)) = self.relay_session {
+            self.relay_session = Some((node_id, None));
+        }
+        self.relay_quota = relay::quota();
+        self.relay_peer = Some(msg.peer);
+        self.framed.write(StCommand::RelayReply(RelayReply::new(
+            msg.node_id,
+            RelayStatus::Ok,
+        )))
+    }
+}
+
+impl Handler<relay::Forward> for Connection {
+    type Result = ();
+
+    fn handle(&mut self, msg: relay::Forward, _ctx: &mut Self::Context) -> Self::Result {
+        self.framed.write(msg.0)
+    }
+}
+
+impl Handler<relay::Closed> for Connection {
+    type Result = ();
+
+    fn handle(&mut self, _msg: relay::Closed, ctx: &mut Self::Context) -> Self::Result {
+        self.relay_peer = None;
+        self.close_with_error(ProtocolError::Disconnect, ctx)
     }
 }
 
@@ -404,11 +1626,94 @@ impl Handler<crate::codec::Hello> for Connection {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: crate::codec::Hello, _ctx: &mut Self::Context) -> Self::Result {
+        self.node_id = Some(msg.node_id);
         self.framed.write(StCommand::hello(msg.node_id));
         Ok(())
     }
 }
 
+impl Handler<crate::codec::KeepAlive> for Connection {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _msg: crate::codec::KeepAlive, _ctx: &mut Self::Context) -> Self::Result {
+        self.last_activity = Instant::now();
+        self.framed.write(StCommand::Nop);
+        Ok(())
+    }
+}
+
+impl Handler<serve_queue::Serve> for Connection {
+    type Result = ();
+
+    fn handle(&mut self, msg: serve_queue::Serve, ctx: &mut Self::Context) -> Self::Result {
+        self.turn_requested = false;
+        for _ in 0..msg.blocks {
+            if self.closing || self.write_queue.is_full() {
+                break;
+            }
+            match self.deferred_blocks.pop_front() {
+                Some(get_block) => self.handle_get_block(get_block, ctx),
+                None => break,
+            }
+        }
+        if !self.closing {
+            self.request_turn(ctx);
+        }
+    }
+}
+
+impl Handler<GetInfo> for Connection {
+    type Result = MessageResult<GetInfo>;
+
+    fn handle(&mut self, _msg: GetInfo, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(ConnectionInfo {
+            id: self.connection_id,
+            peer: self.peer_addr.to_string(),
+            peer_id: self.peer_id.map(crate::hash_encoding::encode),
+            age: self.opened.elapsed().as_secs(),
+            bytes_in: self.bytes_in.get(),
+            bytes_out: self.write_queue.written(),
+            outstanding_requests: self.block_requests.len()
+                + self.ask_requests.len()
+                + self.relay_requests.len(),
+            deferred_requests: self.deferred_blocks.len(),
+            current_file: self
+                .current_file
+                .as_ref()
+                .map(|file_desc| crate::hash_encoding::encode(file_desc.map_hash)),
+            idle: self.is_idle(),
+        })
+    }
+}
+
+impl Handler<GetDebugInfo> for Connection {
+    type Result = MessageResult<GetDebugInfo>;
+
+    fn handle(&mut self, _msg: GetDebugInfo, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(ConnectionDebugInfo {
+            peer: self.peer_addr.to_string(),
+            block_requests: self.block_requests.len(),
+            ask_requests: self.ask_requests.len(),
+            relay_requests: self.relay_requests.len(),
+            partial_blocks: self.partial_blocks.len(),
+            deferred_blocks: self.deferred_blocks.len(),
+            write_queue: self.write_queue.queued(),
+            write_queue_full: self.write_queue.is_full(),
+            last_activity_ms: self.last_activity.elapsed().as_millis() as u64,
+        })
+    }
+}
+
+impl Handler<CloseIdle> for Connection {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CloseIdle, ctx: &mut Self::Context) -> Self::Result {
+        if self.is_idle() {
+            self.close_idle(ctx);
+        }
+    }
+}
+
 impl Handler<crate::codec::Bye> for Connection {
     type Result = Result<(), Error>;
 
@@ -427,18 +1732,106 @@ impl Handler<crate::codec::Bye> for Connection {
     }
 }
 
-pub struct ConnectionRef(Addr<Connection>);
+/// Makes the connection check the node id of the peer against `expected`, or the one
+/// pinned for its address, see `pins::check`. Answers once the peer sent `hello`, and
+/// proved its node id when pinning.
+#[derive(Clone, Copy)]
+pub struct VerifyPeer {
+    pub expected: Option<u128>,
+    pub repin: bool,
+}
+
+impl Message for VerifyPeer {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<VerifyPeer> for Connection {
+    type Result = ResponseFuture<Result<(), Error>>;
+
+    fn handle(&mut self, msg: VerifyPeer, ctx: &mut Self::Context) -> Self::Result {
+        let (rx, tx) = oneshot::channel();
+        self.verify_peer = Some(msg);
+        self.verify_reply = Some(rx);
+        let _ = self.verify_peer(ctx);
+        Box::pin(async move { tx.await? })
+    }
+}
+
+/// Fetches the inline files of a resource in one block, none if the peer did not
+/// announce any in its ask reply. Inline data sent with the reply is used instead of
+/// requesting the block.
+pub struct GetInlineFiles(pub u128);
+
+impl Message for GetInlineFiles {
+    type Result = Result<Vec<(u32, Bytes)>, Error>;
+}
+
+impl Handler<GetInlineFiles> for Connection {
+    type Result = ResponseFuture<Result<Vec<(u32, Bytes)>, Error>>;
+
+    fn handle(&mut self, msg: GetInlineFiles, ctx: &mut Self::Context) -> Self::Result {
+        let files = match self.inline_files.get(&msg.0) {
+            Some(files) => files.clone(),
+            None => return Box::pin(future::ok(Vec::new())),
+        };
+        if let Some(bytes) = self.inline_data.remove(&msg.0) {
+            return Box::pin(future::ok(split_inline(files, bytes)));
+        }
+        let get_block = GetBlock {
+            hash: msg.0,
+            file_nr: INLINE_FILE_NR,
+            block_nr: 0,
+        };
+        let block = <Self as Handler<GetBlock>>::handle(self, get_block, ctx);
+        Box::pin(async move { Ok(split_inline(files, block.await?.bytes)) })
+    }
+}
+
+/// Files missing from `bytes` are left out, to be fetched like others.
+fn split_inline(files: Vec<(u32, Range<usize>)>, bytes: Bytes) -> Vec<(u32, Bytes)> {
+    files
+        .into_iter()
+        .filter(|(_, range)| range.start <= range.end && range.end <= bytes.len())
+        .map(|(file_nr, range)| (file_nr, bytes.slice(range)))
+        .collect()
+}
+
+/// Connection opened for downloads, said goodbye to once the last clone is dropped.
+#[derive(Clone)]
+pub struct ConnectionRef(Rc<ManagedConnection>);
+
+struct ManagedConnection(Addr<Connection>);
 
 impl Deref for ConnectionRef {
     type Target = Addr<Connection>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &(self.0).0
     }
 }
 
-impl Drop for ConnectionRef {
+impl Drop for ManagedConnection {
     fn drop(&mut self) {
         self.0.do_send(crate::codec::Bye::new());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_inline() {
+        let bytes = Bytes::from_static(b"abcdef");
+        // A range of a wrapped offset ends before it starts.
+        let wrapped = Range { start: 5, end: 3 };
+        let files = vec![(0, 0..2), (1, 2..6), (2, 4..7), (3, wrapped)];
+        assert_eq!(
+            split_inline(files, bytes),
+            vec![
+                (0, Bytes::from_static(b"ab")),
+                (1, Bytes::from_static(b"cdef"))
+            ]
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use crate::connection::Connection;
+use actix::prelude::*;
+use futures::future;
+use futures::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Part of idle connections closed at once when shedding.
+const SHED_DIVISOR: usize = 4;
+
+/// Time a connection gets to answer `GetDebugInfo` before it is reported unresponsive.
+const DEBUG_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Live state of a connection, as shown by `GET /connections`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionInfo {
+    pub id: usize,
+    pub peer: String,
+    pub peer_id: Option<String>,
+    /// Seconds since the connection was opened
+    pub age: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Asks and block requests sent to the peer and not answered yet
+    pub outstanding_requests: usize,
+    /// Block requests of the peer waiting for their turn or the write queue to drain
+    pub deferred_requests: usize,
+    pub current_file: Option<String>,
+    /// No requests in either direction and nothing left to write
+    pub idle: bool,
+}
+
+/// Requests a connection is waiting on, as shown by `GET /debug/actors`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionDebugInfo {
+    pub peer: String,
+    /// Block requests sent to the peer and not answered yet
+    pub block_requests: usize,
+    /// Asks sent to the peer and not answered yet
+    pub ask_requests: usize,
+    pub relay_requests: usize,
+    /// Blocks of which only some chunks arrived
+    pub partial_blocks: usize,
+    /// Block requests of the peer waiting for their turn or the write queue to drain
+    pub deferred_blocks: usize,
+    /// Bytes written and not sent to the peer yet
+    pub write_queue: usize,
+    pub write_queue_full: bool,
+    /// Milliseconds since anything arrived from the peer
+    pub last_activity_ms: u64,
+}
+
+/// A connection and its answer to `GetDebugInfo`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionDebug {
+    pub id: usize,
+    /// Milliseconds the connection took to answer, `None` if it did not answer in time
+    pub response_ms: Option<u64>,
+    #[serde(flatten)]
+    pub info: Option<ConnectionDebugInfo>,
+}
+
+/// Tracks open connections.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    connections: HashMap<usize, Addr<Connection>>,
+}
+
+impl Actor for ConnectionRegistry {
+    type Context = Context<Self>;
+}
+
+impl Supervised for ConnectionRegistry {}
+
+impl SystemService for ConnectionRegistry {}
+
+struct Register {
+    connection_id: usize,
+    connection: Addr<Connection>,
+}
+
+impl Message for Register {
+    type Result = ();
+}
+
+impl Handler<Register> for ConnectionRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: Register, _ctx: &mut Self::Context) -> Self::Result {
+        self.connections.insert(msg.connection_id, msg.connection);
+    }
+}
+
+struct Unregister {
+    connection_id: usize,
+}
+
+impl Message for Unregister {
+    type Result = ();
+}
+
+impl Handler<Unregister> for ConnectionRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unregister, _ctx: &mut Self::Context) -> Self::Result {
+        self.connections.remove(&msg.connection_id);
+    }
+}
+
+/// Asks a connection for its `ConnectionInfo`.
+pub struct GetInfo;
+
+impl Message for GetInfo {
+    type Result = ConnectionInfo;
+}
+
+/// Asks a connection for its `ConnectionDebugInfo`.
+pub struct GetDebugInfo;
+
+impl Message for GetDebugInfo {
+    type Result = ConnectionDebugInfo;
+}
+
+/// Closes the connection if it is still idle.
+pub struct CloseIdle;
+
+impl Message for CloseIdle {
+    type Result = ();
+}
+
+struct List;
+
+impl Message for List {
+    type Result = Vec<ConnectionInfo>;
+}
+
+impl Handler<List> for ConnectionRegistry {
+    type Result = ResponseFuture<Vec<ConnectionInfo>>;
+
+    fn handle(&mut self, _msg: List, _ctx: &mut Self::Context) -> Self::Result {
+        // Connections closing meanwhile are skipped.
+        let infos: Vec<_> = self
+            .connections
+            .values()
+            .map(|connection| connection.send(GetInfo).map(|r| r.ok()))
+            .collect();
+        Box::pin(future::join_all(infos).map(|infos| {
+            let mut infos: Vec<ConnectionInfo> = infos.into_iter().flatten().collect();
+            infos.sort_by_key(|info| info.id);
+            infos
+        }))
+    }
+}
+
+struct DebugList;
+
+impl Message for DebugList {
+    type Result = Vec<ConnectionDebug>;
+}
+
+impl Handler<DebugList> for ConnectionRegistry {
+    type Result = ResponseFuture<Vec<ConnectionDebug>>;
+
+    fn handle(&mut self, _msg: DebugList, _ctx: &mut Self::Context) -> Self::Result {
+        // Unlike `List`, connections stuck in a handler are reported rather than waited for.
+        let infos: Vec<_> = self
+            .connections
+            .iter()
+            .map(|(&id, connection)| {
+                let sent = Instant::now();
+                connection
+                    .send(GetDebugInfo)
+                    .timeout(DEBUG_TIMEOUT)
+                    .map(move |r| match r {
+                        Ok(info) => Some(ConnectionDebug {
+                            id,
+                            response_ms: Some(sent.elapsed().as_millis() as u64),
+                            info: Some(info),
+                        }),
+                        Err(MailboxError::Timeout) => Some(ConnectionDebug {
+                            id,
+                            response_ms: None,
+                            info: None,
+                        }),
+                        Err(MailboxError::Closed) => None,
+                    })
+            })
+            .collect();
+        Box::pin(future::join_all(infos).map(|infos| {
+            let mut infos: Vec<ConnectionDebug> = infos.into_iter().flatten().collect();
+            infos.sort_by_key(|info| info.id);
+            infos
+        }))
+    }
+}
+
+struct ShedIdle;
+
+impl Message for ShedIdle {
+    type Result = ();
+}
+
+impl Handler<ShedIdle> for ConnectionRegistry {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ShedIdle, ctx: &mut Self::Context) -> Self::Result {
+        let connections = self.connections.clone();
+        let infos = self.handle(List, ctx);
+        ctx.spawn(
+            infos
+                .map(move |infos| {
+                    let idle: Vec<_> = infos.into_iter().filter(|info| info.idle).collect();
+                    let count = std::cmp::max(idle.len() / SHED_DIVISOR, 1);
+                    // Ids grow with time, so the oldest connections come first.
+                    for info in idle.into_iter().take(count) {
+                        log::info!("[{}] shedding idle connection {}", info.id, info.peer);
+                        if let Some(connection) = connections.get(&info.id) {
+                            connection.do_send(CloseIdle);
+                        }
+                    }
+                })
+                .into_actor(self),
+        );
+    }
+}
+
+pub fn register(connection_id: usize, connection: Addr<Connection>) {
+    ConnectionRegistry::from_registry().do_send(Register {
+        connection_id,
+        connection,
+    })
+}
+
+pub fn unregister(connection_id: usize) {
+    ConnectionRegistry::from_registry().do_send(Unregister { connection_id })
+}
+
+/// Closes the oldest idle connections to free file descriptors.
+pub fn shed_idle() {
+    ConnectionRegistry::from_registry().do_send(ShedIdle)
+}
+
+pub fn list() -> impl Future<Output = Result<Vec<ConnectionInfo>, MailboxError>> {
+    ConnectionRegistry::from_registry().send(List)
+}
+
+/// Debug state of the open connections, see `ConnectionDebug`.
+pub fn debug_list() -> impl Future<Output = Result<Vec<ConnectionDebug>, MailboxError>> {
+    ConnectionRegistry::from_registry()
+        .send(DebugList)
+        .timeout(DEBUG_TIMEOUT + Duration::from_secs(1))
+}
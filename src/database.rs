@@ -1,19 +1,34 @@
+use crate::codec::InlineFile;
+use crate::command::ShareStatus;
 use crate::error::Error;
-use crate::filemap::FileMap;
+use crate::filemap::{BundleFormat, FileMap, HashAlgorithm, BLOCK_SIZE};
+use crate::identity;
+use crate::name_index::NameIndex;
+use crate::supervisor::{self, Guarded, Restart};
 use crate::user_report::UserReportHandle;
 use actix::prelude::*;
-use rand::Rng;
+use futures::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Read;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 use std::{fs, path, time};
 
 /// metadata format
 const FORMAT_VERSION: u32 = 1;
 
+/// Directories of the database with copies of files made for sharing them.
+const OWNED_DIRS: [&str; 3] = ["encrypted", "archives", "streams"];
+
+/// Resources whose file maps received from peers are kept, the oldest go first.
+const MAX_CACHED_FILE_MAPS: usize = 256;
+
 #[derive(Serialize, Deserialize)]
 struct Meta {
     /// Metadata format version
@@ -22,6 +37,34 @@ struct Meta {
     id: u128,
     /// Reserved for future use
     flags: Vec<String>,
+    /// Hex Ed25519 secret key, missing for nodes with ids not derived from it
+    #[serde(default)]
+    identity_key: Option<String>,
+}
+
+/// Restricts who can download a resource.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Access {
+    pub token: Option<u128>,
+    /// Node ids of allowed peers
+    pub peers: Vec<u128>,
+}
+
+impl Access {
+    /// Resources without a token and peer list can be downloaded by anyone.
+    pub fn is_open(&self) -> bool {
+        self.token.is_none() && self.peers.is_empty()
+    }
+
+    /// Listed peers and peers presenting the token are allowed. `peer_id` is the node id
+    /// the peer proved, see `identity::prove`.
+    pub fn allows(&self, peer_id: Option<u128>, token: Option<u128>) -> bool {
+        self.is_open()
+            || (self.token.is_some() && self.token == token)
+            || peer_id
+                .map(|peer_id| self.peers.contains(&peer_id))
+                .unwrap_or(false)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -30,18 +73,111 @@ pub struct FileDesc {
     pub files: Vec<(FileMap, PathBuf)>,
     pub inline_data: Vec<u8>,
     pub valid_to: Option<time::SystemTime>,
+    pub hash_algorithm: HashAlgorithm,
+    #[serde(skip)]
+    pub access: Access,
+    /// Files packed into `inline_data`.
+    #[serde(skip)]
+    pub inline_files: Vec<InlineFile>,
+    /// When the upload that set the entry was registered.
+    #[serde(skip, default = "SystemTime::now")]
+    pub shared_at: SystemTime,
+    /// Namespaces the resource was uploaded in, sorted. Removing it from one leaves it
+    /// shared for the others.
+    #[serde(skip)]
+    pub namespaces: Vec<String>,
+    /// Caller-defined key/value pairs of the resource and its files.
+    #[serde(skip)]
+    pub metadata: ResourceMetadata,
+}
+
+/// Key/value pairs and expiries given by the uploads of a resource.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceMetadata {
+    pub values: BTreeMap<String, String>,
+    /// Metadata of single files by file name.
+    pub files: BTreeMap<String, FileMetadata>,
+    /// Expiry of the whole resource, instead of the one given by the upload timeout.
+    pub valid_to: Option<SystemTime>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileMetadata {
+    pub values: BTreeMap<String, String>,
+    pub valid_to: Option<SystemTime>,
+}
+
+impl ResourceMetadata {
+    /// The earliest of the expiries, with `default` for the resource unless it has one.
+    /// The resource is only served as a whole, so it expires with its first file.
+    pub fn valid_to(&self, default: Option<SystemTime>) -> Option<SystemTime> {
+        self.files
+            .values()
+            .filter_map(|file| file.valid_to)
+            .chain(self.valid_to.or(default))
+            .min()
+    }
+
+    /// Values of `other` are added, replacing the ones under the same keys.
+    fn merge(&mut self, other: &ResourceMetadata) {
+        self.values
+            .extend(other.values.iter().map(|(k, v)| (k.clone(), v.clone())));
+        for (name, file) in &other.files {
+            let merged = self.files.entry(name.clone()).or_default();
+            merged
+                .values
+                .extend(file.values.iter().map(|(k, v)| (k.clone(), v.clone())));
+            merged.valid_to = file.valid_to.or(merged.valid_to);
+        }
+        self.valid_to = other.valid_to.or(self.valid_to);
+    }
+
+    /// Whether the resource or one of its files has `key`, with `value` if given.
+    pub fn matches(&self, key: &str, value: Option<&str>) -> bool {
+        let matches = |values: &BTreeMap<String, String>| match (values.get(key), value) {
+            (Some(found), Some(value)) => found == value,
+            (found, None) => found.is_some(),
+            (None, Some(_)) => false,
+        };
+        matches(&self.values) || self.files.values().any(|file| matches(&file.values))
+    }
 }
 
 impl FileDesc {
     #[inline]
     fn log_event(&self, event_name: &str) {
         for (_, file_path) in &self.files {
-            log::info!(
-                "{} {:032x} {}",
-                event_name,
-                self.map_hash,
-                file_path.display()
-            );
+            if self.namespaces.is_empty() {
+                log::info!(
+                    "{} {:032x} {}",
+                    event_name,
+                    self.map_hash,
+                    file_path.display()
+                );
+            } else {
+                log::info!(
+                    "{} {:032x} {} namespace={}",
+                    event_name,
+                    self.map_hash,
+                    file_path.display(),
+                    self.namespaces.join(",")
+                );
+            }
+        }
+    }
+
+    pub fn file_names(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .map(|(file_map, _)| file_map.file_name.to_string())
+            .collect()
+    }
+
+    /// Resources are in every namespace for requests without one.
+    pub fn in_namespace(&self, namespace: Option<&str>) -> bool {
+        match namespace {
+            Some(namespace) => self.namespaces.iter().any(|n| n == namespace),
+            None => true,
         }
     }
 }
@@ -50,24 +186,62 @@ pub struct DatabaseManager {
     dir: PathBuf,
     id: Option<u128>,
     files: HashMap<u128, (Arc<FileDesc>, UserReportHandle)>,
+    /// Temporary files created by downloads, with their creation time.
+    artifacts: HashMap<PathBuf, SystemTime>,
+    /// Resources hidden by a soft delete, with the time they are unshared for good.
+    deleted: HashMap<u128, (Arc<FileDesc>, UserReportHandle, SystemTime)>,
+    /// File maps received from peers, with the time they expire.
+    file_maps: HashMap<u128, (Arc<Vec<FileMap>>, SystemTime)>,
+    /// Resources no longer served because their files don't match the file maps, see
+    /// `Quarantine`.
+    quarantined: HashMap<u128, Quarantined>,
+    /// File names of `files`, see `insert_resource` and `remove_resource`.
+    names: NameIndex,
 }
 
 impl DatabaseManager {
     fn load_hash(&mut self, p: &path::Path) -> Result<(), Error> {
-        let desc: FileDesc = bincode::deserialize_from(fs::OpenOptions::new().read(true).open(p)?)?;
+        let mut desc: FileDesc =
+            bincode::deserialize_from(fs::OpenOptions::new().read(true).open(p)?)?;
+        for (file_map, path) in &mut desc.files {
+            file_map.hash_algorithm = desc.hash_algorithm;
+            file_map.mode = crate::filemap::file_mode(&path).ok();
+            file_map.mtime = crate::filemap::file_mtime(&path).ok();
+        }
+        if let Some(reason) = broken_file(&desc) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, reason).into());
+        }
+        // Inline data of older shares is the content of their only file.
+        if !desc.inline_data.is_empty() && desc.files.len() == 1 {
+            desc.inline_files = vec![InlineFile {
+                file_nr: 0,
+                offset: 0,
+            }];
+        }
         desc.log_event("reshare");
-        self.files
-            .insert(desc.map_hash, (Arc::new(desc), UserReportHandle::empty()));
+        self.insert_resource(Arc::new(desc), UserReportHandle::empty());
         Ok(())
     }
 
+    /// Adds a resource to `files` and its file names to the name index.
+    fn insert_resource(&mut self, desc: Arc<FileDesc>, reporter: UserReportHandle) {
+        self.names.insert(desc.map_hash, desc.file_names());
+        self.files.insert(desc.map_hash, (desc, reporter));
+    }
+
+    fn remove_resource(&mut self, hash: u128) -> Option<(Arc<FileDesc>, UserReportHandle)> {
+        self.names.remove(hash);
+        self.files.remove(&hash)
+    }
+
     fn init(&mut self) -> Result<(), Error> {
         let meta_path = self.dir.join("meta");
-        let id: u128 = rand::thread_rng().gen();
+        let identity_key = identity::generate_key();
         let meta = Meta {
             format: FORMAT_VERSION,
-            id,
+            id: identity::node_id(&identity_key),
             flags: Vec::new(),
+            identity_key: Some(hex_key(&identity_key)),
         };
         serde_json::to_writer_pretty(
             fs::OpenOptions::new()
@@ -78,6 +252,7 @@ impl DatabaseManager {
             &meta,
         )?;
         self.id = Some(meta.id);
+        identity::set_key(&identity_key);
         Ok(())
     }
 
@@ -85,75 +260,459 @@ impl DatabaseManager {
         let meta = self.dir.join("meta");
         if meta.exists() {
             let meta_def: Meta =
-                serde_json::from_reader(fs::OpenOptions::new().read(true).open(meta)?)?;
+                serde_json::from_reader(fs::OpenOptions::new().read(true).open(&meta)?)?;
             if meta_def.format != FORMAT_VERSION {
                 return Err(Error::InvalidMetaVersion {
                     detected_version: meta_def.format,
                 });
             }
-            self.id = Some(meta_def.id)
+            self.id = Some(meta_def.id);
+            match meta_def.identity_key.as_ref().map(|key| parse_key(key)) {
+                Some(Some(key)) if identity::node_id(&key) == meta_def.id => {
+                    identity::set_key(&key)
+                }
+                Some(_) => log::error!("identity key does not match node id, not signing"),
+                None => log::info!(
+                    "node id predates identity keys, file maps can't be signed \
+                     until {} is removed",
+                    meta.display()
+                ),
+            }
         } else {
             return Err(Error::MetadataNotFound);
         }
+        if let Err(e) = self.load_artifacts() {
+            log::error!("load download artifacts error: {}", e);
+        }
+        self.load_hashes()?;
+        Ok(())
+    }
+
+    fn hash_files(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut paths = Vec::new();
         for entry in fs::read_dir(&self.dir)? {
             let path = entry?.path();
-            if path.extension() == Some(".fhash".as_ref()) {
-                if let Err(e) = self.load_hash(&path) {
-                    log::error!("load hash error: {}", e);
+            if path.extension() == Some("fhash".as_ref()) {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Shares the resources of the `.fhash` files, removing the unreadable files. Returns
+    /// the removed ones.
+    fn load_hashes(&mut self) -> Result<Vec<PathBuf>, Error> {
+        let mut lost = Vec::new();
+        for path in self.hash_files()? {
+            if let Err(e) = self.load_hash(&path) {
+                log::error!("load hash error {}: {}", path.display(), e);
+                fs::remove_file(&path)?;
+                lost.push(path);
+            }
+        }
+        Ok(lost)
+    }
+
+    /// Starts a new database in place of the one with unusable metadata, as
+    /// `--db_recovery` says. The identity of the node is lost in any case.
+    fn recover(&mut self, cause: Error) -> Result<(), Error> {
+        let recovery = recovery();
+        log::error!(
+            "database metadata in {} unusable: {}",
+            self.dir.display(),
+            cause
+        );
+        if recovery == Recovery::Abort {
+            return Err(Error::CorruptDatabase(cause.to_string()));
+        }
+        let backup = self.backup()?;
+        log::warn!("backed up database metadata to {}", backup.display());
+        self.init()?;
+        log::warn!(
+            "started database with new node id {:032x}",
+            self.id.unwrap_or_default()
+        );
+        if let Err(e) = self.load_artifacts() {
+            log::error!("load download artifacts error: {}", e);
+        }
+        let lost = match recovery {
+            Recovery::Reset => {
+                let paths = self.hash_files()?;
+                for path in &paths {
                     fs::remove_file(path)?;
                 }
+                paths
             }
+            _ => self.load_hashes()?,
+        };
+        for (hash, (desc, _)) in &self.files {
+            log::warn!("kept {:032x} ({} files)", hash, desc.files.len());
         }
+        for path in &lost {
+            log::warn!("lost {}", path.display());
+        }
+        log::warn!(
+            "database recovered: kept {} resources and {} download artifacts, lost {} resources",
+            self.files.len(),
+            self.artifacts.len(),
+            lost.len()
+        );
         Ok(())
     }
 
-    fn clear_dir(&mut self) -> Result<(), Error> {
+    /// Copies the files at the top of the database directory, the metadata but not the
+    /// stored content, to a new directory next to it.
+    fn backup(&self) -> Result<PathBuf, Error> {
+        let dir = self.dir.canonicalize()?;
+        let secs = SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = dir.file_name().unwrap_or_else(|| "db".as_ref());
+        let backup = dir.with_file_name(format!("{}.backup-{}", name.to_string_lossy(), secs));
+        fs::create_dir(&backup)?;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::copy(entry.path(), backup.join(entry.file_name()))?;
+            }
+        }
+        Ok(backup)
+    }
+
+    fn artifacts_path(&self) -> PathBuf {
+        self.dir.join("artifacts.json")
+    }
+
+    fn load_artifacts(&mut self) -> Result<(), Error> {
+        let path = self.artifacts_path();
+        if path.exists() {
+            self.artifacts =
+                serde_json::from_reader(fs::OpenOptions::new().read(true).open(path)?)?;
+        }
         Ok(())
     }
 
-    fn remove_old_resources(&mut self) {
+    fn save_artifacts(&self) {
+        let result = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.artifacts_path())
+            .map_err(Error::from)
+            .and_then(|f| Ok(serde_json::to_writer(f, &self.artifacts)?));
+        if let Err(e) = result {
+            log::error!("failed to save download artifacts: {}", e);
+        }
+    }
+
+    fn cleanup_artifacts(&mut self, max_age: Duration) -> Vec<PathBuf> {
+        let now = SystemTime::now();
+        let expired: Vec<PathBuf> = self
+            .artifacts
+            .iter()
+            .filter(|(_, created)| {
+                now.duration_since(**created)
+                    .map(|age| age >= max_age)
+                    .unwrap_or(false)
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        let tracked = self.artifacts.len();
+        let mut removed = Vec::new();
+        for path in expired {
+            let result = if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            match result {
+                Ok(()) => {
+                    log::info!("removed download artifact {}", path.display());
+                    removed.push(path.clone());
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => {
+                    log::warn!("unable to remove {}: {}", path.display(), e);
+                    continue;
+                }
+            }
+            self.artifacts.remove(&path);
+        }
+        if self.artifacts.len() != tracked {
+            self.save_artifacts();
+        }
+        removed
+    }
+
+    /// Unshares expired resources and, given `lifetime`, resources with an expiry shared
+    /// longer ago. Files the node keeps in the database directory for them are removed.
+    fn remove_old_resources(&mut self, lifetime: Option<Duration>) -> Swept {
+        let started = time::Instant::now();
         let now = SystemTime::now();
         let expired_file_hashes: Vec<_> = self
             .files
             .iter()
-            .filter(|(_, (v, _))| {
-                v.valid_to
-                    .as_ref()
-                    .map(|valid_to| valid_to < &now)
-                    .unwrap_or(false)
+            .filter(|(_, (v, _))| match v.valid_to {
+                Some(valid_to) => {
+                    valid_to < now
+                        || lifetime
+                            .map(|lifetime| v.shared_at + lifetime <= now)
+                            .unwrap_or(false)
+                }
+                None => false,
             })
             .map(|(&k, _)| k)
             .collect();
 
+        let purged: Vec<_> = self
+            .deleted
+            .iter()
+            .filter(|(_, (_, _, purge_at))| *purge_at <= now)
+            .map(|(&k, _)| k)
+            .collect();
+
+        // Quarantined resources are kept for inspection until they expire.
+        let expired_quarantine: Vec<_> = self
+            .quarantined
+            .iter()
+            .filter(|(_, entry)| entry.desc.valid_to.is_some_and(|valid_to| valid_to < now))
+            .map(|(&k, _)| k)
+            .collect();
+
+        let mut swept = Swept::default();
+        for hash in purged {
+            if let Some((file_desc, _, _)) = self.deleted.remove(&hash) {
+                file_desc.log_event("unshare");
+                crate::stats::forget(file_desc.map_hash);
+                swept.removed.push(file_desc);
+            }
+        }
+        for hash in expired_quarantine {
+            if let Some(entry) = self.quarantined.remove(&hash) {
+                entry.desc.log_event("unshare");
+                crate::stats::forget(hash);
+                swept.removed.push(entry.desc);
+            }
+        }
         for hash in expired_file_hashes {
-            if let Some((file_desc, _)) = self.files.remove(&hash) {
+            if let Some((file_desc, _)) = self.remove_resource(hash) {
                 file_desc.log_event("unshare");
+                crate::stats::forget(file_desc.map_hash);
+                swept.removed.push(file_desc);
+            }
+        }
+        for file_desc in &swept.removed {
+            for (_, path) in &file_desc.files {
+                swept.reclaimed += self.remove_owned_file(path);
             }
         }
+        record_gc(started, swept.removed.len());
+        swept
     }
+
+    /// Unshares resources with missing or changed files and removes entries of the
+    /// directories in `OWNED_DIRS` no resource or artifact uses, older than `orphan_age`.
+    fn check_integrity(&mut self, orphan_age: Duration) -> IntegrityReport {
+        let mut report = IntegrityReport {
+            checked: self.files.len(),
+            ..IntegrityReport::default()
+        };
+        let broken: Vec<_> = self
+            .files
+            .iter()
+            .filter_map(|(&hash, (desc, _))| Some((hash, broken_file(desc)?)))
+            .collect();
+        for (hash, reason) in broken {
+            if let Some((file_desc, _)) = self.remove_resource(hash) {
+                log::warn!("unsharing broken {:032x}: {}", hash, reason);
+                file_desc.log_event("unshare");
+                crate::stats::forget(file_desc.map_hash);
+                for (_, path) in &file_desc.files {
+                    report.reclaimed += self.remove_owned_file(path);
+                }
+            }
+            report.broken.push((hash, reason));
+        }
+
+        let now = SystemTime::now();
+        for dir in OWNED_DIRS.iter().map(|dir| self.dir.join(dir)) {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.filter_map(Result::ok) {
+                let path = entry.path();
+                let recent = entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .map(|modified| now.duration_since(modified).unwrap_or_default() < orphan_age)
+                    .unwrap_or(true);
+                if recent || self.is_used(&path) {
+                    continue;
+                }
+                let size = disk_usage(&path);
+                let result = if path.is_dir() {
+                    fs::remove_dir_all(&path)
+                } else {
+                    fs::remove_file(&path)
+                };
+                match result {
+                    Ok(()) => {
+                        report.reclaimed += size;
+                        report.orphans.push(path);
+                    }
+                    Err(e) => log::warn!("unable to remove {}: {}", path.display(), e),
+                }
+            }
+        }
+        log::info!(
+            "integrity check: {} resources, {} broken, {} unused files removed, {} bytes freed",
+            report.checked,
+            report.broken.len(),
+            report.orphans.len(),
+            report.reclaimed
+        );
+        report
+    }
+
+    /// Shared, soft deleted and quarantined resources, the files of all are kept.
+    fn resources(&self) -> impl Iterator<Item = &FileDesc> {
+        self.files
+            .values()
+            .map(|(desc, _)| desc.as_ref())
+            .chain(self.deleted.values().map(|(desc, _, _)| desc.as_ref()))
+            .chain(self.quarantined.values().map(|entry| entry.desc.as_ref()))
+    }
+
+    /// Whether a shared file or an artifact is or lies under `path`.
+    fn is_used(&self, path: &path::Path) -> bool {
+        self.resources()
+            .any(|desc| desc.files.iter().any(|(_, p)| p.starts_with(path)))
+            || self
+                .artifacts
+                .keys()
+                .any(|artifact| artifact.starts_with(path) || path.starts_with(artifact))
+    }
+
+    /// Removes a file of an unshared resource if it is kept in the database directory
+    /// and no other resource shares it, returns the bytes freed.
+    fn remove_owned_file(&self, path: &path::Path) -> u64 {
+        if !path.starts_with(&self.dir)
+            || self
+                .resources()
+                .any(|desc| desc.files.iter().any(|(_, p)| p == path))
+        {
+            return 0;
+        }
+        let size = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => return 0,
+        };
+        if let Err(e) = fs::remove_file(path) {
+            log::warn!("unable to remove {}: {}", path.display(), e);
+            return 0;
+        }
+        // Directories of uploads go with their last file, `streams` and the like stay.
+        let below_top = |dir: &path::Path| match dir.parent() {
+            Some(parent) => parent != self.dir && parent.starts_with(&self.dir),
+            None => false,
+        };
+        for dir in path.ancestors().skip(1).take_while(|dir| below_top(dir)) {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+        size
+    }
+}
+
+/// Describes the first file of the resource missing or differing in size from its map.
+fn broken_file(desc: &FileDesc) -> Option<String> {
+    desc.files
+        .iter()
+        .filter(|(file_map, _)| !file_map.blocks.is_empty())
+        .find_map(
+            |(file_map, path)| match crate::encryption::file_size(path) {
+                Ok(size) if size == file_map.file_size => None,
+                Ok(size) => Some(format!(
+                    "{} has {} bytes instead of {}",
+                    path.display(),
+                    size,
+                    file_map.file_size
+                )),
+                Err(e) => Some(format!("{}: {}", path.display(), e)),
+            },
+        )
+}
+
+/// Bytes of the files under `path`.
+fn disk_usage(path: &path::Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| disk_usage(&entry.path()))
+                    .sum()
+            })
+            .unwrap_or(0),
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
+
+fn hex_key(key: &[u8; 32]) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
 }
 
 impl Actor for DatabaseManager {
     type Context = SyncContext<Self>;
 
     fn started(&mut self, _: &mut Self::Context) {
+        log::info!("db started id=0x{:032x}", self.id.unwrap_or_default());
+        // Nothing is being uploaded yet, so all unused files are left over.
+        self.check_integrity(Duration::from_secs(0));
+    }
+}
+
+impl DatabaseManager {
+    fn new(dir: PathBuf) -> Self {
+        DatabaseManager {
+            dir,
+            files: HashMap::new(),
+            id: None,
+            artifacts: HashMap::new(),
+            deleted: HashMap::new(),
+            file_maps: HashMap::new(),
+            quarantined: HashMap::new(),
+            names: NameIndex::default(),
+        }
+    }
+
+    /// Loads the database, starting a new one when there is none or its metadata is
+    /// unreadable.
+    fn open(&mut self) -> Result<(), Error> {
         log::debug!("starting db on {}", self.dir.display());
         match self.load() {
-            e @ Err(Error::InvalidMetaVersion { .. })
-            | e @ Err(Error::MetadataNotFound)
-            | e @ Err(Error::InvalidJsonFormat(_)) => {
-                log::debug!("load meta error: {}", e.unwrap_err());
-                // TODO: Better error handling.
-                self.clear_dir().unwrap();
-                self.init().unwrap();
-            }
-            Err(e) => {
-                log::error!("init db fail: {}", e);
-                System::current().stop()
-            }
-            Ok(()) => (),
+            Err(Error::MetadataNotFound) if self.hash_files()?.is_empty() => self.init(),
+            Err(e @ Error::InvalidMetaVersion { .. })
+            | Err(e @ Error::MetadataNotFound)
+            | Err(e @ Error::InvalidJsonFormat(_)) => self.recover(e),
+            result => result,
         }
-        log::info!("db started id=0x{:032x}", self.id.as_ref().unwrap());
     }
 }
 
@@ -162,23 +721,294 @@ static APP_INFO: app_dirs::AppInfo = app_dirs::AppInfo {
     author: "golem.network",
 };
 
-pub fn database_manager(cache_path: &Option<PathBuf>) -> Addr<DatabaseManager> {
-    let dir = cache_path.clone().unwrap_or_else(|| {
-        app_dirs::app_dir(app_dirs::AppDataType::UserCache, &APP_INFO, "db").unwrap()
-    });
+/// Database directory in the user cache directory, created if missing.
+pub fn default_dir() -> Result<PathBuf, Error> {
+    app_dirs::app_dir(app_dirs::AppDataType::UserCache, &APP_INFO, "db").map_err(|e| {
+        Error::IO(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no user cache directory: {}", e),
+        ))
+    })
+}
 
-    let addr = SyncArbiter::start(1, move || {
-        let man = DatabaseManager {
-            dir: dir.clone(),
-            files: HashMap::new(),
-            id: None,
-        };
+/// `cache_path` or the default directory. The daemon resolves the default at startup.
+pub fn database_dir(cache_path: &Option<PathBuf>) -> PathBuf {
+    cache_path
+        .clone()
+        .unwrap_or_else(|| default_dir().expect("database directory not resolved at startup"))
+}
 
-        man
+/// Loads the database in `cache_path` and starts serving it.
+pub fn database_manager(cache_path: &Option<PathBuf>) -> Result<Addr<DatabaseManager>, Error> {
+    let dir = database_dir(cache_path);
+    let mut man = DatabaseManager::new(dir.clone());
+    man.open()?;
+
+    let loaded = Mutex::new(Some(man));
+    let addr = SyncArbiter::start(1, move || {
+        let loaded = loaded.lock().ok().and_then(|mut loaded| loaded.take());
+        loaded.unwrap_or_else(|| {
+            let mut man = DatabaseManager::new(dir.clone());
+            if let Err(e) = man.open() {
+                log::error!("reopen db fail: {}", e);
+            }
+            man
+        })
     });
     let _ = GcWorker(addr.clone().recipient()).start();
 
-    addr
+    Ok(addr)
+}
+
+/// What happens to a database with unusable metadata, selected with `--db_recovery`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Back up the metadata, start a new database and share the readable resources again.
+    Salvage,
+    /// Back up the metadata and start an empty database.
+    Reset,
+    /// Refuse to start.
+    Abort,
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Recovery::Salvage => "salvage",
+            Recovery::Reset => "reset",
+            Recovery::Abort => "abort",
+        })
+    }
+}
+
+impl FromStr for Recovery {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "salvage" => Ok(Recovery::Salvage),
+            "reset" => Ok(Recovery::Reset),
+            "abort" => Ok(Recovery::Abort),
+            _ => Err(Error::InvalidArgument(format!(
+                "invalid recovery: {} (expected salvage, reset or abort)",
+                s
+            ))),
+        }
+    }
+}
+
+static RECOVERY: AtomicU8 = AtomicU8::new(Recovery::Salvage as u8);
+
+pub fn set_recovery(recovery: Recovery) {
+    RECOVERY.store(recovery as u8, Ordering::Relaxed);
+}
+
+fn recovery() -> Recovery {
+    match RECOVERY.load(Ordering::Relaxed) {
+        1 => Recovery::Reset,
+        2 => Recovery::Abort,
+        _ => Recovery::Salvage,
+    }
+}
+
+/// Files below this size are kept in memory and served together, see `pack_inline`.
+static INLINE_THRESHOLD: AtomicUsize = AtomicUsize::new(200);
+
+pub fn set_inline_threshold(threshold: usize) {
+    INLINE_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+/// Packs the content of the files below the inline threshold, as long as they fit
+/// into one block. Returns the sealed inline data and the packed files.
+pub fn pack_inline(files: &[(FileMap, PathBuf)]) -> std::io::Result<(Vec<u8>, Vec<InlineFile>)> {
+    let threshold = INLINE_THRESHOLD.load(Ordering::Relaxed) as u64;
+    let mut data = Vec::new();
+    let mut inline_files = Vec::new();
+    for (file_nr, (file_map, path)) in files.iter().enumerate() {
+        if file_map.file_size == 0
+            || file_map.file_size >= threshold
+            || file_map.link.is_some()
+            || data.len() as u64 + file_map.file_size > BLOCK_SIZE as u64
+        {
+            continue;
+        }
+        let offset = data.len();
+        crate::encryption::StoredFile::open(path)?.read_to_end(&mut data)?;
+        if (data.len() - offset) as u64 != file_map.file_size {
+            // Changed since hashing, served from disk like other files.
+            data.truncate(offset);
+            continue;
+        }
+        inline_files.push(InlineFile {
+            file_nr: file_nr as u32,
+            offset: offset as u32,
+        });
+    }
+    if inline_files.is_empty() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+    Ok((crate::encryption::seal(&data), inline_files))
+}
+
+/// Requests sent with `request` and not answered yet.
+static QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+static QUEUE_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Requests beyond `limit` waiting for the database are answered with busy errors
+/// instead of being queued.
+pub fn set_queue_limit(limit: usize) {
+    QUEUE_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// The limit of `set_queue_limit`, if any.
+pub fn queue_limit() -> Option<usize> {
+    Some(QUEUE_LIMIT.load(Ordering::Relaxed)).filter(|&limit| limit != usize::MAX)
+}
+
+pub fn queue_depth() -> usize {
+    QUEUE_DEPTH.load(Ordering::Relaxed)
+}
+
+pub fn is_overloaded() -> bool {
+    queue_depth() >= QUEUE_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Sweeps of expired resources, periodic and requested by `/admin/gc`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GcInfo {
+    pub runs: u64,
+    /// Seconds since the last sweep ended
+    pub last_run_secs_ago: Option<u64>,
+    pub last_duration_ms: u64,
+    /// Resources unshared by the last sweep
+    pub last_removed: usize,
+    pub max_duration_ms: u64,
+    /// Seconds between periodic sweeps
+    pub interval_secs: u64,
+}
+
+struct GcRuns {
+    runs: u64,
+    last_run: Option<time::Instant>,
+    last_duration: Duration,
+    last_removed: usize,
+    max_duration: Duration,
+}
+
+static GC_RUNS: Mutex<GcRuns> = Mutex::new(GcRuns {
+    runs: 0,
+    last_run: None,
+    last_duration: Duration::ZERO,
+    last_removed: 0,
+    max_duration: Duration::ZERO,
+});
+
+fn record_gc(started: time::Instant, removed: usize) {
+    let mut gc = GC_RUNS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let took = started.elapsed();
+    gc.runs += 1;
+    gc.last_run = Some(time::Instant::now());
+    gc.last_duration = took;
+    gc.last_removed = removed;
+    gc.max_duration = gc.max_duration.max(took);
+}
+
+pub fn gc_info() -> GcInfo {
+    let gc = GC_RUNS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    GcInfo {
+        runs: gc.runs,
+        last_run_secs_ago: gc.last_run.map(|at| at.elapsed().as_secs()),
+        last_duration_ms: gc.last_duration.as_millis() as u64,
+        last_removed: gc.last_removed,
+        max_duration_ms: gc.max_duration.as_millis() as u64,
+        interval_secs: GC_INTERVAL.as_secs(),
+    }
+}
+
+struct QueueSlot;
+
+impl QueueSlot {
+    fn new() -> Self {
+        QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed);
+        QueueSlot
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Restart for DatabaseManager {
+    const NAME: &'static str = "database";
+
+    /// Shares are only kept in memory, so they stay. Cached file maps are dropped.
+    fn restart(&mut self) {
+        self.file_maps.clear();
+        // The handler may have changed `files` without the index.
+        self.names = NameIndex::default();
+        for (hash, (desc, _)) in &self.files {
+            self.names.insert(*hash, desc.file_names());
+        }
+    }
+}
+
+impl<M> Handler<Guarded<M>> for DatabaseManager
+where
+    Self: Handler<M>,
+    M: Message + Send + 'static,
+    M::Result: Send + 'static,
+{
+    type Result = Result<M::Result, Error>;
+
+    fn handle(&mut self, msg: Guarded<M>, ctx: &mut Self::Context) -> Self::Result {
+        supervisor::handle_guarded(self, msg, ctx)
+    }
+}
+
+/// Sends `msg` to the database, counted in `queue_depth` until it is answered.
+pub fn request<M>(
+    m: &Addr<DatabaseManager>,
+    msg: M,
+) -> impl Future<Output = Result<M::Result, Error>>
+where
+    M: Message + Send + 'static,
+    M::Result: Send + 'static,
+    DatabaseManager: Handler<M>,
+{
+    let slot = QueueSlot::new();
+    m.send::<Guarded<M>>(Guarded::new::<DatabaseManager>(msg))
+        .map(move |r| {
+            drop(slot);
+            r.map_err(Error::from).and_then(|r| r)
+        })
+}
+
+/// Sends `msg` to the database without waiting for the answer.
+pub fn notify<M>(m: &Addr<DatabaseManager>, msg: M)
+where
+    M: Message + Send + 'static,
+    M::Result: Send + 'static,
+    DatabaseManager: Handler<M>,
+{
+    m.do_send::<Guarded<M>>(Guarded::new::<DatabaseManager>(msg))
+}
+
+/// Like [`request`], for messages answered with a `Result`.
+pub fn call<M, T>(m: &Addr<DatabaseManager>, msg: M) -> impl Future<Output = Result<T, Error>>
+where
+    M: Message<Result = Result<T, Error>> + Send + 'static,
+    T: Send + 'static,
+    DatabaseManager: Handler<M>,
+{
+    request(m, msg).map(|r| r?)
 }
 
 struct GetId;
@@ -195,11 +1025,8 @@ impl Handler<GetId> for DatabaseManager {
     }
 }
 
-pub fn id(m: &Addr<DatabaseManager>) -> impl Future<Item = u128, Error = Error> {
-    m.send(GetId).then(|r| match r {
-        Ok(r) => r,
-        Err(e) => Err(e.into()),
-    })
+pub fn id(m: &Addr<DatabaseManager>) -> impl Future<Output = Result<u128, Error>> {
+    call(m, GetId)
 }
 
 pub struct GetHash(pub u128);
@@ -220,6 +1047,99 @@ impl Handler<GetHash> for DatabaseManager {
     }
 }
 
+/// Shared files with the same content as the file maps, to copy instead of fetching.
+/// Directories, links and empty files are never looked up.
+pub struct FindFiles(pub Vec<FileMap>);
+
+impl Message for FindFiles {
+    type Result = Vec<Option<(FileMap, PathBuf)>>;
+}
+
+impl Handler<FindFiles> for DatabaseManager {
+    type Result = MessageResult<FindFiles>;
+
+    fn handle(&mut self, msg: FindFiles, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            msg.0
+                .iter()
+                .map(|wanted| {
+                    if wanted.blocks.is_empty() || wanted.link.is_some() {
+                        return None;
+                    }
+                    self.files
+                        .values()
+                        .flat_map(|(desc, _)| desc.files.iter())
+                        .find(|(file_map, _)| {
+                            file_map.file_size == wanted.file_size
+                                && file_map.hash_algorithm == wanted.hash_algorithm
+                                && file_map.blocks == wanted.blocks
+                        })
+                        .cloned()
+                })
+                .collect(),
+        )
+    }
+}
+
+struct Quarantined {
+    desc: Arc<FileDesc>,
+    reason: String,
+    since: SystemTime,
+}
+
+/// Stops serving a resource whose files were found not to match its file maps. It is
+/// served again once shared again.
+pub struct Quarantine {
+    pub hash: u128,
+    pub reason: String,
+}
+
+impl Message for Quarantine {
+    type Result = Result<bool, Error>;
+}
+
+impl Handler<Quarantine> for DatabaseManager {
+    type Result = Result<bool, Error>;
+
+    fn handle(&mut self, msg: Quarantine, _ctx: &mut Self::Context) -> Self::Result {
+        let (desc, _) = match self.remove_resource(msg.hash) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        log::warn!("quarantined {:032x}: {}", msg.hash, msg.reason);
+        desc.log_event("quarantine");
+        self.quarantined.insert(
+            msg.hash,
+            Quarantined {
+                desc,
+                reason: msg.reason,
+                since: SystemTime::now(),
+            },
+        );
+        Ok(true)
+    }
+}
+
+/// Quarantined resources with the reason and the time they were quarantined.
+pub struct ListQuarantined;
+
+impl Message for ListQuarantined {
+    type Result = Vec<(u128, String, SystemTime)>;
+}
+
+impl Handler<ListQuarantined> for DatabaseManager {
+    type Result = MessageResult<ListQuarantined>;
+
+    fn handle(&mut self, _: ListQuarantined, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            self.quarantined
+                .iter()
+                .map(|(&hash, entry)| (hash, entry.reason.clone(), entry.since))
+                .collect(),
+        )
+    }
+}
+
 pub struct RemoveHash(pub u128);
 
 impl Message for RemoveHash {
@@ -230,9 +1150,10 @@ impl Handler<RemoveHash> for DatabaseManager {
     type Result = Result<Option<Arc<FileDesc>>, Error>;
 
     fn handle(&mut self, msg: RemoveHash, _ctx: &mut Self::Context) -> Self::Result {
-        let prev = self.files.remove(&msg.0);
+        let prev = self.remove_resource(msg.0);
         Ok(if let Some((file_desc, _)) = prev {
             file_desc.log_event("unshare");
+            crate::stats::forget(file_desc.map_hash);
             Some(file_desc)
         } else {
             None
@@ -240,34 +1161,219 @@ impl Handler<RemoveHash> for DatabaseManager {
     }
 }
 
+static FILE_MAPS_TTL: AtomicU64 = AtomicU64::new(600);
+
+/// How long file maps received from peers are kept, 0 keeps none.
+pub fn set_file_maps_ttl(ttl: Duration) {
+    FILE_MAPS_TTL.store(ttl.as_secs(), Ordering::Relaxed);
+}
+
+/// Keeps file maps received for `hash` and verified against it for `set_file_maps_ttl`,
+/// so downloads asking for the resource again need not fetch them.
+pub struct CacheFileMaps {
+    pub hash: u128,
+    pub files: Arc<Vec<FileMap>>,
+}
+
+impl Message for CacheFileMaps {
+    type Result = ();
+}
+
+impl Handler<CacheFileMaps> for DatabaseManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: CacheFileMaps, _ctx: &mut Self::Context) -> Self::Result {
+        let ttl = FILE_MAPS_TTL.load(Ordering::Relaxed);
+        if ttl == 0 {
+            return;
+        }
+        let now = SystemTime::now();
+        self.file_maps.retain(|_, (_, expires)| *expires > now);
+        if self.file_maps.len() >= MAX_CACHED_FILE_MAPS && !self.file_maps.contains_key(&msg.hash) {
+            let oldest = self
+                .file_maps
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                self.file_maps.remove(&oldest);
+            }
+        }
+        self.file_maps
+            .insert(msg.hash, (msg.files, now + Duration::from_secs(ttl)));
+    }
+}
+
+/// File maps received for a resource lately, see `CacheFileMaps`.
+pub struct GetCachedFileMaps(pub u128);
+
+impl Message for GetCachedFileMaps {
+    type Result = Option<Arc<Vec<FileMap>>>;
+}
+
+impl Handler<GetCachedFileMaps> for DatabaseManager {
+    type Result = Option<Arc<Vec<FileMap>>>;
+
+    fn handle(&mut self, msg: GetCachedFileMaps, _ctx: &mut Self::Context) -> Self::Result {
+        match self.file_maps.get(&msg.0) {
+            Some((files, expires)) if *expires > SystemTime::now() => Some(files.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Removes a resource from `namespace`, or from all of them. A resource left in none is
+/// unshared, or with `grace` hidden from asks for it and unshared by the first sweep after.
+pub struct Unshare {
+    pub hash: u128,
+    pub namespace: Option<String>,
+    pub grace: Option<Duration>,
+}
+
+impl Message for Unshare {
+    type Result = Result<Option<Arc<FileDesc>>, Error>;
+}
+
+impl Handler<Unshare> for DatabaseManager {
+    type Result = Result<Option<Arc<FileDesc>>, Error>;
+
+    fn handle(&mut self, msg: Unshare, _ctx: &mut Self::Context) -> Self::Result {
+        let namespace = msg.namespace.as_deref();
+        let entry = match self.files.get_mut(&msg.hash) {
+            Some(entry) if entry.0.in_namespace(namespace) => entry,
+            _ => return Ok(None),
+        };
+        if let Some(namespace) = namespace {
+            if entry.0.namespaces.len() > 1 {
+                let mut updated = entry.0.as_ref().clone();
+                updated.namespaces.retain(|n| n != namespace);
+                entry.0 = Arc::new(updated);
+                log::info!("removed {:032x} from namespace {}", msg.hash, namespace);
+                return Ok(Some(entry.0.clone()));
+            }
+        }
+        let (file_desc, reporter) = match self.remove_resource(msg.hash) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        match msg.grace {
+            Some(grace) => {
+                file_desc.log_event("hide");
+                self.deleted.insert(
+                    msg.hash,
+                    (file_desc.clone(), reporter, SystemTime::now() + grace),
+                );
+            }
+            None => {
+                file_desc.log_event("unshare");
+                crate::stats::forget(file_desc.map_hash);
+            }
+        }
+        Ok(Some(file_desc))
+    }
+}
+
+/// Shares a soft deleted resource of `namespace` again.
+pub struct RestoreHash {
+    pub hash: u128,
+    pub namespace: Option<String>,
+}
+
+impl Message for RestoreHash {
+    type Result = Result<Option<Arc<FileDesc>>, Error>;
+}
+
+impl Handler<RestoreHash> for DatabaseManager {
+    type Result = Result<Option<Arc<FileDesc>>, Error>;
+
+    fn handle(&mut self, msg: RestoreHash, _ctx: &mut Self::Context) -> Self::Result {
+        match self.deleted.get(&msg.hash) {
+            Some((file_desc, _, _)) if file_desc.in_namespace(msg.namespace.as_deref()) => (),
+            _ => return Ok(None),
+        }
+        Ok(self
+            .deleted
+            .remove(&msg.hash)
+            .map(|(file_desc, reporter, _)| {
+                file_desc.log_event("restore");
+                self.insert_resource(file_desc.clone(), reporter);
+                file_desc
+            }))
+    }
+}
+
 pub struct RegisterHash {
     pub files: Vec<(FileMap, PathBuf)>,
     pub valid_to: Option<time::SystemTime>,
     pub inline_data: Vec<u8>,
+    /// Files packed into `inline_data`, numbered in the order of `files` after sorting.
+    pub inline_files: Vec<InlineFile>,
+    pub hash_algorithm: HashAlgorithm,
+    pub access: Access,
     pub reporter: UserReportHandle,
+    /// Format the resource is hashed in, older ones for downloaded resources hashed by
+    /// older nodes. `files` keep their order for `BundleFormat::InOrder`.
+    pub bundle_format: BundleFormat,
+    /// Namespace the resource is added to.
+    pub namespace: Option<String>,
+    /// Added to the metadata of earlier uploads, see `ResourceMetadata::valid_to` for
+    /// its expiries.
+    pub metadata: ResourceMetadata,
 }
 
 impl Message for RegisterHash {
-    type Result = Result<u128, Error>;
+    type Result = Result<Registration, Error>;
+}
+
+pub struct Registration {
+    pub hash: u128,
+    pub status: ShareStatus,
+    /// Expiry of the earlier share of the resource.
+    pub previous_valid_to: Option<time::SystemTime>,
 }
 
 impl Handler<RegisterHash> for DatabaseManager {
-    type Result = Result<u128, Error>;
+    type Result = Result<Registration, Error>;
 
-    fn handle(&mut self, msg: RegisterHash, _ctx: &mut Self::Context) -> Self::Result {
-        let map_hash = crate::filemap::hash_bundles(msg.files.iter().map(|(map, _path)| map));
+    fn handle(&mut self, mut msg: RegisterHash, _ctx: &mut Self::Context) -> Self::Result {
+        if !msg.bundle_format.keeps_order() {
+            crate::filemap::sort_bundle(&mut msg.files);
+        }
+        let map_hash = msg
+            .bundle_format
+            .hash(msg.hash_algorithm, msg.files.iter().map(|(map, _path)| map));
         let reporter = msg.reporter;
+        // Sharing again cancels a soft delete and a quarantine.
+        self.deleted.remove(&map_hash);
+        self.quarantined.remove(&map_hash);
+        let mut namespaces: Vec<String> = msg.namespace.into_iter().collect();
+        let mut metadata = ResourceMetadata::default();
+        if let Some((prev, _)) = self.files.get(&map_hash) {
+            namespaces.extend(prev.namespaces.iter().cloned());
+            namespaces.sort();
+            namespaces.dedup();
+            metadata = prev.metadata.clone();
+        }
+        metadata.merge(&msg.metadata);
+        let valid_to = msg.metadata.valid_to(msg.valid_to);
         let desc = Arc::new(FileDesc {
             map_hash,
             files: msg.files,
             inline_data: msg.inline_data,
-            valid_to: msg.valid_to.clone(),
+            valid_to,
+            hash_algorithm: msg.hash_algorithm,
+            access: msg.access,
+            inline_files: msg.inline_files,
+            shared_at: SystemTime::now(),
+            namespaces,
+            metadata,
         });
 
-        match self.files.entry(map_hash) {
+        let (status, previous_valid_to) = match self.files.entry(map_hash) {
             Entry::Occupied(mut ent) => {
                 let prev_ent = ent.get_mut();
-                let old_is_longer = match (prev_ent.0.valid_to, msg.valid_to) {
+                let previous_valid_to = prev_ent.0.valid_to;
+                let old_is_longer = match (previous_valid_to, valid_to) {
                     (None, _) => true,
                     (Some(prev_valid_to), Some(new_valid_to)) => prev_valid_to > new_valid_to,
                     _ => false,
@@ -275,19 +1381,49 @@ impl Handler<RegisterHash> for DatabaseManager {
                 if !old_is_longer {
                     prev_ent.0 = desc.clone();
                     desc.log_event("share extend");
+                    (ShareStatus::Extended, previous_valid_to)
+                } else {
+                    if prev_ent.0.access != desc.access
+                        || prev_ent.0.namespaces != desc.namespaces
+                        || prev_ent.0.metadata != desc.metadata
+                    {
+                        // Access set by the latest upload applies, namespaces and
+                        // metadata add up.
+                        let mut updated = prev_ent.0.as_ref().clone();
+                        updated.access = desc.access.clone();
+                        updated.namespaces = desc.namespaces.clone();
+                        updated.metadata = desc.metadata.clone();
+                        prev_ent.0 = Arc::new(updated);
+                    }
+                    (ShareStatus::Present, previous_valid_to)
                 }
             }
             Entry::Vacant(ent) => {
+                // File names are part of the hash, only new resources change the index.
+                self.names.insert(map_hash, desc.file_names());
                 ent.insert((desc.clone(), reporter));
                 desc.log_event("share");
+                (ShareStatus::New, None)
             }
-        }
-        Ok(map_hash)
+        };
+        Ok(Registration {
+            hash: map_hash,
+            status,
+            previous_valid_to,
+        })
     }
 }
 
+/// Resources of `namespace`, all without it.
 #[derive(Default)]
-pub struct List {}
+pub struct List {
+    pub namespace: Option<String>,
+    /// Only resources with the metadata key, and value if given, see
+    /// `ResourceMetadata::matches`.
+    pub metadata: Option<(String, Option<String>)>,
+    /// Only resources with a file name containing the text, ignoring case.
+    pub name: Option<String>,
+}
 
 impl Message for List {
     type Result = Vec<Arc<FileDesc>>;
@@ -296,8 +1432,72 @@ impl Message for List {
 impl Handler<List> for DatabaseManager {
     type Result = MessageResult<List>;
 
-    fn handle(&mut self, _: List, _: &mut Self::Context) -> Self::Result {
-        MessageResult(self.files.values().map(|(f, _)| f).cloned().collect())
+    fn handle(&mut self, msg: List, _: &mut Self::Context) -> Self::Result {
+        let named = msg.name.as_deref().map(|name| self.names.search(name));
+        MessageResult(
+            self.files
+                .iter()
+                .filter(|(hash, _)| named.as_ref().is_none_or(|named| named.contains(hash)))
+                .map(|(_, (f, _))| f)
+                .filter(|f| f.in_namespace(msg.namespace.as_deref()))
+                .filter(|f| match &msg.metadata {
+                    Some((key, value)) => f.metadata.matches(key, value.as_deref()),
+                    None => true,
+                })
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// Registers a temporary download file or upload directory, removed by
+/// `CleanupArtifacts` unless released before.
+pub struct TrackArtifact(pub PathBuf);
+
+impl Message for TrackArtifact {
+    type Result = ();
+}
+
+impl Handler<TrackArtifact> for DatabaseManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: TrackArtifact, _: &mut Self::Context) -> Self::Result {
+        self.artifacts.insert(msg.0, SystemTime::now());
+        self.save_artifacts();
+    }
+}
+
+/// Stops tracking a temporary file that became a regular download or share.
+pub struct ReleaseArtifact(pub PathBuf);
+
+impl Message for ReleaseArtifact {
+    type Result = ();
+}
+
+impl Handler<ReleaseArtifact> for DatabaseManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReleaseArtifact, _: &mut Self::Context) -> Self::Result {
+        if self.artifacts.remove(&msg.0).is_some() {
+            self.save_artifacts();
+        }
+    }
+}
+
+/// Removes tracked download artifacts older than `max_age`.
+pub struct CleanupArtifacts {
+    pub max_age: Duration,
+}
+
+impl Message for CleanupArtifacts {
+    type Result = Vec<PathBuf>;
+}
+
+impl Handler<CleanupArtifacts> for DatabaseManager {
+    type Result = MessageResult<CleanupArtifacts>;
+
+    fn handle(&mut self, msg: CleanupArtifacts, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.cleanup_artifacts(msg.max_age))
     }
 }
 
@@ -311,25 +1511,154 @@ impl Handler<Gc> for DatabaseManager {
     type Result = ();
 
     fn handle(&mut self, _: Gc, _: &mut Self::Context) -> Self::Result {
-        self.remove_old_resources()
+        self.remove_old_resources(None);
+    }
+}
+
+/// Checks the files of shared resources and compacts the database directory, done at
+/// startup as well. See `check_integrity` for `orphan_age`.
+pub struct CheckIntegrity {
+    pub orphan_age: Duration,
+}
+
+#[derive(Default)]
+pub struct IntegrityReport {
+    /// Number of resources checked
+    pub checked: usize,
+    /// Resources unshared, with the file that was missing or changed
+    pub broken: Vec<(u128, String)>,
+    /// Unused entries removed from the database directory
+    pub orphans: Vec<PathBuf>,
+    /// Bytes removed from the database directory
+    pub reclaimed: u64,
+}
+
+impl Message for CheckIntegrity {
+    type Result = IntegrityReport;
+}
+
+impl Handler<CheckIntegrity> for DatabaseManager {
+    type Result = MessageResult<CheckIntegrity>;
+
+    fn handle(&mut self, msg: CheckIntegrity, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.check_integrity(msg.orphan_age))
+    }
+}
+
+/// Runs the sweep of `Gc` now, see `remove_old_resources` for `lifetime`.
+pub struct Sweep {
+    pub lifetime: Option<Duration>,
+}
+
+#[derive(Default)]
+pub struct Swept {
+    pub removed: Vec<Arc<FileDesc>>,
+    /// Bytes of the files removed from the database directory
+    pub reclaimed: u64,
+}
+
+impl Message for Sweep {
+    type Result = Swept;
+}
+
+impl Handler<Sweep> for DatabaseManager {
+    type Result = MessageResult<Sweep>;
+
+    fn handle(&mut self, msg: Sweep, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.remove_old_resources(msg.lifetime))
     }
 }
 
-struct GcWorker(Recipient<Gc>);
+const GC_INTERVAL: Duration = Duration::from_secs(30);
+
+struct GcWorker(Recipient<Guarded<Gc>>);
 
 impl Actor for GcWorker {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let _ = ctx.run_interval(Duration::from_secs(30), |act, ctx| {
+        let _ = ctx.run_interval(GC_INTERVAL, |act, ctx| {
             log::trace!("send gc start");
-            match act.0.do_send(Gc) {
-                Ok(()) => (),
-                Err(e) => {
-                    log::error!("gc error: {}", e);
-                    ctx.stop()
-                }
+            if act.0.connected() {
+                act.0.do_send(Guarded::new::<DatabaseManager>(Gc))
+            } else {
+                log::error!("gc error: database stopped");
+                ctx.stop()
             }
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recover() {
+        let dir = std::env::temp_dir().join(format!("hyperg-recover-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("meta"), "{").unwrap();
+        fs::write(dir.join("broken.fhash"), "x").unwrap();
+
+        let mut man = DatabaseManager::new(dir.clone());
+        man.open().unwrap();
+        assert!(man.id.is_some() && man.files.is_empty());
+        assert!(!dir.join("broken.fhash").exists());
+
+        let name = dir.file_name().unwrap().to_string_lossy().into_owned();
+        let backup = fs::read_dir(dir.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.to_string_lossy()
+                    .contains(&format!("{}.backup-", name))
+            })
+            .unwrap();
+        assert_eq!(fs::read(backup.join("meta")).unwrap(), b"{");
+        assert!(backup.join("broken.fhash").exists());
+
+        // The new metadata is used from now on.
+        let mut reopened = DatabaseManager::new(dir.clone());
+        reopened.open().unwrap();
+        assert_eq!(reopened.id, man.id);
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(backup);
+    }
+
+    #[test]
+    fn test_resource_metadata() {
+        let at = |secs| Some(time::UNIX_EPOCH + Duration::from_secs(secs));
+        let values = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|&(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let mut metadata = ResourceMetadata {
+            values: values(&[("task", "1"), ("owner", "a")]),
+            files: BTreeMap::new(),
+            valid_to: None,
+        };
+        assert_eq!(metadata.valid_to(at(100)), at(100));
+        metadata.files.insert(
+            "a.txt".into(),
+            FileMetadata {
+                values: values(&[("kind", "input")]),
+                valid_to: at(50),
+            },
+        );
+        assert_eq!(metadata.valid_to(at(100)), at(50));
+
+        metadata.merge(&ResourceMetadata {
+            values: values(&[("task", "2")]),
+            files: BTreeMap::new(),
+            valid_to: at(200),
+        });
+        assert_eq!(metadata.values, values(&[("task", "2"), ("owner", "a")]));
+        assert_eq!(metadata.files["a.txt"].valid_to, at(50));
+        assert!(metadata.matches("owner", None));
+        assert!(metadata.matches("task", Some("2")) && !metadata.matches("task", Some("1")));
+        assert!(metadata.matches("kind", Some("input")));
+        assert!(!metadata.matches("missing", None));
+    }
+}
@@ -0,0 +1,93 @@
+//! Discovery of hyperg nodes in the local network over mDNS.
+use crate::error::Error;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+
+#[cfg(feature = "with-mdns")]
+const SERVICE_TYPE: &str = "_hyperg._tcp.local.";
+
+/// Transfer addresses of discovered nodes by mDNS instance name.
+static LAN_PEERS: OnceLock<Mutex<HashMap<String, Vec<SocketAddr>>>> = OnceLock::new();
+
+fn lan_peers_store() -> &'static Mutex<HashMap<String, Vec<SocketAddr>>> {
+    LAN_PEERS.get_or_init(Default::default)
+}
+
+/// Nodes currently advertised in the local network, empty if discovery is off.
+pub fn lan_peers() -> Vec<SocketAddr> {
+    lan_peers_store()
+        .lock()
+        .map(|peers| peers.values().flatten().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Advertises this node and starts tracking other nodes in the local network.
+#[cfg(feature = "with-mdns")]
+pub fn start(node_id: u128, port: u16) -> Result<(), Error> {
+    use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+    let discovery_error = |e: mdns_sd::Error| Error::Discovery(e.to_string());
+    let name = crate::codec::hash_to_hex(node_id);
+    let daemon = ServiceDaemon::new().map_err(discovery_error)?;
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &name,
+        &format!("{}.local.", name),
+        "",
+        port,
+        &[("id", name.as_str())][..],
+    )
+    .map_err(discovery_error)?
+    .enable_addr_auto();
+    daemon.register(service).map_err(discovery_error)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(discovery_error)?;
+
+    std::thread::spawn(move || {
+        let _daemon = daemon;
+        while let Ok(event) = events.recv() {
+            let mut peers = match lan_peers_store().lock() {
+                Ok(peers) => peers,
+                Err(_) => break,
+            };
+            match event {
+                ServiceEvent::ServiceResolved(info) => {
+                    if info.get_property_val_str("id") == Some(name.as_str()) {
+                        continue;
+                    }
+                    let addrs: Vec<SocketAddr> = info
+                        .get_addresses()
+                        .iter()
+                        .filter(|ip| !is_link_local(ip))
+                        .map(|ip| SocketAddr::new(*ip, info.get_port()))
+                        .collect();
+                    log::info!("found lan peer {} at {:?}", info.get_fullname(), addrs);
+                    peers.insert(info.get_fullname().to_string(), addrs);
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    peers.remove(&fullname);
+                    log::info!("lan peer {} gone", fullname);
+                }
+                _ => (),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// IPv6 link local addresses are unusable without the interface scope.
+#[cfg(feature = "with-mdns")]
+fn is_link_local(ip: &std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+        std::net::IpAddr::V4(_) => false,
+    }
+}
+
+#[cfg(not(feature = "with-mdns"))]
+pub fn start(_node_id: u128, _port: u16) -> Result<(), Error> {
+    Err(Error::Discovery(
+        "mdns support is not compiled in".to_string(),
+    ))
+}
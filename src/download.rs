@@ -1,56 +1,1569 @@
 #![allow(unused_imports)]
 
-use crate::codec::{Ask, AskReply};
-use crate::connection::{Connection, ConnectionRef};
-use crate::database::DatabaseManager;
-use crate::error::Error;
-use crate::filemap::FileMap;
+use crate::codec::{Ask, AskReply, Block, GetBlock, Hello, RelayConnect};
+use crate::command::{DownloadReport, FileReport, SourceReport};
+use crate::connection::{Connection, ConnectionRef, GetInlineFiles, VerifyPeer};
+use crate::database::{self, DatabaseManager};
+use crate::error::{Error, PeerFailure, ProtocolError};
+use crate::file_name::FileName;
+use crate::filemap::{
+    self, BlockProof, BundleFormat, FileMap, HashAlgorithm, MerkleProof, BLOCK_SIZE,
+};
 use actix::prelude::*;
-use futures::prelude::*;
-use std::net;
+use futures::{future, prelude::*};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::{fmt, io, net};
 
 use failure::_core::time::Duration;
-use tokio_tcp::{ConnectFuture, TcpStream};
+use futures::future::LocalBoxFuture;
+use futures::stream::FuturesUnordered;
+use tokio::net::TcpStream;
 
-pub fn connect(
+/// Number of blocks requested from a peer before the first one arrives.
+pub const MAX_BLOCKS_IN_FLIGHT: usize = 4;
+
+/// Time a connection attempt gets before the next address of the peer is tried as well.
+const CONNECTION_ATTEMPT_DELAY: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Time LAN peers get to provide a resource before the given peers are asked.
+const LAN_ASK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// How many times `find_peer` follows alternative peers suggested in ask replies.
+const MAX_HINT_HOPS: usize = 2;
+
+/// Alternative peers remembered per resource.
+const MAX_HINTS: usize = 8;
+
+const MAX_HINTED_RESOURCES: usize = 1024;
+
+/// Peers resources were downloaded from, suggested to peers asking for them.
+#[derive(Default)]
+struct PeerHints {
+    peers: HashMap<u128, Vec<net::SocketAddr>>,
+    order: VecDeque<u128>,
+}
+
+static PEER_HINTS: OnceLock<Mutex<PeerHints>> = OnceLock::new();
+
+fn peer_hints_store() -> &'static Mutex<PeerHints> {
+    PEER_HINTS.get_or_init(Default::default)
+}
+
+pub fn remember_peer(hash: u128, peer: net::SocketAddr) {
+    let mut hints = match peer_hints_store().lock() {
+        Ok(hints) => hints,
+        Err(_) => return,
+    };
+    if !hints.peers.contains_key(&hash) {
+        if hints.order.len() >= MAX_HINTED_RESOURCES {
+            if let Some(oldest) = hints.order.pop_front() {
+                hints.peers.remove(&oldest);
+            }
+        }
+        hints.order.push_back(hash);
+    }
+    let peers = hints.peers.entry(hash).or_default();
+    peers.retain(|p| *p != peer);
+    peers.insert(0, peer);
+    peers.truncate(MAX_HINTS);
+}
+
+pub fn peer_hints(hash: u128) -> Vec<net::SocketAddr> {
+    peer_hints_store()
+        .lock()
+        .ok()
+        .and_then(|hints| hints.peers.get(&hash).cloned())
+        .unwrap_or_default()
+}
+
+static ACTIVE_DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a download as active for as long as the guard is alive.
+pub struct DownloadGuard(());
+
+impl DownloadGuard {
+    pub fn new() -> Self {
+        ACTIVE_DOWNLOADS.fetch_add(1, Ordering::SeqCst);
+        DownloadGuard(())
+    }
+}
+
+impl Default for DownloadGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        ACTIVE_DOWNLOADS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn active_downloads() -> usize {
+    ACTIVE_DOWNLOADS.load(Ordering::SeqCst)
+}
+
+/// Temporary name of a file being downloaded into `path`.
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+    part_path.into()
+}
+
+/// Makes sure the directory `path` is downloaded into exists.
+///
+/// Without `create_dest` a missing directory is an error instead of being created. Paths
+/// under symbolic links in `dest` are refused, writing to them would follow the link.
+pub fn prepare_dest(dest: &Path, path: &Path, create_dest: bool) -> Result<(), Error> {
+    if crate::filemap::has_linked_parent(dest, path) {
+        return Err(Error::InvalidArgument(format!(
+            "{} is under a symbolic link of the destination directory",
+            path.display()
+        )));
+    }
+    match path.parent() {
+        Some(parent) if create_dest => std::fs::create_dir_all(parent)?,
+        Some(parent) if !parent.is_dir() => {
+            return Err(Error::InvalidArgument(format!(
+                "destination directory {} doesn't exist",
+                parent.display()
+            )))
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Whether downloads use resources and files this node shares already.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum LocalPolicy {
+    /// Copies a shared resource instead of dialing peers, and files shared in other
+    /// resources instead of fetching their blocks.
+    #[default]
+    First,
+    /// Copies a shared resource and fails without dialing when there is none, the
+    /// behaviour of downloads without peers.
+    Only,
+    /// Always fetches from peers.
+    Never,
+}
+
+impl std::str::FromStr for LocalPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "first" => Ok(LocalPolicy::First),
+            "only" => Ok(LocalPolicy::Only),
+            "never" => Ok(LocalPolicy::Never),
+            _ => Err(Error::InvalidArgument(format!(
+                "invalid local policy: {} (expected first, only or never)",
+                s
+            ))),
+        }
+    }
+}
+
+/// How downloaded blocks are checked against the file maps. Blocks copied from shares of
+/// this node are always checked.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum VerifyPolicy {
+    /// Every block is hashed when it arrives, a bad block fails its request.
+    #[default]
+    Full,
+    /// Files are hashed once complete, a bad block fails the download.
+    EndOfFile,
+    /// Nothing is hashed, for trusted peers.
+    Off,
+}
+
+impl std::str::FromStr for VerifyPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "full" => Ok(VerifyPolicy::Full),
+            "endOfFile" => Ok(VerifyPolicy::EndOfFile),
+            "off" => Ok(VerifyPolicy::Off),
+            _ => Err(Error::InvalidArgument(format!(
+                "invalid verify policy: {} (expected full, endOfFile or off)",
+                s
+            ))),
+        }
+    }
+}
+
+/// Source of blocks copied from files of this node in download reports.
+pub const LOCAL_SOURCE: &str = "local";
+
+/// Copies the blocks of a download a file shared by this node has to `out`, checking
+/// each one against `blocks`. Returns the blocks left to fetch, e.g. all of them when
+/// the shared file changed, and the sizes of the copied ones.
+pub fn copy_shared_blocks(
+    path: &Path,
+    shared: &FileMap,
+    blocks: Vec<u128>,
+    out: &BlockWriter,
+) -> (Vec<(usize, u128)>, Vec<usize>) {
+    let mut missing = Vec::new();
+    let mut copied_sizes = Vec::new();
+    for (block_no, block_hash) in blocks.into_iter().enumerate() {
+        let copied =
+            crate::connection::read_block(path, shared, block_no as u32).and_then(|bytes| {
+                if shared.hash_algorithm.hash_block(&bytes) != block_hash {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "block changed"));
+                }
+                out.write_block(block_no as u32, &bytes)?;
+                Ok(bytes.len())
+            });
+        match copied {
+            Ok(len) => copied_sizes.push(len),
+            Err(e) => {
+                log::debug!("block {} of {} not copied: {}", block_no, path.display(), e);
+                missing.push((block_no, block_hash));
+            }
+        }
+    }
+    (missing, copied_sizes)
+}
+
+/// How files of shares of this node are placed when downloading them, selected with
+/// `--local_copy`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum CopyStrategy {
+    /// A hard link to the shared file, so changing either changes both.
+    HardLink,
+    /// A copy-on-write clone, on file systems supporting it (e.g. Btrfs, XFS).
+    Reflink,
+    /// A copy of the content.
+    Copy,
+}
+
+impl fmt::Display for CopyStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CopyStrategy::HardLink => "hardlink",
+            CopyStrategy::Reflink => "reflink",
+            CopyStrategy::Copy => "copy",
+        })
+    }
+}
+
+impl std::str::FromStr for CopyStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "hardlink" => Ok(CopyStrategy::HardLink),
+            "reflink" => Ok(CopyStrategy::Reflink),
+            "copy" => Ok(CopyStrategy::Copy),
+            _ => Err(Error::InvalidArgument(format!(
+                "invalid copy strategy: {} (expected hardlink, reflink or copy)",
+                s
+            ))),
+        }
+    }
+}
+
+/// Places the plain content of the shared file `src` at `dest`, replacing it, with
+/// `preferred` or the next strategy working: hard links and reflinks need both files on
+/// one file system and `src` not encrypted at rest. Returns the strategy used.
+pub fn copy_shared_file(
+    src: &Path,
+    dest: &Path,
+    preferred: CopyStrategy,
+) -> io::Result<CopyStrategy> {
+    if same_file(src, dest) {
+        // Downloaded to where it is shared from.
+        return Ok(CopyStrategy::HardLink);
+    }
+    // Writing through an existing hard link would change the share.
+    match std::fs::remove_file(dest) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    if crate::encryption::StoredFile::open(src)?.is_encrypted() {
+        crate::encryption::copy_plain(src, dest)?;
+        return Ok(CopyStrategy::Copy);
+    }
+    if preferred == CopyStrategy::HardLink {
+        match std::fs::hard_link(src, dest) {
+            Ok(()) => return Ok(CopyStrategy::HardLink),
+            Err(e) => log::debug!("hard link to {} failed: {}", src.display(), e),
+        }
+    }
+    if preferred != CopyStrategy::Copy {
+        match reflink(src, dest) {
+            Ok(()) => return Ok(CopyStrategy::Reflink),
+            Err(e) => log::debug!("reflink of {} failed: {}", src.display(), e),
+        }
+    }
+    // Uses copy_file_range where available.
+    std::fs::copy(src, dest)?;
+    Ok(CopyStrategy::Copy)
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let src = File::open(src)?;
+    let dest_file = OpenOptions::new().write(true).create_new(true).open(dest)?;
+    if unsafe { libc::ioctl(dest_file.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    drop(dest_file);
+    let _ = std::fs::remove_file(dest);
+    Err(e)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &Path, _dest: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are not supported on this system",
+    ))
+}
+
+/// Hashes `content` in blocks as `file_map` was hashed. Returns the number of the first
+/// block not matching, past the last block for content longer than the file.
+pub fn find_bad_block(mut content: impl io::Read, file_map: &FileMap) -> io::Result<Option<usize>> {
+    let mut buf = Vec::with_capacity(BLOCK_SIZE);
+    for (block_no, &block_hash) in file_map.blocks.iter().enumerate() {
+        buf.clear();
+        io::Read::read_to_end(
+            &mut io::Read::take(&mut content, BLOCK_SIZE as u64),
+            &mut buf,
+        )?;
+        if file_map.hash_algorithm.hash_block(&buf) != block_hash {
+            return Ok(Some(block_no));
+        }
+    }
+    match io::Read::read(&mut content, &mut [0u8; 1])? {
+        0 => Ok(None),
+        _ => Ok(Some(file_map.blocks.len())),
+    }
+}
+
+/// Proofs of the file maps of a resource hashed as a Merkle tree, checking each map and
+/// block taken from peers against the resource hash on its own.
+pub struct BundleProofs {
+    hash: u128,
+    hash_algorithm: HashAlgorithm,
+    files: Vec<MerkleProof>,
+}
+
+impl BundleProofs {
+    /// Proofs of `maps` in the order the peer numbers them, `None` for resources hashed
+    /// in older formats.
+    pub fn new(hash: u128, maps: &[FileMap]) -> Option<Self> {
+        let hash_algorithm = maps.first()?.hash_algorithm;
+        if BundleFormat::of(hash, hash_algorithm, maps) != Some(BundleFormat::Merkle) {
+            return None;
+        }
+        // The tree is over the maps ordered by name.
+        let mut order: Vec<usize> = (0..maps.len()).collect();
+        order.sort_by(|&a, &b| maps[a].file_name.cmp(&maps[b].file_name));
+        let leaves: Vec<u128> = order
+            .iter()
+            .map(|&file_nr| filemap::file_leaf(hash_algorithm, &maps[file_nr]))
+            .collect();
+        let mut proofs: Vec<_> = MerkleProof::all(hash_algorithm, &leaves)
+            .into_iter()
+            .zip(order)
+            .collect();
+        proofs.sort_by_key(|&(_, file_nr)| file_nr);
+        Some(BundleProofs {
+            hash,
+            hash_algorithm,
+            files: proofs.into_iter().map(|(proof, _)| proof).collect(),
+        })
+    }
+
+    /// Proofs of the blocks of file `file_nr`, failing unless `map` is that file of the
+    /// resource.
+    pub fn file(&self, file_nr: usize, map: &FileMap) -> Result<FileProofs, Error> {
+        let file = match self.files.get(file_nr) {
+            Some(proof) if filemap::verify_file(self.hash, self.hash_algorithm, map, proof) => {
+                proof.clone()
+            }
+            _ => return Err(Error::BundleMismatch(self.hash)),
+        };
+        let leaves: Vec<u128> = map
+            .blocks
+            .iter()
+            .map(|&block| filemap::block_leaf(self.hash_algorithm, block))
+            .collect();
+        Ok(FileProofs {
+            hash: self.hash,
+            hash_algorithm: self.hash_algorithm,
+            map: FileMap {
+                blocks: Vec::new(),
+                ..map.clone()
+            },
+            file,
+            blocks: MerkleProof::all(self.hash_algorithm, &leaves),
+        })
+    }
+}
+
+/// Proofs of the blocks of one file of a resource, see `BundleProofs::file`.
+pub struct FileProofs {
+    hash: u128,
+    hash_algorithm: HashAlgorithm,
+    map: FileMap,
+    file: MerkleProof,
+    blocks: Vec<MerkleProof>,
+}
+
+impl FileProofs {
+    /// Whether `block_hash` is block `block_nr` of the file in the resource.
+    pub fn verify(&self, block_nr: usize, block_hash: u128) -> bool {
+        self.blocks.get(block_nr).is_some_and(|block| {
+            let proof = BlockProof {
+                block: block.clone(),
+                file: self.file.clone(),
+            };
+            filemap::verify_block(
+                self.hash,
+                self.hash_algorithm,
+                &self.map,
+                block_hash,
+                &proof,
+            )
+        })
+    }
+}
+
+/// Creates an empty directory of a resource at `path` in `dest`.
+pub fn place_dir(dest: &Path, path: &Path, create_dest: bool) -> Result<(), Error> {
+    prepare_dest(dest, path, create_dest)?;
+    match std::fs::create_dir(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Creates the links of a resource in `dest`, given by their paths, names and targets.
+/// They are placed once the files are, so that no file is written through them.
+///
+/// Links leading out of the download directory, also through other links, are refused
+/// and the links placed removed.
+pub fn place_links(
+    dest: &Path,
+    links: &[(PathBuf, FileName, String)],
+    create_dest: bool,
+) -> Result<(), Error> {
+    let outside = |file_name: &FileName| {
+        Error::InvalidArgument(format!(
+            "link {} leads outside the destination directory",
+            file_name
+        ))
+    };
+    let mut placed = Vec::with_capacity(links.len());
+    let result = links.iter().try_for_each(|(path, file_name, target)| {
+        if !crate::filemap::link_stays_within(file_name, target) {
+            return Err(outside(file_name));
+        }
+        prepare_dest(dest, path, create_dest)?;
+        crate::filemap::create_link(path, target)?;
+        placed.push(path);
+        Ok(())
+    });
+    let result = result.and_then(|()| {
+        for (path, file_name, _) in links {
+            if !crate::filemap::link_resolves_within(dest, path)? {
+                return Err(outside(file_name));
+            }
+        }
+        Ok(())
+    });
+    if result.is_err() {
+        for path in placed {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    result
+}
+
+/// Output file written block by block at the block's offset.
+///
+/// The file is preallocated to its final size, so blocks may arrive in any order.
+#[derive(Clone)]
+pub struct BlockWriter {
+    file: Arc<File>,
+    file_size: u64,
+}
+
+impl BlockWriter {
+    pub fn create(path: &Path, file_size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.set_len(file_size)?;
+        Ok(BlockWriter {
+            file: Arc::new(file),
+            file_size,
+        })
+    }
+
+    pub fn write_block(&self, block_no: u32, bytes: &[u8]) -> io::Result<()> {
+        let offset = block_no as u64 * BLOCK_SIZE as u64;
+        if offset + bytes.len() as u64 > self.file_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "block exceeds file size",
+            ));
+        }
+        write_all_at(&self.file, bytes, offset)
+    }
+
+    /// Writes consecutive blocks starting at `first_block` with one call.
+    pub fn write_blocks(&self, first_block: u32, blocks: &[bytes::Bytes]) -> io::Result<()> {
+        let (last, full) = match blocks.split_last() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        if full.is_empty() || full.iter().any(|bytes| bytes.len() != BLOCK_SIZE) {
+            for (block_no, bytes) in (first_block..).zip(blocks) {
+                self.write_block(block_no, bytes)?;
+            }
+            return Ok(());
+        }
+        let mut run = Vec::with_capacity(full.len() * BLOCK_SIZE + last.len());
+        for bytes in blocks {
+            run.extend_from_slice(bytes);
+        }
+        let offset = first_block as u64 * BLOCK_SIZE as u64;
+        if offset + run.len() as u64 > self.file_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "block exceeds file size",
+            ));
+        }
+        write_all_at(&self.file, &run, offset)
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Writes of a `BlockWriter` done on the blocking thread pool, so slow disks don't stall
+/// other futures. Blocks are kept until `flush_at` bytes are buffered, then consecutive
+/// ones are written with one call. Up to `MAX_QUEUED_WRITES` writes run at once; further
+/// blocks wait for one of them, so the network is read ahead of the disk by a bounded
+/// amount.
+pub struct WriteQueue {
+    writer: BlockWriter,
+    pending: FuturesUnordered<LocalBoxFuture<'static, Result<(), Error>>>,
+    buffered: BTreeMap<u32, bytes::Bytes>,
+    buffered_bytes: usize,
+    flush_at: usize,
+}
+
+/// Writes of a file running at once, see `WriteQueue`.
+const MAX_QUEUED_WRITES: usize = 4;
+
+/// Bytes of a file buffered before writing them, unless the download sets `write_buffer`.
+pub const DEFAULT_WRITE_BUFFER: usize = 4 * BLOCK_SIZE;
+
+/// When downloaded files are synced to disk.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum FsyncPolicy {
+    /// Each file once its blocks are written, before it is moved in place.
+    #[default]
+    File,
+    /// All files once the whole resource is downloaded.
+    Transfer,
+    /// Never, left to the system.
+    Off,
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "file" => Ok(FsyncPolicy::File),
+            "transfer" => Ok(FsyncPolicy::Transfer),
+            "off" => Ok(FsyncPolicy::Off),
+            _ => Err(Error::InvalidArgument(format!(
+                "invalid fsync policy: {} (expected file, transfer or off)",
+                s
+            ))),
+        }
+    }
+}
+
+impl WriteQueue {
+    /// Buffers up to `flush_at` bytes, 0 writes every block when it is queued.
+    pub fn new(writer: BlockWriter, flush_at: usize) -> Self {
+        WriteQueue {
+            writer,
+            pending: FuturesUnordered::new(),
+            buffered: BTreeMap::new(),
+            buffered_bytes: 0,
+            flush_at,
+        }
+    }
+
+    /// Queues a block, returning the error of an earlier write if one failed.
+    pub async fn write(&mut self, block_no: u32, bytes: bytes::Bytes) -> Result<(), Error> {
+        self.buffered_bytes += bytes.len();
+        self.buffered.insert(block_no, bytes);
+        if self.buffered_bytes >= self.flush_at {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the queued writes and, with `sync`, syncs the file.
+    pub async fn finish(mut self, sync: bool) -> Result<(), Error> {
+        self.flush().await?;
+        while !self.pending.is_empty() {
+            self.next_done().await?;
+        }
+        if !sync {
+            return Ok(());
+        }
+        let writer = self.writer;
+        Ok(actix_web::web::block(move || writer.sync()).await??)
+    }
+
+    /// Starts writing the buffered blocks, a write for each run of consecutive ones.
+    async fn flush(&mut self) -> Result<(), Error> {
+        let mut runs: Vec<(u32, Vec<bytes::Bytes>)> = Vec::new();
+        for (block_no, bytes) in std::mem::take(&mut self.buffered) {
+            match runs.last_mut() {
+                Some((first, run)) if *first + run.len() as u32 == block_no => run.push(bytes),
+                _ => runs.push((block_no, vec![bytes])),
+            }
+        }
+        self.buffered_bytes = 0;
+        for (first, run) in runs {
+            while self.pending.len() >= MAX_QUEUED_WRITES {
+                self.next_done().await?;
+            }
+            let writer = self.writer.clone();
+            self.pending.push(
+                actix_web::web::block(move || writer.write_blocks(first, &run))
+                    .map(|r| Ok(r??))
+                    .boxed_local(),
+            );
+        }
+        Ok(())
+    }
+
+    async fn next_done(&mut self) -> Result<(), Error> {
+        self.pending.next().await.unwrap_or(Ok(()))
+    }
+}
+
+/// Syncs files downloaded with `FsyncPolicy::Transfer`.
+pub async fn sync_files(paths: Vec<PathBuf>) -> Result<(), Error> {
+    Ok(actix_web::web::block(move || {
+        for path in paths {
+            // Windows flushes only files opened for writing.
+            OpenOptions::new().write(true).open(path)?.sync_all()?;
+        }
+        Ok::<_, io::Error>(())
+    })
+    .await??)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Peer reachable directly or through a relay it is registered at.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Peer {
+    Direct(net::SocketAddr),
+    /// Direct peer that has to present the node id in `hello`.
+    Identified {
+        addr: net::SocketAddr,
+        node_id: u128,
+    },
+    Relayed {
+        relay: net::SocketAddr,
+        node_id: u128,
+    },
+}
+
+impl Peer {
+    /// Address the connection to the peer is made to.
+    pub fn connect_addr(&self) -> net::SocketAddr {
+        match self {
+            Peer::Direct(addr) | Peer::Identified { addr, .. } => *addr,
+            Peer::Relayed { relay, .. } => *relay,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Direct(addr) => write!(f, "{}", addr),
+            Peer::Identified { addr, node_id } => write!(f, "{}#{:032x}", addr, node_id),
+            Peer::Relayed { relay, node_id } => write!(f, "{:032x}@{}", node_id, relay),
+        }
+    }
+}
+
+/// All addresses of `host`, resolved on the blocking thread pool unless it is an ip.
+pub async fn resolve(host: String, port: u16) -> Result<Vec<net::SocketAddr>, Error> {
+    if let Ok(ip) = host.parse() {
+        return Ok(vec![net::SocketAddr::new(ip, port)]);
+    }
+    actix_web::web::block(move || {
+        use std::net::ToSocketAddrs;
+
+        let addrs: Vec<_> = (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|e| Error::Resolve(host.clone(), e))?
+            .collect();
+        if addrs.is_empty() {
+            let e = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+            return Err(Error::Resolve(host, e));
+        }
+        Ok(addrs)
+    })
+    .await?
+}
+
+/// Orders addresses of a peer so that address families alternate, starting with the
+/// family of the first (preferred) address.
+pub fn interleave_families(peers: Vec<Peer>) -> Vec<Peer> {
+    let first_v6 = match peers.first() {
+        Some(peer) => peer.connect_addr().is_ipv6(),
+        None => return peers,
+    };
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = peers
+        .into_iter()
+        .partition(|peer| peer.connect_addr().is_ipv6() == first_v6);
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connects to the first of `addrs` accepting a connection.
+///
+/// Attempts start `CONNECTION_ATTEMPT_DELAY` apart, or as soon as the previous one
+/// fails; the remaining ones are dropped once a connection is made.
+async fn race_connect(
+    addrs: Vec<net::SocketAddr>,
+) -> Result<(TcpStream, net::SocketAddr), Vec<(net::SocketAddr, Error)>> {
+    let attempt = |addr: net::SocketAddr| async move { (addr, TcpStream::connect(addr).await) };
+    let mut pending: VecDeque<_> = addrs.into();
+    let mut attempts = FuturesUnordered::new();
+    let mut errors = Vec::new();
+    loop {
+        if attempts.is_empty() {
+            match pending.pop_front() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => return Err(errors),
+            }
+        }
+        let next = if pending.is_empty() {
+            attempts.next().await
+        } else {
+            let delay = Box::pin(tokio::time::sleep(CONNECTION_ATTEMPT_DELAY));
+            match future::select(attempts.next(), delay).await {
+                future::Either::Left((next, _)) => next,
+                future::Either::Right(_) => None,
+            }
+        };
+        match next {
+            Some((addr, Ok(stream))) => return Ok((stream, addr)),
+            Some((addr, Err(e))) => errors.push((addr, e.into())),
+            None => (),
+        }
+        if let Some(addr) = pending.pop_front() {
+            attempts.push(attempt(addr));
+        }
+    }
+}
+
+/// Connects to one of the addresses of a peer, in the given order.
+pub async fn connect(
     db: Addr<DatabaseManager>,
-    addr: net::SocketAddr,
+    addrs: Vec<net::SocketAddr>,
     reporter: crate::user_report::UserReportHandle,
-) -> impl Future<Item = ConnectionRef, Error = Error> {
-    TcpStream::connect(&addr).from_err().and_then(move |c| {
-        reporter.add_note(|| format!("connected to {}", addr));
-        Connection::new_managed(db, c, addr, &reporter)
-    })
+) -> Result<(ConnectionRef, net::SocketAddr), Vec<(net::SocketAddr, Error)>> {
+    let (c, addr) = race_connect(addrs).await?;
+    reporter.add_note(|| format!("connected to {}", addr));
+    let connection = Connection::new_managed(db, c, addr, &reporter)
+        .await
+        .map_err(|e| vec![(addr, e)])?;
+    Ok((connection, addr))
 }
 
+/// Connection to a peer shared by the downloads of a batch, or `None` before the first
+/// one connects.
+type SharedSlot = Rc<tokio::sync::Mutex<Option<(ConnectionRef, Peer)>>>;
+
+/// Connections shared by downloads, by the addresses of the peer.
+#[derive(Default)]
+struct SharedConnections(RefCell<HashMap<Vec<Peer>, SharedSlot>>);
+
+tokio::task_local! {
+    static SHARED_CONNECTIONS: SharedConnections;
+}
+
+/// Runs `f` with connections to peers shared by all downloads in it, closed once it ends.
+pub async fn with_shared_connections<F: Future>(f: F) -> F::Output {
+    SHARED_CONNECTIONS
+        .scope(SharedConnections::default(), f)
+        .await
+}
+
+/// Connects to the peer at one of its addresses, opening a relay session first for
+/// relayed peers. Node ids of direct peers are verified, see `VerifyPeer`.
+///
+/// Within `with_shared_connections` a connection to the same peer is reused if it is
+/// still open, and downloads connecting at the same time wait for one connection.
+pub async fn connect_peer(
+    db: Addr<DatabaseManager>,
+    peers: Vec<Peer>,
+    repin: bool,
+    reporter: crate::user_report::UserReportHandle,
+) -> Result<(ConnectionRef, Peer), Vec<(Peer, Error)>> {
+    let slot = SHARED_CONNECTIONS.try_with(|shared| {
+        shared
+            .0
+            .borrow_mut()
+            .entry(peers.clone())
+            .or_default()
+            .clone()
+    });
+    let slot = match slot {
+        Ok(slot) => slot,
+        Err(_) => return connect_new_peer(db, peers, repin, reporter).await,
+    };
+    let mut slot = slot.lock().await;
+    if let Some((connection, peer)) = slot.as_ref().filter(|(c, _)| c.connected()) {
+        reporter.add_note(|| format!("reusing connection to {}", peer));
+        return Ok((connection.clone(), *peer));
+    }
+    let connected = connect_new_peer(db, peers, repin, reporter).await?;
+    *slot = Some(connected.clone());
+    Ok(connected)
+}
+
+async fn connect_new_peer(
+    db: Addr<DatabaseManager>,
+    peers: Vec<Peer>,
+    repin: bool,
+    reporter: crate::user_report::UserReportHandle,
+) -> Result<(ConnectionRef, Peer), Vec<(Peer, Error)>> {
+    let addrs = peers.iter().map(Peer::connect_addr).collect();
+    let peer_of = |addr: net::SocketAddr| {
+        peers
+            .iter()
+            .cloned()
+            .find(|peer| peer.connect_addr() == addr)
+            .unwrap_or(Peer::Direct(addr))
+    };
+    let (connection, addr) = match connect(db.clone(), addrs, reporter).await {
+        Ok(connected) => connected,
+        Err(errors) => {
+            return Err(errors
+                .into_iter()
+                .map(|(addr, e)| (peer_of(addr), e))
+                .collect())
+        }
+    };
+    let peer = peer_of(addr);
+    let result = async {
+        match peer {
+            Peer::Direct(_) | Peer::Identified { .. } => {
+                connection
+                    .send(VerifyPeer {
+                        expected: match peer {
+                            Peer::Identified { node_id, .. } => Some(node_id),
+                            _ => None,
+                        },
+                        repin,
+                    })
+                    .await?
+            }
+            Peer::Relayed { node_id, .. } => {
+                connection.send(RelayConnect { node_id }).await??;
+                let id = crate::database::id(&db).await?;
+                // The relay forwards it, so the peer learns who it talks to.
+                connection.send(Hello::new(id)).await?
+            }
+        }
+    };
+    match result.await {
+        Ok(()) => Ok((connection, peer)),
+        Err(e) => Err(vec![(peer, e)]),
+    }
+}
+
+/// How peers asked for a resource are checked.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AskOptions {
+    /// Access token of the resource, see `AskToken`
+    pub token: Option<u128>,
+    /// Node the file maps have to be signed by
+    pub signer: Option<u128>,
+    /// Accepts and pins new node ids of peers with pinned ones
+    pub repin: bool,
+    /// Accepts file maps not hashing to the resource hash, see `VerifyPolicy::Off`
+    pub unverified: bool,
+}
+
+/// Asks `peers`, given as the addresses of each peer, for the resource.
 pub fn find_peer(
     hash: u128,
+    options: AskOptions,
     db: Addr<DatabaseManager>,
-    addr: Vec<net::SocketAddr>,
+    addr: Vec<Vec<Peer>>,
     reporter: crate::user_report::UserReportHandle,
-) -> impl Future<Item = (ConnectionRef, Vec<FileMap>, net::SocketAddr), Error = Error> {
-    let connections = addr.into_iter().map(move |addr| {
-        let hash = hash;
-        let reporter = reporter.clone();
+) -> FindPeerFuture {
+    let tried = addr.iter().flatten().cloned().collect();
+    find_peer_hops(
+        hash,
+        options,
+        db,
+        addr,
+        reporter,
+        tried,
+        Vec::new(),
+        MAX_HINT_HOPS,
+    )
+}
 
-        reporter.add_note(|| format!("connecting to {}", addr));
+/// Looks for the resource in the local network first, then asks `peers`.
+pub fn find_peer_prefer_lan(
+    hash: u128,
+    options: AskOptions,
+    db: Addr<DatabaseManager>,
+    lan_peers: Vec<net::SocketAddr>,
+    peers: Vec<Vec<Peer>>,
+    reporter: crate::user_report::UserReportHandle,
+) -> FindPeerFuture {
+    if lan_peers.is_empty() {
+        return find_peer(hash, options, db, peers, reporter);
+    }
+    reporter.add_note(|| format!("asking lan peers {:?}", lan_peers));
 
-        connect(db.clone(), addr, reporter.clone())
-            .and_then(move |connection| {
-                connection
-                    .send(Ask::new(hash))
-                    .flatten()
-                    .and_then(move |reply: AskReply| match reply.files {
-                        Some(files) => Ok((connection, files, addr)),
-                        None => Err(Error::ResourceNotFound(reply.hash)),
-                    })
-            })
-            .map_err(move |e| {
-                reporter.add_err(|| format!("failed to connect to {}: {}", addr, e));
+    let lan_peers = lan_peers
+        .into_iter()
+        .map(|peer| vec![Peer::Direct(peer)])
+        .collect();
+    let lan = tokio::time::timeout(
+        LAN_ASK_TIMEOUT,
+        find_peer(hash, options, db.clone(), lan_peers, reporter.clone()),
+    );
+    Box::pin(async move {
+        match lan.await {
+            Ok(Ok(found)) => return Ok(found),
+            Ok(Err(e)) => {
+                reporter.add_note(|| format!("no lan peer provided {:032x}: {}", hash, e))
+            }
+            Err(_) => reporter.add_note(|| format!("no lan peer provided {:032x} in time", hash)),
+        }
+        find_peer(hash, options, db, peers, reporter).await
+    })
+}
 
-                e
+pub type FindPeerFuture =
+    LocalBoxFuture<'static, Result<(ConnectionRef, Vec<FileMap>, Peer), Error>>;
+
+/// Connects to one of the addresses of a peer and asks it for the resource.
+///
+/// Fails with the failures of the addresses and the peers the peer hinted at, and for
+/// file maps not hashing to the resource hash unless `options` accept them.
+async fn ask_peer(
+    hash: u128,
+    options: AskOptions,
+    db: Addr<DatabaseManager>,
+    peers: Vec<Peer>,
+    reporter: crate::user_report::UserReportHandle,
+) -> Result<(ConnectionRef, Vec<FileMap>, Peer), (Vec<PeerFailure>, Vec<net::SocketAddr>)> {
+    reporter.add_note(|| format!("connecting to {:?}", peers));
+
+    let cached = database::request(&db, database::GetCachedFileMaps(hash))
+        .await
+        .ok()
+        .flatten();
+    let (connection, peer) = connect_peer(
+        db.clone(),
+        interleave_families(peers),
+        options.repin,
+        reporter.clone(),
+    )
+    .await
+    .map_err(|errors| {
+        let failures = errors
+            .into_iter()
+            .map(|(peer, e)| {
+                reporter.add_err(|| format!("failed to connect to {}: {}", peer, e));
+                PeerFailure::new(peer, &e)
             })
-    });
+            .collect();
+        (failures, Vec::new())
+    })?;
+
+    let ask = Ask::new(hash, options.token).with_known_files(cached);
+    let reply = async { connection.send(ask).await? }.await;
+    let reply: AskReply = match reply {
+        Ok(reply) => reply,
+        Err(e) => {
+            reporter.add_err(|| format!("failed to connect to {}: {}", peer, e));
+            return Err((vec![PeerFailure::new(peer, &e)], Vec::new()));
+        }
+    };
+    match reply.files {
+        // Maps from other nodes could point to anything.
+        Some(_) if options.signer.is_some() && reply.signed_by != options.signer => Err((
+            vec![PeerFailure::new(
+                peer,
+                &ProtocolError::UnexpectedSigner(reply.hash).into_err(),
+            )],
+            Vec::new(),
+        )),
+        Some(files) => {
+            let verified = files.first().is_none_or(|first| {
+                crate::filemap::bundle_matches(hash, first.hash_algorithm, &files)
+            });
+            if verified {
+                database::notify(
+                    &db,
+                    database::CacheFileMaps {
+                        hash,
+                        files: Arc::new(files.clone()),
+                    },
+                );
+            } else if !options.unverified {
+                let e = Error::BundleMismatch(hash);
+                reporter.add_err(|| format!("{}: {}", peer, e));
+                return Err((vec![PeerFailure::new(peer, &e)], Vec::new()));
+            }
+            Ok((connection, files, peer))
+        }
+        None => Err((
+            vec![PeerFailure::new(peer, &Error::ResourceNotFound(reply.hash))],
+            reply.peers,
+        )),
+    }
+}
+
+/// Asks each of `peers` for the resource without fetching blocks, returning the peers
+/// having it with their file maps and the failures of the others.
+///
+/// Peers not answering within `timeout` fail with `timeout`.
+pub async fn check_availability(
+    hash: u128,
+    options: AskOptions,
+    db: Addr<DatabaseManager>,
+    addr: Vec<Vec<Peer>>,
+    timeout: Option<Duration>,
+    reporter: crate::user_report::UserReportHandle,
+) -> Result<(Vec<(Peer, Vec<FileMap>)>, Vec<PeerFailure>), Error> {
+    let results = future::join_all(addr.into_iter().map(|peers| {
+        let name = peers[0];
+        let ask = ask_peer(hash, options, db.clone(), peers, reporter.clone());
+        async move {
+            let result = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, ask)
+                    .await
+                    .unwrap_or_else(|_| {
+                        let e = io::Error::new(io::ErrorKind::TimedOut, "no answer in time");
+                        Err((vec![PeerFailure::new(name, &e.into())], Vec::new()))
+                    }),
+                None => ask.await,
+            };
+            result.map(|(_connection, files, peer)| (peer, files))
+        }
+    }))
+    .await;
+
+    let mut found = Vec::new();
+    let mut failures = Vec::new();
+    for result in results {
+        match result {
+            Ok(peer) => found.push(peer),
+            Err((peer_failures, _hints)) => failures.extend(peer_failures),
+        }
+    }
+    Ok((found, failures))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn find_peer_hops(
+    hash: u128,
+    options: AskOptions,
+    db: Addr<DatabaseManager>,
+    addr: Vec<Vec<Peer>>,
+    reporter: crate::user_report::UserReportHandle,
+    mut tried: HashSet<Peer>,
+    mut failures: Vec<PeerFailure>,
+    hops: usize,
+) -> FindPeerFuture {
+    Box::pin(async move {
+        let mut asks: FuturesUnordered<_> = addr
+            .into_iter()
+            .map(|peers| ask_peer(hash, options, db.clone(), peers, reporter.clone()))
+            .collect();
+
+        let mut hints = Vec::new();
+        // Stops at the first peer having the resource.
+        while let Some(result) = asks.next().await {
+            match result {
+                Ok(found) => return Ok(found),
+                Err((peer_failures, peers)) => {
+                    failures.extend(peer_failures);
+                    hints.extend(
+                        peers
+                            .into_iter()
+                            .map(Peer::Direct)
+                            .filter(|peer| tried.insert(*peer)),
+                    );
+                }
+            }
+        }
+        if hops == 0 || hints.is_empty() {
+            return Err(Error::NoPeers(hash, failures));
+        }
+        reporter.add_note(|| format!("following peer hints {:?}", hints));
+        find_peer_hops(
+            hash,
+            options,
+            db,
+            hints.into_iter().map(|peer| vec![peer]).collect(),
+            reporter,
+            tried,
+            failures,
+            hops - 1,
+        )
+        .await
+    })
+}
+
+/// Blocks received from each source of a download, for its report.
+#[derive(Clone, Default)]
+pub struct SourceStats(Rc<RefCell<Vec<SourceReport>>>);
+
+impl SourceStats {
+    pub fn add(&self, source: String, bytes: usize) {
+        let mut sources = self.0.borrow_mut();
+        let report = match sources.iter().position(|report| report.source == source) {
+            Some(pos) => &mut sources[pos],
+            None => {
+                sources.push(SourceReport {
+                    source,
+                    blocks: 0,
+                    bytes: 0,
+                });
+                sources.last_mut().unwrap()
+            }
+        };
+        report.blocks += 1;
+        report.bytes += bytes as u64;
+    }
+
+    pub fn take(&self) -> Vec<SourceReport> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+}
+
+/// Report of a download started at `started` that placed `files`.
+pub fn report(
+    started: std::time::Instant,
+    files: Vec<FileReport>,
+    sources: Vec<SourceReport>,
+    signer: Option<u128>,
+) -> DownloadReport {
+    let duration = started.elapsed().as_secs_f64();
+    let bytes = files.iter().map(|file| file.size).sum();
+    DownloadReport {
+        files,
+        sources,
+        duration,
+        bytes,
+        throughput: if duration > 0.0 {
+            bytes as f64 / duration
+        } else {
+            0.0
+        },
+        signer: signer.map(crate::hash_encoding::encode),
+    }
+}
+
+/// Time a block request gets before the download fails.
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(300);
+
+type SwitchFuture = future::Shared<LocalBoxFuture<'static, bool>>;
+
+/// Peer blocks of a download are requested from, replaced by one of the other given
+/// peers when a block takes longer than `slow_block` to arrive.
+#[derive(Clone)]
+pub struct BlockSource(Rc<RefCell<BlockSourceState>>);
+
+struct BlockSourceState {
+    connection: ConnectionRef,
+    peer: Peer,
+    /// Bumped on every switch, so blocks late at the same time switch once.
+    generation: usize,
+    switching: Option<SwitchFuture>,
+    alternatives: Vec<Vec<Peer>>,
+    find: Rc<dyn Fn(Vec<Vec<Peer>>) -> FindPeerFuture>,
+    slow_block: Option<Duration>,
+    reporter: crate::user_report::UserReportHandle,
+}
+
+impl BlockSource {
+    /// `find` looks for the resource among `alternatives` when `peer` is too slow,
+    /// without `slow_block` the peer is never replaced.
+    pub fn new(
+        connection: ConnectionRef,
+        peer: Peer,
+        alternatives: Vec<Vec<Peer>>,
+        slow_block: Option<Duration>,
+        find: impl Fn(Vec<Vec<Peer>>) -> FindPeerFuture + 'static,
+        reporter: crate::user_report::UserReportHandle,
+    ) -> Self {
+        let alternatives = alternatives
+            .into_iter()
+            .filter(|peers| !peers.contains(&peer))
+            .collect();
+        BlockSource(Rc::new(RefCell::new(BlockSourceState {
+            connection,
+            peer,
+            generation: 0,
+            switching: None,
+            alternatives,
+            find: Rc::new(find),
+            slow_block,
+            reporter,
+        })))
+    }
+
+    /// Peer blocks are currently requested from.
+    pub fn peer(&self) -> Peer {
+        self.0.borrow().peer
+    }
+
+    /// Requests the inline files of the resource from the current peer, see `GetInlineFiles`.
+    pub fn get_inline_files(
+        &self,
+        hash: u128,
+    ) -> LocalBoxFuture<'static, Result<Vec<(u32, bytes::Bytes)>, Error>> {
+        let request = self
+            .0
+            .borrow()
+            .connection
+            .send(GetInlineFiles(hash))
+            .timeout(BLOCK_TIMEOUT);
+        async move { request.await? }.boxed_local()
+    }
+
+    /// Requests a block, returning it with the peer that sent it.
+    pub fn get_block(
+        &self,
+        msg: GetBlock,
+    ) -> LocalBoxFuture<'static, Result<(Block, Peer), Error>> {
+        let state = self.0.borrow();
+        let generation = state.generation;
+        let peer = state.peer;
+        let this = self.clone();
+        let retry_msg = msg.clone();
+        // Requests still waiting for a replaced peer fail once it disconnects.
+        let request = state.connection.send(msg.clone()).timeout(BLOCK_TIMEOUT);
+        let request = async move {
+            match async { request.await? }.await {
+                Ok(block) => Ok((block, peer)),
+                Err(_) if this.0.borrow().generation != generation => {
+                    this.get_block(retry_msg).await
+                }
+                Err(e) => Err(e),
+            }
+        }
+        .boxed_local();
+        let slow_block = match state.slow_block {
+            Some(slow_block) if !state.alternatives.is_empty() => slow_block,
+            _ => return request,
+        };
+        let this = self.clone();
+        let late = Box::pin(tokio::time::sleep(slow_block));
+        async move {
+            let request = match future::select(request, late).await {
+                future::Either::Left((result, _)) => return result,
+                future::Either::Right(((), request)) => request,
+            };
+            if this.switch(generation).await {
+                this.get_block(msg).await
+            } else {
+                request.await
+            }
+        }
+        .boxed_local()
+    }
+
+    /// Replaces the peer unless it changed since `generation`, true once another one is used.
+    fn switch(&self, generation: usize) -> LocalBoxFuture<'static, bool> {
+        let mut state = self.0.borrow_mut();
+        if state.generation != generation {
+            return Box::pin(future::ready(true));
+        }
+        if let Some(switching) = &state.switching {
+            return switching.clone().boxed_local();
+        }
+        if state.alternatives.is_empty() {
+            return Box::pin(future::ready(false));
+        }
+        log::warn!("peer {} too slow, looking for another one", state.peer);
+        state
+            .reporter
+            .add_err(|| format!("peer {} too slow", state.peer));
+
+        let alternatives = std::mem::take(&mut state.alternatives);
+        let find = (state.find)(alternatives.clone());
+        let this = self.clone();
+        let switching = async move {
+            let r = find.await;
+            let mut state = this.0.borrow_mut();
+            state.switching = None;
+            match r {
+                Ok((connection, _, peer)) => {
+                    log::info!("switched from peer {} to {}", state.peer, peer);
+                    state.reporter.annotate("peer", &peer.to_string());
+                    state.alternatives = alternatives
+                        .into_iter()
+                        .filter(|peers| !peers.contains(&peer))
+                        .collect();
+                    state.connection = connection;
+                    state.peer = peer;
+                    state.generation += 1;
+                    true
+                }
+                Err(e) => {
+                    log::warn!("no other peer, staying with {}: {}", state.peer, e);
+                    false
+                }
+            }
+        }
+        .boxed_local()
+        .shared();
+        state.switching = Some(switching.clone());
+        switching.boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bundle_proofs() {
+        let algo = HashAlgorithm::Blake3;
+        let map = |name: &str, blocks: Vec<u128>| FileMap {
+            file_name: name.into(),
+            file_size: blocks.len() as u64 * BLOCK_SIZE as u64,
+            blocks,
+            hash_algorithm: algo,
+            mode: None,
+            mtime: None,
+            link: None,
+        };
+        // Numbered as the peer sent them, not in the order of the tree.
+        let maps = vec![
+            map("c", vec![5, 6, 7]),
+            map("a", vec![1]),
+            map("b", vec![2, 3]),
+        ];
+        let hash = filemap::hash_bundles(algo, &maps);
+        let proofs = BundleProofs::new(hash, &maps).unwrap();
+        for (file_nr, map) in maps.iter().enumerate() {
+            let file = proofs.file(file_nr, map).unwrap();
+            for (block_nr, &block) in map.blocks.iter().enumerate() {
+                assert!(file.verify(block_nr, block));
+            }
+            assert!(!file.verify(0, 4));
+            assert!(!file.verify(map.blocks.len(), map.blocks[0]));
+        }
+        assert!(proofs.file(0, &maps[1]).is_err());
+        assert!(proofs.file(3, &maps[0]).is_err());
+        assert!(BundleProofs::new(hash + 1, &maps).is_none());
+
+        let mut tampered = proofs.file(0, &maps[0]).unwrap();
+        tampered.blocks[1].siblings[0] ^= 1;
+        assert!(!tampered.verify(1, 6));
+        let mut tampered = proofs.file(0, &maps[0]).unwrap();
+        tampered.file.siblings[0] ^= 1;
+        assert!(!tampered.verify(0, 5));
+    }
+
+    #[test]
+    fn test_out_of_order_blocks() {
+        let dir = std::env::temp_dir().join(format!("hyperg-writer-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let size = BLOCK_SIZE as u64 + 10;
+        let writer = BlockWriter::create(&dir, size).unwrap();
+        writer.write_block(1, &[2u8; 10]).unwrap();
+        writer.write_block(0, &vec![1u8; BLOCK_SIZE]).unwrap();
+        assert!(writer.write_block(1, &[0u8; 11]).is_err());
+        writer.sync().unwrap();
+
+        let data = std::fs::read(&dir).unwrap();
+        std::fs::remove_file(&dir).unwrap();
+        assert_eq!(data.len() as u64, size);
+        assert!(data[..BLOCK_SIZE].iter().all(|&b| b == 1));
+        assert!(data[BLOCK_SIZE..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn test_write_queue() {
+        let path = std::env::temp_dir().join(format!("hyperg-queue-{}", std::process::id()));
+        let size = 2 * BLOCK_SIZE as u64 + 10;
+        // Written block by block, then coalesced into one write.
+        for flush_at in [0, DEFAULT_WRITE_BUFFER] {
+            let _ = std::fs::remove_file(&path);
+            let sys = System::new();
+            sys.block_on(async {
+                let mut writes =
+                    WriteQueue::new(BlockWriter::create(&path, size).unwrap(), flush_at);
+                for block_no in [2u32, 0, 1] {
+                    let len = if block_no == 2 { 10 } else { BLOCK_SIZE };
+                    let bytes = vec![block_no as u8 + 1; len];
+                    writes.write(block_no, bytes.into()).await.unwrap();
+                }
+                writes.finish(flush_at == 0).await.unwrap();
+
+                // Failed writes are reported by a later call.
+                let mut writes = WriteQueue::new(
+                    BlockWriter::create(&path.with_extension("x"), 1).unwrap(),
+                    flush_at,
+                );
+                writes.write(0, vec![0u8; 2].into()).await.unwrap();
+                assert!(writes.finish(true).await.is_err());
+            });
+            let data = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            std::fs::remove_file(path.with_extension("x")).unwrap();
+            assert_eq!(data.len() as u64, size);
+            for (block_no, block) in data.chunks(BLOCK_SIZE).enumerate() {
+                assert!(block.iter().all(|&b| b == block_no as u8 + 1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_bad_block() {
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let mut hasher = crate::filemap::BlockHasher::new(Default::default());
+        hasher.update(&data);
+        let file_map = hasher.finish(FileName::from("data"));
+
+        assert_eq!(find_bad_block(&data[..], &file_map).unwrap(), None);
+        let mut changed = data.clone();
+        changed[BLOCK_SIZE + 1] ^= 1;
+        assert_eq!(find_bad_block(&changed[..], &file_map).unwrap(), Some(1));
+        assert_eq!(find_bad_block(&data[..10], &file_map).unwrap(), Some(0));
+        let mut longer = data.clone();
+        longer.push(0);
+        assert_eq!(find_bad_block(&longer[..], &file_map).unwrap(), Some(1));
+
+        let empty = crate::filemap::BlockHasher::new(Default::default()).finish("empty".into());
+        assert_eq!(find_bad_block(&b""[..], &empty).unwrap(), None);
+        assert_eq!(find_bad_block(&b"x"[..], &empty).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let peer = |addr: &str| Peer::Direct(addr.parse().unwrap());
+        let peers = vec![
+            peer("[::1]:1"),
+            peer("[::2]:1"),
+            peer("[::3]:1"),
+            peer("10.0.0.1:1"),
+        ];
+        assert_eq!(
+            interleave_families(peers),
+            vec![
+                peer("[::1]:1"),
+                peer("10.0.0.1:1"),
+                peer("[::2]:1"),
+                peer("[::3]:1")
+            ]
+        );
+    }
 
-    futures::select_ok(connections).and_then(|(v, _)| Ok(v))
+    #[test]
+    fn test_race_connect() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listening = listener.local_addr().unwrap();
+        let refused = {
+            let closed = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            closed.local_addr().unwrap()
+        };
+        let sys = System::new();
+        let (_, addr) = sys
+            .block_on(race_connect(vec![refused, listening]))
+            .unwrap();
+        assert_eq!(addr, listening);
+        let errors = sys
+            .block_on(race_connect(vec![refused, refused]))
+            .unwrap_err();
+        assert_eq!(errors.len(), 2);
+    }
 }
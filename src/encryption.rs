@@ -0,0 +1,548 @@
+//! Optional encryption of data kept by the node: inline data of shares and files
+//! stored in the `streams` and `archives` directories of the database.
+//!
+//! Encrypted files start with a header holding a random nonce. The content is
+//! XORed with the BLAKE3 keyed output stream of the nonce, so every block can be
+//! decrypted on its own. Integrity is covered by block hashes checked by downloaders.
+//!
+//! Shares encrypted for transfer use the same scheme with their own header and a
+//! key given in the upload and download commands. Peers and relays only see the
+//! encrypted copy, which is what gets hashed.
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+const MAGIC: [u8; 8] = *b"HGENC\0\0\x01";
+const TRANSFER_MAGIC: [u8; 8] = *b"HGE2E\0\0\x01";
+const NONCE_SIZE: usize = 16;
+const KEY_CHECK_SIZE: usize = 8;
+const HEADER_SIZE: usize = MAGIC.len() + NONCE_SIZE + KEY_CHECK_SIZE;
+
+const KEY_CONTEXT: &str = "hyperg 2019-06 at-rest encryption key";
+const TRANSFER_KEY_CONTEXT: &str = "hyperg 2019-06 transfer encryption key";
+
+/// Size of encryption keys, see `parse_key`.
+pub const KEY_SIZE: usize = 32;
+
+/// Environment variable with the key, used without `--encryption_key_file`.
+pub const KEY_ENV: &str = "HYPERG_ENCRYPTION_KEY";
+
+static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+/// Enables encryption of data stored from now on with a key derived from `material`.
+pub fn set_key(material: &[u8]) {
+    let _ = KEY.set(blake3::derive_key(KEY_CONTEXT, material));
+}
+
+/// Reads the key from `path`, or from `HYPERG_ENCRYPTION_KEY` without a path, see
+/// `parse_key`.
+pub fn load_key(path: Option<&Path>) -> io::Result<Option<[u8; KEY_SIZE]>> {
+    let material = match path {
+        Some(path) => std::fs::read(path)?,
+        None => match std::env::var_os(KEY_ENV) {
+            #[cfg(unix)]
+            Some(value) => {
+                use std::os::unix::ffi::OsStringExt;
+                value.into_vec()
+            }
+            #[cfg(not(unix))]
+            Some(value) => value.to_string_lossy().into_owned().into_bytes(),
+            None => return Ok(None),
+        },
+    };
+    parse_key(&material).map(Some)
+}
+
+/// Key given as `KEY_SIZE` bytes used as they are, or as hex digits followed by an
+/// optional line break. Keys have to be random: headers hold a check of the key, which
+/// lets anyone with the data test guessed passphrases offline, so text is refused.
+fn parse_key(material: &[u8]) -> io::Result<[u8; KEY_SIZE]> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "encryption key must be 32 random bytes or 64 hex digits",
+        )
+    };
+    let mut key = [0u8; KEY_SIZE];
+    if material.len() == KEY_SIZE {
+        // Random bytes are all printable once in 10^13 keys, text is a passphrase.
+        if material.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+            return Err(invalid());
+        }
+        key.copy_from_slice(material);
+        return Ok(key);
+    }
+    let mut hex = material;
+    while let [rest @ .., b'\r' | b'\n' | b' '] = hex {
+        hex = rest;
+    }
+    if hex.len() != 2 * KEY_SIZE {
+        return Err(invalid());
+    }
+    for (byte, digits) in key.iter_mut().zip(hex.chunks(2)) {
+        *byte = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .ok_or_else(invalid)?;
+    }
+    Ok(key)
+}
+
+pub fn is_enabled() -> bool {
+    KEY.get().is_some()
+}
+
+fn key() -> Option<[u8; 32]> {
+    KEY.get().copied()
+}
+
+/// Key of a share encrypted for transfer, derived from a shared secret.
+#[derive(Clone, Copy)]
+pub struct TransferKey([u8; 32]);
+
+impl TransferKey {
+    /// Key of 64 hex digits, see `parse_key`.
+    pub fn new(material: &str) -> io::Result<Self> {
+        Ok(TransferKey(blake3::derive_key(
+            TRANSFER_KEY_CONTEXT,
+            &parse_key(material.as_bytes())?,
+        )))
+    }
+}
+
+struct Cipher {
+    magic: [u8; 8],
+    key: [u8; 32],
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl Cipher {
+    fn new(key: [u8; 32]) -> Self {
+        Self::with_magic(MAGIC, key)
+    }
+
+    fn with_magic(magic: [u8; 8], key: [u8; 32]) -> Self {
+        Cipher {
+            magic,
+            key,
+            nonce: rand::random(),
+        }
+    }
+
+    fn key_check(&self) -> [u8; KEY_CHECK_SIZE] {
+        let mut check = [0u8; KEY_CHECK_SIZE];
+        check.copy_from_slice(&blake3::keyed_hash(&self.key, &self.nonce).as_bytes()[..8]);
+        check
+    }
+
+    fn header(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..MAGIC.len()].copy_from_slice(&self.magic);
+        header[MAGIC.len()..MAGIC.len() + NONCE_SIZE].copy_from_slice(&self.nonce);
+        header[MAGIC.len() + NONCE_SIZE..].copy_from_slice(&self.key_check());
+        header
+    }
+
+    /// Cipher of data starting with `header`, `None` for plain data.
+    fn from_header(header: &[u8], key: Option<[u8; 32]>) -> io::Result<Option<Self>> {
+        Self::from_header_with(MAGIC, header, key)
+    }
+
+    fn from_header_with(
+        magic: [u8; 8],
+        header: &[u8],
+        key: Option<[u8; 32]>,
+    ) -> io::Result<Option<Self>> {
+        if header.len() < HEADER_SIZE || header[..MAGIC.len()] != magic {
+            return Ok(None);
+        }
+        let key = key.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data is encrypted, but no encryption key is set",
+            )
+        })?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&header[MAGIC.len()..MAGIC.len() + NONCE_SIZE]);
+        let cipher = Cipher { magic, key, nonce };
+        if header[MAGIC.len() + NONCE_SIZE..HEADER_SIZE] != cipher.key_check() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data is encrypted with another key",
+            ));
+        }
+        Ok(Some(cipher))
+    }
+
+    /// Encrypts or decrypts `buf` found at `offset` of the content.
+    fn apply(&self, offset: u64, buf: &mut [u8]) {
+        let mut hasher = blake3::Hasher::new_keyed(&self.key);
+        hasher.update(&self.nonce);
+        let mut stream = hasher.finalize_xof();
+        stream.set_position(offset);
+
+        let mut key_stream = [0u8; 4096];
+        for chunk in buf.chunks_mut(key_stream.len()) {
+            let key_stream = &mut key_stream[..chunk.len()];
+            stream.fill(key_stream);
+            for (b, k) in chunk.iter_mut().zip(key_stream.iter()) {
+                *b ^= k;
+            }
+        }
+    }
+}
+
+/// Encrypts `data` if encryption is enabled.
+pub fn seal(data: &[u8]) -> Vec<u8> {
+    seal_with(data, key())
+}
+
+fn seal_with(data: &[u8], key: Option<[u8; 32]>) -> Vec<u8> {
+    let cipher = match key {
+        Some(key) => Cipher::new(key),
+        None => return data.to_vec(),
+    };
+    let mut sealed = Vec::with_capacity(HEADER_SIZE + data.len());
+    sealed.extend_from_slice(&cipher.header());
+    sealed.extend_from_slice(data);
+    cipher.apply(0, &mut sealed[HEADER_SIZE..]);
+    sealed
+}
+
+/// Decrypts data returned by `seal`.
+pub fn unseal(data: &[u8]) -> io::Result<Vec<u8>> {
+    unseal_with(data, key())
+}
+
+fn unseal_with(data: &[u8], key: Option<[u8; 32]>) -> io::Result<Vec<u8>> {
+    match Cipher::from_header(data, key)? {
+        Some(cipher) => {
+            let mut plain = data[HEADER_SIZE..].to_vec();
+            cipher.apply(0, &mut plain);
+            Ok(plain)
+        }
+        None => Ok(data.to_vec()),
+    }
+}
+
+/// File read and written as plain content, encrypted on disk if it has the header.
+///
+/// Offsets used with `Seek` and `set_len` are offsets of the plain content.
+pub struct StoredFile {
+    file: File,
+    cipher: Option<Cipher>,
+    pos: u64,
+}
+
+impl StoredFile {
+    /// Opens a file for reading.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with(OpenOptions::new().read(true), path.as_ref(), key())
+    }
+
+    /// Creates a new file, encrypted if encryption is enabled.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Self::init(file, key())
+    }
+
+    /// Opens a file to continue writing it, creating it if missing.
+    ///
+    /// Existing files keep their encryption.
+    pub fn open_append(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_append_with(path.as_ref(), key())
+    }
+
+    fn open_append_with(path: &Path, key: Option<[u8; 32]>) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+        let mut stored = Self::open_with(&options, path, key)?;
+        if stored.file.metadata()?.len() == 0 {
+            stored = Self::init(stored.file, key)?;
+        }
+        Ok(stored)
+    }
+
+    fn open_with(options: &OpenOptions, path: &Path, key: Option<[u8; 32]>) -> io::Result<Self> {
+        let mut file = options.open(path)?;
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        (&mut file)
+            .take(HEADER_SIZE as u64)
+            .read_to_end(&mut header)?;
+        let cipher = Cipher::from_header(&header, key)?;
+        let mut stored = StoredFile {
+            file,
+            cipher,
+            pos: 0,
+        };
+        stored.seek(SeekFrom::Start(0))?;
+        Ok(stored)
+    }
+
+    fn init(mut file: File, key: Option<[u8; 32]>) -> io::Result<Self> {
+        let cipher = key.map(Cipher::new);
+        if let Some(cipher) = &cipher {
+            file.write_all(&cipher.header())?;
+        }
+        Ok(StoredFile {
+            file,
+            cipher,
+            pos: 0,
+        })
+    }
+
+    fn header_size(&self) -> u64 {
+        match self.cipher {
+            Some(_) => HEADER_SIZE as u64,
+            None => 0,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// The underlying file, positioned after the header.
+    #[cfg(feature = "with-mmap")]
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Size of the plain content.
+    pub fn len(&self) -> io::Result<u64> {
+        Ok(self
+            .file
+            .metadata()?
+            .len()
+            .saturating_sub(self.header_size()))
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    pub fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size + self.header_size())
+    }
+
+    pub fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+impl Read for StoredFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(buf)?;
+        if let Some(cipher) = &self.cipher {
+            cipher.apply(self.pos, &mut buf[..n]);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for StoredFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match &self.cipher {
+            Some(cipher) => {
+                let mut encrypted = buf.to_vec();
+                cipher.apply(self.pos, &mut encrypted);
+                self.file.write(&encrypted)?
+            }
+            None => self.file.write(buf)?,
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for StoredFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let header_size = self.header_size();
+        let pos = match pos {
+            SeekFrom::Start(offset) => SeekFrom::Start(offset + header_size),
+            pos => pos,
+        };
+        let file_pos = self.file.seek(pos)?;
+        if file_pos < header_size {
+            self.file.seek(SeekFrom::Start(header_size))?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before start of content",
+            ));
+        }
+        self.pos = file_pos - header_size;
+        Ok(self.pos)
+    }
+}
+
+/// Size of the plain content of a file.
+pub fn file_size(path: impl AsRef<Path>) -> io::Result<u64> {
+    StoredFile::open(path)?.len()
+}
+
+/// Copies the plain content of a stored file to `dest`.
+pub fn copy_plain(src: impl AsRef<Path>, dest: impl AsRef<Path>) -> io::Result<u64> {
+    io::copy(&mut StoredFile::open(src)?, &mut File::create(dest)?)
+}
+
+/// Writes an encrypted copy of `src` with the same permissions and modification time to
+/// `dest`, to be shared instead of `src`.
+pub fn encrypt_for_transfer(
+    src: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    key: TransferKey,
+) -> io::Result<u64> {
+    let cipher = Cipher::with_magic(TRANSFER_MAGIC, key.0);
+    let mut out = StoredFile::create(dest.as_ref())?;
+    out.write_all(&cipher.header())?;
+    let size = transform(&cipher, &mut StoredFile::open(src.as_ref())?, &mut out)?;
+    let metadata = std::fs::metadata(src)?;
+    out.file.set_modified(metadata.modified()?)?;
+    std::fs::set_permissions(dest, metadata.permissions())?;
+    Ok(size)
+}
+
+/// Writes the plain content of `src`, encrypted by `encrypt_for_transfer`, to `dest`.
+pub fn decrypt_transfer(
+    src: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    key: TransferKey,
+) -> io::Result<u64> {
+    let mut src = StoredFile::open(src)?;
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    (&mut src)
+        .take(HEADER_SIZE as u64)
+        .read_to_end(&mut header)?;
+    let cipher =
+        Cipher::from_header_with(TRANSFER_MAGIC, &header, Some(key.0))?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data is not encrypted for transfer",
+            )
+        })?;
+    transform(&cipher, &mut src, &mut File::create(dest)?)
+}
+
+fn transform(cipher: &Cipher, src: &mut impl Read, dest: &mut impl Write) -> io::Result<u64> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut offset = 0;
+    loop {
+        let n = src.read(&mut buf)?;
+        if n == 0 {
+            return Ok(offset);
+        }
+        cipher.apply(offset, &mut buf[..n]);
+        dest.write_all(&buf[..n])?;
+        offset += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_key() {
+        let dir = std::env::temp_dir().join(format!("hyperg-key-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let load = |name: &str, material: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, material).unwrap();
+            load_key(Some(&path))
+        };
+
+        let hex = format!("{}\r\n", "0f".repeat(KEY_SIZE));
+        assert_eq!(load("hex", hex.as_bytes()).unwrap(), Some([0x0f; KEY_SIZE]));
+        // Used as they are, trailing line breaks and invalid UTF-8 included.
+        let mut a = [b'\n'; KEY_SIZE];
+        a[..3].copy_from_slice(b"\x01\xff\x80");
+        let mut b = a;
+        b[1..3].copy_from_slice(b"\xfe\x81");
+        assert_eq!(load("a", &a).unwrap(), Some(a));
+        assert_ne!(
+            blake3::derive_key(KEY_CONTEXT, &load("a", &a).unwrap().unwrap()),
+            blake3::derive_key(KEY_CONTEXT, &load("b", &b).unwrap().unwrap())
+        );
+
+        // Passphrases could be guessed against the key check of encrypted data.
+        assert!(load("text", b"correct horse battery staple 123").is_err());
+        assert!(load("short", b"secret\n").is_err());
+        assert!(load("empty", b"").is_err());
+        assert!(load("odd", "0g".repeat(KEY_SIZE).as_bytes()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stored_file() {
+        let key = Some(blake3::derive_key(KEY_CONTEXT, b"test key"));
+        let path = std::env::temp_dir().join(format!("hyperg-enc-{}", std::process::id()));
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+
+        let mut file = StoredFile::open_append_with(&path, key).unwrap();
+        file.write_all(&data[..6000]).unwrap();
+        drop(file);
+        let mut file = StoredFile::open_append_with(&path, key).unwrap();
+        file.seek(SeekFrom::Start(5000)).unwrap();
+        file.write_all(&data[5000..]).unwrap();
+        drop(file);
+
+        let raw = std::fs::read(&path).unwrap();
+        assert_eq!(raw.len(), HEADER_SIZE + data.len());
+        assert_ne!(&raw[HEADER_SIZE..], &data[..]);
+
+        let other_key = Some(blake3::derive_key(KEY_CONTEXT, b"other key"));
+        let read_only = OpenOptions::new().read(true).clone();
+        assert!(StoredFile::open_with(&read_only, &path, other_key).is_err());
+        assert!(StoredFile::open_with(&read_only, &path, None).is_err());
+
+        let mut file = StoredFile::open_with(&read_only, &path, key).unwrap();
+        assert_eq!(file.len().unwrap(), data.len() as u64);
+        file.seek(SeekFrom::Start(4321)).unwrap();
+        let mut buf = vec![0u8; 100];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &data[4321..4421]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            unseal_with(&seal_with(b"inline", key), key).unwrap(),
+            b"inline"
+        );
+        assert_eq!(unseal_with(b"plain", key).unwrap(), b"plain");
+    }
+
+    #[test]
+    fn test_transfer() {
+        let dir = std::env::temp_dir().join(format!("hyperg-e2e-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("plain"), &data).unwrap();
+        let key = TransferKey::new(&"17".repeat(KEY_SIZE)).unwrap();
+
+        encrypt_for_transfer(dir.join("plain"), dir.join("sealed"), key).unwrap();
+        let sealed = std::fs::read(dir.join("sealed")).unwrap();
+        assert_eq!(sealed.len(), HEADER_SIZE + data.len());
+        assert_ne!(&sealed[HEADER_SIZE..], &data[..]);
+
+        let other_key = TransferKey::new(&"71".repeat(KEY_SIZE)).unwrap();
+        assert!(TransferKey::new("task secret").is_err());
+        assert!(decrypt_transfer(dir.join("sealed"), dir.join("out"), other_key).is_err());
+        assert!(decrypt_transfer(dir.join("plain"), dir.join("out"), key).is_err());
+        decrypt_transfer(dir.join("sealed"), dir.join("out"), key).unwrap();
+        assert_eq!(std::fs::read(dir.join("out")).unwrap(), data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,9 @@
+use crate::codec::RelayStatus;
+use crate::mode::NodeMode;
 use failure::Fail;
-use std::io;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{fmt, io};
 
 #[derive(Debug, Clone, Fail)]
 pub enum ProtocolError {
@@ -17,12 +21,295 @@ pub enum ProtocolError {
 
     #[fail(display = "handshake timeout")]
     HandshakeTimeout,
+
+    #[fail(display = "get block before ask")]
+    MissingAsk,
+
+    #[fail(display = "unexpected hash {:032x}", _0)]
+    UnexpectedHash(u128),
+
+    #[fail(display = "invalid file number {}", _0)]
+    InvalidFileNo(u32),
+
+    #[fail(display = "too many pending requests")]
+    TooManyRequests,
+
+    #[fail(display = "invalid block part")]
+    InvalidBlockPart,
+
+    #[fail(display = "unknown relay session")]
+    InvalidRelayToken,
+
+    #[fail(display = "relay quota exceeded")]
+    RelayQuotaExceeded,
+
+    #[fail(display = "access to {:032x} denied", _0)]
+    Unauthorized(u128),
+
+    #[fail(display = "invalid file map signature of {:032x}", _0)]
+    InvalidSignature(u128),
+
+    #[fail(display = "file maps of {:032x} not signed by the expected peer", _0)]
+    UnexpectedSigner(u128),
+
+    #[fail(
+        display = "node id {:032x} differs from {:032x} pinned for the address",
+        seen, pinned
+    )]
+    PeerIdChanged { pinned: u128, seen: u128 },
+
+    #[fail(
+        display = "peer presented node id {:032x} instead of {:032x}",
+        seen, expected
+    )]
+    UnexpectedPeerId { expected: u128, seen: u128 },
+
+    #[fail(display = "node id {:032x} not proven for a pinned address", _0)]
+    UnprovenPeerId(u128),
+
+    #[fail(display = "challenge not solved")]
+    ChallengeFailed,
+
+    #[fail(display = "invalid file maps page of {:032x}", _0)]
+    InvalidFileMaps(u128),
+
+    #[fail(display = "connection lost: {}", _1)]
+    ConnectionLost(io::ErrorKind, String),
+
+    #[fail(display = "busy")]
+    Busy,
+
+    #[fail(display = "in maintenance")]
+    Maintenance,
+
+    #[fail(display = "monthly traffic quota exceeded")]
+    QuotaExceeded,
+
+    #[fail(display = "{:032x} is blocked", _0)]
+    Blocked(u128),
+
+    /// Failure of this node, peers only get the code.
+    #[fail(display = "internal error: {}", _0)]
+    Internal(String),
+
+    #[fail(display = "peer closed the connection with error {}", _0)]
+    Remote(u16),
 }
 
 impl ProtocolError {
     pub fn into_err(&self) -> Error {
         Error::ProtocolError(self.clone())
     }
+
+    pub fn connection_lost(e: &io::Error) -> Self {
+        ProtocolError::ConnectionLost(e.kind(), e.to_string())
+    }
+
+    /// Code sent to the peer in the `error` packet, the code received for `Remote`.
+    pub fn code(&self) -> u16 {
+        let code = match self {
+            ProtocolError::Disconnect | ProtocolError::DisconnectByMe => ErrorCode::Disconnected,
+            ProtocolError::InvalidHandshake => ErrorCode::InvalidHandshake,
+            ProtocolError::MissingHandshake => ErrorCode::MissingHandshake,
+            ProtocolError::HandshakeTimeout => ErrorCode::HandshakeTimeout,
+            ProtocolError::MissingAsk => ErrorCode::MissingAsk,
+            ProtocolError::UnexpectedHash(_) => ErrorCode::UnexpectedHash,
+            ProtocolError::InvalidFileNo(_) => ErrorCode::InvalidFileNo,
+            ProtocolError::TooManyRequests => ErrorCode::TooManyRequests,
+            ProtocolError::InvalidBlockPart => ErrorCode::InvalidBlockPart,
+            ProtocolError::InvalidRelayToken => ErrorCode::InvalidRelayToken,
+            ProtocolError::RelayQuotaExceeded => ErrorCode::RelayQuotaExceeded,
+            ProtocolError::Unauthorized(_) => ErrorCode::Unauthorized,
+            ProtocolError::InvalidSignature(_) => ErrorCode::InvalidSignature,
+            ProtocolError::UnexpectedSigner(_) => ErrorCode::UnexpectedSigner,
+            ProtocolError::PeerIdChanged { .. } => ErrorCode::PeerIdChanged,
+            ProtocolError::UnexpectedPeerId { .. } => ErrorCode::UnexpectedPeerId,
+            ProtocolError::UnprovenPeerId(_) => ErrorCode::UnprovenPeerId,
+            ProtocolError::ChallengeFailed => ErrorCode::ChallengeFailed,
+            ProtocolError::InvalidFileMaps(_) => ErrorCode::InvalidFileMaps,
+            ProtocolError::ConnectionLost(io::ErrorKind::TimedOut, _) => ErrorCode::Timeout,
+            ProtocolError::ConnectionLost(..) => ErrorCode::ConnectionLost,
+            ProtocolError::Busy => ErrorCode::Busy,
+            ProtocolError::Maintenance => ErrorCode::Unavailable,
+            ProtocolError::QuotaExceeded => ErrorCode::QuotaExceeded,
+            ProtocolError::Blocked(_) => ErrorCode::Blocked,
+            ProtocolError::Internal(_) => ErrorCode::Internal,
+            ProtocolError::Remote(code) => return *code,
+        };
+        code as u16
+    }
+
+    /// Whether the peer is told about the error before the connection closes.
+    pub fn is_reported_to_peer(&self) -> bool {
+        !matches!(
+            self,
+            ProtocolError::Disconnect
+                | ProtocolError::DisconnectByMe
+                | ProtocolError::ConnectionLost(..)
+                | ProtocolError::Remote(_)
+        )
+    }
+}
+
+/// Stable error codes, sent to peers in `error` packets and returned in RPC error responses.
+///
+/// Hundreds group them: 1xx protocol, 2xx resource, 3xx peer, 4xx disk, 5xx timeout and
+/// 9xx request or internal errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    Protocol = 100,
+    InvalidHandshake = 101,
+    MissingHandshake = 102,
+    MissingAsk = 103,
+    UnexpectedHash = 104,
+    InvalidFileNo = 105,
+    TooManyRequests = 106,
+    InvalidBlockPart = 107,
+    InvalidRelayToken = 108,
+    InvalidSignature = 109,
+    UnexpectedSigner = 110,
+    PeerIdChanged = 111,
+    UnexpectedPeerId = 112,
+    ChallengeFailed = 113,
+    InvalidFileMaps = 114,
+    UnprovenPeerId = 115,
+
+    ResourceNotFound = 200,
+    Unauthorized = 201,
+    InvalidBlockHash = 202,
+    Blocked = 203,
+    CorruptShare = 204,
+    BundleMismatch = 205,
+
+    Disconnected = 300,
+    ConnectionLost = 301,
+    NoPeers = 302,
+    RelayFailed = 303,
+    RelayQuotaExceeded = 304,
+    ResolveFailed = 305,
+    HttpSource = 306,
+    ConnectFailed = 307,
+    Busy = 308,
+    Unavailable = 309,
+    QuotaExceeded = 310,
+
+    Disk = 400,
+    InvalidMetadata = 401,
+
+    Timeout = 500,
+    HandshakeTimeout = 501,
+
+    InvalidArgument = 900,
+    Internal = 901,
+    TruncatedBody = 902,
+    TrailingData = 903,
+    BodyChecksum = 904,
+}
+
+/// Why a peer could not provide a resource.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum PeerFailureReason {
+    ConnectionRefused,
+    ConnectFailed,
+    HandshakeFailed,
+    HashUnknown,
+    Timeout,
+    Disconnected,
+    RelayFailed,
+    Unauthorized,
+    InvalidSignature,
+    ResolveFailed,
+    Busy,
+    Unavailable,
+    IdentityMismatch,
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerFailure {
+    /// Peer address, `<node id>@<relay address>` for relayed peers
+    pub peer: String,
+    pub reason: PeerFailureReason,
+    pub code: u16,
+    pub message: String,
+}
+
+impl PeerFailure {
+    pub fn new(peer: impl fmt::Display, e: &Error) -> Self {
+        let reason = match e {
+            Error::IO(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                PeerFailureReason::ConnectionRefused
+            }
+            Error::IO(e) if e.kind() == io::ErrorKind::TimedOut => PeerFailureReason::Timeout,
+            Error::IO(_) => PeerFailureReason::ConnectFailed,
+            Error::ProtocolError(ProtocolError::InvalidHandshake)
+            | Error::ProtocolError(ProtocolError::MissingHandshake)
+            | Error::ProtocolError(ProtocolError::HandshakeTimeout)
+            | Error::ProtocolError(ProtocolError::ChallengeFailed) => {
+                PeerFailureReason::HandshakeFailed
+            }
+            Error::ProtocolError(ProtocolError::Remote(code))
+                if *code == ErrorCode::ChallengeFailed as u16 =>
+            {
+                PeerFailureReason::HandshakeFailed
+            }
+            Error::ProtocolError(ProtocolError::ConnectionLost(io::ErrorKind::TimedOut, _)) => {
+                PeerFailureReason::Timeout
+            }
+            Error::ProtocolError(ProtocolError::Disconnect)
+            | Error::ProtocolError(ProtocolError::DisconnectByMe)
+            | Error::ProtocolError(ProtocolError::ConnectionLost(..))
+            | Error::RequestCanceled(_) => PeerFailureReason::Disconnected,
+            Error::ResourceNotFound(_) => PeerFailureReason::HashUnknown,
+            Error::Relay(_) | Error::ProtocolError(ProtocolError::RelayQuotaExceeded) => {
+                PeerFailureReason::RelayFailed
+            }
+            Error::ProtocolError(ProtocolError::Unauthorized(_)) => PeerFailureReason::Unauthorized,
+            Error::ProtocolError(ProtocolError::InvalidSignature(_))
+            | Error::ProtocolError(ProtocolError::UnexpectedSigner(_)) => {
+                PeerFailureReason::InvalidSignature
+            }
+            Error::ProtocolError(ProtocolError::PeerIdChanged { .. })
+            | Error::ProtocolError(ProtocolError::UnexpectedPeerId { .. })
+            | Error::ProtocolError(ProtocolError::UnprovenPeerId(_)) => {
+                PeerFailureReason::IdentityMismatch
+            }
+            Error::Mailbox(actix::MailboxError::Timeout) => PeerFailureReason::Timeout,
+            Error::Resolve(..) => PeerFailureReason::ResolveFailed,
+            Error::ProtocolError(ProtocolError::Busy) => PeerFailureReason::Busy,
+            Error::ProtocolError(ProtocolError::Remote(code))
+                if *code == ErrorCode::Busy as u16 =>
+            {
+                PeerFailureReason::Busy
+            }
+            Error::ProtocolError(ProtocolError::Maintenance)
+            | Error::ProtocolError(ProtocolError::QuotaExceeded)
+            | Error::ProtocolError(ProtocolError::Blocked(_)) => PeerFailureReason::Unavailable,
+            Error::ProtocolError(ProtocolError::Remote(code))
+                if *code == ErrorCode::Unavailable as u16
+                    || *code == ErrorCode::QuotaExceeded as u16
+                    || *code == ErrorCode::Blocked as u16 =>
+            {
+                PeerFailureReason::Unavailable
+            }
+            _ => PeerFailureReason::Other,
+        };
+        PeerFailure {
+            peer: peer.to_string(),
+            reason,
+            code: e.code(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for PeerFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.peer, self.message)
+    }
 }
 
 #[derive(Debug, Fail)]
@@ -31,24 +318,139 @@ pub enum Error {
     IO(#[cause] io::Error),
     #[fail(display = "invalid format: {}", _0)]
     InvalidJsonFormat(#[cause] serde_json::Error),
+    #[fail(display = "invalid {} command: {}", command, message)]
+    InvalidCommand { command: String, message: String },
     #[fail(display = "invalid format: {}", _0)]
     InvalidBinFormat(#[cause] bincode::Error),
     #[fail(display = "invalid matadata version: {}", detected_version)]
     InvalidMetaVersion { detected_version: u32 },
     #[fail(display = "matadata not found")]
     MetadataNotFound,
+    #[fail(display = "unusable database metadata: {}", _0)]
+    CorruptDatabase(String),
     #[fail(display = "{} not working", _0)]
     ServiceFail(&'static str),
+    #[fail(display = "{} panicked: {}", _0, _1)]
+    Panicked(&'static str, String),
     #[fail(display = "{}", _0)]
     Mailbox(actix::MailboxError),
     #[fail(display = "request canceled {}", _0)]
-    RequestCanceled(#[cause] futures::Canceled),
+    RequestCanceled(#[cause] futures::channel::oneshot::Canceled),
     #[fail(display = "resource {:032x} not found", _0)]
     ResourceNotFound(u128),
     #[fail(display = "invalid block hash {:032x}", _0)]
     InvalidBlockHash(u128),
+    #[fail(display = "file maps sent for {:032x} don't hash to it", _0)]
+    BundleMismatch(u128),
     #[fail(display = "{}", _0)]
     ProtocolError(#[cause] ProtocolError),
+    #[fail(display = "rpc error {}: {}", status, message)]
+    Rpc {
+        status: u16,
+        code: Option<u16>,
+        message: String,
+    },
+    #[fail(display = "{}", _0)]
+    InvalidArgument(String),
+    #[fail(display = "resource {:032x} not available from any peer", _0)]
+    NoPeers(u128, Vec<PeerFailure>),
+    #[fail(display = "discovery error: {}", _0)]
+    Discovery(String),
+    #[fail(display = "tls error: {}", _0)]
+    Tls(String),
+    #[fail(display = "grpc error: {}", _0)]
+    Grpc(String),
+    #[fail(display = "trace output error: {}", _0)]
+    Trace(String),
+    #[fail(display = "relay refused session: {}", _0)]
+    Relay(RelayStatus),
+    #[fail(display = "watch error: {}", _0)]
+    Watch(#[cause] notify::Error),
+    #[fail(display = "http source {}: {}", url, message)]
+    HttpSource { url: String, message: String },
+    #[fail(display = "failed to resolve {}: {}", _0, _1)]
+    Resolve(String, #[cause] io::Error),
+    #[fail(
+        display = "shared copy of {:032x} is corrupt: block {} of {} doesn't match",
+        hash, block, file
+    )]
+    CorruptShare {
+        hash: u128,
+        file: String,
+        block: usize,
+    },
+    #[fail(display = "too many requests queued, retry later")]
+    Busy,
+    #[fail(display = "refused in {} mode", _0)]
+    Unavailable(NodeMode),
+    #[fail(display = "request body truncated at {} bytes", received)]
+    TruncatedBody {
+        received: usize,
+        expected: Option<usize>,
+    },
+    #[fail(display = "trailing data after the JSON value in {} bytes of body", _0)]
+    TrailingData(usize),
+    #[fail(display = "request body of {} bytes doesn't match its checksum", _0)]
+    BodyChecksum(usize),
+}
+
+impl Error {
+    /// Stable code of the error, see `ErrorCode`.
+    pub fn code(&self) -> u16 {
+        let code = match self {
+            Error::ProtocolError(e) => return e.code(),
+            Error::Rpc {
+                code: Some(code), ..
+            } => return *code,
+            Error::IO(e) if e.kind() == io::ErrorKind::TimedOut => ErrorCode::Timeout,
+            Error::IO(e) if is_connect_error(e.kind()) => ErrorCode::ConnectFailed,
+            Error::IO(_) => ErrorCode::Disk,
+            Error::InvalidBinFormat(_) => ErrorCode::Protocol,
+            Error::InvalidMetaVersion { .. }
+            | Error::MetadataNotFound
+            | Error::CorruptDatabase(_) => ErrorCode::InvalidMetadata,
+            Error::ResourceNotFound(_) => ErrorCode::ResourceNotFound,
+            Error::InvalidBlockHash(_) => ErrorCode::InvalidBlockHash,
+            Error::BundleMismatch(_) => ErrorCode::BundleMismatch,
+            Error::CorruptShare { .. } => ErrorCode::CorruptShare,
+            Error::NoPeers(..) => ErrorCode::NoPeers,
+            Error::Relay(_) => ErrorCode::RelayFailed,
+            Error::Resolve(..) => ErrorCode::ResolveFailed,
+            Error::HttpSource { .. } => ErrorCode::HttpSource,
+            Error::Busy => ErrorCode::Busy,
+            Error::Unavailable(_) => ErrorCode::Unavailable,
+            Error::Mailbox(actix::MailboxError::Timeout) => ErrorCode::Timeout,
+            Error::InvalidJsonFormat(_)
+            | Error::InvalidCommand { .. }
+            | Error::InvalidArgument(_) => ErrorCode::InvalidArgument,
+            Error::TruncatedBody { .. } => ErrorCode::TruncatedBody,
+            Error::TrailingData(_) => ErrorCode::TrailingData,
+            Error::BodyChecksum(_) => ErrorCode::BodyChecksum,
+            Error::ServiceFail(_)
+            | Error::Panicked(..)
+            | Error::Mailbox(_)
+            | Error::RequestCanceled(_)
+            | Error::Rpc { .. }
+            | Error::Discovery(_)
+            | Error::Tls(_)
+            | Error::Grpc(_)
+            | Error::Trace(_)
+            | Error::Watch(_) => ErrorCode::Internal,
+        };
+        code as u16
+    }
+}
+
+fn is_connect_error(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::BrokenPipe
+    )
 }
 
 macro_rules! convert {
@@ -63,11 +465,24 @@ macro_rules! convert {
     };
 }
 
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        crate::fd_monitor::check_error(&e);
+        Error::IO(e)
+    }
+}
+
+impl From<actix_web::error::BlockingError> for Error {
+    fn from(_: actix_web::error::BlockingError) -> Self {
+        Error::ServiceFail("blocking thread pool")
+    }
+}
+
 convert! {
-    io::Error => IO,
     bincode::Error => InvalidBinFormat,
     serde_json::Error => InvalidJsonFormat,
     actix::MailboxError => Mailbox,
-    futures::Canceled => RequestCanceled,
-    ProtocolError => ProtocolError
+    futures::channel::oneshot::Canceled => RequestCanceled,
+    ProtocolError => ProtocolError,
+    notify::Error => Watch
 }
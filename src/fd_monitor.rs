@@ -0,0 +1,153 @@
+//! Reaction to file descriptor exhaustion.
+//!
+//! Usage of descriptors is checked periodically and errors like `EMFILE` are
+//! reported here as they happen. Under pressure the transfer server stops
+//! accepting connections and the oldest idle connections are closed until
+//! usage drops again.
+use crate::connection_registry;
+use actix::prelude::*;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Accepting stays paused at least this long after exhaustion.
+const PAUSE_DURATION: Duration = Duration::from_secs(5);
+
+/// Percent of the limit in use at which accepting is paused.
+const HIGH_WATERMARK: u64 = 90;
+
+/// Percent of the limit in use below which accepting resumes.
+const LOW_WATERMARK: u64 = 80;
+
+static EXHAUSTED: AtomicBool = AtomicBool::new(false);
+
+/// True for errors caused by running out of file descriptors.
+pub fn is_exhaustion(e: &io::Error) -> bool {
+    // EMFILE and ENFILE
+    matches!(e.raw_os_error(), Some(24) | Some(23))
+}
+
+/// Notes an I/O error, making the monitor react if descriptors ran out.
+pub fn check_error(e: &io::Error) {
+    if is_exhaustion(e) && !EXHAUSTED.swap(true, Ordering::SeqCst) {
+        log::warn!("file descriptors exhausted: {}", e);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FdUsage {
+    pub open: u64,
+    pub limit: u64,
+}
+
+impl FdUsage {
+    fn percent(&self) -> u64 {
+        self.open * 100 / self.limit.max(1)
+    }
+}
+
+/// Descriptors open by the process and the soft limit.
+#[cfg(target_os = "linux")]
+pub fn fd_usage() -> Option<FdUsage> {
+    // Reading these needs a descriptor too.
+    let open = std::fs::read_dir("/proc/self/fd")
+        .map_err(|e| check_error(&e))
+        .ok()?
+        .count() as u64;
+    let limits = std::fs::read_to_string("/proc/self/limits")
+        .map_err(|e| check_error(&e))
+        .ok()?;
+    let limit = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()?;
+    Some(FdUsage { open, limit })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fd_usage() -> Option<FdUsage> {
+    None
+}
+
+pub struct FdMonitor {
+    server: actix_server::ServerHandle,
+    paused_until: Option<Instant>,
+}
+
+impl FdMonitor {
+    pub fn start(server: actix_server::ServerHandle) -> Addr<FdMonitor> {
+        FdMonitor {
+            server,
+            paused_until: None,
+        }
+        .start()
+    }
+
+    fn check(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let usage = fd_usage();
+        let exhausted = EXHAUSTED.swap(false, Ordering::SeqCst);
+        let high = usage
+            .map(|usage| usage.percent() >= HIGH_WATERMARK)
+            .unwrap_or(false);
+
+        if exhausted || high {
+            let open = usage.map(|usage| usage.open.to_string());
+            let limit = usage.map(|usage| usage.limit.to_string());
+            log::warn!(
+                "fd pressure: open={} limit={} connections={} exhausted={}, pausing accept and closing idle connections; consider raising the open files limit (ulimit -n)",
+                open.as_deref().unwrap_or("unknown"),
+                limit.as_deref().unwrap_or("unknown"),
+                crate::connection::active_connections(),
+                exhausted
+            );
+            if self.paused_until.is_none() {
+                ctx.spawn(self.server.pause().into_actor(self));
+            }
+            self.paused_until = Some(Instant::now() + PAUSE_DURATION);
+            connection_registry::shed_idle();
+            return;
+        }
+
+        let paused_until = match self.paused_until {
+            Some(paused_until) => paused_until,
+            None => return,
+        };
+        let low = usage
+            .map(|usage| usage.percent() < LOW_WATERMARK)
+            .unwrap_or(true);
+        if low && paused_until <= Instant::now() {
+            log::info!("fd pressure relieved, accepting connections");
+            self.paused_until = None;
+            ctx.spawn(self.server.resume().into_actor(self));
+        }
+    }
+}
+
+impl Actor for FdMonitor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(CHECK_INTERVAL, |act, ctx| act.check(ctx));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_exhaustion() {
+        assert!(is_exhaustion(&io::Error::from_raw_os_error(24)));
+        assert!(!is_exhaustion(&io::Error::from(io::ErrorKind::NotFound)));
+        #[cfg(target_os = "linux")]
+        {
+            let usage = fd_usage().unwrap();
+            assert!(usage.open > 0 && usage.limit >= usage.open);
+        }
+    }
+}
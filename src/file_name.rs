@@ -0,0 +1,549 @@
+//! File names of shared files, kept as the bytes the sharing system uses.
+//!
+//! Names are `/` separated paths relative to the download directory. UTF-8 names are
+//! normalized to NFC, so the same name typed on different systems hashes the same;
+//! other names (e.g. Latin-1 names on Linux) are kept as they are.
+use crate::error::Error;
+use schemars::JsonSchema;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// How names are turned into paths when downloading.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum NamePolicy {
+    /// Names are used as they are, ones the system can't store fail the download.
+    #[default]
+    Keep,
+    /// Names are transliterated to ASCII names valid on all systems.
+    Portable,
+}
+
+/// What is done with names of a bundle differing only in case, which are the same file
+/// on Windows and (by default) macOS.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum CasePolicy {
+    /// Names are used as they are, on case-insensitive systems later files replace
+    /// earlier ones.
+    Keep,
+    /// Colliding names fail the upload or download.
+    Error,
+    /// Later colliding names get a ` (1)`, ` (2)`, ... suffix before the extension.
+    Rename,
+}
+
+/// `error` where file names are case-insensitive, `keep` elsewhere.
+impl Default for CasePolicy {
+    fn default() -> Self {
+        if cfg!(any(windows, target_os = "macos")) {
+            CasePolicy::Error
+        } else {
+            CasePolicy::Keep
+        }
+    }
+}
+
+impl fmt::Display for CasePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CasePolicy::Keep => "keep",
+            CasePolicy::Error => "error",
+            CasePolicy::Rename => "rename",
+        })
+    }
+}
+
+impl FromStr for CasePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "keep" => Ok(CasePolicy::Keep),
+            "error" => Ok(CasePolicy::Error),
+            "rename" => Ok(CasePolicy::Rename),
+            _ => Err(Error::InvalidArgument(format!(
+                "invalid case policy: {} (expected keep, error or rename)",
+                s
+            ))),
+        }
+    }
+}
+
+/// A name differing only in case from an earlier name of the bundle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseCollision {
+    pub name: FileName,
+    pub collides_with: FileName,
+    /// New name of the file with `CasePolicy::Rename`.
+    pub renamed_to: Option<FileName>,
+}
+
+/// Finds names differing only in case from earlier ones and applies `policy` to them,
+/// renaming them in place.
+pub fn resolve_case<'a>(
+    names: impl IntoIterator<Item = &'a mut FileName>,
+    policy: CasePolicy,
+) -> Result<Vec<CaseCollision>, Error> {
+    let mut names: Vec<_> = names.into_iter().collect();
+    let mut keys: HashSet<_> = names.iter().map(|name| name.case_key()).collect();
+    let mut first_names = HashMap::new();
+    let mut collisions = Vec::new();
+    for name in &mut names {
+        let collides_with = match first_names.entry(name.case_key()) {
+            Entry::Vacant(entry) => {
+                entry.insert(name.clone());
+                continue;
+            }
+            Entry::Occupied(entry) if entry.get() == *name => continue,
+            Entry::Occupied(entry) => entry.get().clone(),
+        };
+        let renamed_to = match policy {
+            CasePolicy::Keep => None,
+            CasePolicy::Error => {
+                return Err(Error::InvalidArgument(format!(
+                    "file names {:?} and {:?} differ only in case, use the rename case policy",
+                    collides_with, name
+                )))
+            }
+            CasePolicy::Rename => {
+                let renamed = (1..)
+                    .map(|n| name.with_suffix(n))
+                    .find(|renamed| keys.insert(renamed.case_key()))
+                    .unwrap_or_else(|| unreachable!());
+                Some(renamed)
+            }
+        };
+        collisions.push(CaseCollision {
+            name: name.clone(),
+            collides_with,
+            renamed_to: renamed_to.clone(),
+        });
+        if let Some(renamed) = renamed_to {
+            **name = renamed;
+        }
+    }
+    Ok(collisions)
+}
+
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileName(Vec<u8>);
+
+impl FileName {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(name) => name.into(),
+            Err(e) => FileName(e.into_bytes()),
+        }
+    }
+
+    pub fn from_os_str(name: &OsStr) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            FileName::from_bytes(name.as_bytes().to_vec())
+        }
+        #[cfg(not(unix))]
+        {
+            name.to_string_lossy().into_owned().into()
+        }
+    }
+
+    /// Name of a relative path, with its components joined by `/`.
+    pub fn from_relative_path(path: &Path) -> Self {
+        let mut bytes = Vec::new();
+        for component in path.components() {
+            if !bytes.is_empty() {
+                bytes.push(b'/');
+            }
+            bytes.extend_from_slice(FileName::from_os_str(component.as_os_str()).as_bytes());
+        }
+        FileName::from_bytes(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// The name as a relative path, lossy where the system doesn't use bytes.
+    pub fn as_path(&self) -> Cow<'_, Path> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            Cow::Borrowed(Path::new(OsStr::from_bytes(&self.0)))
+        }
+        #[cfg(not(unix))]
+        {
+            match self.to_string_lossy() {
+                Cow::Borrowed(name) => Cow::Borrowed(Path::new(name)),
+                Cow::Owned(name) => Cow::Owned(PathBuf::from(name)),
+            }
+        }
+    }
+
+    /// Empty directories are named with a trailing `/`.
+    pub fn is_dir(&self) -> bool {
+        self.0.ends_with(b"/")
+    }
+
+    /// Components of the name, without the trailing `/` of directories.
+    pub fn components(&self) -> impl Iterator<Item = &[u8]> {
+        let name = if self.is_dir() {
+            &self.0[..self.0.len() - 1]
+        } else {
+            &self.0[..]
+        };
+        name.split(|&b| b == b'/')
+    }
+
+    /// Key equal for names differing only in case.
+    fn case_key(&self) -> Vec<u8> {
+        let name = self.0.strip_suffix(b"/").unwrap_or(&self.0);
+        match std::str::from_utf8(name) {
+            Ok(name) => name.to_lowercase().into_bytes(),
+            Err(_) => name.to_ascii_lowercase(),
+        }
+    }
+
+    /// The name with ` (n)` appended to the last component, before its extension.
+    fn with_suffix(&self, n: usize) -> FileName {
+        let dir = self.is_dir();
+        let mut name = self.0.clone();
+        if dir {
+            name.pop();
+        }
+        let last = name.iter().rposition(|&b| b == b'/').map_or(0, |i| i + 1);
+        // Dot files like `.profile` have no extension.
+        let at = match name[last..].iter().rposition(|&b| b == b'.') {
+            Some(dot) if dot > 0 && !dir => last + dot,
+            _ => name.len(),
+        };
+        name.splice(at..at, format!(" ({})", n).into_bytes());
+        if dir {
+            name.push(b'/');
+        }
+        FileName(name)
+    }
+
+    /// Checks that the name is a relative path staying within the download directory.
+    pub fn check(&self) -> Result<(), Error> {
+        let valid = !self.0.is_empty()
+            && !self.0.contains(&0)
+            && self
+                .components()
+                .all(|c| !c.is_empty() && c != b"." && c != b"..");
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument(format!(
+                "invalid file name: {:?}",
+                self
+            )))
+        }
+    }
+
+    /// Path the file is downloaded to in `dest`.
+    pub fn to_path(&self, dest: &Path, policy: NamePolicy) -> Result<PathBuf, Error> {
+        self.check()?;
+        let mut path = dest.to_path_buf();
+        for component in self.components() {
+            let component = match policy {
+                NamePolicy::Keep => Cow::Borrowed(native_component(component).ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "file name {:?} can't be stored on this system, download with portable names",
+                        self
+                    ))
+                })?),
+                NamePolicy::Portable => Cow::Owned(portable_component(component).into()),
+            };
+            // A component pushed as a root, prefix or `..` would leave `dest`.
+            let mut parts = Path::new(&component).components();
+            match (parts.next(), parts.next()) {
+                (Some(Component::Normal(_)), None) => path.push(component),
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "invalid file name: {:?}",
+                        self
+                    )))
+                }
+            }
+        }
+        debug_assert!(path.starts_with(dest));
+        Ok(path)
+    }
+}
+
+#[cfg(unix)]
+fn native_component(component: &[u8]) -> Option<&OsStr> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(OsStr::from_bytes(component))
+}
+
+#[cfg(not(unix))]
+fn native_component(component: &[u8]) -> Option<&OsStr> {
+    let component = std::str::from_utf8(component).ok()?;
+    if is_windows_name(component) {
+        Some(OsStr::new(component))
+    } else {
+        None
+    }
+}
+
+/// Whether Windows can store a file or directory named `component`.
+#[cfg_attr(unix, allow(dead_code))]
+fn is_windows_name(component: &str) -> bool {
+    !component.ends_with(['.', ' '])
+        && !component
+            .chars()
+            .any(|c| c.is_control() || "<>:\"\\|?*".contains(c))
+        && !is_reserved(component)
+}
+
+fn is_reserved(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or_default().trim_end();
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+}
+
+/// ASCII transliteration of a name component, valid on Windows and Unix.
+///
+/// Compatibility forms can decompose to separators and dots (e.g. `／` to `/`), so those
+/// are replaced and the result is checked again.
+fn portable_component(component: &[u8]) -> String {
+    let mut name: String = String::from_utf8_lossy(component)
+        .nfkd()
+        .filter(|&c| !is_combining_mark(c))
+        .map(|c| match c {
+            ' '..='~' if !"<>:\"/\\|?*".contains(c) => c,
+            _ => '_',
+        })
+        .collect();
+    name.truncate(name.trim_end_matches(['.', ' ']).len());
+    if name.is_empty() || name == "." || name == ".." {
+        name = "_".to_string();
+    }
+    if is_reserved(&name) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+impl From<String> for FileName {
+    fn from(name: String) -> Self {
+        FileName(name.nfc().collect::<String>().into_bytes())
+    }
+}
+
+impl From<&str> for FileName {
+    fn from(name: &str) -> Self {
+        FileName(name.nfc().collect::<String>().into_bytes())
+    }
+}
+
+impl fmt::Display for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_lossy())
+    }
+}
+
+impl fmt::Debug for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_string_lossy(), f)
+    }
+}
+
+/// UTF-8 names are strings, so file maps of such names keep their legacy encoding and hash.
+impl Serialize for FileName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.to_str() {
+            Some(name) => serializer.serialize_str(name),
+            None => serializer.serialize_bytes(&self.0),
+        }
+    }
+}
+
+/// Names are kept as received, a changed name would not match the hash.
+impl<'de> Deserialize<'de> for FileName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NameVisitor;
+
+        impl<'de> Visitor<'de> for NameVisitor {
+            type Value = FileName;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("file name")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<FileName, E> {
+                Ok(FileName(v.as_bytes().to_vec()))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<FileName, E> {
+                Ok(FileName(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<FileName, E> {
+                Ok(FileName(v))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<FileName, A::Error> {
+                let mut bytes = Vec::new();
+                while let Some(b) = seq.next_element()? {
+                    bytes.push(b);
+                }
+                Ok(FileName(bytes))
+            }
+        }
+
+        deserializer.deserialize_byte_buf(NameVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serialization() {
+        let name = FileName::from("dir/zażółć.txt");
+        assert_eq!(
+            bincode::serialize(&name).unwrap(),
+            bincode::serialize("dir/zażółć.txt").unwrap()
+        );
+        let latin1 = FileName::from_bytes(b"caf\xe9".to_vec());
+        let decoded: FileName =
+            bincode::deserialize(&bincode::serialize(&latin1).unwrap()).unwrap();
+        assert_eq!(decoded.as_bytes(), b"caf\xe9");
+    }
+
+    #[test]
+    fn test_normalization() {
+        assert_eq!(FileName::from("e\u{301}").as_bytes(), "é".as_bytes());
+        assert_eq!(
+            FileName::from_bytes("a/e\u{301}".as_bytes().to_vec()),
+            FileName::from("a/é")
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        for name in &["", "/a", "a//b", "a/../b", "..", "./a", "a\0b"] {
+            assert!(FileName::from(*name).check().is_err(), "{:?}", name);
+        }
+        for name in &["a", "a/b", "a/b/", "..a", "a b/c.d"] {
+            assert!(FileName::from(*name).check().is_ok(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_portable() {
+        let dest = Path::new("dest");
+        let path = |name: FileName| name.to_path(dest, NamePolicy::Portable).unwrap();
+        assert_eq!(
+            path("Zażółć/ﬁle?.txt".into()),
+            dest.join("Zazo_c").join("file_.txt")
+        );
+        assert_eq!(path("con.txt".into()), dest.join("_con.txt"));
+        assert_eq!(path("a. ".into()), dest.join("a"));
+        assert_eq!(path("日本".into()), dest.join("__"));
+        assert_eq!(
+            path(FileName::from_bytes(b"caf\xe9".to_vec())),
+            dest.join("caf_")
+        );
+        assert_eq!(path("／etc／x".into()), dest.join("_etc_x"));
+        assert_eq!(path("..／x".into()), dest.join(".._x"));
+        assert_eq!(path("．．".into()), dest.join("_"));
+        assert_eq!(path("a/．．/b".into()), dest.join("a").join("_").join("b"));
+    }
+
+    #[test]
+    fn test_windows_names() {
+        for name in &["a:b", "a\\b", "aux", "LPT1.log", "a.", "a ", "a\u{1}"] {
+            assert!(!is_windows_name(name), "{:?}", name);
+        }
+        for name in &["a", "a.b", "console", "zażółć"] {
+            assert!(is_windows_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        let bundle = || -> Vec<FileName> {
+            [
+                "Readme.txt",
+                "docs/",
+                "readme.txt",
+                "DOCS/",
+                "README.txt",
+                "Readme (1).txt",
+            ]
+            .iter()
+            .map(|&name| name.into())
+            .collect()
+        };
+        let mut names = bundle();
+        let collisions = resolve_case(&mut names, CasePolicy::Keep).unwrap();
+        assert_eq!(names, bundle());
+        assert_eq!(collisions.len(), 3);
+        assert_eq!(collisions[0].name, "readme.txt".into());
+        assert_eq!(collisions[0].collides_with, "Readme.txt".into());
+        assert!(resolve_case(&mut names, CasePolicy::Error).is_err());
+
+        let collisions = resolve_case(&mut names, CasePolicy::Rename).unwrap();
+        let renamed: Vec<_> = collisions.iter().map(|c| c.renamed_to.clone()).collect();
+        assert_eq!(
+            renamed,
+            vec![
+                Some("readme (2).txt".into()),
+                Some("DOCS (1)/".into()),
+                Some("README (3).txt".into())
+            ]
+        );
+        assert_eq!(names[2], "readme (2).txt".into());
+        assert!(resolve_case(&mut names, CasePolicy::Error)
+            .unwrap()
+            .is_empty());
+        assert!(resolve_case(
+            &mut vec!["Zażółć".into(), "ZAŻÓŁĆ".into()],
+            CasePolicy::Error
+        )
+        .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_keep_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = FileName::from_bytes(b"a/caf\xe9".to_vec());
+        let path = name.to_path(Path::new("/dest"), NamePolicy::Keep).unwrap();
+        assert_eq!(path.as_os_str().as_bytes(), b"/dest/a/caf\xe9");
+        assert_eq!(
+            FileName::from_relative_path(Path::new("a/caf\u{e9}")),
+            "a/café".into()
+        );
+    }
+}
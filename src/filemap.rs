@@ -1,19 +1,121 @@
+use crate::file_name::FileName;
 use serde::{Deserialize, Serialize};
 use sha2::digest::Digest;
 use std::borrow::Borrow;
 use std::cmp::min;
 use std::convert::TryInto;
-use std::io::Read;
-use std::path::Path;
-use std::{fs, io};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use std::{fmt, io};
 
 pub const BLOCK_SIZE: usize = 1024 * 1024 * 4;
 
+/// Hash function used for blocks and bundle hashes, truncated to 128 bits.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha224,
+    Blake3,
+}
+
+impl Default for HashAlgorithm {
+    /// Maps received from peers not sending an algorithm identifier.
+    fn default() -> Self {
+        HashAlgorithm::Sha224
+    }
+}
+
+impl HashAlgorithm {
+    pub fn hash_block(self, block: &[u8]) -> u128 {
+        match self {
+            HashAlgorithm::Sha224 => {
+                let mut digest = sha2::Sha224::new();
+                digest.input(block);
+                extract_results(digest)
+            }
+            HashAlgorithm::Blake3 => truncate_blake3(blake3::hash(block)),
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha224" => Ok(HashAlgorithm::Sha224),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!(
+                "invalid hash algorithm: {} (expected sha224 or blake3)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HashAlgorithm::Sha224 => "sha224",
+            HashAlgorithm::Blake3 => "blake3",
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FileMap {
-    pub file_name: String,
+    pub file_name: FileName,
     pub file_size: u64,
     pub blocks: Vec<u128>,
+    /// Not part of the legacy map layout, sent separately in `AskReply`.
+    #[serde(skip)]
+    pub hash_algorithm: HashAlgorithm,
+    /// Unix permission bits, sent separately in `AskReply` and not covered by the hash.
+    #[serde(skip)]
+    pub mode: Option<u32>,
+    /// Modification time, sent like `mode`.
+    #[serde(skip)]
+    pub mtime: Option<SystemTime>,
+    /// Target of a symbolic link kept as a link, relative to the directory of the link.
+    /// Links have no blocks. Sent after `mtime` and covered by the hash.
+    #[serde(skip)]
+    pub link: Option<String>,
+}
+
+impl FileMap {
+    /// Entry for an empty directory, named with a trailing `/`.
+    pub fn directory(name: FileName, hash_algorithm: HashAlgorithm) -> Self {
+        let mut name = name.as_bytes().to_vec();
+        if !name.ends_with(b"/") {
+            name.push(b'/');
+        }
+        FileMap {
+            file_name: FileName::from_bytes(name),
+            file_size: 0,
+            blocks: Vec::new(),
+            hash_algorithm,
+            mode: None,
+            mtime: None,
+            link: None,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_name.is_dir()
+    }
+
+    /// Whether both maps describe the same content, ignoring the fields the hash doesn't
+    /// cover.
+    pub fn same_content(&self, other: &FileMap) -> bool {
+        self.file_name == other.file_name
+            && self.file_size == other.file_size
+            && self.blocks == other.blocks
+            && self.link == other.link
+            && self.hash_algorithm == other.hash_algorithm
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -22,68 +124,719 @@ pub struct BlobDesc {
     pub files: Vec<FileMap>,
 }
 
+#[inline]
+fn truncate_blake3(hash: blake3::Hash) -> u128 {
+    u128::from_le_bytes(hash.as_bytes()[0..16].try_into().unwrap())
+}
+
 #[inline]
 fn extract_results<D: Digest>(digest: D) -> u128 {
     u128::from_le_bytes(digest.result()[0..16].try_into().unwrap())
 }
 
-pub fn hash_file(
+#[inline]
+pub fn block_count(file_size: u64) -> usize {
+    file_size.div_ceil(BLOCK_SIZE as u64).try_into().unwrap()
+}
+
+/// Hashes block `block_no` of a file that is `file_size` bytes long.
+pub fn hash_file_block(
     path: impl AsRef<Path>,
-    file_name: impl Into<String>,
-) -> Result<FileMap, io::Error> {
-    let mut file = fs::OpenOptions::new().read(true).open(path)?;
-    let file_size = file.metadata()?.len();
-    let file_name = file_name.into();
-    let num_of_blocks = ((file_size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64)
-        .try_into()
-        .unwrap();
-
-    let mut buf = Vec::with_capacity(BLOCK_SIZE);
-    buf.resize(BLOCK_SIZE, 0);
-
-    let mut blocks = Vec::with_capacity(num_of_blocks);
-
-    // hash block
-
-    let mut rem_file_bytes = file_size;
-    while rem_file_bytes > 0 {
-        let mut rem_block_bytes = BLOCK_SIZE;
-        let mut digest = sha2::Sha224::new();
-        while rem_block_bytes > 0 && rem_file_bytes > 0 {
-            let to_read = min(buf.len(), rem_block_bytes);
-            let len = file.read(&mut buf[..to_read])?;
-
-            if len == 0 {
-                return Err(io::Error::new(io::ErrorKind::Other, "Unexpected EOF"));
+    block_no: usize,
+    file_size: u64,
+    hash_algorithm: HashAlgorithm,
+) -> io::Result<u128> {
+    let mut file = crate::encryption::StoredFile::open(path)?;
+    let offset = block_no as u64 * BLOCK_SIZE as u64;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut buf = vec![0u8; min(BLOCK_SIZE as u64, file_size.saturating_sub(offset)) as usize];
+    let mut pos = 0;
+    while pos < buf.len() {
+        let len = file.read(&mut buf[pos..])?;
+
+        if len == 0 {
+            return Err(io::Error::other("Unexpected EOF"));
+        }
+        pos += len;
+    }
+
+    Ok(hash_algorithm.hash_block(&buf))
+}
+
+/// Builds a file map from data arriving in chunks of any size.
+pub struct BlockHasher {
+    hash_algorithm: HashAlgorithm,
+    pending: Vec<u8>,
+    blocks: Vec<u128>,
+    file_size: u64,
+}
+
+impl BlockHasher {
+    pub fn new(hash_algorithm: HashAlgorithm) -> Self {
+        BlockHasher {
+            hash_algorithm,
+            pending: Vec::new(),
+            blocks: Vec::new(),
+            file_size: 0,
+        }
+    }
+
+    /// Continues after `blocks` full blocks.
+    pub fn resume(hash_algorithm: HashAlgorithm, blocks: Vec<u128>) -> Self {
+        BlockHasher {
+            hash_algorithm,
+            pending: Vec::new(),
+            file_size: blocks.len() as u64 * BLOCK_SIZE as u64,
+            blocks,
+        }
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Hashes of the full blocks seen so far.
+    pub fn blocks(&self) -> &[u128] {
+        &self.blocks
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.file_size += data.len() as u64;
+        while !data.is_empty() {
+            let len = min(BLOCK_SIZE - self.pending.len(), data.len());
+            self.pending.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.pending.len() == BLOCK_SIZE {
+                self.blocks
+                    .push(self.hash_algorithm.hash_block(&self.pending));
+                self.pending.clear();
             }
+        }
+    }
 
-            rem_block_bytes -= len;
-            rem_file_bytes -= len as u64;
-            digest.input(&buf[0..len]);
+    pub fn finish(mut self, file_name: FileName) -> FileMap {
+        if !self.pending.is_empty() {
+            self.blocks
+                .push(self.hash_algorithm.hash_block(&self.pending));
         }
-        let hash = extract_results(digest);
+        FileMap {
+            file_name,
+            file_size: self.file_size,
+            blocks: self.blocks,
+            hash_algorithm: self.hash_algorithm,
+            mode: None,
+            mtime: None,
+            link: None,
+        }
+    }
+}
 
-        blocks.push(hash);
+/// Permission bits of the file at `path` kept in its file map.
+///
+/// Only the `rwx` bits are kept. Where there are none, read-only files map to `0o444`
+/// and other files to `0o644`.
+pub fn file_mode(path: impl AsRef<Path>) -> io::Result<u32> {
+    let permissions = fs::metadata(path)?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Ok(permissions.mode() & 0o777)
     }
+    #[cfg(not(unix))]
+    {
+        Ok(if permissions.readonly() { 0o444 } else { 0o644 })
+    }
+}
+
+/// Applies permission bits from a file map to a downloaded file.
+///
+/// Where there are no permission bits, a mode without any write bit makes the file
+/// read-only.
+pub fn set_file_mode(path: impl AsRef<Path>, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path.as_ref())?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(mode & 0o777);
+    }
+    #[cfg(not(unix))]
+    {
+        permissions.set_readonly(mode & 0o222 == 0);
+    }
+    fs::set_permissions(path, permissions)
+}
+
+/// Whether a link named `file_name` (with `/` separated directories) pointing to `target`
+/// stays within the directory the resource is placed in.
+pub fn link_stays_within(file_name: &FileName, target: &str) -> bool {
+    let mut depth = file_name.components().count() - 1;
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir if depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Links followed resolving a target before it is taken for a loop.
+const MAX_LINK_HOPS: u32 = 40;
 
-    Ok(FileMap {
-        file_name,
-        file_size,
-        blocks,
+/// Whether the link at `path` in `root` leads within `root`, also through the links in
+/// `root` its target passes. Parts of the target not existing yet are taken by name.
+pub fn link_resolves_within(root: &Path, path: &Path) -> io::Result<bool> {
+    let dir = match path.strip_prefix(root).map(Path::parent) {
+        Ok(Some(dir)) => dir.components().map(|c| c.as_os_str().to_owned()).collect(),
+        _ => return Ok(false),
+    };
+    let target = fs::read_link(path)?;
+    Ok(resolve_within(root, dir, &target, &mut 0).is_some())
+}
+
+/// Directories below `root` `target` leads to from `dir`, `None` when it leaves `root`.
+fn resolve_within(
+    root: &Path,
+    mut dir: Vec<OsString>,
+    target: &Path,
+    hops: &mut u32,
+) -> Option<Vec<OsString>> {
+    for component in target.components() {
+        match component {
+            Component::Normal(name) => {
+                dir.push(name.to_owned());
+                let path: PathBuf = dir.iter().fold(root.to_path_buf(), |path, c| path.join(c));
+                if let Ok(link) = fs::read_link(&path) {
+                    *hops += 1;
+                    if *hops > MAX_LINK_HOPS {
+                        return None;
+                    }
+                    dir.pop();
+                    dir = resolve_within(root, dir, &link, hops)?;
+                }
+            }
+            Component::CurDir => (),
+            Component::ParentDir => {
+                dir.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(dir)
+}
+
+/// Whether a directory `path` is in below `root` is a symbolic link, which writing to
+/// `path` would follow. Paths outside `root` count as linked.
+pub fn has_linked_parent(root: &Path, path: &Path) -> bool {
+    let dir = match path.strip_prefix(root).map(Path::parent) {
+        Ok(Some(dir)) => dir,
+        _ => return true,
+    };
+    let mut parent = root.to_path_buf();
+    dir.components().any(|c| {
+        parent.push(c);
+        fs::symlink_metadata(&parent).is_ok_and(|meta| meta.file_type().is_symlink())
     })
 }
 
-pub fn hash_bundles(maps: impl IntoIterator<Item = impl Borrow<FileMap>>) -> u128 {
-    let mut digest = sha2::Sha224::new();
-    for map in maps {
-        // TODO: Handle this
-        bincode::serialize_into(&mut digest, map.borrow()).unwrap();
+/// Creates a symbolic link at `path` pointing to `target`.
+pub fn create_link(path: impl AsRef<Path>, target: &str) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, path)
+    }
+    #[cfg(windows)]
+    {
+        std::os::windows::fs::symlink_file(target.replace('/', "\\"), path)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (path, target);
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "symbolic links are not supported",
+        ))
+    }
+}
+
+pub fn file_mtime(path: impl AsRef<Path>) -> io::Result<SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
+/// Sets the modification time of a downloaded file, before `set_file_mode` makes it
+/// read-only.
+pub fn set_file_mtime(path: impl AsRef<Path>, mtime: SystemTime) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_modified(mtime)
+}
+
+/// Version of the resource hash: 1 hashed the maps in the order they were given, 2 orders
+/// them by file name first and 3 makes the hash the root of a Merkle tree over the maps
+/// ordered by name, see `BundleFormat`.
+pub const BUNDLE_FORMAT: u32 = 3;
+
+/// How a resource hash digests the file maps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BundleFormat {
+    /// One digest of the maps in the order they were given, format 1.
+    InOrder,
+    /// One digest of the maps ordered by file name, format 2.
+    Sorted,
+    /// Merkle tree over the maps ordered by file name, format 3.
+    Merkle,
+}
+
+impl BundleFormat {
+    /// Format `maps` give `hash` in, trying the current one first.
+    pub fn of(
+        hash: u128,
+        hash_algorithm: HashAlgorithm,
+        maps: &[impl Borrow<FileMap>],
+    ) -> Option<Self> {
+        [
+            BundleFormat::Merkle,
+            BundleFormat::Sorted,
+            BundleFormat::InOrder,
+        ]
+        .iter()
+        .copied()
+        .find(|format| format.hash(hash_algorithm, maps.iter().map(Borrow::borrow)) == hash)
+    }
+
+    /// Whether maps stay in the order they were given, otherwise they are ordered by name.
+    pub fn keeps_order(self) -> bool {
+        self == BundleFormat::InOrder
+    }
+
+    pub fn hash(
+        self,
+        hash_algorithm: HashAlgorithm,
+        maps: impl IntoIterator<Item = impl Borrow<FileMap>>,
+    ) -> u128 {
+        let mut maps: Vec<_> = maps.into_iter().collect();
+        if !self.keeps_order() {
+            maps.sort_by(|a, b| a.borrow().file_name.cmp(&b.borrow().file_name));
+        }
+        match self {
+            BundleFormat::InOrder | BundleFormat::Sorted => {
+                hash_bundles_in_order(hash_algorithm, maps)
+            }
+            BundleFormat::Merkle => {
+                let leaves: Vec<u128> = maps
+                    .iter()
+                    .map(|map| file_leaf(hash_algorithm, map.borrow()))
+                    .collect();
+                merkle_root(hash_algorithm, &leaves)
+            }
+        }
+    }
+}
+
+/// Orders `files` by file name, the order resources are hashed, stored and served in.
+pub fn sort_bundle<P>(files: &mut [(FileMap, P)]) {
+    files.sort_by(|(a, _), (b, _)| a.file_name.cmp(&b.file_name));
+}
+
+/// Resource hash of `maps` in any order.
+pub fn hash_bundles(
+    hash_algorithm: HashAlgorithm,
+    maps: impl IntoIterator<Item = impl Borrow<FileMap>>,
+) -> u128 {
+    BundleFormat::Merkle.hash(hash_algorithm, maps)
+}
+
+/// Whether `maps` are the ones of resource `hash`, including resources hashed in older
+/// formats.
+pub fn bundle_matches(hash: u128, hash_algorithm: HashAlgorithm, maps: &[FileMap]) -> bool {
+    BundleFormat::of(hash, hash_algorithm, maps).is_some()
+}
+
+/// One digest of `maps` in the given order, the resource hash before `BUNDLE_FORMAT` 3.
+pub fn hash_bundles_in_order(
+    hash_algorithm: HashAlgorithm,
+    maps: impl IntoIterator<Item = impl Borrow<FileMap>>,
+) -> u128 {
+    match hash_algorithm {
+        HashAlgorithm::Sha224 => {
+            let mut digest = sha2::Sha224::new();
+            for map in maps {
+                // TODO: Handle this
+                bincode::serialize_into(&mut digest, map.borrow()).unwrap();
+                if let Some(link) = &map.borrow().link {
+                    bincode::serialize_into(&mut digest, link).unwrap();
+                }
+            }
+            extract_results(digest)
+        }
+        HashAlgorithm::Blake3 => {
+            let mut digest = blake3::Hasher::new();
+            for map in maps {
+                bincode::serialize_into(&mut digest, map.borrow()).unwrap();
+                if let Some(link) = &map.borrow().link {
+                    bincode::serialize_into(&mut digest, link).unwrap();
+                }
+            }
+            truncate_blake3(digest.finalize())
+        }
+    }
+}
+
+/// Prefixes keeping leaves and inner nodes of Merkle trees apart.
+const MERKLE_LEAF: u8 = 0;
+const MERKLE_NODE: u8 = 1;
+
+fn merkle_hash(hash_algorithm: HashAlgorithm, prefix: u8, data: &[u8]) -> u128 {
+    let mut input = Vec::with_capacity(data.len() + 1);
+    input.push(prefix);
+    input.extend_from_slice(data);
+    hash_algorithm.hash_block(&input)
+}
+
+fn merkle_leaf(hash_algorithm: HashAlgorithm, data: &[u8]) -> u128 {
+    merkle_hash(hash_algorithm, MERKLE_LEAF, data)
+}
+
+fn merkle_node(hash_algorithm: HashAlgorithm, left: u128, right: u128) -> u128 {
+    let mut data = [0u8; 32];
+    data[..16].copy_from_slice(&left.to_le_bytes());
+    data[16..].copy_from_slice(&right.to_le_bytes());
+    merkle_hash(hash_algorithm, MERKLE_NODE, &data)
+}
+
+/// Next level of a Merkle tree, the last node of an odd level moves up unchanged.
+fn merkle_level(hash_algorithm: HashAlgorithm, level: &[u128]) -> Vec<u128> {
+    level
+        .chunks(2)
+        .map(|pair| match *pair {
+            [left, right] => merkle_node(hash_algorithm, left, right),
+            [single] => single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Root of the Merkle tree over `leaves`, a leaf of no data when there are none.
+pub fn merkle_root(hash_algorithm: HashAlgorithm, leaves: &[u128]) -> u128 {
+    if leaves.is_empty() {
+        return merkle_leaf(hash_algorithm, &[]);
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = merkle_level(hash_algorithm, &level);
+    }
+    level[0]
+}
+
+/// Leaf of a block hash in the tree of its file.
+pub fn block_leaf(hash_algorithm: HashAlgorithm, block_hash: u128) -> u128 {
+    merkle_leaf(hash_algorithm, &block_hash.to_le_bytes())
+}
+
+fn file_leaf_of(hash_algorithm: HashAlgorithm, map: &FileMap, blocks_root: u128) -> u128 {
+    let data = bincode::serialize(&(&map.file_name, map.file_size, &map.link, blocks_root))
+        .expect("file map serializes");
+    merkle_leaf(hash_algorithm, &data)
+}
+
+/// Leaf of a file map in the tree of its resource: its name, size, link target and the
+/// root of the tree over its blocks.
+pub fn file_leaf(hash_algorithm: HashAlgorithm, map: &FileMap) -> u128 {
+    let blocks: Vec<u128> = map
+        .blocks
+        .iter()
+        .map(|&block| block_leaf(hash_algorithm, block))
+        .collect();
+    file_leaf_of(hash_algorithm, map, merkle_root(hash_algorithm, &blocks))
+}
+
+/// Hashes leading from leaf `index` of `leaves` to the root of their Merkle tree.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub leaves: u64,
+    pub index: u64,
+    pub siblings: Vec<u128>,
+}
+
+impl MerkleProof {
+    pub fn new(hash_algorithm: HashAlgorithm, leaves: &[u128], index: usize) -> Self {
+        let mut siblings = Vec::new();
+        let mut level = leaves.to_vec();
+        let mut pos = index;
+        while level.len() > 1 {
+            let sibling = pos ^ 1;
+            if sibling < level.len() {
+                siblings.push(level[sibling]);
+            }
+            level = merkle_level(hash_algorithm, &level);
+            pos /= 2;
+        }
+        MerkleProof {
+            leaves: leaves.len() as u64,
+            index: index as u64,
+            siblings,
+        }
+    }
+
+    /// Proofs of every leaf, building the tree once.
+    pub fn all(hash_algorithm: HashAlgorithm, leaves: &[u128]) -> Vec<Self> {
+        let mut levels = vec![leaves.to_vec()];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = merkle_level(hash_algorithm, &levels[levels.len() - 1]);
+            levels.push(next);
+        }
+        (0..leaves.len())
+            .map(|index| {
+                let mut pos = index;
+                let mut siblings = Vec::new();
+                for level in &levels[..levels.len() - 1] {
+                    if let Some(&sibling) = level.get(pos ^ 1) {
+                        siblings.push(sibling);
+                    }
+                    pos /= 2;
+                }
+                MerkleProof {
+                    leaves: leaves.len() as u64,
+                    index: index as u64,
+                    siblings,
+                }
+            })
+            .collect()
+    }
+
+    /// Root of the tree `leaf` is in, `None` when the proof doesn't fit its shape.
+    pub fn root(&self, hash_algorithm: HashAlgorithm, leaf: u128) -> Option<u128> {
+        if self.index >= self.leaves {
+            return None;
+        }
+        let mut siblings = self.siblings.iter();
+        let (mut node, mut pos, mut len) = (leaf, self.index, self.leaves);
+        while len > 1 {
+            if pos % 2 == 1 {
+                node = merkle_node(hash_algorithm, *siblings.next()?, node);
+            } else if pos + 1 < len {
+                node = merkle_node(hash_algorithm, node, *siblings.next()?);
+            }
+            pos /= 2;
+            len = len.div_ceil(2);
+        }
+        match siblings.next() {
+            None => Some(node),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Proof that a file map belongs to a resource, for maps ordered by name.
+pub fn file_proof(
+    hash_algorithm: HashAlgorithm,
+    maps: &[impl Borrow<FileMap>],
+    file_nr: usize,
+) -> MerkleProof {
+    let leaves: Vec<u128> = maps
+        .iter()
+        .map(|map| file_leaf(hash_algorithm, map.borrow()))
+        .collect();
+    MerkleProof::new(hash_algorithm, &leaves, file_nr)
+}
+
+/// Whether `map` belongs to the resource of Merkle root `hash`.
+pub fn verify_file(
+    hash: u128,
+    hash_algorithm: HashAlgorithm,
+    map: &FileMap,
+    proof: &MerkleProof,
+) -> bool {
+    proof.root(hash_algorithm, file_leaf(hash_algorithm, map)) == Some(hash)
+}
+
+/// Proof that a block belongs to a resource: the path to the root of its file's blocks,
+/// and the path from the file to the resource root.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockProof {
+    pub block: MerkleProof,
+    pub file: MerkleProof,
+}
+
+impl BlockProof {
+    pub fn new(
+        hash_algorithm: HashAlgorithm,
+        maps: &[impl Borrow<FileMap>],
+        file_nr: usize,
+        block_nr: usize,
+    ) -> Self {
+        let blocks: Vec<u128> = maps[file_nr]
+            .borrow()
+            .blocks
+            .iter()
+            .map(|&block| block_leaf(hash_algorithm, block))
+            .collect();
+        BlockProof {
+            block: MerkleProof::new(hash_algorithm, &blocks, block_nr),
+            file: file_proof(hash_algorithm, maps, file_nr),
+        }
+    }
+}
+
+/// Whether `block_hash` is block `proof.block.index` of the file named, sized and linked
+/// like `file` in the resource of Merkle root `hash`. Blocks of `file` are not needed.
+pub fn verify_block(
+    hash: u128,
+    hash_algorithm: HashAlgorithm,
+    file: &FileMap,
+    block_hash: u128,
+    proof: &BlockProof,
+) -> bool {
+    if proof.block.leaves != block_count(file.file_size) as u64 {
+        return false;
     }
-    extract_results(digest)
+    proof
+        .block
+        .root(hash_algorithm, block_leaf(hash_algorithm, block_hash))
+        .and_then(|blocks_root| {
+            proof.file.root(
+                hash_algorithm,
+                file_leaf_of(hash_algorithm, file, blocks_root),
+            )
+        })
+        == Some(hash)
 }
 
-pub fn hash_block(block: &[u8]) -> u128 {
-    let mut digest = sha2::Sha224::new();
-    digest.input(block);
-    extract_results(digest)
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block_hasher() {
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let mut hasher = BlockHasher::new(HashAlgorithm::Blake3);
+        for chunk in data.chunks(1000) {
+            hasher.update(chunk);
+        }
+        let file_map = hasher.finish("a".into());
+
+        assert_eq!(file_map.file_size, data.len() as u64);
+        assert_eq!(file_map.blocks.len(), block_count(file_map.file_size));
+        assert_eq!(
+            file_map.blocks,
+            vec![
+                HashAlgorithm::Blake3.hash_block(&data[..BLOCK_SIZE]),
+                HashAlgorithm::Blake3.hash_block(&data[BLOCK_SIZE..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bundle_order() {
+        let map = |name: &str, block| FileMap {
+            file_name: name.into(),
+            file_size: 1,
+            blocks: vec![block],
+            hash_algorithm: HashAlgorithm::Blake3,
+            mode: None,
+            mtime: None,
+            link: None,
+        };
+        let sorted = [map("a", 1), map("b", 2)];
+        let unsorted = [map("b", 2), map("a", 1)];
+        let hash = hash_bundles(HashAlgorithm::Blake3, &sorted);
+
+        assert_eq!(hash_bundles(HashAlgorithm::Blake3, &unsorted), hash);
+        let flat = BundleFormat::Sorted.hash(HashAlgorithm::Blake3, &unsorted);
+        assert_eq!(hash_bundles_in_order(HashAlgorithm::Blake3, &sorted), flat);
+        let legacy = hash_bundles_in_order(HashAlgorithm::Blake3, &unsorted);
+        assert_ne!(legacy, flat);
+        assert_ne!(flat, hash);
+
+        let format = |hash, maps: &[FileMap]| BundleFormat::of(hash, HashAlgorithm::Blake3, maps);
+        assert_eq!(format(hash, &unsorted), Some(BundleFormat::Merkle));
+        assert_eq!(format(flat, &unsorted), Some(BundleFormat::Sorted));
+        assert_eq!(format(legacy, &unsorted), Some(BundleFormat::InOrder));
+        assert!(bundle_matches(legacy, HashAlgorithm::Blake3, &unsorted));
+        assert!(!bundle_matches(legacy, HashAlgorithm::Blake3, &sorted));
+    }
+
+    #[test]
+    fn test_merkle_proofs() {
+        let algo = HashAlgorithm::Blake3;
+        let maps: Vec<FileMap> = (0..5u128)
+            .map(|nr| FileMap {
+                file_name: format!("f{}", nr).as_str().into(),
+                file_size: (nr as u64 + 1) * BLOCK_SIZE as u64,
+                blocks: (0..=nr).map(|block| nr * 100 + block).collect(),
+                hash_algorithm: algo,
+                mode: None,
+                mtime: None,
+                link: None,
+            })
+            .collect();
+        let hash = hash_bundles(algo, &maps);
+
+        for (file_nr, map) in maps.iter().enumerate() {
+            let proof = file_proof(algo, &maps, file_nr);
+            assert!(verify_file(hash, algo, map, &proof));
+            assert!(!verify_file(hash, algo, &maps[(file_nr + 1) % 5], &proof));
+            for (block_nr, &block) in map.blocks.iter().enumerate() {
+                let proof = BlockProof::new(algo, &maps, file_nr, block_nr);
+                assert!(verify_block(hash, algo, map, block, &proof));
+                assert!(!verify_block(hash, algo, map, block + 1, &proof));
+            }
+        }
+
+        let leaves: Vec<u128> = maps.iter().map(|map| file_leaf(algo, map)).collect();
+        let proofs: Vec<_> = (0..5).map(|nr| file_proof(algo, &maps, nr)).collect();
+        assert_eq!(MerkleProof::all(algo, &leaves), proofs);
+
+        let mut proof = BlockProof::new(algo, &maps, 4, 2);
+        assert!(!verify_block(hash, algo, &maps[3], 402, &proof));
+        proof.file.index = 3;
+        assert!(!verify_block(hash, algo, &maps[4], 402, &proof));
+        proof.file.index = 4;
+        proof.file.siblings.push(0);
+        assert!(!verify_block(hash, algo, &maps[4], 402, &proof));
+    }
+
+    #[test]
+    fn test_directory() {
+        let dir = FileMap::directory("a/b".into(), HashAlgorithm::Blake3);
+        assert_eq!(dir.file_name, "a/b/".into());
+        assert!(dir.is_dir() && dir.blocks.is_empty());
+        assert!(!BlockHasher::new(HashAlgorithm::Blake3)
+            .finish("empty".into())
+            .is_dir());
+    }
+
+    #[test]
+    fn test_link_stays_within() {
+        assert!(link_stays_within(&"a".into(), "b"));
+        assert!(link_stays_within(&"d/a".into(), "../b"));
+        assert!(link_stays_within(&"d/a".into(), "./e/../../b"));
+        assert!(!link_stays_within(&"a".into(), "../b"));
+        assert!(!link_stays_within(&"d/a".into(), "../../b"));
+        assert!(!link_stays_within(&"a".into(), "/etc/passwd"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link_resolves_within() {
+        let root = std::env::temp_dir().join(format!("hyperg-links-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("a")).unwrap();
+        create_link(root.join("a/b"), "..").unwrap();
+        create_link(root.join("c"), "a/b/..").unwrap();
+        create_link(root.join("d"), "a/b/a/missing").unwrap();
+        create_link(root.join("e"), "e").unwrap();
+
+        assert!(link_resolves_within(&root, &root.join("a/b")).unwrap());
+        assert!(!link_resolves_within(&root, &root.join("c")).unwrap());
+        assert!(link_resolves_within(&root, &root.join("d")).unwrap());
+        assert!(!link_resolves_within(&root, &root.join("e")).unwrap());
+        assert!(!has_linked_parent(&root, &root.join("a/x")));
+        assert!(has_linked_parent(&root, &root.join("a/b/x")));
+        assert!(has_linked_parent(&root, Path::new("/x")));
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
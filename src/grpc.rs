@@ -0,0 +1,397 @@
+//! gRPC interface next to the JSON RPC, served on `--grpc_port` by `with-grpc` builds, see
+//! `proto/hyperg.proto`.
+//!
+//! The service only translates messages: its calls are passed to the RPC system, which
+//! runs them like the corresponding JSON commands and answers with their JSON results.
+use crate::command::{Command, ListQuery};
+use crate::error::Error;
+use futures::channel::{mpsc, oneshot};
+use std::net::SocketAddr;
+
+/// What a gRPC call asks for.
+pub enum Action {
+    Command(Box<Command>),
+    /// Result of `GET /status`
+    Status,
+    /// Result of `GET /resources`
+    List(ListQuery),
+}
+
+pub struct Call {
+    pub action: Action,
+    pub request_id: String,
+    /// JSON result of the action
+    pub reply: oneshot::Sender<Result<serde_json::Value, Error>>,
+}
+
+/// Starts the server listening on `addr`, the calls it receives have to be answered from
+/// the returned stream. Returns the address listened on as well, with the port chosen for 0.
+#[cfg(feature = "with-grpc")]
+pub fn serve(addr: SocketAddr) -> Result<(SocketAddr, mpsc::UnboundedReceiver<Call>), Error> {
+    let incoming = tonic::transport::server::TcpIncoming::bind(addr)?;
+    let addr = incoming.local_addr()?;
+    let (calls, receiver) = mpsc::unbounded();
+    let server = tonic::transport::Server::builder()
+        .add_service(service::proto::hyperg_server::HypergServer::new(
+            service::Service { calls },
+        ))
+        .serve_with_incoming(incoming);
+    actix::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("grpc server failed: {}", e);
+        }
+    });
+    log::info!("grpc listening on {}", addr);
+    Ok((addr, receiver))
+}
+
+#[cfg(not(feature = "with-grpc"))]
+pub fn serve(_addr: SocketAddr) -> Result<(SocketAddr, mpsc::UnboundedReceiver<Call>), Error> {
+    Err(Error::Grpc("grpc support is not compiled in".to_string()))
+}
+
+#[cfg(feature = "with-grpc")]
+mod service {
+    use super::{Action, Call};
+    use crate::command::{
+        Command, DownloadResult, ListQuery, PeerInfo, StatusResult, UploadResult,
+    };
+    use crate::error::{Error, ErrorCode};
+    use futures::channel::{mpsc, oneshot};
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+    use tonic::{Code, Request, Response, Status};
+
+    pub mod proto {
+        tonic::include_proto!("hyperg.v1");
+    }
+
+    use proto::{peer, Address};
+
+    /// Metadata key of the stable code of a failed call, see `ErrorCode`.
+    const CODE_METADATA: &str = "hyperg-code";
+
+    pub struct Service {
+        pub calls: mpsc::UnboundedSender<Call>,
+    }
+
+    impl Service {
+        async fn call<T: serde::de::DeserializeOwned>(
+            &self,
+            action: Action,
+            request_id: String,
+        ) -> Result<T, Status> {
+            let (reply, answer) = oneshot::channel();
+            self.calls
+                .unbounded_send(Call {
+                    action,
+                    request_id,
+                    reply,
+                })
+                .map_err(|_| Status::unavailable("node is shutting down"))?;
+            let value = answer
+                .await
+                .map_err(|_| Status::unavailable("node is shutting down"))?
+                .map_err(|e| status_of(&e))?;
+            serde_json::from_value(value).map_err(|e| Status::internal(e.to_string()))
+        }
+    }
+
+    #[tonic::async_trait]
+    impl proto::hyperg_server::Hyperg for Service {
+        async fn upload(
+            &self,
+            request: Request<proto::UploadRequest>,
+        ) -> Result<Response<proto::UploadReply>, Status> {
+            let request_id = request_id(&request);
+            let request = request.into_inner();
+            let command = Command::Upload {
+                files: Some(
+                    request
+                        .files
+                        .into_iter()
+                        .map(|(path, name)| (path.into(), name))
+                        .collect(),
+                ),
+                timeout: request.timeout,
+                hash: None,
+                user: None,
+                token: request.token,
+                allowed_peers: Some(request.allowed_peers).filter(|peers| !peers.is_empty()),
+                encryption_key: None,
+                namespace: request.namespace,
+                case_policy: None,
+                metadata: Some(request.metadata.into_iter().collect::<BTreeMap<_, _>>())
+                    .filter(|metadata| !metadata.is_empty()),
+                valid_to: request.valid_to,
+                file_options: None,
+                id: None,
+            };
+            let result: UploadResult = self
+                .call(Action::Command(Box::new(command)), request_id)
+                .await?;
+            Ok(Response::new(proto::UploadReply {
+                hash: result.hash,
+                status: result
+                    .status
+                    .map(|status| name_of(&status))
+                    .unwrap_or_default(),
+            }))
+        }
+
+        async fn download(
+            &self,
+            request: Request<proto::DownloadRequest>,
+        ) -> Result<Response<proto::DownloadReply>, Status> {
+            let request_id = request_id(&request);
+            let request = request.into_inner();
+            let command = Command::Download {
+                hash: request.hash,
+                dest: request.dest.into(),
+                peers: request
+                    .peers
+                    .into_iter()
+                    .map(peer_info)
+                    .collect::<Result<_, _>>()?,
+                timeout: request.timeout,
+                user: None,
+                token: request.token,
+                encryption_key: None,
+                signer: request.signer,
+                share_after_download: request.share_after_download,
+                repin: false,
+                create_dest: None,
+                restore_mtime: None,
+                file_names: Default::default(),
+                case_policy: None,
+                local: parse(request.local)?,
+                verify: parse(request.verify)?,
+                write_buffer: None,
+                fsync: None,
+                size: None,
+            };
+            let result: DownloadResult = self
+                .call(Action::Command(Box::new(command)), request_id)
+                .await?;
+            let report = result.report.as_ref();
+            Ok(Response::new(proto::DownloadReply {
+                files: result
+                    .files
+                    .iter()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect(),
+                bytes: report.map_or(0, |report| report.bytes),
+                duration: report.map_or(0.0, |report| report.duration),
+            }))
+        }
+
+        async fn status(
+            &self,
+            request: Request<proto::StatusRequest>,
+        ) -> Result<Response<proto::StatusReply>, Status> {
+            let result: StatusResult = self.call(Action::Status, request_id(&request)).await?;
+            Ok(Response::new(proto::StatusReply {
+                id: result.id,
+                version: result.version,
+                shares: result.shares as u64,
+                active_downloads: result.active_downloads as u64,
+                active_connections: result.active_connections as u64,
+                cache_usage: result.cache_usage,
+                mode: result.mode.to_string(),
+            }))
+        }
+
+        async fn list(
+            &self,
+            request: Request<proto::ListRequest>,
+        ) -> Result<Response<proto::ListReply>, Status> {
+            let request_id = request_id(&request);
+            let request = request.into_inner();
+            let query = ListQuery {
+                namespace: request.namespace,
+                meta: request.meta,
+                name: request.name,
+            };
+            let resources: Vec<ListedResource> = self.call(Action::List(query), request_id).await?;
+            Ok(Response::new(proto::ListReply {
+                resources: resources
+                    .into_iter()
+                    .map(|resource| proto::Resource {
+                        hash: resource.hash,
+                        files: resource.files,
+                        total_size: resource.total_size,
+                        valid_to: resource.valid_to,
+                        paths: resource.paths,
+                        namespaces: resource.namespaces,
+                        metadata: resource.metadata.into_iter().collect(),
+                    })
+                    .collect(),
+            }))
+        }
+    }
+
+    /// Entry of the `GET /resources` answer.
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct ListedResource {
+        hash: String,
+        files: u64,
+        total_size: u64,
+        valid_to: Option<u64>,
+        paths: Vec<String>,
+        namespaces: Vec<String>,
+        metadata: BTreeMap<String, String>,
+    }
+
+    /// Id given by the client in `x-request-id`, or a new random one, like for the RPC.
+    fn request_id<T>(request: &Request<T>) -> String {
+        request
+            .metadata()
+            .get("x-request-id")
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= 64
+                    && id
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+            })
+            .map(ToString::to_string)
+            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()))
+    }
+
+    fn parse<T: FromStr<Err = Error>>(value: Option<String>) -> Result<Option<T>, Status> {
+        value
+            .map(|value| value.parse())
+            .transpose()
+            .map_err(|e| status_of(&e))
+    }
+
+    fn peer_info(peer: proto::Peer) -> Result<PeerInfo, Status> {
+        let address = |address: Option<Address>| {
+            let address =
+                address.ok_or_else(|| Status::invalid_argument("peer without address"))?;
+            let port = u16::try_from(address.port)
+                .map_err(|_| Status::invalid_argument(format!("invalid port {}", address.port)))?;
+            Ok::<_, Status>((address.host, port))
+        };
+        Ok(match peer.peer {
+            Some(peer::Peer::Tcp(tcp)) => {
+                let (host, port) = address(Some(tcp))?;
+                PeerInfo::TCP(host, port)
+            }
+            Some(peer::Peer::Node(node)) => {
+                let (host, port) = address(node.address)?;
+                PeerInfo::Node(host, port, node.node_id)
+            }
+            Some(peer::Peer::Relay(node)) => {
+                let (host, port) = address(node.address)?;
+                PeerInfo::Relay(host, port, node.node_id)
+            }
+            Some(peer::Peer::Http(url)) => PeerInfo::Http(url),
+            None => return Err(Status::invalid_argument("empty peer")),
+        })
+    }
+
+    /// Name of a unit enum variant in JSON answers.
+    fn name_of<T: serde::Serialize>(value: &T) -> String {
+        match serde_json::to_value(value) {
+            Ok(serde_json::Value::String(name)) => name,
+            _ => String::new(),
+        }
+    }
+
+    /// Status of a failed call, with the stable code of the error in `hyperg-code`.
+    fn status_of(e: &Error) -> Status {
+        let code = e.code();
+        let is = |codes: &[ErrorCode]| codes.iter().any(|&c| c as u16 == code);
+        let message = match e {
+            Error::Rpc { message, .. } => message.clone(),
+            e => e.to_string(),
+        };
+        let grpc_code = match e {
+            Error::Rpc {
+                status, code: None, ..
+            } => match status {
+                400 => Code::InvalidArgument,
+                404 => Code::NotFound,
+                503 => Code::Unavailable,
+                _ => Code::Internal,
+            },
+            _ if is(&[
+                ErrorCode::InvalidArgument,
+                ErrorCode::TruncatedBody,
+                ErrorCode::TrailingData,
+                ErrorCode::BodyChecksum,
+            ]) =>
+            {
+                Code::InvalidArgument
+            }
+            _ if is(&[ErrorCode::ResourceNotFound]) => Code::NotFound,
+            _ if is(&[ErrorCode::Unauthorized, ErrorCode::Blocked]) => Code::PermissionDenied,
+            _ if is(&[ErrorCode::NoPeers, ErrorCode::Busy, ErrorCode::Unavailable]) => {
+                Code::Unavailable
+            }
+            _ if is(&[ErrorCode::QuotaExceeded]) => Code::ResourceExhausted,
+            _ if is(&[ErrorCode::Timeout, ErrorCode::HandshakeTimeout]) => Code::DeadlineExceeded,
+            _ => Code::Internal,
+        };
+        let mut status = Status::new(grpc_code, message);
+        status
+            .metadata_mut()
+            .insert(CODE_METADATA, code.to_string().parse().unwrap());
+        status
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_status_of() {
+            let status = status_of(&Error::ResourceNotFound(1));
+            assert_eq!(status.code(), Code::NotFound);
+            assert_eq!(status.metadata().get(CODE_METADATA).unwrap(), "200");
+
+            let status = status_of(&Error::Rpc {
+                status: 400,
+                code: Some(ErrorCode::InvalidArgument as u16),
+                message: "unknown field `sizes`".to_string(),
+            });
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(status.message(), "unknown field `sizes`");
+
+            let status = status_of(&Error::Rpc {
+                status: 400,
+                code: None,
+                message: "invalid hash".to_string(),
+            });
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(status.metadata().get(CODE_METADATA).unwrap(), "901");
+        }
+
+        #[test]
+        fn test_peer_info() {
+            let node = |port| proto::Peer {
+                peer: Some(peer::Peer::Node(proto::Node {
+                    address: Some(Address {
+                        host: "10.0.0.1".to_string(),
+                        port,
+                    }),
+                    node_id: "ab".to_string(),
+                })),
+            };
+            match peer_info(node(3282)).unwrap() {
+                PeerInfo::Node(host, 3282, id) => assert_eq!((&*host, &*id), ("10.0.0.1", "ab")),
+                peer => panic!("unexpected {:?}", peer),
+            }
+            assert_eq!(
+                peer_info(node(70000)).unwrap_err().code(),
+                Code::InvalidArgument
+            );
+            assert!(peer_info(proto::Peer { peer: None }).is_err());
+        }
+    }
+}
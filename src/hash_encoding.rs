@@ -0,0 +1,159 @@
+//! Text encodings of resource hashes, node ids and tokens in the RPC API and the command
+//! line.
+//!
+//! Hex (32 digits) is the default. `base32` is the multibase encoding of the 16 big endian
+//! bytes: `b` followed by 26 lowercase RFC 4648 base32 digits without padding. Both are
+//! accepted wherever a hash is expected, whichever encoding the node emits.
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum HashEncoding {
+    #[default]
+    Hex,
+    /// Multibase base32, 27 characters.
+    Base32,
+}
+
+impl HashEncoding {
+    pub fn encode(self, hash: u128) -> String {
+        match self {
+            HashEncoding::Hex => format!("{:032x}", hash),
+            HashEncoding::Base32 => {
+                let mut encoded = String::with_capacity(27);
+                encoded.push('b');
+                for i in 0..25 {
+                    encoded.push(BASE32_ALPHABET[(hash >> (123 - 5 * i)) as usize & 31] as char);
+                }
+                // The last digit holds the lowest 3 bits followed by 2 zero bits.
+                encoded.push(BASE32_ALPHABET[(hash as usize & 7) << 2] as char);
+                encoded
+            }
+        }
+    }
+}
+
+impl fmt::Display for HashEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HashEncoding::Hex => "hex",
+            HashEncoding::Base32 => "base32",
+        })
+    }
+}
+
+impl FromStr for HashEncoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "hex" => Ok(HashEncoding::Hex),
+            "base32" => Ok(HashEncoding::Base32),
+            _ => Err(Error::InvalidArgument(format!(
+                "invalid hash encoding: {} (expected hex or base32)",
+                s
+            ))),
+        }
+    }
+}
+
+static ENCODING: AtomicU8 = AtomicU8::new(HashEncoding::Hex as u8);
+
+/// Encoding hashes are emitted in.
+pub fn current() -> HashEncoding {
+    match ENCODING.load(Ordering::SeqCst) {
+        1 => HashEncoding::Base32,
+        _ => HashEncoding::Hex,
+    }
+}
+
+pub fn set(encoding: HashEncoding) {
+    ENCODING.store(encoding as u8, Ordering::SeqCst);
+}
+
+/// `hash` in the current encoding.
+pub fn encode(hash: u128) -> String {
+    current().encode(hash)
+}
+
+/// Parses a hash in hex, multibase hex (`f` prefix) or multibase base32 (`b` prefix).
+///
+/// Base32 digits are case insensitive.
+pub fn parse(s: &str) -> Result<u128, Error> {
+    let invalid = || Error::InvalidArgument(format!("invalid hash: {}", s));
+    if s.len() == 27 && (s.starts_with('b') || s.starts_with('B')) {
+        if let Some(hash) = parse_base32(&s[1..]) {
+            return Ok(hash);
+        }
+    }
+    let hex = if s.len() == 33 && (s.starts_with('f') || s.starts_with('F')) {
+        &s[1..]
+    } else {
+        s
+    };
+    if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    u128::from_str_radix(hex, 16).map_err(|_| invalid())
+}
+
+fn parse_base32(digits: &str) -> Option<u128> {
+    let mut hash: u128 = 0;
+    for (i, b) in digits.bytes().enumerate() {
+        let digit = BASE32_ALPHABET
+            .iter()
+            .position(|&c| c == b.to_ascii_lowercase())? as u128;
+        if i < 25 {
+            hash = hash << 5 | digit;
+        } else if digit & 3 == 0 {
+            hash = hash << 3 | digit >> 2;
+        } else {
+            return None;
+        }
+    }
+    Some(hash)
+}
+
+/// `hash` as given by a node, in the current encoding when it is valid.
+pub fn reencode(hash: &str) -> String {
+    parse(hash).map(encode).unwrap_or_else(|_| hash.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let hash = 0x4d95_2518_3664_edc6_f7ff_086d_4f0b_2d42;
+        let base32 = HashEncoding::Base32.encode(hash);
+        assert_eq!(base32, "bjwkskgbwmtw4n577bbwu6cznii");
+        for encoded in &[
+            HashEncoding::Hex.encode(hash),
+            base32.clone(),
+            base32.to_uppercase(),
+            format!("f{:032x}", hash),
+        ] {
+            assert_eq!(parse(encoded).unwrap(), hash, "{}", encoded);
+        }
+        for &hash in &[0, 1, u128::MAX] {
+            assert_eq!(parse(&HashEncoding::Base32.encode(hash)).unwrap(), hash);
+        }
+        assert_eq!(parse("abc").unwrap(), 0xabc);
+        for invalid in &[
+            "",
+            "+1",
+            "xyz",
+            "bjwkskgbwmtw4n577bbwu6cznij",
+            &"1".repeat(33),
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
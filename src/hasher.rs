@@ -0,0 +1,129 @@
+use crate::error::Error;
+use crate::file_name::FileName;
+use crate::filemap::{block_count, file_mode, file_mtime, hash_file_block, FileMap, HashAlgorithm};
+use crate::supervisor::{self, Guarded, Restart};
+use actix::prelude::*;
+use futures::{future, prelude::*};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Worker hashing file blocks, run in a `SyncArbiter` pool.
+pub struct Hasher;
+
+impl Actor for Hasher {
+    type Context = SyncContext<Self>;
+}
+
+impl Restart for Hasher {
+    const NAME: &'static str = "hasher";
+
+    /// Workers keep no state.
+    fn restart(&mut self) {}
+}
+
+impl<M> Handler<Guarded<M>> for Hasher
+where
+    Self: Handler<M>,
+    M: Message + Send + 'static,
+    M::Result: Send + 'static,
+{
+    type Result = Result<M::Result, Error>;
+
+    fn handle(&mut self, msg: Guarded<M>, ctx: &mut Self::Context) -> Self::Result {
+        supervisor::handle_guarded(self, msg, ctx)
+    }
+}
+
+struct HashBlock {
+    path: Arc<PathBuf>,
+    block_no: usize,
+    file_size: u64,
+    hash_algorithm: HashAlgorithm,
+}
+
+impl Message for HashBlock {
+    type Result = Result<u128, io::Error>;
+}
+
+impl Handler<HashBlock> for Hasher {
+    type Result = Result<u128, io::Error>;
+
+    fn handle(&mut self, msg: HashBlock, _ctx: &mut Self::Context) -> Self::Result {
+        hash_file_block(
+            msg.path.as_ref(),
+            msg.block_no,
+            msg.file_size,
+            msg.hash_algorithm,
+        )
+    }
+}
+
+/// Starts a pool of `threads` hashing workers. Zero means one per CPU.
+pub fn start(threads: usize) -> Addr<Hasher> {
+    let threads = match threads {
+        0 => std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1),
+        n => n,
+    };
+    log::debug!("starting {} hash threads", threads);
+    SyncArbiter::start(threads, || Hasher)
+}
+
+/// Builds file maps for `files`, hashing all their blocks concurrently.
+///
+/// Fails if a name is not a relative path within the download directory.
+pub fn hash_files<N: Into<FileName>>(
+    hasher: &Addr<Hasher>,
+    files: impl IntoIterator<Item = (PathBuf, N)>,
+    hash_algorithm: HashAlgorithm,
+) -> impl Future<Output = Result<Vec<(FileMap, PathBuf)>, Error>> {
+    let hasher = hasher.clone();
+    let files: Result<Vec<_>, Error> = files
+        .into_iter()
+        .map(|(path, file_name)| {
+            let file_name = file_name.into();
+            file_name.check()?;
+            let file_map = FileMap {
+                file_name,
+                file_size: crate::encryption::file_size(&path)?,
+                blocks: Vec::new(),
+                hash_algorithm,
+                mode: file_mode(&path).ok(),
+                mtime: file_mtime(&path).ok(),
+                link: None,
+            };
+            Ok((Arc::new(path), file_map))
+        })
+        .collect();
+
+    async move {
+        future::try_join_all(files?.into_iter().map(move |(path, mut file_map)| {
+            let file_size = file_map.file_size;
+            let blocks: Vec<_> = (0..block_count(file_size))
+                .map(|block_no| {
+                    hasher
+                        .send(Guarded::new::<Hasher>(HashBlock {
+                            path: path.clone(),
+                            block_no,
+                            file_size,
+                            hash_algorithm,
+                        }))
+                        .map(|r| match r {
+                            Ok(Ok(hash)) => hash.map_err(Error::from),
+                            Ok(Err(e)) => Err(e),
+                            Err(e) => Err(e.into()),
+                        })
+                })
+                .collect();
+
+            future::try_join_all(blocks).map_ok(move |blocks| {
+                let path = Arc::try_unwrap(path).unwrap_or_else(|path| path.as_ref().clone());
+                file_map.blocks = blocks;
+                (file_map, path)
+            })
+        }))
+        .await
+    }
+}
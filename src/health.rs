@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Process readiness flags consulted by `/readyz`.
+#[derive(Default)]
+pub struct Health {
+    listening: AtomicBool,
+    stopping: AtomicBool,
+}
+
+impl Health {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Health::default())
+    }
+
+    pub fn set_listening(&self) {
+        self.listening.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_stopping(&self) {
+        if !self.stopping.swap(true, Ordering::SeqCst) {
+            log::info!("shutdown requested, reporting not ready");
+            notify("STOPPING=1");
+        }
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+}
+
+/// Flips the stopping flag as soon as the process receives a termination signal.
+///
+/// Signal handling itself (graceful stop of the servers) is left to actix-server.
+pub fn watch_shutdown(health: Arc<Health>) {
+    let h = health.clone();
+    actix::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            h.set_stopping();
+        }
+    });
+
+    #[cfg(unix)]
+    actix::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut sigterm) = signal(SignalKind::terminate()) {
+            sigterm.recv().await;
+            health.set_stopping();
+        }
+    });
+}
+
+/// Sends a state update to systemd when running as a `Type=notify` unit.
+///
+/// Does nothing when `NOTIFY_SOCKET` is not set.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket_path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let path = socket_path.to_string_lossy();
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(name) = path.strip_prefix('@') {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                return socket.send_to_addr(state.as_bytes(), &addr);
+            }
+        }
+        socket.send_to(state.as_bytes(), &*path)
+    });
+
+    if let Err(e) = result {
+        log::warn!("sd_notify {} failed: {}", state, e);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
@@ -0,0 +1,222 @@
+//! Plain HTTP servers as additional sources of blocks.
+//!
+//! File maps always come from a gst peer; blocks are fetched with `Range` requests
+//! and verified against them like blocks received from peers.
+use crate::client::parse_response;
+use crate::error::Error;
+use crate::file_name::FileName;
+use crate::filemap::BLOCK_SIZE;
+use actix_web::web;
+use std::cmp::min;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+const DEFAULT_PORT: u16 = 80;
+
+#[derive(Debug)]
+pub struct HttpSource {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+    failed: AtomicBool,
+}
+
+impl HttpSource {
+    /// Parses a `http://<host>[:<port>]/<path>` url.
+    ///
+    /// Urls ending with `/` name a directory with the files of the resource,
+    /// other urls the file of a single file resource.
+    pub fn parse(url: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidArgument(format!("invalid http source: {}", url));
+        if !url
+            .get(..7)
+            .map(|scheme| scheme.eq_ignore_ascii_case("http://"))
+            .unwrap_or(false)
+        {
+            return Err(invalid());
+        }
+        let rest = &url[7..];
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        // Credentials are not supported, servers are expected to be public.
+        let authority = &authority[authority.rfind('@').map(|idx| idx + 1).unwrap_or(0)..];
+        let (host, port) = match authority.rfind(':') {
+            Some(idx) if !authority.ends_with(']') => (
+                &authority[..idx],
+                authority[idx + 1..].parse().map_err(|_| invalid())?,
+            ),
+            _ => (authority, DEFAULT_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(HttpSource {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Path of `file_name` on the server, for a resource of `files` files.
+    pub fn file_path(&self, file_name: &FileName, files: usize) -> String {
+        if !self.path.ends_with('/') && files == 1 {
+            return self.path.clone();
+        }
+        let mut path = self.path.clone();
+        if !path.ends_with('/') {
+            path.push('/');
+        }
+        for &b in file_name.as_bytes() {
+            match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                    path.push(b as char)
+                }
+                b => path.push_str(&format!("%{:02X}", b)),
+            }
+        }
+        path
+    }
+
+    /// False once the source failed to deliver a valid block.
+    pub fn is_usable(&self) -> bool {
+        !self.failed.load(Ordering::Relaxed)
+    }
+
+    pub fn set_failed(&self) {
+        self.failed.store(true, Ordering::Relaxed)
+    }
+
+    /// Blocking `GET` of `len` bytes at `offset` of the file at `path`.
+    pub fn get_range(&self, path: &str, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        let error = |message: String| Error::HttpSource {
+            url: self.url.clone(),
+            message,
+        };
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let host = if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        };
+        let head = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nRange: bytes={}-{}\r\nConnection: close\r\n\r\n",
+            path,
+            host,
+            offset,
+            offset + len as u64 - 1
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.flush()?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let (status, mut body) = parse_response(&response)?;
+        match status {
+            206 => (),
+            // Servers ignoring `Range` send the whole file.
+            200 if body.len() as u64 >= offset + len as u64 => {
+                body = body[offset as usize..offset as usize + len].to_vec();
+            }
+            status => return Err(error(format!("unexpected status {}", status))),
+        }
+        if body.len() != len {
+            return Err(error(format!(
+                "expected {} bytes, received {}",
+                len,
+                body.len()
+            )));
+        }
+        Ok(body)
+    }
+
+    fn connect(&self) -> Result<TcpStream, Error> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host not found");
+        for addr in (self.host.as_str(), self.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error.into())
+    }
+}
+
+/// Fetches block `block_no` of a file of `file_size` bytes on the blocking thread pool.
+pub fn fetch_block(
+    source: std::sync::Arc<HttpSource>,
+    path: String,
+    block_no: u32,
+    file_size: u64,
+) -> impl Future<Output = Result<Vec<u8>, Error>> {
+    let offset = block_no as u64 * BLOCK_SIZE as u64;
+    let len = min(BLOCK_SIZE as u64, file_size.saturating_sub(offset)) as usize;
+    async move { web::block(move || source.get_range(&path, offset, len)).await? }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse() {
+        let source = HttpSource::parse("http://example.com:8080/data/").unwrap();
+        assert_eq!(source.host, "example.com");
+        assert_eq!(source.port, 8080);
+        assert_eq!(
+            source.file_path(&"a b/c.txt".into(), 2),
+            "/data/a%20b/c.txt"
+        );
+
+        let source = HttpSource::parse("HTTP://[::1]/file.bin").unwrap();
+        assert_eq!(source.host, "::1");
+        assert_eq!(source.port, DEFAULT_PORT);
+        assert_eq!(source.file_path(&"other.bin".into(), 1), "/file.bin");
+        assert_eq!(
+            source.file_path(&"other.bin".into(), 2),
+            "/file.bin/other.bin"
+        );
+
+        assert!(HttpSource::parse("https://example.com/").is_err());
+        assert!(HttpSource::parse("http://:80/").is_err());
+    }
+
+    #[test]
+    fn test_get_range() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let n = stream.read(&mut request).unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).into_owned();
+            stream
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\n\r\nbcd")
+                .unwrap();
+            request
+        });
+        let source = HttpSource::parse(&format!("http://127.0.0.1:{}/f", port)).unwrap();
+        assert_eq!(source.get_range("/f", 1, 3).unwrap(), b"bcd");
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /f HTTP/1.1\r\n"));
+        assert!(request.contains("Range: bytes=1-3\r\n"));
+    }
+}
@@ -0,0 +1,268 @@
+//! Ed25519 identity of the node, used to sign file maps sent in ask replies and to prove
+//! the node id to peers.
+//!
+//! Node ids of nodes created with an identity key are derived from its public key,
+//! so downloaders can check a signature against the id the peer announced in `Hello`
+//! without learning the key in advance.
+use crate::codec::{AskReply, FileMapSignature, Identify, IdentityProof};
+use crate::error::{Error, ProtocolError};
+use curve25519_dalek::MontgomeryPoint;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+const NODE_ID_CONTEXT: &str = "hyperg 2019-06 node id";
+const PROOF_CONTEXT: &[u8] = b"hyperg 2026-10 identity proof";
+const CHANNEL_KEY_CONTEXT: &str = "hyperg 2026-10 channel key";
+
+static KEY: OnceLock<SigningKey> = OnceLock::new();
+
+static SIGNING: AtomicBool = AtomicBool::new(false);
+
+/// New secret key, stored in the database metadata.
+pub fn generate_key() -> [u8; 32] {
+    rand::random()
+}
+
+/// Node id bound to the public key of `secret_key`.
+pub fn node_id(secret_key: &[u8; 32]) -> u128 {
+    node_id_of(
+        SigningKey::from_bytes(secret_key)
+            .verifying_key()
+            .as_bytes(),
+    )
+}
+
+fn node_id_of(public_key: &[u8; 32]) -> u128 {
+    let hash = blake3::derive_key(NODE_ID_CONTEXT, public_key);
+    u128::from_le_bytes(hash[..16].try_into().unwrap())
+}
+
+/// Sets the key of the node, once its id is known to be derived from it.
+pub fn set_key(secret_key: &[u8; 32]) {
+    let _ = KEY.set(SigningKey::from_bytes(secret_key));
+}
+
+/// Makes `sign` sign ask replies from now on.
+pub fn enable_signing() {
+    SIGNING.store(true, Ordering::SeqCst);
+}
+
+/// Signs the file maps of the reply if signing is enabled.
+pub fn sign(reply: &mut AskReply) {
+    match KEY.get() {
+        Some(key) if SIGNING.load(Ordering::SeqCst) => sign_with(reply, key),
+        _ => (),
+    }
+}
+
+fn sign_with(reply: &mut AskReply, key: &SigningKey) {
+    reply.signature = Some(FileMapSignature {
+        public_key: key.verifying_key().to_bytes(),
+        signature: key.sign(&reply.signed_content()).to_bytes().to_vec(),
+    });
+}
+
+/// Checks the signature of a reply received from the peer with `peer_id`, if known.
+///
+/// Returns the id of the signing node, `None` for unsigned replies.
+pub fn verify(reply: &AskReply, peer_id: Option<u128>) -> Result<Option<u128>, Error> {
+    let signature = match &reply.signature {
+        Some(signature) => signature,
+        None => return Ok(None),
+    };
+    let invalid = || ProtocolError::InvalidSignature(reply.hash).into_err();
+    // The peer's `Hello` may still be on the way, the key alone identifies the signer.
+    let signer = node_id_of(&signature.public_key);
+    if peer_id.map(|peer_id| peer_id != signer).unwrap_or(false) {
+        return Err(invalid());
+    }
+    let public_key = VerifyingKey::from_bytes(&signature.public_key).map_err(|_| invalid())?;
+    let bytes: [u8; 64] = signature
+        .signature
+        .as_slice()
+        .try_into()
+        .map_err(|_| invalid())?;
+    public_key
+        .verify_strict(&reply.signed_content(), &Signature::from_bytes(&bytes))
+        .map_err(|_| invalid())?;
+    Ok(Some(signer))
+}
+
+/// New `Identify` for a peer, with the secret of its key share.
+pub fn identify() -> (Identify, [u8; 32]) {
+    let secret: [u8; 32] = rand::random();
+    let identify = Identify {
+        nonce: rand::random(),
+        key_share: MontgomeryPoint::mul_base_clamped(secret).to_bytes(),
+    };
+    (identify, secret)
+}
+
+/// Proof of the node id for the peer with `verifier_id` that sent `identify`, with the key
+/// authenticating packets sent to it from now on, see `codec::PacketAuth`. `None` for nodes
+/// without an identity key.
+///
+/// The proof covers the key shares of both nodes, so a node passing on `Identify` of its
+/// peer and the proof can't learn the key and send packets in the name of this node.
+pub fn prove(identify: &Identify, verifier_id: u128) -> Option<(IdentityProof, [u8; 32])> {
+    KEY.get()
+        .and_then(|key| prove_with(key, identify, verifier_id, rand::random()))
+}
+
+pub(crate) fn prove_with(
+    key: &SigningKey,
+    identify: &Identify,
+    verifier_id: u128,
+    secret: [u8; 32],
+) -> Option<(IdentityProof, [u8; 32])> {
+    let key_share = MontgomeryPoint::mul_base_clamped(secret).to_bytes();
+    let content = proof_content(identify, verifier_id, &key_share);
+    let channel_key = channel_key(identify.key_share, secret, &content)?;
+    let proof = IdentityProof {
+        public_key: key.verifying_key().to_bytes(),
+        key_share,
+        signature: key.sign(&content).to_bytes().to_vec(),
+    };
+    Some((proof, channel_key))
+}
+
+fn proof_content(identify: &Identify, verifier_id: u128, key_share: &[u8; 32]) -> Vec<u8> {
+    let mut content = PROOF_CONTEXT.to_vec();
+    content.extend_from_slice(&identify.nonce.to_le_bytes());
+    content.extend_from_slice(&verifier_id.to_le_bytes());
+    content.extend_from_slice(&identify.key_share);
+    content.extend_from_slice(key_share);
+    content
+}
+
+/// Key derived from the X25519 shared secret and the signed content, `None` for key shares
+/// of low order giving no secret.
+fn channel_key(peer_share: [u8; 32], secret: [u8; 32], content: &[u8]) -> Option<[u8; 32]> {
+    let shared = MontgomeryPoint(peer_share).mul_clamped(secret);
+    if shared.as_bytes() == &[0; 32] {
+        return None;
+    }
+    let mut hasher = blake3::Hasher::new_derive_key(CHANNEL_KEY_CONTEXT);
+    hasher.update(shared.as_bytes());
+    hasher.update(content);
+    Some(*hasher.finalize().as_bytes())
+}
+
+/// Key authenticating packets of the peer with `peer_id`, if `proof` answers `identify`
+/// sent with the key share of `secret` by the node with `verifier_id`.
+pub fn verify_proof(
+    proof: &IdentityProof,
+    identify: &Identify,
+    secret: [u8; 32],
+    verifier_id: u128,
+    peer_id: u128,
+) -> Option<[u8; 32]> {
+    if node_id_of(&proof.public_key) != peer_id {
+        return None;
+    }
+    let bytes: [u8; 64] = proof.signature.as_slice().try_into().ok()?;
+    let content = proof_content(identify, verifier_id, &proof.key_share);
+    VerifyingKey::from_bytes(&proof.public_key)
+        .and_then(|public_key| public_key.verify_strict(&content, &Signature::from_bytes(&bytes)))
+        .ok()?;
+    channel_key(proof.key_share, secret, &content)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filemap::FileMap;
+
+    #[test]
+    fn test_sign() {
+        let secret_key = generate_key();
+        let key = SigningKey::from_bytes(&secret_key);
+        let file_map = FileMap {
+            file_name: "a".into(),
+            file_size: 1,
+            blocks: vec![7],
+            hash_algorithm: Default::default(),
+            mode: None,
+            mtime: None,
+            link: None,
+        };
+        let mut reply = AskReply {
+            hash: 1,
+            files: Some(vec![file_map]),
+            ..Default::default()
+        };
+        assert_eq!(verify(&reply, Some(3)).unwrap(), None);
+
+        sign_with(&mut reply, &key);
+        let id = node_id(&secret_key);
+        assert_eq!(verify(&reply, Some(id)).unwrap(), Some(id));
+        assert_eq!(verify(&reply, None).unwrap(), Some(id));
+        assert!(verify(&reply, Some(id + 1)).is_err());
+
+        reply.files.as_mut().unwrap()[0].blocks[0] = 8;
+        assert!(verify(&reply, Some(id)).is_err());
+    }
+
+    #[test]
+    fn test_prove() {
+        let secret_key = generate_key();
+        let key = SigningKey::from_bytes(&secret_key);
+        let id = node_id(&secret_key);
+        let (identify, secret) = identify();
+        let (proof, channel_key) = prove_with(&key, &identify, 9, rand::random()).unwrap();
+
+        assert_eq!(
+            verify_proof(&proof, &identify, secret, 9, id),
+            Some(channel_key)
+        );
+        let other_nonce = Identify {
+            nonce: identify.nonce + 1,
+            ..identify
+        };
+        assert!(verify_proof(&proof, &other_nonce, secret, 9, id).is_none());
+        assert!(verify_proof(&proof, &identify, secret, 10, id).is_none());
+        assert!(verify_proof(&proof, &identify, secret, 9, id + 1).is_none());
+        let low_order = Identify {
+            key_share: [0; 32],
+            ..identify
+        };
+        assert!(prove_with(&key, &low_order, 9, rand::random()).is_none());
+    }
+
+    #[test]
+    fn test_relayed_proof() {
+        let secret_key = generate_key();
+        let key = SigningKey::from_bytes(&secret_key);
+        let id = node_id(&secret_key);
+        let (identify, secret) = identify();
+
+        // A node in the middle asking with its own key share learns a key, but its proof is
+        // refused.
+        let (relayed, relay_secret) = super::identify();
+        let relayed = Identify {
+            nonce: identify.nonce,
+            ..relayed
+        };
+        let (proof, relay_key) = prove_with(&key, &relayed, 9, rand::random()).unwrap();
+        assert!(verify_proof(&proof, &identify, secret, 9, id).is_none());
+        assert_eq!(
+            verify_proof(&proof, &relayed, relay_secret, 9, id),
+            Some(relay_key)
+        );
+
+        // Passing the `Identify` on as it is, the node gets a proof the verifier accepts,
+        // but not the key the prover and the verifier share.
+        let (proof, session_key) = prove_with(&key, &identify, 9, rand::random()).unwrap();
+        assert_eq!(
+            verify_proof(&proof, &identify, secret, 9, id),
+            Some(session_key)
+        );
+        let content = proof_content(&identify, 9, &proof.key_share);
+        assert_ne!(
+            channel_key(proof.key_share, relay_secret, &content),
+            Some(session_key)
+        );
+    }
+}
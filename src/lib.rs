@@ -0,0 +1,47 @@
+//! Simple resource transfer for the Golem network: the transfer protocol, the resource
+//! database and the clients of both, shared by the `hyperg` daemon and its benchmarks.
+pub mod archive;
+pub mod audit;
+pub mod blocklist;
+pub mod cli;
+pub mod client;
+pub mod codec;
+pub mod command;
+pub mod config;
+pub mod connection;
+pub mod connection_registry;
+pub mod database;
+pub mod discovery;
+pub mod download;
+pub mod encryption;
+pub mod error;
+pub mod fd_monitor;
+pub mod file_name;
+pub mod filemap;
+pub mod grpc;
+pub mod hash_encoding;
+pub mod hasher;
+pub mod health;
+pub mod http_source;
+pub mod identity;
+pub mod log_config;
+pub mod mode;
+pub mod name_index;
+#[cfg(test)]
+mod netsim;
+pub mod openapi;
+pub mod pins;
+pub mod relay;
+pub mod rpc_body;
+pub mod serve_queue;
+pub mod server;
+pub mod startup;
+pub mod stats;
+pub mod stream;
+pub mod supervisor;
+pub mod tls;
+pub mod trace;
+pub mod user_report;
+pub mod version;
+pub mod watch;
+pub mod write_queue;
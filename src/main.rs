@@ -1,38 +1,67 @@
-use crate::codec::{hash_to_hex, Block, GetBlock};
-use crate::command::{DownloadResult, PeerInfo, UploadResult};
-use crate::database::{DatabaseManager, RegisterHash};
-use crate::download::find_peer;
-use crate::filemap::{hash_block, FileMap};
+// The `json!` of `effective_config` nests deeper than the default limit.
+#![recursion_limit = "256"]
+
 use actix::Addr;
-use actix_web::middleware::Logger;
-use actix_web::{delete, get, post, web, App, HttpResponse, HttpServer};
+use actix_cors::Cors;
+use actix_service::Service;
+use actix_web::body::{self, BoxBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use actix_web::middleware::{self, Condition, Logger};
+use actix_web::{delete, get, post, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer};
+use bytes::Bytes;
 use futures::{future, prelude::*};
+use hyperg::codec::{hash_to_hex, Block, GetBlock};
+use hyperg::command::{
+    BatchStatus, DownloadResult, FileReport, PeerInfo, UploadResult, Verification,
+};
+use hyperg::database::{Access, DatabaseManager, RegisterHash};
+use hyperg::download::{
+    find_peer_prefer_lan, part_path, BlockWriter, CopyStrategy, DownloadGuard, FsyncPolicy,
+    LocalPolicy, Peer, VerifyPolicy, WriteQueue, DEFAULT_WRITE_BUFFER, MAX_BLOCKS_IN_FLIGHT,
+};
+use hyperg::error::PeerFailure;
+use hyperg::file_name::{self, CasePolicy, NamePolicy};
+use hyperg::filemap::{BundleFormat, FileMap, HashAlgorithm, BLOCK_SIZE};
+use hyperg::startup::ExitCode;
+use hyperg::{
+    archive, audit, blocklist, cli, client, codec, command, config, connection,
+    connection_registry, database, discovery, download, encryption, error, fd_monitor, filemap,
+    grpc, hash_encoding, hasher, health, http_source, identity, log_config, mode, openapi, pins,
+    relay, rpc_body, serve_queue, server, startup, stats, stream, supervisor, tls, trace,
+    user_report, version, watch,
+};
+use tracing::Instrument;
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::io::Write;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::net::{self, IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
-mod codec;
-mod command;
-mod connection;
-pub(crate) mod database;
-mod download;
-pub(crate) mod error;
-pub(crate) mod filemap;
-mod log_config;
-mod server;
-mod user_report;
-mod version;
+/// Seconds RPC clients are asked to wait while the database is overloaded.
+const RETRY_AFTER: u64 = 1;
+
+/// Correlation id of RPC requests, taken from the request or generated.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Max size of JSON request bodies, uploads of bundles with 100k+ files list them all.
+const MAX_JSON_BODY: usize = 256 * 1024 * 1024;
+
+/// Resources of a `download_batch` downloaded at the same time unless it says otherwise.
+const BATCH_CONCURRENCY: usize = 4;
 
 #[derive(StructOpt, Clone)]
 #[structopt(raw(global_setting = "structopt::clap::AppSettings::DisableVersion"))]
 struct ServerOpts {
+    /// TOML file with defaults for flags, keys are flag names
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
     /// Database path
     #[structopt(long)]
     db: Option<PathBuf>,
@@ -57,6 +86,29 @@ struct ServerOpts {
     #[structopt(long, default_value = "3292")]
     rpc_port: u16,
 
+    /// PEM certificate chain to serve RPC over HTTPS with, needs a `with-tls` build
+    #[structopt(long, parse(from_os_str))]
+    rpc_tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--rpc_tls_cert`
+    #[structopt(long, parse(from_os_str))]
+    rpc_tls_key: Option<PathBuf>,
+
+    /// Origin of web pages allowed to call RPC from browsers, `*` for any
+    #[structopt(long = "rpc_cors_origin")]
+    rpc_cors_origins: Vec<String>,
+
+    /// Reject `/api` calls with bodies shorter than their Content-Length, not matching
+    /// their x-content-sha256 or followed by more than one JSON value, and add the
+    /// checksum to the answers
+    #[structopt(long)]
+    rpc_check_body: bool,
+
+    /// TCP port for the gRPC interface to listen on at `--rpc_host`, needs a `with-grpc`
+    /// build
+    #[structopt(long)]
+    grpc_port: Option<u16>,
+
     /// Database sweep interval in seconds
     #[structopt(long, default_value = "86400")]
     sweep_interval: u32,
@@ -65,6 +117,156 @@ struct ServerOpts {
     #[structopt(long, default_value = "86400")]
     sweep_lifetime: u32,
 
+    /// Number of threads hashing shared files, 0 for one per CPU
+    #[structopt(long, default_value = "0")]
+    hash_threads: usize,
+
+    /// Hash algorithm for new shares: sha224, or blake3 once every downloader supports it
+    #[structopt(long, default_value = "sha224")]
+    hash_algorithm: HashAlgorithm,
+
+    /// Encoding of hashes and node ids in RPC replies and client output: hex or base32,
+    /// both are accepted in requests
+    #[structopt(long, default_value = "hex")]
+    hash_encoding: hash_encoding::HashEncoding,
+
+    /// Share files placed in the directory until they are removed
+    #[structopt(long, number_of_values = 1)]
+    watch: Vec<PathBuf>,
+
+    /// Age in seconds after which leftover .part/.bak files of downloads are removed
+    #[structopt(long, default_value = "86400")]
+    artifact_max_age: u64,
+
+    /// Files of new shares smaller than this many bytes are kept in memory and sent to
+    /// peers together in one block, 0 to serve all files from disk
+    #[structopt(long, default_value = "200")]
+    inline_threshold: usize,
+
+    /// Send the packed small files of a resource with the ask reply, saving downloaders
+    /// a block request. Older peers receive the data without using it
+    #[structopt(long)]
+    inline_replies: bool,
+
+    /// Advertise this node and find peers in the local network over mDNS
+    #[structopt(long)]
+    lan_discovery: bool,
+
+    /// Serve the status page at `/ui`
+    #[structopt(long)]
+    ui: bool,
+
+    /// Stay registered at the relay, so peers can download through it from behind NAT
+    #[structopt(long)]
+    relay: Option<SocketAddr>,
+
+    /// Forward traffic for peers registered at this node
+    #[structopt(long)]
+    relay_server: bool,
+
+    /// Max number of peers registered at this relay
+    #[structopt(long, default_value = "64")]
+    relay_max_peers: usize,
+
+    /// Max MiB of blocks relayed per session in each direction
+    #[structopt(long, default_value = "1024")]
+    relay_quota_mb: u64,
+
+    /// Max number of database requests waiting, further RPC calls and asks of peers are
+    /// refused until the queue drains
+    #[structopt(long, default_value = "256")]
+    db_queue_limit: usize,
+
+    /// What to do with a database whose metadata can't be read: salvage, reset or abort
+    #[structopt(long, default_value = "salvage")]
+    db_recovery: database::Recovery,
+
+    /// How downloads place files this node shares: hardlink, reflink or copy, falling back
+    /// to the next one on other file systems. Hard linked files change with the share
+    #[structopt(long, default_value = "reflink")]
+    local_copy: CopyStrategy,
+
+    /// Seconds file maps received from peers are kept for further downloads of the
+    /// resource (0 keeps none)
+    #[structopt(long, default_value = "600")]
+    file_maps_ttl: u64,
+
+    /// Seconds a peer has to identify itself after connecting
+    #[structopt(long, default_value = "60")]
+    handshake_timeout: u64,
+
+    /// KiB/s below which a download switches to another of the given peers, 0 to keep
+    /// the first one
+    #[structopt(long, default_value = "256")]
+    min_peer_rate: u64,
+
+    /// Seconds after which connections without traffic are closed, 0 to keep them open
+    #[structopt(long, default_value = "300")]
+    idle_timeout: u64,
+
+    /// Transfer connections accepted from one IP address per minute, 0 for no limit.
+    /// Addresses exceeding it are refused for `--ip_ban` seconds
+    #[structopt(long, default_value = "120")]
+    ip_conn_rate: usize,
+
+    /// Transfer connections open from one IP address at once, 0 for no limit
+    #[structopt(long, default_value = "64")]
+    ip_max_conns: usize,
+
+    /// Seconds an address exceeding `--ip_conn_rate` is refused
+    #[structopt(long, default_value = "60")]
+    ip_ban: u64,
+
+    /// Blocks served per turn to a peer address, as `<ip>=<blocks>`; others get 1. Peers
+    /// take turns in serving their block requests
+    #[structopt(long = "peer_weight", parse(try_from_str = "parse_peer_weight"))]
+    peer_weights: Vec<(IpAddr, usize)>,
+
+    /// MiB served to peers per calendar month (UTC) after which asks for new transfers
+    /// are refused, 0 for no quota
+    #[structopt(long, default_value = "0")]
+    monthly_quota_mb: u64,
+
+    /// Make peers connecting to the transfer port find a hash with this many leading zero
+    /// bits (0 to echo a nonce) before asks are answered. Peers without support for it
+    /// can not download then
+    #[structopt(long)]
+    challenge_bits: Option<u8>,
+
+    /// Encrypt inline data and files stored in the database directory with the key in the
+    /// file, 32 random bytes or 64 hex digits, instead of HYPERG_ENCRYPTION_KEY
+    #[structopt(long, parse(from_os_str))]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Sign file maps sent to peers with the identity key of the node
+    #[structopt(long)]
+    sign_filemaps: bool,
+
+    /// Remember node ids of peers by address and refuse peers presenting another one
+    #[structopt(long)]
+    pin_peers: bool,
+
+    /// JSON file with hashes of resources to refuse serving, changed by the blocklist command
+    #[structopt(long, parse(from_os_str))]
+    blocklist: Option<PathBuf>,
+
+    /// Record every block served with the peer as a JSON line in this file
+    #[structopt(long, parse(from_os_str))]
+    audit_log: Option<PathBuf>,
+
+    /// MiB after which the audit log is rotated, 0 never rotates it
+    #[structopt(long, default_value = "100")]
+    audit_log_max_mb: u64,
+
+    /// Record only every n-th block served in the audit log
+    #[structopt(long, default_value = "1")]
+    audit_sample: u64,
+
+    /// Write spans of transfers, connections and blocks as JSON lines to this file, or
+    /// export them to the OTLP collector at this http:// url (needs a `with-otlp` build)
+    #[structopt(long)]
+    trace_output: Option<String>,
+
     /// Log to file
     #[structopt(long)]
     logfile: Option<PathBuf>,
@@ -73,14 +275,154 @@ struct ServerOpts {
     #[structopt(long, default_value = "info")]
     loglevel: log::Level,
 
+    /// Telemetry backend: none, log, sentry or sentry:<dsn>
+    #[structopt(long)]
+    telemetry: Option<user_report::Telemetry>,
+
+    /// What telemetry reports contain: off, errors, breadcrumbs or full
+    #[structopt(long, default_value = "full")]
+    telemetry_privacy: user_report::Privacy,
+
     /// Prints version information
     #[structopt(long, short)]
     version: bool,
+
+    /// Prints status of the running instance
+    #[structopt(long)]
+    status: bool,
+
+    /// Prints status as JSON
+    #[structopt(long)]
+    json: bool,
+
+    #[structopt(subcommand)]
+    command: Option<cli::ClientCommand>,
 }
 
 struct State {
     db: Addr<DatabaseManager>,
+    hasher: Addr<hasher::Hasher>,
     opts: Arc<ServerOpts>,
+    health: Arc<health::Health>,
+    config: Arc<serde_json::Value>,
+}
+
+/// Options of `State::download`, those of `Command::Download` with the defaults applied.
+#[derive(Clone)]
+struct DownloadOptions {
+    user_id: Option<String>,
+    token: Option<String>,
+    encryption_key: Option<encryption::TransferKey>,
+    signer: Option<String>,
+    share_after_download: bool,
+    repin: bool,
+    create_dest: bool,
+    restore_mtime: bool,
+    file_names: NamePolicy,
+    case_policy: CasePolicy,
+    local: LocalPolicy,
+    verify: VerifyPolicy,
+    write_buffer: usize,
+    fsync: FsyncPolicy,
+}
+
+/// Effective configuration, logged on start and served at `/config`.
+fn effective_config(opts: &ServerOpts) -> serde_json::Value {
+    let paths = |paths: &[PathBuf]| -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect()
+    };
+    let path = |path: &Option<PathBuf>| path.as_ref().map(|path| path.display().to_string());
+    let db = database::database_dir(&opts.db);
+    let db = std::env::current_dir().map_or_else(|_| db.clone(), |dir| dir.join(&db));
+    serde_json::json!({
+        "version": version::PACKAGE_VERSION,
+        "features": version::features(),
+        "config": path(&opts.config),
+        "db": db.display().to_string(),
+        "transfer": SocketAddr::new(opts.host, opts.port).to_string(),
+        "rpc": SocketAddr::new(opts.rpc_host, opts.rpc_port).to_string(),
+        "rpcTls": opts.rpc_tls_cert.is_some(),
+        "rpcCorsOrigins": opts.rpc_cors_origins,
+        "rpcCheckBody": opts.rpc_check_body,
+        "grpc": opts
+            .grpc_port
+            .map(|port| SocketAddr::new(opts.rpc_host, port).to_string()),
+        "ui": opts.ui,
+        "artifactMaxAge": opts.artifact_max_age,
+        "hashThreads": opts.hash_threads,
+        "hashAlgorithm": opts.hash_algorithm.to_string(),
+        "hashEncoding": opts.hash_encoding.to_string(),
+        "watch": paths(&opts.watch),
+        "inlineThreshold": opts.inline_threshold,
+        "inlineReplies": opts.inline_replies,
+        "lanDiscovery": opts.lan_discovery,
+        "relay": opts.relay.map(|addr| addr.to_string()),
+        "relayServer": opts.relay_server,
+        "relayMaxPeers": opts.relay_max_peers,
+        "relayQuotaMb": opts.relay_quota_mb,
+        "dbQueueLimit": opts.db_queue_limit,
+        "dbRecovery": opts.db_recovery.to_string(),
+        "localCopy": opts.local_copy.to_string(),
+        "fileMapsTtl": opts.file_maps_ttl,
+        "handshakeTimeout": opts.handshake_timeout,
+        "minPeerRate": opts.min_peer_rate,
+        "idleTimeout": opts.idle_timeout,
+        "ipConnRate": opts.ip_conn_rate,
+        "ipMaxConns": opts.ip_max_conns,
+        "ipBan": opts.ip_ban,
+        "challengeBits": opts.challenge_bits,
+        "monthlyQuotaMb": opts.monthly_quota_mb,
+        "peerWeights": opts
+            .peer_weights
+            .iter()
+            .map(|(ip, weight)| (ip.to_string(), weight))
+            .collect::<HashMap<_, _>>(),
+        "encryptionKeyFile": path(&opts.encryption_key_file),
+        "signFilemaps": opts.sign_filemaps,
+        "pinPeers": opts.pin_peers,
+        "blocklist": path(&opts.blocklist),
+        "auditLog": path(&opts.audit_log),
+        "auditLogMaxMb": opts.audit_log_max_mb,
+        "auditSample": opts.audit_sample,
+        "traceOutput": opts.trace_output,
+        "logfile": path(&opts.logfile),
+        "loglevel": opts.loglevel.to_string().to_lowercase(),
+        "telemetry": opts.telemetry.clone().unwrap_or_default().name(),
+        "telemetryPrivacy": opts.telemetry_privacy,
+    })
+}
+
+/// What to do about a database failing to open.
+fn database_hint(e: &error::Error) -> &'static str {
+    match e {
+        error::Error::IO(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            "hint: make the directory writable for this user or choose another one with --db"
+        }
+        error::Error::IO(e) if e.kind() == io::ErrorKind::NotFound => {
+            "hint: create the directory or choose another one with --db"
+        }
+        error::Error::CorruptDatabase(_) => {
+            "hint: --db_recovery salvage backs up the metadata and keeps the readable \
+             resources, --db_recovery reset starts with an empty database"
+        }
+        _ => "hint: check the disk, or move the directory away to start with an empty database",
+    }
+}
+
+/// Flags of the daemon, exiting with `ExitCode::Config` when they are invalid.
+fn parse_args(args: Vec<std::ffi::OsString>) -> ServerOpts {
+    ServerOpts::from_iter_safe(args).unwrap_or_else(|e| match e.kind {
+        structopt::clap::ErrorKind::HelpDisplayed
+        | structopt::clap::ErrorKind::VersionDisplayed => e.exit(),
+        _ => {
+            eprintln!("{}", e.message);
+            let error = e.message.lines().next().unwrap_or_default();
+            startup::exit(ExitCode::Config, error.trim_start_matches("error: "))
+        }
+    })
 }
 
 fn resolve_host(src: &str) -> Result<IpAddr, <IpAddr as FromStr>::Err> {
@@ -90,19 +432,33 @@ fn resolve_host(src: &str) -> Result<IpAddr, <IpAddr as FromStr>::Err> {
     }
 }
 
+fn parse_peer_weight(src: &str) -> Result<(IpAddr, usize), String> {
+    let mut parts = src.splitn(2, '=');
+    let ip = parts.next().unwrap_or_default();
+    let weight = parts
+        .next()
+        .ok_or_else(|| format!("expected <ip>=<blocks>, got {}", src))?;
+    let ip = ip
+        .parse()
+        .map_err(|e| format!("invalid address {}: {}", ip, e))?;
+    match weight.parse() {
+        Ok(0) | Err(_) => Err(format!("invalid weight {}", weight)),
+        Ok(weight) => Ok((ip, weight)),
+    }
+}
+
 impl State {
-    fn id(&self) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-        database::id(&self.db)
-            .and_then(|id| {
-                let id = crate::codec::hash_to_hex(id);
-                let version = version::PACKAGE_VERSION.into();
-                Ok(HttpResponse::Ok().json(command::IdResult { id, version }))
-            })
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e))
+    async fn id(&self) -> Result<HttpResponse, actix_web::Error> {
+        let id = database::id(&self.db).await.map_err(rpc_error)?;
+        Ok(HttpResponse::Ok().json(command::IdResult {
+            id: hash_encoding::encode(id),
+            version: version::PACKAGE_VERSION.into(),
+            bundle_format: filemap::BUNDLE_FORMAT,
+        }))
     }
 
-    fn addresses(&self) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-        future::ok(HttpResponse::Ok().json(command::AddressesResult {
+    async fn addresses(&self) -> Result<HttpResponse, actix_web::Error> {
+        Ok(HttpResponse::Ok().json(command::AddressesResult {
             addresses: command::AddressSpec::TCP {
                 address: self.opts.host.to_string(),
                 port: self.opts.port,
@@ -110,427 +466,2956 @@ impl State {
         }))
     }
 
-    fn upload(
+    #[allow(clippy::too_many_arguments)]
+    async fn upload(
         &self,
         files: impl IntoIterator<Item = (PathBuf, String)>,
         timeout: Option<f64>,
+        user_id: Option<String>,
+        access: Access,
+        namespace: Option<String>,
+        case_policy: CasePolicy,
+        metadata: database::ResourceMetadata,
         reporter: user_report::UserReportHandle,
-    ) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-        let hashed: Result<Vec<(filemap::FileMap, PathBuf)>, _> = files
-            .into_iter()
-            .map(|(path, file_name)| Ok((filemap::hash_file(&path, file_name)?, path)))
-            .collect();
-
-        let db = self.db.clone();
-
-        hashed.into_future().and_then(move |file_maps| {
-            let inline_data = if file_maps.len() == 1 {
-                if file_maps[0].0.file_size < 200 {
-                    match std::fs::read(&file_maps[0].1) {
-                        Ok(v) => v,
-                        Err(e) => return future::Either::B(future::err(e.into())),
-                    }
-                } else {
-                    Vec::new()
-                }
-            } else {
-                Vec::new()
-            };
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let upload = async {
+            let file_maps = hasher::hash_files(&self.hasher, files, self.opts.hash_algorithm)
+                .await
+                .map_err(rpc_error)?;
+            register(
+                self.db.clone(),
+                file_maps,
+                timeout,
+                user_id,
+                access,
+                namespace,
+                case_policy,
+                metadata,
+                reporter,
+            )
+            .await
+        };
+        upload.instrument(tracing::info_span!("upload")).await
+    }
 
-            // We do not trust timeout value for now.
-            // Keeping file hash for 3 days should be good enough.
-            let valid_to = Some(
-                SystemTime::now()
-                    + Duration::from_secs(
-                        timeout.unwrap_or_else(|| 3600.0 * 24.0 * 3f64).ceil() as u64
-                    ),
-            );
+    async fn hash(
+        &self,
+        files: HashMap<PathBuf, String>,
+        hash_algorithm: Option<String>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let hash_algorithm = hash_algorithm
+            .map(|name| name.parse())
+            .transpose()
+            .map_err(actix_web::error::ErrorBadRequest)?
+            .unwrap_or(self.opts.hash_algorithm);
 
-            future::Either::A(
-                db.send(RegisterHash {
-                    files: file_maps,
-                    valid_to,
-                    inline_data,
-                    reporter,
+        let mut file_maps = hasher::hash_files(&self.hasher, files, hash_algorithm)
+            .await
+            .map_err(rpc_error)?;
+        filemap::sort_bundle(&mut file_maps);
+        let hash = filemap::hash_bundles(
+            hash_algorithm,
+            file_maps.iter().map(|(file_map, _)| file_map),
+        );
+        Ok(HttpResponse::Ok().json(command::HashResult {
+            hash: hash_encoding::encode(hash),
+            hash_algorithm: hash_algorithm.to_string(),
+            files: file_maps
+                .into_iter()
+                .map(|(file_map, path)| command::HashedFile {
+                    path,
+                    file_name: file_map.file_name.to_string(),
+                    file_size: file_map.file_size,
+                    blocks: file_map
+                        .blocks
+                        .into_iter()
+                        .map(hash_encoding::encode)
+                        .collect(),
                 })
-                .then(|r| match r {
-                    Err(_e) => Err(actix_web::error::ErrorInternalServerError("database lost")),
-                    Ok(Err(e)) => Err(actix_web::error::ErrorInternalServerError(e)),
-                    Ok(Ok(hash)) => Ok(HttpResponse::Ok().json(UploadResult {
-                        hash: hash_to_hex(hash),
-                    })),
-                }),
-            )
-        })
+                .collect(),
+        }))
     }
 
-    fn check(
-        &self,
-        hash: &str,
-    ) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-        let db = self.db.clone();
-        u128::from_str_radix(hash, 16)
-            .into_future()
-            .map_err(|_e| actix_web::error::ErrorBadRequest("hash not found"))
-            .and_then(move |hash| {
-                db.send(database::GetHash(hash))
-                    .flatten()
-                    .map_err(actix_web::error::ErrorInternalServerError)
-            })
-            .and_then(|r: Option<(Arc<database::FileDesc>, _)>| {
-                if let Some((desc, _)) = r {
-                    Ok(HttpResponse::Ok().json(UploadResult {
-                        hash: hash_to_hex(desc.map_hash),
-                    }))
-                } else {
-                    Err(actix_web::error::ErrorBadRequest("hash not found"))
-                }
-            })
+    async fn check(&self, hash: &str) -> Result<HttpResponse, actix_web::Error> {
+        let hash = hash_encoding::parse(hash)
+            .map_err(|_e| actix_web::error::ErrorBadRequest("hash not found"))?;
+        let r: Option<(Arc<database::FileDesc>, _)> =
+            database::call(&self.db, database::GetHash(hash))
+                .await
+                .map_err(rpc_error)?;
+        match r {
+            Some((desc, _)) => Ok(HttpResponse::Ok().json(UploadResult {
+                hash: hash_encoding::encode(desc.map_hash),
+                status: None,
+                previous_valid_to: None,
+                case_collisions: Vec::new(),
+            })),
+            None => Err(actix_web::error::ErrorBadRequest("hash not found")),
+        }
     }
 
-    fn download(
+    async fn download(
         &self,
         hash: String,
         dest: PathBuf,
-        peers: Vec<PeerInfo>,
-        _timeout: Option<f64>,
+        peers: Vec<PeerAddress>,
+        http_sources: Vec<Arc<http_source::HttpSource>>,
+        options: DownloadOptions,
         reporter: user_report::UserReportHandle,
-    ) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-        let hash = match u128::from_str_radix(&hash, 16) {
-            Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
-            Ok(hash) => hash,
-        };
+    ) -> Result<DownloadResult, error::Error> {
+        let DownloadOptions {
+            user_id,
+            token,
+            encryption_key,
+            signer,
+            share_after_download,
+            repin,
+            create_dest,
+            restore_mtime,
+            file_names,
+            case_policy,
+            local,
+            verify,
+            write_buffer,
+            fsync,
+        } = options;
+        let hash = hash_encoding::parse(&hash)?;
+        let token = token
+            .as_ref()
+            .map(|token| hash_encoding::parse(token))
+            .transpose()?;
+        let signer = signer
+            .as_ref()
+            .map(|signer| hash_encoding::parse(signer))
+            .transpose()?;
 
-        let peers: HashSet<_> = match peers
-            .into_iter()
-            .map(|peer_info| match peer_info {
-                PeerInfo::TCP(address, port) => Ok(SocketAddr::new(address.parse()?, port)),
-            })
-            .collect::<Result<_, std::net::AddrParseError>>()
-        {
-            Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
-            Ok(addrs) => addrs,
+        if peers.is_empty() && !http_sources.is_empty() {
+            return Err(error::Error::InvalidArgument(
+                "http sources need a peer providing file maps".into(),
+            ));
+        }
+        if local != LocalPolicy::Never {
+            let found = database::call(&self.db, database::GetHash(hash)).await?;
+            let copied = found.map(|(desc, _)| {
+                reporter.add_note(|| "copying the shared resource".to_string());
+                copy_local(
+                    &desc,
+                    &dest,
+                    create_dest,
+                    restore_mtime,
+                    file_names,
+                    case_policy,
+                    encryption_key,
+                    self.opts.local_copy,
+                    verify != VerifyPolicy::Off,
+                    &reporter,
+                )
+            });
+            match copied {
+                Some(Err(e @ error::Error::CorruptShare { .. })) => {
+                    let quarantine = database::Quarantine {
+                        hash,
+                        reason: e.to_string(),
+                    };
+                    database::call(&self.db, quarantine).await?;
+                    reporter.emit_warn(e.to_string());
+                    if local == LocalPolicy::Only || peers.is_empty() {
+                        return Err(e);
+                    }
+                }
+                Some(r) => return r,
+                None if local == LocalPolicy::Only || peers.is_empty() => {
+                    return Err(error::Error::InvalidArgument("hash not found".into()));
+                }
+                None => (),
+            }
+        } else if peers.is_empty() {
+            return Err(error::Error::InvalidArgument(
+                "no peers to download from".into(),
+            ));
+        }
+        let download_guard = DownloadGuard::new();
+        let started = Instant::now();
+        let db = self.db.clone();
+        // Fair share of the connection for each block in flight.
+        let slow_block = match self.opts.min_peer_rate {
+            0 => None,
+            rate => Some(Duration::from_millis(
+                (MAX_BLOCKS_IN_FLIGHT * BLOCK_SIZE) as u64 * 1000 / (rate * 1024),
+            )),
         };
 
-        future::Either::A(
-            find_peer(
+        let download = async {
+            let (peers, resolve_failures) = resolve_peers(peers).await?;
+            let alternatives = peers.clone();
+            let lan_peers = discovery::lan_peers()
+                .into_iter()
+                .filter(|peer| !peers.iter().flatten().any(|p| p.connect_addr() == *peer))
+                .collect();
+
+            let ask_options = download::AskOptions {
+                token,
+                signer,
+                repin,
+                unverified: verify == VerifyPolicy::Off,
+            };
+            let (connection, file_map, peer) = find_peer_prefer_lan(
                 hash,
-                self.db.clone(),
-                peers.into_iter().collect(),
+                ask_options,
+                db.clone(),
+                lan_peers,
+                peers,
                 reporter.clone(),
             )
-            .and_then(move |(connection, file_map, peer): (_, Vec<FileMap>, _)| {
-                use futures::prelude::*;
-                reporter.add_note(|| "got connection!".to_string());
-                reporter.annotate("peer", &peer);
-
-                futures::stream::iter_ok(file_map.into_iter().enumerate())
-                    .and_then(move |(file_no, file_map)| {
-                        let reporter = reporter.clone();
-                        let hash = hash;
-                        let out_path = dest.join(&file_map.file_name);
-                        let connection = connection.clone();
-
-                        if out_path.exists() {
-                            reporter
-                                .emit_warn(format!("path: {} already exists", out_path.display()));
-                            log::warn!("path: {} already exists", out_path.display());
-                            let _ = std::fs::rename(&out_path, out_path.with_extension("bak"));
-                        }
+            .await
+            .map_err(|e| match e {
+                error::Error::NoPeers(hash, mut failures) => {
+                    failures.extend(resolve_failures);
+                    error::Error::NoPeers(hash, failures)
+                }
+                e => e,
+            })?;
+
+            reporter.add_note(|| "got connection!".to_string());
+            reporter.annotate("peer", &peer.to_string());
+            let switch_db = db.clone();
+            let switch_reporter = reporter.clone();
+            let block_source = download::BlockSource::new(
+                connection,
+                peer,
+                alternatives,
+                slow_block,
+                move |peers| {
+                    download::find_peer(
+                        hash,
+                        ask_options,
+                        switch_db.clone(),
+                        peers,
+                        switch_reporter.clone(),
+                    )
+                },
+                reporter.clone(),
+            );
+            let shared_maps = if share_after_download {
+                Some(file_map.clone())
+            } else {
+                None
+            };
+
+            let files_count = file_map.len();
+            let verification = match file_map.first() {
+                _ if verify == VerifyPolicy::Off => Verification::Unverified,
+                Some(first) if !filemap::bundle_matches(hash, first.hash_algorithm, &file_map) => {
+                    return Err(error::Error::BundleMismatch(hash));
+                }
+                _ => Verification::Verified,
+            };
+            let proofs = match verification {
+                Verification::Verified => download::BundleProofs::new(hash, &file_map),
+                _ => None,
+            };
+            // Names the system can't store fail the download before anything is written.
+            let mut names: Vec<_> = file_map
+                .iter()
+                .map(|file_map| file_map.file_name.clone())
+                .collect();
+            let case_collisions = resolve_case(&mut names, case_policy, &reporter)?;
+            let out_paths = names
+                .iter()
+                .map(|name| name.to_path(&dest, file_names))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut local_files = match local {
+                LocalPolicy::Never => vec![None; files_count],
+                _ => database::request(&db, database::FindFiles(file_map.clone())).await?,
+            };
+            let sources = download::SourceStats::default();
+            let mut reports = Vec::with_capacity(files_count);
+            let mut written = Vec::new();
+            let mut links = Vec::new();
+            // Small files packed by the peer arrive in one block, saving a request each.
+            let mut inline_files: HashMap<u32, Bytes> =
+                match block_source.get_inline_files(hash).await {
+                    Ok(files) => files.into_iter().collect(),
+                    Err(e) => {
+                        log::warn!("inline files failed, fetching them by block: {}", e);
+                        HashMap::new()
+                    }
+                };
+
+            for (file_no, (file_map, out_path)) in file_map.into_iter().zip(out_paths).enumerate() {
+                // Maps and blocks of Merkle bundles are checked against the hash one by one.
+                let file_proofs = proofs
+                    .as_ref()
+                    .map(|proofs| proofs.file(file_no, &file_map))
+                    .transpose()?;
+                let placed = |path| FileReport {
+                    path,
+                    size: 0,
+                    blocks: 0,
+                    verification,
+                    copy: None,
+                };
+                if file_map.is_dir() {
+                    download::place_dir(&dest, &out_path, create_dest)?;
+                    reports.push(placed(out_path));
+                    continue;
+                }
+                if out_path.exists() {
+                    reporter.emit_warn(format!("path: {} already exists", out_path.display()));
+                    log::warn!("path: {} already exists", out_path.display());
+                    let bak_path = out_path.with_extension("bak");
+                    if std::fs::rename(&out_path, &bak_path).is_ok() {
+                        database::notify(&db, database::TrackArtifact(bak_path));
+                    }
+                }
+
+                if let Some(target) = file_map.link {
+                    links.push((out_path.clone(), file_map.file_name, target));
+                    reports.push(placed(out_path));
+                    continue;
+                }
 
-                        std::fs::OpenOptions::new()
-                            .write(true)
-                            .create_new(true)
-                            .open(&out_path)
-                            .into_future()
-                            .from_err()
-                            .and_then(move |mut out_file| {
-                                let block_reporter = reporter.clone();
-                                futures::stream::iter_ok(file_map.blocks.into_iter().enumerate())
-                                    .and_then(move |(block_no, block_hash_val)| {
-                                        reporter.add_note(|| {
-                                            format!(
-                                                "start block block_no:{}, block_hash: {:032x}",
-                                                block_no, block_hash_val
-                                            )
-                                        });
-                                        connection
-                                            .send(GetBlock {
-                                                hash,
-                                                file_nr: file_no as u32,
-                                                block_nr: block_no as u32,
-                                            })
-                                            // min 110Kb/s
-                                            .timeout(Duration::from_secs(300))
-                                            .flatten()
-                                            .and_then(move |b| {
-                                                let block_hash_calc =
-                                                    hash_block(b.bytes.as_slice());
-                                                if block_hash_calc == block_hash_val {
-                                                    Ok(b)
-                                                } else {
-                                                    Err(crate::error::Error::InvalidBlockHash(
-                                                        block_hash_calc,
-                                                    ))
-                                                }
-                                            })
+                let part_path = part_path(&out_path);
+                let _ = std::fs::remove_file(&part_path);
+                database::notify(&db, database::TrackArtifact(part_path.clone()));
+
+                download::prepare_dest(&dest, &out_path, create_dest)?;
+                let out_file = BlockWriter::create(&part_path, file_map.file_size)?;
+                let hash_algorithm = file_map.hash_algorithm;
+                let file_size = file_map.file_size;
+                let blocks = file_map.blocks.len();
+                let mut inline_bytes = inline_files
+                    .remove(&(file_no as u32))
+                    .filter(|_| blocks == 1);
+                let mode = file_map.mode;
+                let mtime = file_map.mtime.filter(|_| restore_mtime);
+                let http_sources: Vec<_> = http_sources
+                    .iter()
+                    .map(|source| {
+                        let path = source.file_path(&file_map.file_name, files_count);
+                        (source.clone(), path)
+                    })
+                    .collect();
+                let check_blocks = verify == VerifyPolicy::Full;
+                let file_proofs = file_proofs.as_ref();
+                let check_file = (verify == VerifyPolicy::EndOfFile).then(|| file_map.clone());
+
+                // Blocks of files this node shares already are copied, the rest fetched.
+                let missing = match local_files[file_no].take() {
+                    Some((shared, path)) => {
+                        reporter.add_note(|| format!("copying {}", path.display()));
+                        let out_file = out_file.clone();
+                        let blocks = file_map.blocks;
+                        let (missing, copied_sizes) = web::block(move || {
+                            download::copy_shared_blocks(&path, &shared, blocks, &out_file)
+                        })
+                        .await?;
+                        for size in copied_sizes {
+                            sources.add(download::LOCAL_SOURCE.to_string(), size);
+                        }
+                        missing
+                    }
+                    None => file_map.blocks.into_iter().enumerate().collect(),
+                };
+                let mut fetched = futures::stream::iter(missing)
+                    .map(|(block_no, block_hash_val)| {
+                        let span =
+                            tracing::debug_span!("block", file_nr = file_no, block_nr = block_no);
+                        reporter.add_note(|| {
+                            format!(
+                                "start block block_no:{}, block_hash: {:032x}",
+                                block_no, block_hash_val
+                            )
+                        });
+                        let verify = move |b: Block| {
+                            if !check_blocks {
+                                return Ok(b);
+                            }
+                            let block_hash_calc = hash_algorithm.hash_block(b.bytes.as_ref());
+                            let valid = match file_proofs {
+                                Some(proofs) => proofs.verify(block_no, block_hash_calc),
+                                None => block_hash_calc == block_hash_val,
+                            };
+                            if valid {
+                                Ok(b)
+                            } else {
+                                Err(error::Error::InvalidBlockHash(block_hash_calc))
+                            }
+                        };
+                        let get_block = {
+                            let block_source = block_source.clone();
+                            move || {
+                                block_source
+                                    .get_block(GetBlock {
+                                        hash,
+                                        file_nr: file_no as u32,
+                                        block_nr: block_no as u32,
                                     })
-                                    .for_each(move |b: Block| {
-                                        block_reporter.add_note(|| {
-                                            format!("writing block block_no:{}", b.block_nr)
-                                        });
-                                        out_file.write_all(b.bytes.as_slice())?;
-                                        Ok(())
+                                    .map(move |r| {
+                                        let (b, peer) = r?;
+                                        Ok::<_, error::Error>((verify(b)?, peer.to_string()))
                                     })
-                                    .and_then(|()| Ok(out_path))
-                            })
+                            }
+                        };
+                        if let Some(bytes) = inline_bytes.take() {
+                            let b = verify(Block {
+                                hash,
+                                file_nr: file_no as u32,
+                                block_nr: block_no as u32,
+                                bytes,
+                            });
+                            let peer = block_source.peer().to_string();
+                            return async move {
+                                match b {
+                                    Ok(b) => Ok((b, peer)),
+                                    Err(e) => {
+                                        log::warn!("inline block failed, fetching it: {}", e);
+                                        get_block().await
+                                    }
+                                }
+                            }
+                            .instrument(span)
+                            .boxed_local();
+                        }
+                        // Blocks are spread over the HTTP sources and the peer.
+                        let fetch = match http_sources
+                            .get(block_no % (http_sources.len() + 1))
+                            .filter(|(source, _)| source.is_usable())
+                        {
+                            Some((source, path)) => {
+                                let source = source.clone();
+                                let fetch = http_source::fetch_block(
+                                    source.clone(),
+                                    path.clone(),
+                                    block_no as u32,
+                                    file_size,
+                                );
+                                async move {
+                                    let b = fetch.await.and_then(|bytes| {
+                                        verify(Block {
+                                            hash,
+                                            file_nr: file_no as u32,
+                                            block_nr: block_no as u32,
+                                            bytes: bytes.into(),
+                                        })
+                                    });
+                                    match b {
+                                        Ok(b) => Ok((b, source.url().to_string())),
+                                        Err(e) => {
+                                            log::warn!(
+                                                "block {} from {} failed, using peer: {}",
+                                                block_no,
+                                                source.url(),
+                                                e
+                                            );
+                                            source.set_failed();
+                                            get_block().await
+                                        }
+                                    }
+                                }
+                                .boxed_local()
+                            }
+                            None => get_block().boxed_local(),
+                        };
+                        fetch.instrument(span).boxed_local()
                     })
-                    .collect()
-                    .and_then(|files| Ok(HttpResponse::Ok().json(DownloadResult { files: files })))
+                    .buffer_unordered(MAX_BLOCKS_IN_FLIGHT);
+                let mut writes = WriteQueue::new(out_file, write_buffer);
+                while let Some(r) = fetched.next().await {
+                    let (b, source): (Block, String) = r?;
+                    reporter.add_note(|| format!("writing block block_no:{}", b.block_nr));
+                    stats::fetched(hash, user_id.clone(), b.bytes.len());
+                    sources.add(source, b.bytes.len());
+                    writes.write(b.block_nr, b.bytes).await?;
+                }
+                drop(fetched);
+                writes.finish(fsync == FsyncPolicy::File).await?;
+                // Hashed in one pass, as the encrypted content was.
+                if let Some(check_file) = check_file {
+                    let (bad_block, check_file) = {
+                        let part_path = part_path.clone();
+                        web::block(move || {
+                            let file = std::fs::File::open(&part_path)?;
+                            Ok::<_, io::Error>((
+                                download::find_bad_block(file, &check_file)?,
+                                check_file,
+                            ))
+                        })
+                        .await??
+                    };
+                    if let Some(block_no) = bad_block {
+                        log::warn!(
+                            "block {} of {} doesn't match",
+                            block_no,
+                            check_file.file_name
+                        );
+                        let _ = std::fs::remove_file(&part_path);
+                        return Err(error::Error::InvalidBlockHash(
+                            check_file.blocks.get(block_no).copied().unwrap_or_default(),
+                        ));
+                    }
+                }
+
+                match encryption_key {
+                    Some(key) => {
+                        encryption::decrypt_transfer(&part_path, &out_path, key)?;
+                        std::fs::remove_file(&part_path)?;
+                    }
+                    None => std::fs::rename(&part_path, &out_path)?,
+                }
+                if let Some(mtime) = mtime {
+                    filemap::set_file_mtime(&out_path, mtime)?;
+                }
+                if let Some(mode) = mode {
+                    filemap::set_file_mode(&out_path, mode)?;
+                }
+                database::notify(&db, database::ReleaseArtifact(part_path));
+                written.push(out_path.clone());
+                reports.push(FileReport {
+                    path: out_path,
+                    size: file_size,
+                    blocks,
+                    verification,
+                    copy: None,
+                });
+            }
+            download::place_links(&dest, &links, create_dest)?;
+
+            if fsync == FsyncPolicy::Transfer {
+                download::sync_files(written).await?;
+            }
+            let files: Vec<PathBuf> = reports.iter().map(|report| report.path.clone()).collect();
+            let report = download::report(started, reports, sources.take(), signer);
+            match block_source.peer() {
+                Peer::Direct(addr) | Peer::Identified { addr, .. } => {
+                    download::remember_peer(hash, addr)
+                }
+                Peer::Relayed { .. } => (),
+            }
+            if let Some(file_maps) = shared_maps {
+                share_downloaded(
+                    db.clone(),
+                    hash,
+                    file_maps.into_iter().zip(files.iter().cloned()).collect(),
+                    user_id.clone(),
+                    reporter.clone(),
+                )
+                .await?;
+            }
+            Ok(DownloadResult {
+                files,
+                report: Some(report),
+                case_collisions,
             })
-            .map_err(actix_web::error::ErrorInternalServerError),
-        )
+        };
+
+        let span = tracing::info_span!(
+            "transfer",
+            hash = %hash_encoding::encode(hash),
+            dest = %dest.display(),
+        );
+        let r = download.instrument(span).await;
+        drop(download_guard);
+        r
     }
 
-    fn mimic_download(
+    async fn replicate(
         &self,
         hash: String,
+        targets: Vec<String>,
         dest: PathBuf,
-    ) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-        let hash = match u128::from_str_radix(&hash, 16) {
-            Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
-            Ok(hash) => hash,
-        };
+        peers: Option<Vec<PeerInfo>>,
+        request_id: String,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let map_hash = hash_encoding::parse(&hash).map_err(actix_web::error::ErrorBadRequest)?;
+        let targets = targets
+            .iter()
+            .map(|target| target.parse::<SocketAddr>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(actix_web::error::ErrorBadRequest)?;
+        let host = self.opts.host;
+        let port = self.opts.port;
+
+        let r: Option<(Arc<database::FileDesc>, _)> =
+            database::call(&self.db, database::GetHash(map_hash))
+                .await
+                .map_err(rpc_error)?;
+        let (file_desc, _) =
+            r.ok_or_else(|| actix_web::error::ErrorBadRequest("hash not found"))?;
+        let token = file_desc.access.token.map(hash_to_hex);
+        let targets = future::join_all(targets.into_iter().map(|target| {
+            let request_id = request_id.clone();
+            let command = command::Command::Download {
+                hash: hash_to_hex(map_hash),
+                dest: dest.clone(),
+                peers: match &peers {
+                    Some(peers) => peers.clone(),
+                    None => vec![local_peer(host, port, target)],
+                },
+                timeout: None,
+                user: None,
+                token: token.clone(),
+                encryption_key: None,
+                signer: None,
+                share_after_download: true,
+                repin: false,
+                create_dest: None,
+                restore_mtime: None,
+                file_names: NamePolicy::default(),
+                case_policy: None,
+                local: None,
+                verify: None,
+                write_buffer: None,
+                fsync: None,
+                size: None,
+            };
+            let client = client::RpcClient::new(target).with_request_id(request_id.clone());
+            async move {
+                let e = match web::block(move || client.call::<DownloadResult>(&command)).await {
+                    Ok(Ok(result)) => {
+                        return command::ReplicaStatus {
+                            target: target.to_string(),
+                            files: result.files,
+                            error: None,
+                        }
+                    }
+                    Ok(Err(e)) => e.to_string(),
+                    Err(e) => e.to_string(),
+                };
+                log::warn!("[{}] replication to {} failed: {}", request_id, target, e);
+                command::ReplicaStatus {
+                    target: target.to_string(),
+                    files: Vec::new(),
+                    error: Some(e),
+                }
+            }
+        }))
+        .await;
+        Ok(HttpResponse::Ok().json(command::ReplicateResult { targets }))
+    }
 
+    async fn check_availability(
+        &self,
+        hash: String,
+        peers: Vec<PeerInfo>,
+        timeout: Option<f64>,
+        token: Option<String>,
+        signer: Option<String>,
+        reporter: user_report::UserReportHandle,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let (hash, token, signer) = hash_encoding::parse(&hash)
+            .and_then(|hash| {
+                let token = token
+                    .map(|token| hash_encoding::parse(&token))
+                    .transpose()?;
+                let signer = signer
+                    .map(|signer| hash_encoding::parse(&signer))
+                    .transpose()?;
+                Ok((hash, token, signer))
+            })
+            .map_err(actix_web::error::ErrorBadRequest)?;
+        // HTTP sources can't tell whether they have the resource.
+        let (peers, _http_sources) = parse_peers(peers)?;
+        let timeout = timeout.map(Duration::from_secs_f64);
         let db = self.db.clone();
-        future::Either::A(
-            db.send(database::GetHash(hash))
-                .flatten()
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e))
-                .and_then(|o: Option<(Arc<database::FileDesc>, _)>| {
-                    o.ok_or_else(|| actix_web::error::ErrorBadRequest("hash not found"))
-                        .into_future()
-                        .from_err()
-                        .and_then(|(desc, _)| {
-                            futures::stream::iter_ok(desc.files.to_vec().into_iter().enumerate())
-                                .and_then(move |(_, (file_map, path_buf))| {
-                                    let out_path = dest.join(&file_map.file_name);
-
-                                    if let Some(parent) = out_path.parent() {
-                                        // Copy fails either way if the parent path does not exist
-                                        let _ = fs::create_dir_all(parent);
-                                    }
 
-                                    fs::copy(path_buf, out_path.clone())
-                                        .into_future()
-                                        .map_err(|e| actix_web::error::ErrorInternalServerError(e))
-                                        .and_then(|_| Ok(out_path))
-                                })
-                                .collect()
-                        })
-                        .and_then(|files| Ok(HttpResponse::Ok().json(DownloadResult { files })))
-                })
-                .map_err(actix_web::error::ErrorInternalServerError),
-        )
+        let availability = async {
+            let (peers, resolve_failures) = resolve_peers(peers).await?;
+            let options = download::AskOptions {
+                token,
+                signer,
+                ..Default::default()
+            };
+            let (found, mut failures) =
+                download::check_availability(hash, options, db, peers, timeout, reporter).await?;
+            failures.extend(resolve_failures);
+            Ok::<_, error::Error>((found, failures))
+        };
+        let (found, failures) = availability.await.map_err(rpc_error)?;
+        let peers: Vec<_> = found
+            .into_iter()
+            .map(|(peer, files)| command::PeerAvailability {
+                peer: peer.to_string(),
+                size: files.iter().map(|file_map| file_map.file_size).sum(),
+                files: files.len(),
+            })
+            .collect();
+        Ok(HttpResponse::Ok().json(command::AvailabilityResult {
+            hash: hash_encoding::encode(hash),
+            available: !peers.is_empty(),
+            size: peers.first().map(|peer| peer.size),
+            files: peers.first().map(|peer| peer.files),
+            peers,
+            failures,
+        }))
     }
 }
 
-#[post("/api")]
-fn api(
-    state: web::Data<State>,
-    body: web::Json<command::Command>,
-) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::error::Error>> {
-    body.0.log_start();
-    match body.0 {
-        command::Command::Id => Box::new(state.id()),
-        command::Command::Addresses => Box::new(state.addresses()),
-        command::Command::Upload {
-            files: Some(files),
-            timeout,
-            hash: None,
-            user,
-        } => {
-            let reporter = user_report::UserReportHandle::start(&user);
-            reporter.annotate("api", &("upload", &files, timeout));
-            Box::new(reporter.wrap_future("upload", state.upload(files, timeout, reporter.clone())))
-        }
-        command::Command::Upload {
-            files: None,
-            timeout,
-            hash: Some(hash),
-            user,
-            ..
-        } => {
-            let reporter = user_report::UserReportHandle::start(&user);
-            reporter.annotate("api", &("check", &hash, timeout));
-            Box::new(reporter.wrap_future("check", state.check(&hash)))
-        }
-        command::Command::Download {
-            hash,
-            dest,
-            peers,
-            timeout,
-            user,
-        } => {
-            let reporter = user_report::UserReportHandle::start(&user);
-            reporter.annotate("api", &("download", &hash, &dest, &peers, timeout));
-            if peers.len() == 0 {
-                // Legacy HyperG behaviour:
-                // If no peers were provided, mimic the download process by copying locally stored files
-                Box::new(reporter.wrap_future("mimic_download", state.mimic_download(hash, dest)))
-            } else {
-                Box::new(reporter.wrap_future(
-                    "download",
-                    state.download(hash, dest, peers, timeout, reporter.clone()),
-                ))
+/// Downloads a resource shared by this node by copying its files.
+#[allow(clippy::too_many_arguments)]
+fn copy_local(
+    desc: &database::FileDesc,
+    dest: &Path,
+    create_dest: bool,
+    restore_mtime: bool,
+    file_names: NamePolicy,
+    case_policy: CasePolicy,
+    encryption_key: Option<encryption::TransferKey>,
+    copy_strategy: CopyStrategy,
+    verify: bool,
+    reporter: &user_report::UserReportHandle,
+) -> Result<DownloadResult, error::Error> {
+    let started = Instant::now();
+    let mut names: Vec<_> = desc
+        .files
+        .iter()
+        .map(|(file_map, _)| file_map.file_name.clone())
+        .collect();
+    let case_collisions = resolve_case(&mut names, case_policy, reporter)?;
+    let mut reports = Vec::with_capacity(desc.files.len());
+    let mut links = Vec::new();
+    for ((file_map, path_buf), name) in desc.files.iter().cloned().zip(names) {
+        let out_path = name.to_path(dest, file_names)?;
+
+        let copy = match &file_map.link {
+            _ if file_map.is_dir() => {
+                download::place_dir(dest, &out_path, create_dest)?;
+                None
             }
-        }
-        other_command => {
-            log::warn!("bad command: {:?}", other_command);
-            Box::new(future::err(actix_web::error::ErrorBadRequest(format!(
-                "invalid command"
-            ))))
-        }
+            Some(target) => {
+                links.push((out_path.clone(), file_map.file_name.clone(), target.clone()));
+                None
+            }
+            None => {
+                download::prepare_dest(dest, &out_path, create_dest)?;
+                let copy = match encryption_key {
+                    Some(key) => {
+                        encryption::decrypt_transfer(&path_buf, &out_path, key)?;
+                        CopyStrategy::Copy
+                    }
+                    None => download::copy_shared_file(&path_buf, &out_path, copy_strategy)?,
+                };
+                let bad_block = match encryption_key {
+                    _ if !verify => None,
+                    // The encrypted copy is what was hashed.
+                    Some(_) => download::find_bad_block(
+                        encryption::StoredFile::open(&path_buf)?,
+                        &file_map,
+                    )?,
+                    None => download::find_bad_block(std::fs::File::open(&out_path)?, &file_map)?,
+                };
+                if let Some(block) = bad_block {
+                    // A hard link is the shared file itself.
+                    if copy != CopyStrategy::HardLink {
+                        let _ = std::fs::remove_file(&out_path);
+                    }
+                    return Err(error::Error::CorruptShare {
+                        hash: desc.map_hash,
+                        file: file_map.file_name.to_string(),
+                        block,
+                    });
+                }
+                // A hard link has the times and mode of the share already.
+                if copy != CopyStrategy::HardLink {
+                    if let Some(mtime) = file_map.mtime.filter(|_| restore_mtime) {
+                        filemap::set_file_mtime(&out_path, mtime)?;
+                    }
+                    if let Some(mode) = file_map.mode {
+                        filemap::set_file_mode(&out_path, mode)?;
+                    }
+                }
+                Some(copy)
+            }
+        };
+        reports.push(FileReport {
+            path: out_path,
+            size: file_map.file_size,
+            blocks: file_map.blocks.len(),
+            verification: if verify {
+                Verification::Copied
+            } else {
+                Verification::Unverified
+            },
+            copy,
+        });
     }
+    download::place_links(dest, &links, create_dest)?;
+    let files = reports.iter().map(|report| report.path.clone()).collect();
+    let report = download::report(started, reports, Vec::new(), None);
+    Ok(DownloadResult {
+        files,
+        report: Some(report),
+        case_collisions,
+    })
 }
 
-#[get("/resources")]
-fn list_resources(
-    state: web::Data<State>,
-) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-    //Box::new(
-    state
-        .db
-        .send(database::List::default())
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))
-        .and_then(|resources| {
-            let output: Vec<serde_json::Value> = resources
-                .into_iter()
-                .map(|resource| {
-                    let hash = hash_to_hex(resource.map_hash);
-                    let n_files = resource.files.len();
-                    let size: u64 = resource
-                        .files
-                        .iter()
-                        .map(|(file_map, _)| file_map.file_size)
-                        .sum();
-                    let valid_to = resource
-                        .valid_to
-                        .and_then(|ts| Some(ts.duration_since(UNIX_EPOCH).ok()?.as_secs()));
-
-                    serde_json::json!({
-                        "hash": hash,
-                        "files": n_files,
-                        "totalSize": size,
-                        "validTo": valid_to
-                    })
-                })
-                .collect();
-
-            Ok(HttpResponse::Ok().json(output))
-        })
+/// Shares already hashed files.
+#[allow(clippy::too_many_arguments)]
+async fn register(
+    db: Addr<DatabaseManager>,
+    mut file_maps: Vec<(FileMap, PathBuf)>,
+    timeout: Option<f64>,
+    user_id: Option<String>,
+    access: Access,
+    namespace: Option<String>,
+    case_policy: CasePolicy,
+    mut metadata: database::ResourceMetadata,
+    reporter: user_report::UserReportHandle,
+) -> Result<HttpResponse, actix_web::Error> {
+    // Sorted first, so the same file keeps its name whatever order it was given in.
+    filemap::sort_bundle(&mut file_maps);
+    let file_metadata: Vec<_> = file_maps
+        .iter()
+        .map(|(file_map, _)| metadata.files.remove(&file_map.file_name.to_string()))
+        .collect();
+    let case_collisions = resolve_case(
+        file_maps
+            .iter_mut()
+            .map(|(file_map, _)| &mut file_map.file_name),
+        case_policy,
+        &reporter,
+    )
+    .map_err(rpc_error)?;
+    // Renamed files keep their metadata.
+    metadata.files = file_maps
+        .iter()
+        .zip(file_metadata)
+        .filter_map(|((file_map, _), file)| Some((file_map.file_name.to_string(), file?)))
+        .collect();
+    let registration = register_hash(
+        db,
+        file_maps,
+        timeout,
+        access,
+        BundleFormat::Merkle,
+        namespace,
+        metadata,
+        reporter,
+    )
+    .await
+    .map_err(rpc_error)?;
+    stats::set_owner(registration.hash, user_id);
+    Ok(HttpResponse::Ok().json(UploadResult {
+        hash: hash_encoding::encode(registration.hash),
+        status: Some(registration.status),
+        previous_valid_to: registration
+            .previous_valid_to
+            .and_then(|ts| Some(ts.duration_since(UNIX_EPOCH).ok()?.as_secs())),
+        case_collisions,
+    }))
 }
 
-#[get("/resources/{resourceId}")]
-fn get_resource_info(
-    state: web::Data<State>,
-    path: web::Path<(String,)>,
-) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-    let hash = match u128::from_str_radix(&path.0, 16) {
-        Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
-        Ok(hash) => hash,
+/// Applies `case_policy` to the names of a bundle, warning about names differing only
+/// in case.
+fn resolve_case<'a>(
+    names: impl IntoIterator<Item = &'a mut file_name::FileName>,
+    case_policy: CasePolicy,
+    reporter: &user_report::UserReportHandle,
+) -> Result<Vec<command::CaseCollisionReport>, error::Error> {
+    let collisions = file_name::resolve_case(names, case_policy)?;
+    for collision in &collisions {
+        let message = match &collision.renamed_to {
+            Some(renamed_to) => format!(
+                "file name {:?} differs only in case from {:?}, renamed to {:?}",
+                collision.name, collision.collides_with, renamed_to
+            ),
+            None => format!(
+                "file name {:?} differs only in case from {:?}",
+                collision.name, collision.collides_with
+            ),
+        };
+        log::warn!("{}", message);
+        reporter.emit_warn(message);
+    }
+    Ok(collisions.into_iter().map(Into::into).collect())
+}
+
+/// Adds already hashed files to the database.
+///
+/// See `RegisterHash` for `bundle_format`.
+#[allow(clippy::too_many_arguments)]
+async fn register_hash(
+    db: Addr<DatabaseManager>,
+    mut file_maps: Vec<(FileMap, PathBuf)>,
+    timeout: Option<f64>,
+    access: Access,
+    bundle_format: BundleFormat,
+    namespace: Option<String>,
+    metadata: database::ResourceMetadata,
+    reporter: user_report::UserReportHandle,
+) -> Result<database::Registration, error::Error> {
+    let hash_algorithm = match file_maps.first() {
+        Some((file_map, _)) => file_map.hash_algorithm,
+        None => HashAlgorithm::default(),
+    };
+    if !bundle_format.keeps_order() {
+        // Inline files are numbered in the order the database keeps them.
+        filemap::sort_bundle(&mut file_maps);
+    }
+    let (inline_data, inline_files) = database::pack_inline(&file_maps)?;
+
+    // We do not trust timeout value for now.
+    // Keeping file hash for 3 days should be good enough.
+    let valid_to = Some(
+        SystemTime::now()
+            + Duration::from_secs(timeout.unwrap_or_else(|| 3600.0 * 24.0 * 3f64).ceil() as u64),
+    );
+
+    database::call(
+        &db,
+        RegisterHash {
+            files: file_maps,
+            valid_to,
+            inline_data,
+            inline_files,
+            hash_algorithm,
+            access,
+            reporter,
+            bundle_format,
+            namespace,
+            metadata,
+        },
+    )
+    .await
+}
+
+/// Transfer address of this node as seen from `target`.
+fn local_peer(host: IpAddr, port: u16, target: SocketAddr) -> PeerInfo {
+    let ip = if host.is_unspecified() {
+        // Connecting a UDP socket only picks the route, nothing is sent.
+        let bind_addr: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        std::net::UdpSocket::bind(bind_addr)
+            .and_then(|socket| {
+                socket.connect(target)?;
+                socket.local_addr()
+            })
+            .map(|addr| addr.ip())
+            .unwrap_or(host)
+    } else {
+        host
+    };
+    PeerInfo::TCP(ip.to_string(), port)
+}
+
+/// Shares downloaded files under the hash they were downloaded by.
+async fn share_downloaded(
+    db: Addr<DatabaseManager>,
+    hash: u128,
+    file_maps: Vec<(FileMap, PathBuf)>,
+    user_id: Option<String>,
+    reporter: user_report::UserReportHandle,
+) -> Result<(), error::Error> {
+    // Peers are not required to send maps matching the hash they were asked for.
+    let hash_algorithm = match file_maps.first() {
+        Some((file_map, _)) => file_map.hash_algorithm,
+        None => HashAlgorithm::default(),
     };
+    let maps: Vec<&FileMap> = file_maps.iter().map(|(map, _)| map).collect();
+    // Resources hashed in older formats keep them, so they are shared under the same hash.
+    let bundle_format = match BundleFormat::of(hash, hash_algorithm, &maps) {
+        Some(bundle_format) => bundle_format,
+        None => {
+            return Err(error::Error::InvalidArgument(format!(
+                "file maps of {:032x} do not match the hash, not sharing",
+                hash
+            )))
+        }
+    };
+    let hash = register_hash(
+        db,
+        file_maps,
+        None,
+        Access::default(),
+        bundle_format,
+        None,
+        database::ResourceMetadata::default(),
+        reporter,
+    )
+    .await?
+    .hash;
+    log::info!("sharing downloaded {:032x}", hash);
+    stats::set_owner(hash, user_id);
+    Ok(())
+}
+
+/// Shares encrypted copies of the files kept in the database directory.
+async fn encrypt_files(
+    db_dir: &Path,
+    files: HashMap<PathBuf, String>,
+    key: encryption::TransferKey,
+) -> Result<Vec<(PathBuf, String)>, actix_web::Error> {
+    let dir = db_dir.join("encrypted").join(hash_to_hex(rand::random()));
+
+    web::block(move || {
+        fs::create_dir_all(&dir)?;
+        let encrypted = files
+            .into_iter()
+            .enumerate()
+            .map(|(file_no, (path, file_name))| {
+                let encrypted_path = dir.join(file_no.to_string());
+                encryption::encrypt_for_transfer(&path, &encrypted_path, key)?;
+                Ok((encrypted_path, file_name))
+            })
+            .collect::<io::Result<Vec<_>>>();
+        if encrypted.is_err() {
+            let _ = fs::remove_dir_all(&dir);
+        }
+        encrypted
+    })
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?
+    .map_err(actix_web::error::ErrorInternalServerError)
+}
+
+/// Host name or ip, port and, for peers registered at a relay, node id of a gst peer.
+type PeerAddress = (String, u16, PeerNodeId);
+
+/// Node id given with the address of a peer.
+#[derive(Clone, Copy)]
+enum PeerNodeId {
+    Any,
+    /// The peer has to present it.
+    Expected(u128),
+    /// The peer is registered under it at the relay on the address.
+    Relayed(u128),
+}
+
+/// Splits download sources into gst peers and HTTP sources.
+fn parse_peers(
+    peers: Vec<PeerInfo>,
+) -> Result<(Vec<PeerAddress>, Vec<Arc<http_source::HttpSource>>), actix_web::error::Error> {
+    let mut gst_peers = Vec::new();
+    let mut http_sources = Vec::new();
+    for peer_info in peers {
+        match peer_info {
+            PeerInfo::TCP(host, port) => gst_peers.push((host, port, PeerNodeId::Any)),
+            PeerInfo::Node(host, port, node_id) => {
+                let node_id =
+                    hash_encoding::parse(&node_id).map_err(actix_web::error::ErrorBadRequest)?;
+                gst_peers.push((host, port, PeerNodeId::Expected(node_id)));
+            }
+            PeerInfo::Relay(host, port, node_id) => {
+                let node_id =
+                    hash_encoding::parse(&node_id).map_err(actix_web::error::ErrorBadRequest)?;
+                gst_peers.push((host, port, PeerNodeId::Relayed(node_id)));
+            }
+            PeerInfo::Http(url) => {
+                let source = http_source::HttpSource::parse(&url)
+                    .map_err(actix_web::error::ErrorBadRequest)?;
+                http_sources.push(Arc::new(source));
+            }
+        }
+    }
+    Ok((gst_peers, http_sources))
+}
+
+/// Resolves host names of peers to all their addresses, connections race between them.
+///
+/// Names that can't be resolved are returned as failures.
+async fn resolve_peers(
+    peers: Vec<PeerAddress>,
+) -> Result<(Vec<Vec<Peer>>, Vec<PeerFailure>), error::Error> {
+    let results = future::join_all(peers.into_iter().map(|(host, port, node_id)| async move {
+        match download::resolve(host.clone(), port).await {
+            Ok(addrs) => Ok(addrs
+                .into_iter()
+                .map(|addr| match node_id {
+                    PeerNodeId::Any => Peer::Direct(addr),
+                    PeerNodeId::Expected(node_id) => Peer::Identified { addr, node_id },
+                    PeerNodeId::Relayed(node_id) => Peer::Relayed {
+                        relay: addr,
+                        node_id,
+                    },
+                })
+                .collect::<Vec<_>>()),
+            Err(e) => {
+                let name = match node_id {
+                    PeerNodeId::Any => format!("{}:{}", host, port),
+                    PeerNodeId::Expected(node_id) => {
+                        format!("{}:{}#{:032x}", host, port, node_id)
+                    }
+                    PeerNodeId::Relayed(node_id) => {
+                        format!("{:032x}@{}:{}", node_id, host, port)
+                    }
+                };
+                log::warn!("peer {}: {}", name, e);
+                Err(PeerFailure::new(name, &e))
+            }
+        }
+    }))
+    .await;
+
+    let mut seen = HashSet::new();
+    let mut peers = Vec::new();
+    let mut failures = Vec::new();
+    for result in results {
+        match result {
+            Ok(mut addrs) => {
+                addrs.retain(|peer| seen.insert(*peer));
+                if !addrs.is_empty() {
+                    peers.push(addrs);
+                }
+            }
+            Err(failure) => failures.push(failure),
+        }
+    }
+    Ok((peers, failures))
+}
 
-    future::Either::A(
-        state
-            .db
-            .send(database::GetHash(hash))
+/// Metadata of an upload with files keyed by their names, see `register`.
+fn parse_metadata(
+    metadata: Option<BTreeMap<String, String>>,
+    valid_to: Option<u64>,
+    file_options: Option<HashMap<PathBuf, command::FileOptions>>,
+    files: &HashMap<PathBuf, String>,
+) -> Result<database::ResourceMetadata, actix_web::error::Error> {
+    let now = SystemTime::now();
+    let expiry = |valid_to: Option<u64>| {
+        valid_to
+            .map(|secs| {
+                let valid_to = UNIX_EPOCH + Duration::from_secs(secs);
+                if valid_to <= now {
+                    return Err(actix_web::error::ErrorBadRequest(format!(
+                        "valid_to {} is in the past",
+                        secs
+                    )));
+                }
+                Ok(valid_to)
+            })
+            .transpose()
+    };
+    let check_keys = |values: &BTreeMap<String, String>| {
+        // `=` separates keys from values in the `meta` filter of `GET /resources`.
+        match values
+            .keys()
+            .find(|key| key.is_empty() || key.contains('='))
+        {
+            Some(key) => Err(actix_web::error::ErrorBadRequest(format!(
+                "invalid metadata key {:?}",
+                key
+            ))),
+            None => Ok(()),
+        }
+    };
+    let values = metadata.unwrap_or_default();
+    check_keys(&values)?;
+    let mut parsed = database::ResourceMetadata {
+        values,
+        files: BTreeMap::new(),
+        valid_to: expiry(valid_to)?,
+    };
+    for (path, options) in file_options.unwrap_or_default() {
+        let name = files.get(&path).ok_or_else(|| {
+            actix_web::error::ErrorBadRequest(format!(
+                "file_options of {} which is not in files",
+                path.display()
+            ))
+        })?;
+        check_keys(&options.metadata)?;
+        parsed.files.insert(
+            file_name::FileName::from(name.as_str()).to_string(),
+            database::FileMetadata {
+                values: options.metadata,
+                valid_to: expiry(options.valid_to)?,
+            },
+        );
+    }
+    Ok(parsed)
+}
+
+fn parse_encryption_key(
+    encryption_key: Option<String>,
+) -> Result<Option<encryption::TransferKey>, actix_web::error::Error> {
+    encryption_key
+        .map(|key| encryption::TransferKey::new(&key).map_err(actix_web::error::ErrorBadRequest))
+        .transpose()
+}
+
+/// Outcome of a resource of `download_batch`, failures listed like by `download_error`.
+fn batch_resource_result(
+    hash: String,
+    r: Result<DownloadResult, error::Error>,
+) -> command::BatchResourceResult {
+    match r {
+        Ok(result) => command::BatchResourceResult {
+            hash,
+            status: BatchStatus::Done,
+            result: Some(result),
+            error: None,
+            code: None,
+            peers: Vec::new(),
+        },
+        Err(e) => command::BatchResourceResult {
+            hash,
+            status: BatchStatus::Failed,
+            result: None,
+            error: Some(e.to_string()),
+            code: Some(e.code()),
+            peers: match e {
+                error::Error::NoPeers(_, failures) => failures,
+                _ => Vec::new(),
+            },
+        },
+    }
+}
+
+/// Lists per-peer failures, so the caller can skip or retry particular peers.
+fn download_error(e: error::Error) -> actix_web::error::Error {
+    match e {
+        error::Error::NoPeers(hash, ref failures) => {
+            let response = HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string(),
+                "code": e.code(),
+                "hash": hash_encoding::encode(hash),
+                "peers": failures,
+            }));
+            actix_web::error::InternalError::from_response(e, response).into()
+        }
+        e => rpc_error(e),
+    }
+}
+
+/// Error response with the message and the stable code of the error.
+fn rpc_error(e: error::Error) -> actix_web::error::Error {
+    let mut response = match e {
+        error::Error::InvalidArgument(_)
+        | error::Error::InvalidJsonFormat(_)
+        | error::Error::InvalidCommand { .. }
+        | error::Error::TruncatedBody { .. }
+        | error::Error::TrailingData(_)
+        | error::Error::BodyChecksum(_) => HttpResponse::BadRequest(),
+        error::Error::Unavailable(_) => HttpResponse::ServiceUnavailable(),
+        error::Error::Busy => {
+            let mut response = HttpResponse::ServiceUnavailable();
+            response.insert_header(("Retry-After", RETRY_AFTER.to_string()));
+            response
+        }
+        _ => HttpResponse::InternalServerError(),
+    };
+    let (command, schema) = match &e {
+        error::Error::InvalidCommand { command, .. } => {
+            (Some(command.clone()), openapi::command_schema(command))
+        }
+        _ => (None, None),
+    };
+    let response = response.json(command::ErrorResult {
+        error: e.to_string(),
+        code: e.code(),
+        command,
+        schema,
+    });
+    actix_web::error::InternalError::from_response(e, response).into()
+}
+
+fn parse_access(
+    token: Option<String>,
+    allowed_peers: Option<Vec<String>>,
+) -> Result<Access, actix_web::error::Error> {
+    let parse_hash = |s: &str| hash_encoding::parse(s).map_err(actix_web::error::ErrorBadRequest);
+    Ok(Access {
+        token: token.as_ref().map(|token| parse_hash(token)).transpose()?,
+        peers: allowed_peers
+            .iter()
             .flatten()
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e))
-            .and_then(|r| match r {
-                None => Ok(HttpResponse::NotFound().body("resource not found")),
-                Some((file_desc, _)) => {
-                    let mut size: u64 = 0;
-                    let files: Vec<(String, String)> = file_desc
-                        .files
-                        .iter()
-                        .map(|(file_map, path)| {
-                            size += file_map.file_size;
-                            (path.display().to_string(), file_map.file_name.clone())
-                        })
-                        .collect();
-                    let valid_to = file_desc
-                        .valid_to
-                        .map(|ts| ts.duration_since(UNIX_EPOCH).unwrap().as_secs());
-
-                    Ok(HttpResponse::Ok().json(serde_json::json!({
-                        "hash": hash_to_hex(file_desc.map_hash),
-                        "files": files,
-                        "totalSize": size,
-                        "validTo": valid_to
-                    })))
+            .map(|peer| parse_hash(peer))
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn request_id_of(request: &HttpRequest) -> String {
+    request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_default()
+}
+
+#[post("/api")]
+async fn api(
+    state: web::Data<State>,
+    body: Bytes,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let request_id = request_id_of(&request);
+    let command = command::Command::parse(&body).map_err(|e| {
+        log::warn!("[{}] {}", request_id, e);
+        rpc_error(e)
+    })?;
+    run_command(state, command, request_id).await
+}
+
+/// Runs a command of `/api` or of the `/api/v2` routes.
+async fn run_command(
+    state: web::Data<State>,
+    command: command::Command,
+    request_id: String,
+) -> Result<HttpResponse, actix_web::Error> {
+    command.log_start(&request_id);
+    match command {
+        command::Command::Id => state.id().await,
+        command::Command::Addresses => state.addresses().await,
+        command::Command::Upload {
+            files: Some(files),
+            timeout,
+            hash: None,
+            user,
+            token,
+            allowed_peers,
+            encryption_key,
+            namespace,
+            case_policy,
+            metadata,
+            valid_to,
+            file_options,
+            id: _,
+        } => {
+            mode::check_share().map_err(rpc_error)?;
+            let case_policy = case_policy.unwrap_or_default();
+            let metadata = parse_metadata(metadata, valid_to, file_options, &files)?;
+            let access = parse_access(token, allowed_peers)?;
+            let encryption_key = parse_encryption_key(encryption_key)?;
+            let reporter = user_report::UserReportHandle::start(&user).with_request_id(&request_id);
+            reporter.annotate("api", &("upload", &files, timeout));
+            if let Some(namespace) = &namespace {
+                reporter.annotate("namespace", namespace);
+            }
+            let user_id = user.as_ref().map(|u| u.id.clone());
+            match encryption_key {
+                Some(key) => {
+                    let db_dir = database::database_dir(&state.opts.db);
+                    let upload = async {
+                        let files = encrypt_files(&db_dir, files, key).await?;
+                        state
+                            .upload(
+                                files,
+                                timeout,
+                                user_id,
+                                access,
+                                namespace,
+                                case_policy,
+                                metadata,
+                                reporter.clone(),
+                            )
+                            .await
+                    };
+                    reporter.wrap_future("upload", upload).await
+                }
+                None => {
+                    reporter
+                        .wrap_future(
+                            "upload",
+                            state.upload(
+                                files,
+                                timeout,
+                                user_id,
+                                access,
+                                namespace,
+                                case_policy,
+                                metadata,
+                                reporter.clone(),
+                            ),
+                        )
+                        .await
+                }
+            }
+        }
+        command::Command::Upload {
+            files: None,
+            timeout,
+            hash: Some(hash),
+            user,
+            ..
+        } => {
+            let reporter = user_report::UserReportHandle::start(&user).with_request_id(&request_id);
+            reporter.annotate("api", &("check", &hash, timeout));
+            reporter.wrap_future("check", state.check(&hash)).await
+        }
+        command::Command::Download {
+            hash,
+            dest,
+            peers,
+            timeout,
+            user,
+            token,
+            encryption_key,
+            signer,
+            share_after_download,
+            repin,
+            create_dest,
+            restore_mtime,
+            file_names,
+            case_policy,
+            local,
+            verify,
+            write_buffer,
+            fsync,
+            size: _,
+        } => {
+            let create_dest = create_dest.unwrap_or(true);
+            let case_policy = case_policy.unwrap_or_default();
+            let restore_mtime = restore_mtime.unwrap_or(true);
+            if !create_dest && !dest.is_dir() {
+                return Err(rpc_error(error::Error::InvalidArgument(format!(
+                    "destination directory {} doesn't exist",
+                    dest.display()
+                ))));
+            }
+            mode::check_transfer()
+                .and_then(|()| {
+                    if share_after_download {
+                        mode::check_share()
+                    } else {
+                        Ok(())
+                    }
+                })
+                .map_err(rpc_error)?;
+            let encryption_key = parse_encryption_key(encryption_key)?;
+            if share_after_download && encryption_key.is_some() {
+                // Decrypted files no longer match the file maps.
+                return Err(actix_web::error::ErrorBadRequest(
+                    "share_after_download can't be used with encryption_key",
+                ));
+            }
+            let reporter = user_report::UserReportHandle::start(&user).with_request_id(&request_id);
+            reporter.annotate("api", &("download", &hash, &dest, &peers, timeout));
+            // Legacy HyperG behaviour: without peers locally stored files are copied.
+            let local = local.unwrap_or(if peers.is_empty() {
+                LocalPolicy::Only
+            } else {
+                LocalPolicy::First
+            });
+            let (peers, http_sources) = parse_peers(peers)?;
+            let user_id = user.as_ref().map(|u| u.id.clone());
+            let download = state
+                .download(
+                    hash,
+                    dest,
+                    peers,
+                    http_sources,
+                    DownloadOptions {
+                        user_id,
+                        token,
+                        encryption_key,
+                        signer,
+                        share_after_download,
+                        repin,
+                        create_dest,
+                        restore_mtime,
+                        file_names,
+                        case_policy,
+                        local,
+                        verify: verify.unwrap_or_default(),
+                        write_buffer: write_buffer.unwrap_or(DEFAULT_WRITE_BUFFER),
+                        fsync: fsync.unwrap_or_default(),
+                    },
+                    reporter.clone(),
+                )
+                .map(|r| {
+                    r.map(|result| HttpResponse::Ok().json(result))
+                        .map_err(download_error)
+                });
+            reporter.wrap_future("download", download).await
+        }
+        command::Command::DownloadBatch {
+            resources,
+            peers,
+            timeout: _,
+            user,
+            concurrency,
+            signer,
+            share_after_download,
+            repin,
+            create_dest,
+            restore_mtime,
+            file_names,
+            case_policy,
+            local,
+            verify,
+            write_buffer,
+            fsync,
+        } => {
+            mode::check_transfer()
+                .and_then(|()| {
+                    if share_after_download {
+                        mode::check_share()
+                    } else {
+                        Ok(())
+                    }
+                })
+                .map_err(rpc_error)?;
+            let local = local.unwrap_or(if peers.is_empty() {
+                LocalPolicy::Only
+            } else {
+                LocalPolicy::First
+            });
+            if peers.is_empty() && local == LocalPolicy::Never {
+                return Err(actix_web::error::ErrorBadRequest(
+                    "download_batch needs peers",
+                ));
+            }
+            let create_dest = create_dest.unwrap_or(true);
+            let mut hashes = HashSet::new();
+            let mut keys = Vec::with_capacity(resources.len());
+            for resource in &resources {
+                // Asks on a connection are told apart by the hash.
+                let hash = hash_encoding::parse(&resource.hash).map_err(rpc_error)?;
+                if !hashes.insert(hash) {
+                    return Err(actix_web::error::ErrorBadRequest(format!(
+                        "{} is listed twice",
+                        resource.hash
+                    )));
                 }
-            }),
+                if !create_dest && !resource.dest.is_dir() {
+                    return Err(rpc_error(error::Error::InvalidArgument(format!(
+                        "destination directory {} doesn't exist",
+                        resource.dest.display()
+                    ))));
+                }
+                let key = parse_encryption_key(resource.encryption_key.clone())?;
+                if share_after_download && key.is_some() {
+                    return Err(actix_web::error::ErrorBadRequest(
+                        "share_after_download can't be used with encryption_key",
+                    ));
+                }
+                keys.push(key);
+            }
+            let (peers, http_sources) = parse_peers(peers)?;
+            let options = DownloadOptions {
+                user_id: user.as_ref().map(|u| u.id.clone()),
+                token: None,
+                encryption_key: None,
+                signer,
+                share_after_download,
+                repin,
+                create_dest,
+                restore_mtime: restore_mtime.unwrap_or(true),
+                file_names,
+                case_policy: case_policy.unwrap_or_default(),
+                local,
+                verify: verify.unwrap_or_default(),
+                write_buffer: write_buffer.unwrap_or(DEFAULT_WRITE_BUFFER),
+                fsync: fsync.unwrap_or_default(),
+            };
+            let downloads = resources.into_iter().zip(keys).map(|(resource, key)| {
+                let command::BatchResource {
+                    hash, dest, token, ..
+                } = resource;
+                let reporter =
+                    user_report::UserReportHandle::start(&user).with_request_id(&request_id);
+                reporter.annotate("api", &("download_batch", &hash, &dest));
+                let download = state.download(
+                    hash.clone(),
+                    dest,
+                    peers.clone(),
+                    http_sources.clone(),
+                    DownloadOptions {
+                        token,
+                        encryption_key: key,
+                        ..options.clone()
+                    },
+                    reporter.clone(),
+                );
+                async move {
+                    let r = download.await;
+                    if let Err(e) = &r {
+                        reporter.emit_fail(e);
+                    }
+                    batch_resource_result(hash, r)
+                }
+            });
+            let resources: Vec<_> = download::with_shared_connections(
+                futures::stream::iter(downloads)
+                    .buffered(concurrency.unwrap_or(BATCH_CONCURRENCY).max(1))
+                    .collect(),
+            )
+            .await;
+            let failed = resources
+                .iter()
+                .filter(|resource| resource.status == BatchStatus::Failed)
+                .count();
+            Ok(HttpResponse::Ok().json(command::DownloadBatchResult { resources, failed }))
+        }
+        command::Command::Replicate {
+            hash,
+            targets,
+            dest,
+            peers,
+        } => {
+            mode::check_transfer().map_err(rpc_error)?;
+            state
+                .replicate(hash, targets, dest, peers, request_id)
+                .await
+        }
+        command::Command::Hash {
+            files,
+            hash_algorithm,
+        } => state.hash(files, hash_algorithm).await,
+        command::Command::CheckAvailability {
+            hash,
+            peers,
+            timeout,
+            token,
+            signer,
+        } => {
+            mode::check_transfer().map_err(rpc_error)?;
+            let reporter = user_report::UserReportHandle::empty().with_request_id(&request_id);
+            state
+                .check_availability(hash, peers, timeout, token, signer, reporter)
+                .await
+        }
+        command::Command::Mode { mode: new_mode } => {
+            if let Some(new_mode) = new_mode {
+                mode::set(new_mode);
+            }
+            Ok(HttpResponse::Ok().json(command::ModeResult {
+                mode: mode::current(),
+            }))
+        }
+        command::Command::Privacy { level } => {
+            if let Some(level) = level {
+                user_report::set_privacy(level);
+            }
+            Ok(HttpResponse::Ok().json(command::PrivacyResult {
+                level: user_report::privacy(),
+            }))
+        }
+        command::Command::Blocklist { add, remove } => {
+            let parse = |hashes: Vec<String>| {
+                hashes
+                    .iter()
+                    .map(|hash| hash_encoding::parse(hash))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(actix_web::error::ErrorBadRequest)
+            };
+            let hashes = blocklist::update(&parse(add)?, &parse(remove)?).map_err(rpc_error)?;
+            Ok(HttpResponse::Ok().json(command::BlocklistResult {
+                hashes: hashes.into_iter().map(hash_encoding::encode).collect(),
+            }))
+        }
+        other_command => {
+            log::warn!("[{}] bad command: {:?}", request_id, other_command);
+            Err(actix_web::error::ErrorBadRequest(format!(
+                "invalid command"
+            )))
+        }
+    }
+}
+
+/// Runs the command `name` with the fields in the body of a `/api/v2` route.
+async fn v2_command(
+    state: web::Data<State>,
+    name: &str,
+    body: &[u8],
+    request: &HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let request_id = request_id_of(request);
+    let command = command::Command::parse_fields(name, body).map_err(|e| {
+        log::warn!("[{}] {}", request_id, e);
+        rpc_error(e)
+    })?;
+    run_command(state, command, request_id).await
+}
+
+#[get("/node")]
+async fn v2_node(
+    state: web::Data<State>,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    run_command(state, command::Command::Id, request_id_of(&request)).await
+}
+
+#[post("/resources")]
+async fn v2_upload(
+    state: web::Data<State>,
+    body: Bytes,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    v2_command(state, "upload", &body, &request).await
+}
+
+#[post("/resources/hash")]
+async fn v2_hash(
+    state: web::Data<State>,
+    body: Bytes,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    v2_command(state, "hash", &body, &request).await
+}
+
+#[post("/transfers")]
+async fn v2_download(
+    state: web::Data<State>,
+    body: Bytes,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    v2_command(state, "download", &body, &request).await
+}
+
+#[post("/transfers/batch")]
+async fn v2_download_batch(
+    state: web::Data<State>,
+    body: Bytes,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    v2_command(state, "download_batch", &body, &request).await
+}
+
+#[post("/replications")]
+async fn v2_replicate(
+    state: web::Data<State>,
+    body: Bytes,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    v2_command(state, "replicate", &body, &request).await
+}
+
+/// Connected peers, like `/connections`.
+#[get("/peers")]
+async fn v2_peers() -> Result<HttpResponse, actix_web::Error> {
+    let connections = connection_registry::list()
+        .await
+        .map_err(|e| rpc_error(e.into()))?;
+    Ok(HttpResponse::Ok().json(connections))
+}
+
+#[post("/peers/availability")]
+async fn v2_check_availability(
+    state: web::Data<State>,
+    body: Bytes,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    v2_command(state, "check_availability", &body, &request).await
+}
+
+/// Answers the calls of the gRPC interface like the corresponding RPC requests.
+async fn serve_grpc_calls(
+    state: web::Data<State>,
+    mut calls: futures::channel::mpsc::UnboundedReceiver<grpc::Call>,
+) {
+    while let Some(call) = calls.next().await {
+        let state = state.clone();
+        actix::spawn(async move {
+            let result = match call.action {
+                _ if database::is_overloaded() => Err(error::Error::Busy),
+                grpc::Action::Command(command) => {
+                    let response = run_command(state, *command, call.request_id)
+                        .await
+                        .unwrap_or_else(|e| e.error_response());
+                    json_of(response).await
+                }
+                grpc::Action::Status => status_result(&state)
+                    .await
+                    .and_then(|result| Ok(serde_json::to_value(result)?)),
+                grpc::Action::List(query) => list_result(&state, query).await.map(Into::into),
+            };
+            let _ = call.reply.send(result);
+        });
+    }
+}
+
+/// JSON body of an RPC answer, the error it describes for failures.
+async fn json_of(response: HttpResponse) -> Result<serde_json::Value, error::Error> {
+    let code = response.status().as_u16();
+    let body = body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| error::Error::Rpc {
+            status: code,
+            code: None,
+            message: e.to_string(),
+        })?;
+    if code >= 300 {
+        return Err(client::error_of(code, &body));
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[get("/resources")]
+async fn list_resources(
+    state: web::Data<State>,
+    query: web::Query<command::ListQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let output = list_result(&state, query.into_inner())
+        .await
+        .map_err(rpc_error)?;
+    Ok(HttpResponse::Ok().json(output))
+}
+
+/// Answer of `GET /resources`, also of the gRPC `List` call.
+async fn list_result(
+    state: &State,
+    query: command::ListQuery,
+) -> Result<Vec<serde_json::Value>, error::Error> {
+    let list = database::List {
+        namespace: query.namespace,
+        metadata: query.meta.map(|meta| match meta.split_once('=') {
+            Some((key, value)) => (key.to_string(), Some(value.to_string())),
+            None => (meta, None),
+        }),
+        name: query.name.clone(),
+    };
+    let resources = database::request(&state.db, list).await?;
+    let name = query.name.map(|name| name.to_lowercase());
+    let output: Vec<serde_json::Value> = resources
+        .into_iter()
+        .map(|resource| {
+            let hash = hash_encoding::encode(resource.map_hash);
+            let n_files = resource.files.len();
+            let size: u64 = resource
+                .files
+                .iter()
+                .map(|(file_map, _)| file_map.file_size)
+                .sum();
+            let valid_to = resource
+                .valid_to
+                .and_then(|ts| Some(ts.duration_since(UNIX_EPOCH).ok()?.as_secs()));
+
+            let paths: Vec<_> = resource
+                .files
+                .iter()
+                .map(|(_, path)| path.display().to_string())
+                .collect();
+
+            let mut output = serde_json::json!({
+                "hash": hash,
+                "files": n_files,
+                "paths": paths,
+                "totalSize": size,
+                "validTo": valid_to,
+                "namespaces": resource.namespaces,
+                "metadata": resource.metadata.values,
+                "fileMetadata": file_metadata_json(&resource.metadata),
+            });
+            if let Some(name) = &name {
+                output["matchedFiles"] = resource
+                    .files
+                    .iter()
+                    .map(|(file_map, _)| file_map.file_name.to_string())
+                    .filter(|file_name| file_name.to_lowercase().contains(name))
+                    .collect();
+            }
+            output
+        })
+        .collect();
+    Ok(output)
+}
+
+#[get("/status")]
+async fn status(state: web::Data<State>) -> Result<HttpResponse, actix_web::Error> {
+    let result = status_result(&state).await.map_err(rpc_error)?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Answer of `GET /status`, also of the gRPC `Status` call.
+async fn status_result(state: &State) -> Result<command::StatusResult, error::Error> {
+    let addresses = command::AddressSpec::TCP {
+        address: state.opts.host.to_string(),
+        port: state.opts.port,
+    };
+
+    let (id, resources, quarantined) = future::try_join3(
+        database::id(&state.db),
+        database::request(&state.db, database::List::default()).err_into(),
+        database::request(&state.db, database::ListQuarantined).err_into(),
     )
+    .await?;
+    let limits = server::limit_stats();
+    let cache_usage = resources
+        .iter()
+        .flat_map(|resource| resource.files.iter())
+        .map(|(file_map, _)| file_map.file_size)
+        .sum();
+
+    Ok(command::StatusResult {
+        id: hash_encoding::encode(id),
+        version: version::PACKAGE_VERSION.into(),
+        addresses,
+        shares: resources.len(),
+        active_downloads: download::active_downloads(),
+        active_connections: connection::active_connections(),
+        cache_usage,
+        db_queue: database::queue_depth(),
+        mode: mode::current(),
+        refused_connections: limits.refused,
+        banned_addresses: limits.banned,
+        actor_restarts: supervisor::restarts()
+            .into_iter()
+            .map(|(name, count)| (name.to_string(), count))
+            .collect(),
+        quarantined: quarantined
+            .into_iter()
+            .map(|(hash, reason, since)| command::QuarantineReport {
+                hash: hash_encoding::encode(hash),
+                reason,
+                since: since
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs()),
+            })
+            .collect(),
+    })
+}
+
+#[get("/version")]
+async fn get_version() -> HttpResponse {
+    HttpResponse::Ok().json(command::VersionResult {
+        version: version::PACKAGE_VERSION.into(),
+        build: version::build_info(),
+        protocol: command::ProtocolInfo {
+            version: codec::PROTO_VERSION,
+            opcodes: codec::OP_NAMES.iter().map(|&name| name.into()).collect(),
+            bundle_format: filemap::BUNDLE_FORMAT,
+            hash_algorithms: [HashAlgorithm::Sha224, HashAlgorithm::Blake3]
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            hash_encodings: [
+                hash_encoding::HashEncoding::Hex,
+                hash_encoding::HashEncoding::Base32,
+            ]
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        },
+        features: version::features().into_iter().map(Into::into).collect(),
+    })
+}
+
+#[get("/stats")]
+async fn get_stats() -> Result<HttpResponse, actix_web::Error> {
+    let users = stats::get_stats().await.map_err(|e| rpc_error(e.into()))?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+#[get("/traffic")]
+async fn get_traffic() -> Result<HttpResponse, actix_web::Error> {
+    let traffic = stats::traffic().await.map_err(|e| rpc_error(e.into()))?;
+    let month = stats::month_of(SystemTime::now());
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "month": month,
+        "served": traffic.month_served(&month),
+        "quota": stats::monthly_quota(),
+        "quotaExceeded": stats::quota_exceeded(),
+        "months": traffic.served,
+    })))
+}
+
+#[get("/errors")]
+async fn get_errors() -> HttpResponse {
+    HttpResponse::Ok().json(user_report::recent_errors())
+}
+
+#[get("/config")]
+async fn get_config(state: web::Data<State>) -> HttpResponse {
+    HttpResponse::Ok().json(&*state.config)
+}
+
+#[get("/ui")]
+async fn status_page(state: web::Data<State>) -> HttpResponse {
+    if !state.opts.ui {
+        return HttpResponse::NotFound().body("status page disabled, see --ui");
+    }
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("ui.html"))
+}
+
+#[get("/connections")]
+async fn get_connections() -> Result<HttpResponse, actix_web::Error> {
+    let connections = connection_registry::list()
+        .await
+        .map_err(|e| rpc_error(e.into()))?;
+    Ok(HttpResponse::Ok().json(connections))
+}
+
+#[get("/debug/actors")]
+async fn debug_actors() -> HttpResponse {
+    // Answers even when the registry is stuck, a hang is what this is for.
+    let connections = match connection_registry::debug_list().await {
+        Ok(connections) => Some(connections),
+        Err(e) => {
+            log::warn!("connection registry did not answer: {}", e);
+            None
+        }
+    };
+    HttpResponse::Ok().json(serde_json::json!({
+        "actors": supervisor::mailboxes(),
+        "dbQueue": database::queue_depth(),
+        "dbQueueLimit": database::queue_limit(),
+        "gc": database::gc_info(),
+        "activeDownloads": download::active_downloads(),
+        "connections": connections,
+    }))
+}
+
+#[get("/resources/{resourceId}")]
+async fn get_resource_info(
+    state: web::Data<State>,
+    path: web::Path<(String,)>,
+    query: web::Query<command::NamespaceQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let hash = hash_encoding::parse(&path.0).map_err(actix_web::error::ErrorBadRequest)?;
+
+    let r = database::call(&state.db, database::GetHash(hash))
+        .await
+        .map_err(rpc_error)?;
+    match r {
+        Some((file_desc, _)) if file_desc.in_namespace(query.namespace.as_deref()) => {
+            let mut size: u64 = 0;
+            let files: Vec<(String, String)> = file_desc
+                .files
+                .iter()
+                .map(|(file_map, path)| {
+                    size += file_map.file_size;
+                    (path.display().to_string(), file_map.file_name.to_string())
+                })
+                .collect();
+            let valid_to = file_desc
+                .valid_to
+                .map(|ts| ts.duration_since(UNIX_EPOCH).unwrap().as_secs());
+            let served = stats::resource_stats(hash)
+                .await
+                .map_err(|e| rpc_error(e.into()))?
+                .unwrap_or_default();
+
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "hash": hash_encoding::encode(file_desc.map_hash),
+                "files": files,
+                "totalSize": size,
+                "validTo": valid_to,
+                "namespaces": file_desc.namespaces,
+                "metadata": file_desc.metadata.values,
+                "fileMetadata": file_metadata_json(&file_desc.metadata),
+                "stats": {
+                    "asks": served.asks,
+                    "blocksServed": served.blocks_served,
+                    "bytesServed": served.bytes_served,
+                    "lastRequested": served.last_requested,
+                    "uniquePeers": served.peers.len(),
+                },
+            })))
+        }
+        _ => Ok(HttpResponse::NotFound().body("resource not found")),
+    }
+}
+
+/// Metadata and expiries of the files having them, by file name.
+fn file_metadata_json(metadata: &database::ResourceMetadata) -> serde_json::Value {
+    metadata
+        .files
+        .iter()
+        .map(|(name, file)| {
+            let valid_to = file
+                .valid_to
+                .and_then(|ts| Some(ts.duration_since(UNIX_EPOCH).ok()?.as_secs()));
+            let file = serde_json::json!({"metadata": file.values, "validTo": valid_to});
+            (name.clone(), file)
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn proof_json(proof: &filemap::MerkleProof) -> serde_json::Value {
+    serde_json::json!({
+        "leaves": proof.leaves,
+        "index": proof.index,
+        "siblings": proof.siblings.iter().map(|&hash| hash_to_hex(hash)).collect::<Vec<_>>(),
+    })
+}
+
+#[get("/resources/{resourceId}/proof")]
+async fn get_resource_proof(
+    state: web::Data<State>,
+    path: web::Path<(String,)>,
+    query: web::Query<command::ProofQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let hash = hash_encoding::parse(&path.0).map_err(actix_web::error::ErrorBadRequest)?;
+
+    let file_desc = match database::call(&state.db, database::GetHash(hash))
+        .await
+        .map_err(rpc_error)?
+    {
+        Some((file_desc, _)) if file_desc.in_namespace(query.namespace.as_deref()) => file_desc,
+        _ => return Ok(HttpResponse::NotFound().body("resource not found")),
+    };
+    let hash_algorithm = file_desc.hash_algorithm;
+    let maps: Vec<&FileMap> = file_desc.files.iter().map(|(map, _)| map).collect();
+    if BundleFormat::of(hash, hash_algorithm, &maps) != Some(BundleFormat::Merkle) {
+        return Err(rpc_error(error::Error::InvalidArgument(format!(
+            "{:032x} is hashed in an older format, without proofs",
+            hash
+        ))));
+    }
+    let file_nr = match maps
+        .iter()
+        .position(|map| map.file_name.to_string() == query.file)
+    {
+        Some(file_nr) => file_nr,
+        None => return Ok(HttpResponse::NotFound().body("file not found")),
+    };
+    let map = maps[file_nr];
+    let mut proof = serde_json::json!({
+        "hash": hash_encoding::encode(hash),
+        "hashAlgorithm": hash_algorithm.to_string(),
+        "file": {
+            "name": query.file,
+            "size": map.file_size,
+            "link": map.link,
+            "blocks": map.blocks.len(),
+        },
+        "fileProof": proof_json(&filemap::file_proof(hash_algorithm, &maps, file_nr)),
+    });
+    if let Some(block_nr) = query.block {
+        let block_nr = block_nr as usize;
+        let block = match map.blocks.get(block_nr) {
+            Some(&block) => block,
+            None => return Ok(HttpResponse::NotFound().body("block not found")),
+        };
+        let block_proof = filemap::BlockProof::new(hash_algorithm, &maps, file_nr, block_nr);
+        proof["block"] = serde_json::json!({
+            "hash": hash_to_hex(block),
+            "proof": proof_json(&block_proof.block),
+        });
+    }
+    Ok(HttpResponse::Ok().json(proof))
 }
 
 #[delete("/resources/{resourceId}")]
-fn remove_resource(
+async fn remove_resource(
     state: web::Data<State>,
     path: web::Path<(String,)>,
-) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-    let hash = match u128::from_str_radix(&path.0, 16) {
-        Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
-        Ok(hash) => hash,
+    query: web::Query<command::RemoveQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let hash = hash_encoding::parse(&path.0).map_err(actix_web::error::ErrorBadRequest)?;
+    let unshare = database::Unshare {
+        hash,
+        namespace: query.namespace.clone(),
+        grace: query.grace.map(Duration::from_secs),
     };
-    future::Either::A(
-        state
-            .db
-            .send(database::RemoveHash(hash))
-            .flatten()
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e))
-            .and_then(|r: Option<Arc<database::FileDesc>>| match r {
-                None => Ok(HttpResponse::NotFound().body("resource not found")),
-                Some(_) => Ok(HttpResponse::NoContent().finish()),
-            }),
+    let r = database::call(&state.db, unshare)
+        .await
+        .map_err(rpc_error)?;
+    match r {
+        None => Ok(HttpResponse::NotFound().body("resource not found")),
+        Some(_) => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+#[post("/resources/{resourceId}/restore")]
+async fn restore_resource(
+    state: web::Data<State>,
+    path: web::Path<(String,)>,
+    query: web::Query<command::NamespaceQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let hash = hash_encoding::parse(&path.0).map_err(actix_web::error::ErrorBadRequest)?;
+    let restore = database::RestoreHash {
+        hash,
+        namespace: query.into_inner().namespace,
+    };
+    let r = database::call(&state.db, restore)
+        .await
+        .map_err(rpc_error)?;
+    match r {
+        None => Ok(HttpResponse::NotFound().body("deleted resource not found")),
+        Some(_) => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+#[get("/resources/{resourceId}/archive")]
+async fn export_resource(
+    state: web::Data<State>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let hash = hash_encoding::parse(&path.0).map_err(actix_web::error::ErrorBadRequest)?;
+
+    let r = database::call(&state.db, database::GetHash(hash))
+        .await
+        .map_err(rpc_error)?;
+    match r {
+        None => Ok(HttpResponse::NotFound().body("resource not found")),
+        Some((file_desc, _)) => {
+            let files = file_desc
+                .files
+                .iter()
+                .map(|(file_map, path)| (path.clone(), file_map.file_name.clone()))
+                .collect();
+            // A failed export ends the stream, the client sees a truncated archive.
+            let body = archive::export_tar(files).map(Ok::<_, actix_web::Error>);
+
+            Ok(HttpResponse::Ok()
+                .content_type("application/x-tar")
+                .insert_header((
+                    "Content-Disposition",
+                    format!(
+                        "attachment; filename=\"{}.tar\"",
+                        hash_encoding::encode(hash)
+                    ),
+                ))
+                .streaming(body))
+        }
+    }
+}
+
+#[post("/resources/archive")]
+async fn import_resource(
+    state: web::Data<State>,
+    query: web::Query<command::ArchiveQuery>,
+    mut body: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let symlinks = query.symlinks;
+    let namespace = query.namespace.clone();
+    let dest = database::database_dir(&state.opts.db)
+        .join("archives")
+        .join(hash_to_hex(rand::random()));
+    let archive_path = dest.with_extension("upload");
+
+    let mut file = mode::check_share()
+        .and_then(|()| Ok(fs::create_dir_all(&dest)?))
+        .and_then(|()| Ok(fs::File::create(&archive_path)?))
+        .map_err(rpc_error)?;
+    while let Some(chunk) = body.next().await {
+        file.write_all(&chunk?).map_err(|e| rpc_error(e.into()))?;
+    }
+    drop(file);
+    let archive::Unpacked { files, links, dirs } = web::block(move || {
+        let files = archive::unpack(&archive_path, &dest, symlinks);
+        let _ = fs::remove_file(&archive_path);
+        if files.is_err() {
+            let _ = fs::remove_dir_all(&dest);
+        }
+        files
+    })
+    .await
+    .map_err(actix_web::error::ErrorBadRequest)?
+    .map_err(actix_web::error::ErrorBadRequest)?;
+
+    if files.is_empty() && links.is_empty() && dirs.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("archive is empty"));
+    }
+    let reporter = user_report::UserReportHandle::empty();
+    let hash_algorithm = state.opts.hash_algorithm;
+    let mut file_maps = hasher::hash_files(&state.hasher, files, hash_algorithm)
+        .await
+        .map_err(rpc_error)?;
+    file_maps.extend(links.into_iter().map(|link| link.file_map(hash_algorithm)));
+    file_maps.extend(
+        dirs.into_iter()
+            .map(|(path, name)| (FileMap::directory(name, hash_algorithm), path)),
+    );
+    register(
+        state.db.clone(),
+        file_maps,
+        None,
+        None,
+        Access::default(),
+        namespace,
+        CasePolicy::default(),
+        database::ResourceMetadata::default(),
+        reporter,
+    )
+    .await
+}
+
+#[post("/resources/stream")]
+async fn stream_resource(
+    state: web::Data<State>,
+    query: web::Query<command::StreamQuery>,
+    request: HttpRequest,
+    mut body: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    // A dropped connection just ends the payload.
+    let expected_size = request
+        .headers()
+        .get(actix_web::http::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok())
+        .map(|len| len + query.offset);
+    let command::StreamQuery {
+        name,
+        timeout,
+        upload,
+        offset,
+        namespace,
+    } = query.into_inner();
+    let db_dir = database::database_dir(&state.opts.db);
+    let mut upload = mode::check_share()
+        .and_then(|()| {
+            stream::StreamUpload::open(
+                &db_dir,
+                upload.as_ref().map(AsRef::as_ref),
+                name,
+                offset,
+                state.opts.hash_algorithm,
+            )
+        })
+        .map_err(rpc_error)?;
+    let dir = upload.dir().to_owned();
+    let resumable = upload.is_resumable();
+    let db = state.db.clone();
+    database::notify(&db, database::TrackArtifact(dir.clone()));
+    if resumable {
+        database::notify(&db, database::TrackArtifact(stream::state_path(&dir)));
+    }
+
+    let written =
+        async {
+            while let Some(chunk) = body.next().await {
+                upload.write(&chunk?).map_err(rpc_error)?;
+            }
+            match expected_size {
+                Some(size) if size != upload.size() => Err(actix_web::error::ErrorBadRequest(
+                    format!("incomplete body, {} of {} bytes", upload.size(), size),
+                )),
+                _ => upload.finish().map_err(rpc_error),
+            }
+        };
+    let r = written.await;
+    match &r {
+        Ok(_) => {
+            database::notify(&db, database::ReleaseArtifact(stream::state_path(&dir)));
+            database::notify(&db, database::ReleaseArtifact(dir));
+        }
+        // Kept until continued or removed as an artifact.
+        Err(_) if resumable => (),
+        Err(_) => {
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+    let reporter = user_report::UserReportHandle::empty();
+    register(
+        db,
+        vec![r?],
+        timeout,
+        None,
+        Access::default(),
+        namespace,
+        CasePolicy::default(),
+        database::ResourceMetadata::default(),
+        reporter,
     )
+    .await
+}
+
+#[get("/resources/stream/{upload}")]
+async fn stream_status(
+    state: web::Data<State>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let db_dir = database::database_dir(&state.opts.db);
+    match stream::upload_state(&db_dir, &path.0) {
+        Ok(Some(upload_state)) => Ok(HttpResponse::Ok().json(command::UploadStatus {
+            upload: path.0.clone(),
+            offset: upload_state.offset(),
+            name: upload_state.name,
+        })),
+        Ok(None) => Ok(HttpResponse::NotFound().body("upload not found")),
+        Err(e) => Err(rpc_error(e)),
+    }
+}
+
+#[post("/artifacts/cleanup")]
+async fn cleanup_artifacts(
+    state: web::Data<State>,
+    query: web::Query<command::CleanupQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let max_age = Duration::from_secs(query.max_age.unwrap_or(state.opts.artifact_max_age));
+
+    let removed = database::request(&state.db, database::CleanupArtifacts { max_age })
+        .await
+        .map_err(rpc_error)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "removed": removed })))
+}
+
+#[post("/admin/gc")]
+async fn admin_gc(
+    state: web::Data<State>,
+    query: web::Query<command::GcQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let lifetime = query.lifetime_override.map(Duration::from_secs);
+    let swept = database::request(&state.db, database::Sweep { lifetime })
+        .await
+        .map_err(rpc_error)?;
+    let removed: Vec<serde_json::Value> = swept
+        .removed
+        .iter()
+        .map(|resource| {
+            serde_json::json!({
+                "hash": hash_encoding::encode(resource.map_hash),
+                "files": resource.files.len(),
+                "totalSize": resource
+                    .files
+                    .iter()
+                    .map(|(file_map, _)| file_map.file_size)
+                    .sum::<u64>(),
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "removed": removed,
+        "reclaimedBytes": swept.reclaimed,
+    })))
+}
+
+#[post("/admin/integrity")]
+async fn admin_integrity(
+    state: web::Data<State>,
+    query: web::Query<command::CleanupQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let orphan_age = Duration::from_secs(query.max_age.unwrap_or(state.opts.artifact_max_age));
+    let report = database::request(&state.db, database::CheckIntegrity { orphan_age })
+        .await
+        .map_err(rpc_error)?;
+    let broken: Vec<serde_json::Value> = report
+        .broken
+        .iter()
+        .map(|(hash, reason)| {
+            serde_json::json!({ "hash": hash_encoding::encode(*hash), "reason": reason })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "checked": report.checked,
+        "broken": broken,
+        "orphans": report.orphans,
+        "reclaimedBytes": report.reclaimed,
+    })))
+}
+
+/// Id of the RPC request, kept in the request extensions.
+struct RequestId(String);
+
+/// Id given by the client in `X-Request-Id`, or a new random one.
+fn request_id(req: &actix_web::dev::ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 64
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+        })
+        .map(ToString::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()))
+}
+
+/// Routes taking the JSON fields of a command, see `v2_command`.
+fn is_command_route(method: &actix_web::http::Method, path: &str) -> bool {
+    path == "/api"
+        || (method == actix_web::http::Method::POST
+            && matches!(
+                path.strip_prefix("/api/v2"),
+                Some(
+                    "/resources"
+                        | "/resources/hash"
+                        | "/transfers"
+                        | "/transfers/batch"
+                        | "/replications"
+                        | "/peers/availability"
+                )
+            ))
+}
+
+/// Checks bodies of `/api` calls with `--rpc_check_body`, see `rpc_body::check`, and adds
+/// the checksum of the answer.
+async fn check_rpc_body(
+    mut req: ServiceRequest,
+    next: middleware::Next<BoxBody>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let enabled = req
+        .app_data::<web::Data<State>>()
+        .is_some_and(|state| state.opts.rpc_check_body);
+    if !enabled || !is_command_route(req.method(), req.path()) {
+        return next.call(req).await;
+    }
+    let mut payload = req.take_payload();
+    let mut bytes = bytes::BytesMut::new();
+    // A connection closed early leaves the body short of its Content-Length.
+    while let Some(Ok(chunk)) = payload.next().await {
+        if bytes.len() + chunk.len() > MAX_JSON_BODY {
+            return Err(rpc_error(error::Error::InvalidArgument(
+                "request body too large".into(),
+            )));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    let bytes = bytes.freeze();
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    let checksum = req
+        .headers()
+        .get(rpc_body::CHECKSUM_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if let Err(e) = rpc_body::check(&bytes, content_length, checksum) {
+        let request_id = request_id_of(req.request());
+        log::warn!(
+            "[{}] rejected RPC body of {} bytes (content-length {:?}): {}",
+            request_id,
+            bytes.len(),
+            content_length,
+            e
+        );
+        return Ok(req.error_response(rpc_error(e)));
+    }
+    req.set_payload(bytes.into());
+
+    let (req, response) = next.call(req).await?.into_parts();
+    let (mut response, answer) = response.into_parts();
+    let answer = body::to_bytes(answer)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
+    if let Ok(value) = HeaderValue::from_str(&rpc_body::checksum(&answer)) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(rpc_body::CHECKSUM_HEADER), value);
+    }
+    Ok(ServiceResponse::new(
+        req,
+        response.set_body(answer).map_into_boxed_body(),
+    ))
+}
+
+/// Endpoints answered while the database is overloaded.
+fn is_probe(path: &str) -> bool {
+    matches!(path, "/healthz" | "/readyz" | "/status" | "/version")
+}
+
+#[get("/openapi.json")]
+async fn get_openapi() -> HttpResponse {
+    HttpResponse::Ok().json(openapi::document())
+}
+
+/// Lets pages from `origins` call RPC from browsers.
+fn cors(origins: &[String]) -> Cors {
+    let cors = Cors::default()
+        .allowed_methods(["GET", "POST", "DELETE"])
+        .allow_any_header()
+        .expose_headers([REQUEST_ID_HEADER, rpc_body::CHECKSUM_HEADER])
+        .max_age(3600);
+    if origins.iter().any(|origin| origin == "*") {
+        return cors.allow_any_origin();
+    }
+    origins
+        .iter()
+        .fold(cors, |cors, origin| cors.allowed_origin(origin))
+}
+
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+#[get("/readyz")]
+async fn readyz(state: web::Data<State>) -> HttpResponse {
+    match database::id(&state.db).await {
+        Err(e) => HttpResponse::ServiceUnavailable().body(format!("database: {}", e)),
+        Ok(_) if !state.health.is_listening() => {
+            HttpResponse::ServiceUnavailable().body("listener not bound")
+        }
+        Ok(_) if state.health.is_stopping() => {
+            HttpResponse::ServiceUnavailable().body("shutting down")
+        }
+        Ok(_) if mode::current() == mode::NodeMode::Maintenance => {
+            HttpResponse::ServiceUnavailable().body("maintenance")
+        }
+        Ok(_) => HttpResponse::Ok().body("ready"),
+    }
 }
 
 fn main() -> std::io::Result<()> {
-    user_report::init();
-    let args = ServerOpts::from_args();
+    let mut args = parse_args(std::env::args_os().collect());
+    if let Some(config) = args.config.clone() {
+        match config::merge_args(std::env::args_os().collect(), &config) {
+            Ok(merged) => args = parse_args(merged),
+            Err(e) => {
+                startup::fail(
+                    ExitCode::Config,
+                    format!("unable to read {}: {}", config.display(), e),
+                );
+            }
+        }
+    }
 
     if args.version {
         println!("{}", version::PACKAGE_VERSION);
         return Ok(());
     }
 
+    hash_encoding::set(args.hash_encoding);
+
+    if args.status {
+        if let Err(e) = cli::status(SocketAddr::new(args.rpc_host, args.rpc_port), args.json) {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(command) = args.command.clone() {
+        if let Err(e) = cli::run(command, SocketAddr::new(args.rpc_host, args.rpc_port)) {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    user_report::init(&args.telemetry.clone().unwrap_or_default());
+    user_report::set_privacy(args.telemetry_privacy);
     log_config::init(args.loglevel, args.logfile.as_ref().map(AsRef::as_ref));
     version::startup_log();
 
-    let sys = actix::System::new("hyperg");
+    if args.db.is_none() {
+        match database::default_dir() {
+            Ok(dir) => args.db = Some(dir),
+            Err(e) => {
+                startup::fail(
+                    ExitCode::Config,
+                    format!("{}, choose a database directory with --db", e),
+                );
+            }
+        }
+    }
 
-    let db = database::database_manager(&args.db);
-    let opts = Arc::new(args);
+    match encryption::load_key(args.encryption_key_file.as_ref().map(AsRef::as_ref)) {
+        Ok(Some(key)) => {
+            encryption::set_key(&key);
+            log::info!("encryption of stored data enabled");
+        }
+        Ok(None) => (),
+        Err(e) => {
+            startup::fail(
+                ExitCode::Config,
+                format!("unable to read encryption key: {}", e),
+            );
+        }
+    }
 
-    let server_opts = opts.clone();
+    if args.sign_filemaps {
+        identity::enable_signing();
+    }
 
-    let _transfer_server = server::new(db.clone(), (opts.host, opts.port))?;
+    if args.pin_peers {
+        let path = database::database_dir(&args.db).join("pinned_peers.json");
+        if let Err(e) = pins::enable(path) {
+            startup::fail(
+                ExitCode::Config,
+                format!("unable to read pinned peers: {}", e),
+            );
+        }
+    }
 
-    let _rpc_server = HttpServer::new(move || {
-        App::new()
-            .wrap(Logger::default())
-            .data(State {
-                db: db.clone(),
-                opts: opts.clone(),
+    if let Some(path) = &args.blocklist {
+        if let Err(e) = blocklist::load(path.clone()) {
+            startup::fail(ExitCode::Config, format!("unable to read blocklist: {}", e));
+        }
+    }
+
+    if let Some(path) = &args.audit_log {
+        let max_size = args.audit_log_max_mb.saturating_mul(1024 * 1024);
+        if let Err(e) = audit::enable(path.clone(), max_size, args.audit_sample) {
+            startup::fail(ExitCode::Config, format!("unable to open audit log: {}", e));
+        }
+    }
+
+    if let Some(output) = &args.trace_output {
+        if let Err(e) = trace::init(output) {
+            startup::fail(
+                ExitCode::Config,
+                format!("unable to set up --trace_output: {}", e),
+            );
+        }
+    }
+
+    let rpc_tls = match (&args.rpc_tls_cert, &args.rpc_tls_key) {
+        (Some(cert), Some(key)) => match tls::server_config(cert, key) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                startup::fail(ExitCode::Config, format!("unable to set up rpc tls: {}", e));
+            }
+        },
+        (None, None) => None,
+        _ => {
+            startup::fail(
+                ExitCode::Config,
+                "--rpc_tls_cert and --rpc_tls_key have to be given together",
+            );
+        }
+    };
+
+    if let Some(origin) = args
+        .rpc_cors_origins
+        .iter()
+        .find(|origin| *origin != "*" && origin.parse::<actix_web::http::Uri>().is_err())
+    {
+        startup::fail(
+            ExitCode::Config,
+            format!("invalid --rpc_cors_origin {}", origin),
+        );
+    }
+
+    match args.challenge_bits {
+        Some(bits) if bits > codec::MAX_CHALLENGE_BITS => {
+            startup::fail(
+                ExitCode::Config,
+                format!(
+                    "--challenge_bits can be at most {}",
+                    codec::MAX_CHALLENGE_BITS
+                ),
+            );
+        }
+        Some(bits) => {
+            log::warn!(
+                "--challenge_bits {}: peers without challenge support can't download from this node",
+                bits
+            );
+            connection::set_challenge(bits)
+        }
+        None => (),
+    }
+
+    let sys = actix::System::new();
+    sys.block_on(async move {
+        connection::set_timeouts(
+            Duration::from_secs(args.handshake_timeout),
+            match args.idle_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+        );
+
+        database::set_recovery(args.db_recovery);
+        let db = match database::database_manager(&args.db) {
+            Ok(db) => db,
+            Err(e) => {
+                let dir = database::database_dir(&args.db);
+                let error = format!("unable to open database in {}: {}", dir.display(), e);
+                eprintln!("error: {}", error);
+                eprintln!("{}", database_hint(&e));
+                startup::exit(ExitCode::Database, error);
+            }
+        };
+        stats::persist(database::database_dir(&args.db));
+        stats::set_monthly_quota(args.monthly_quota_mb.saturating_mul(1024 * 1024));
+        database::notify(
+            &db,
+            database::CleanupArtifacts {
+                max_age: Duration::from_secs(args.artifact_max_age),
+            },
+        );
+        let hasher = hasher::start(args.hash_threads);
+        for dir in &args.watch {
+            if let Err(e) = watch::start(dir, db.clone(), hasher.clone(), args.hash_algorithm) {
+                startup::fail(
+                    ExitCode::Config,
+                    format!("unable to watch {}: {}", dir.display(), e),
+                );
+            }
+        }
+        if args.relay_server {
+            relay::enable(relay::RelayConfig {
+                max_peers: args.relay_max_peers,
+                quota: args.relay_quota_mb * 1024 * 1024,
+            });
+        }
+        if let Some(relay_addr) = args.relay {
+            relay::RelayClient::start(db.clone(), relay_addr);
+        }
+        database::set_queue_limit(args.db_queue_limit);
+        database::set_file_maps_ttl(Duration::from_secs(args.file_maps_ttl));
+        database::set_inline_threshold(args.inline_threshold);
+        connection::set_inline_replies(args.inline_replies);
+        let config = Arc::new(effective_config(&args));
+        log::info!("[CONFIG] {}", config);
+        let opts = Arc::new(args);
+        let health = health::Health::new();
+
+        let server_opts = opts.clone();
+
+        serve_queue::set_weights(opts.peer_weights.iter().cloned().collect());
+        server::set_limits(server::ConnectionLimits {
+            rate: opts.ip_conn_rate,
+            concurrent: opts.ip_max_conns,
+            ban: Duration::from_secs(opts.ip_ban),
+        });
+        let transfer_addr = SocketAddr::new(opts.host, opts.port);
+        let (transfer_server, transfer_addr) = net::TcpListener::bind(transfer_addr)
+            .and_then(|listener| {
+                let addr = listener.local_addr()?;
+                Ok((server::listen(db.clone(), listener)?, addr))
             })
-            .service(list_resources)
-            .service(get_resource_info)
-            .service(remove_resource)
-            .service(api)
-    })
-    .bind((server_opts.rpc_host, server_opts.rpc_port))?
-    .start();
+            .unwrap_or_else(|e| {
+                startup::fail(
+                    ExitCode::Bind,
+                    format!("unable to listen on {}: {}", transfer_addr, e),
+                )
+            });
+        fd_monitor::FdMonitor::start(transfer_server.handle());
+        actix::spawn(transfer_server);
+        let rpc_health = health.clone();
+        let db_ready = db.clone();
+        let (grpc_hasher, grpc_config) = (hasher.clone(), config.clone());
+
+        let rpc_server = HttpServer::new(move || {
+            App::new()
+                .wrap(middleware::from_fn(check_rpc_body))
+                .wrap_fn(|req, srv| {
+                    if database::is_overloaded() && !is_probe(req.path()) {
+                        return future::Either::Left(future::ok(
+                            req.error_response(rpc_error(error::Error::Busy)),
+                        ));
+                    }
+                    future::Either::Right(srv.call(req))
+                })
+                .wrap_fn(|req, srv| {
+                    let request_id = request_id(&req);
+                    req.extensions_mut().insert(RequestId(request_id.clone()));
+                    let response = srv.call(req);
+                    async move {
+                        let mut response = response.await?;
+                        if let Ok(value) = HeaderValue::from_str(&request_id) {
+                            response
+                                .headers_mut()
+                                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                        }
+                        Ok(response)
+                    }
+                })
+                .wrap(Condition::new(
+                    !opts.rpc_cors_origins.is_empty(),
+                    cors(&opts.rpc_cors_origins),
+                ))
+                .wrap(Logger::new(
+                    r#"[%{x-request-id}o] %a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#,
+                ))
+                .app_data(web::JsonConfig::default().limit(MAX_JSON_BODY))
+                .app_data(web::PayloadConfig::new(MAX_JSON_BODY))
+                .app_data(web::Data::new(State {
+                    db: db.clone(),
+                    hasher: hasher.clone(),
+                    opts: opts.clone(),
+                    health: rpc_health.clone(),
+                    config: config.clone(),
+                }))
+                .service(healthz)
+                .service(readyz)
+                .service(status)
+                .service(get_version)
+                .service(get_openapi)
+                .service(get_stats)
+                .service(get_traffic)
+                .service(get_errors)
+                .service(get_config)
+                .service(status_page)
+                .service(get_connections)
+                .service(debug_actors)
+                .service(list_resources)
+                .service(import_resource)
+                .service(stream_resource)
+                .service(stream_status)
+                .service(export_resource)
+                .service(cleanup_artifacts)
+                .service(admin_gc)
+                .service(admin_integrity)
+                .service(get_resource_info)
+                .service(get_resource_proof)
+                .service(remove_resource)
+                .service(restore_resource)
+                .service(api)
+                // Legacy routes above stay for older Golem clients.
+                .service(
+                    web::scope("/api/v2")
+                        .service(v2_node)
+                        .service(list_resources)
+                        .service(v2_upload)
+                        .service(v2_hash)
+                        .service(import_resource)
+                        .service(stream_resource)
+                        .service(stream_status)
+                        .service(export_resource)
+                        .service(get_resource_info)
+                        .service(get_resource_proof)
+                        .service(remove_resource)
+                        .service(restore_resource)
+                        .service(v2_download)
+                        .service(v2_download_batch)
+                        .service(v2_replicate)
+                        .service(v2_peers)
+                        .service(v2_check_availability),
+                )
+        });
+        let rpc_addr = SocketAddr::new(server_opts.rpc_host, server_opts.rpc_port);
+        let rpc_server = match rpc_tls {
+            #[cfg(feature = "with-tls")]
+            Some(config) => rpc_server.bind_rustls_021(rpc_addr, config),
+            #[cfg(not(feature = "with-tls"))]
+            Some(config) => match config {},
+            None => rpc_server.bind(rpc_addr),
+        }
+        .unwrap_or_else(|e| {
+            startup::fail(
+                ExitCode::Bind,
+                format!("unable to listen for rpc on {}: {}", rpc_addr, e),
+            )
+        });
+        let rpc_addr = rpc_server.addrs().first().copied().unwrap_or(rpc_addr);
+        actix::spawn(rpc_server.run());
+
+        let mut grpc_addr = None;
+        if let Some(port) = server_opts.grpc_port {
+            let addr = SocketAddr::new(server_opts.rpc_host, port);
+            match grpc::serve(addr) {
+                Ok((addr, calls)) => {
+                    grpc_addr = Some(addr);
+                    let state = web::Data::new(State {
+                        db: db_ready.clone(),
+                        hasher: grpc_hasher,
+                        opts: server_opts.clone(),
+                        health: health.clone(),
+                        config: grpc_config,
+                    });
+                    actix::spawn(serve_grpc_calls(state, calls));
+                }
+                Err(e @ error::Error::IO(_)) => startup::fail(
+                    ExitCode::Bind,
+                    format!("unable to listen for grpc on {}: {}", addr, e),
+                ),
+                Err(e) => startup::fail(
+                    ExitCode::Config,
+                    format!("unable to start grpc server: {}", e),
+                ),
+            }
+        }
+
+        health.set_listening();
+        health::watch_shutdown(health.clone());
+        let lan_discovery = server_opts.lan_discovery;
+        actix::spawn(async move {
+            match database::id(&db_ready).await {
+                Ok(id) => {
+                    if lan_discovery {
+                        if let Err(e) = discovery::start(id, transfer_addr.port()) {
+                            log::error!("lan discovery disabled: {}", e);
+                        }
+                    }
+                    health::notify("READY=1");
+                    startup::announce(&startup::Handshake::Ready {
+                        version: version::PACKAGE_VERSION.into(),
+                        node_id: hash_encoding::encode(id),
+                        pid: std::process::id(),
+                        transfer: transfer_addr,
+                        rpc: rpc_addr,
+                        grpc: grpc_addr,
+                    });
+                }
+                Err(e) => startup::fail(ExitCode::Database, format!("database not ready: {}", e)),
+            }
+        });
+    });
 
-    sys.run()
+    let r = sys.run();
+    trace::shutdown();
+    r
 }
@@ -0,0 +1,91 @@
+//! Runtime modes operators switch to before migrating the database or taking the node
+//! out of rotation, without stopping running transfers.
+use crate::error::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum NodeMode {
+    #[default]
+    Normal,
+    /// Existing shares are served, no new ones are added.
+    ReadOnly,
+    /// Running transfers finish, new downloads, uploads and asks of peers are refused.
+    Maintenance,
+}
+
+impl fmt::Display for NodeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NodeMode::Normal => "normal",
+            NodeMode::ReadOnly => "readOnly",
+            NodeMode::Maintenance => "maintenance",
+        })
+    }
+}
+
+impl FromStr for NodeMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "normal" => Ok(NodeMode::Normal),
+            "readOnly" => Ok(NodeMode::ReadOnly),
+            "maintenance" => Ok(NodeMode::Maintenance),
+            _ => Err(Error::InvalidArgument(format!("invalid mode: {}", s))),
+        }
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(NodeMode::Normal as u8);
+
+pub fn current() -> NodeMode {
+    match MODE.load(Ordering::SeqCst) {
+        1 => NodeMode::ReadOnly,
+        2 => NodeMode::Maintenance,
+        _ => NodeMode::Normal,
+    }
+}
+
+pub fn set(mode: NodeMode) {
+    if MODE.swap(mode as u8, Ordering::SeqCst) != mode as u8 {
+        log::info!("switched to {} mode", mode);
+    }
+}
+
+/// Fails unless new shares can be added.
+pub fn check_share() -> Result<(), Error> {
+    match current() {
+        NodeMode::Normal => Ok(()),
+        mode => Err(Error::Unavailable(mode)),
+    }
+}
+
+/// Fails unless new transfers can be started.
+pub fn check_transfer() -> Result<(), Error> {
+    match current() {
+        NodeMode::Maintenance => Err(Error::Unavailable(NodeMode::Maintenance)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for mode in &[NodeMode::Normal, NodeMode::ReadOnly, NodeMode::Maintenance] {
+            assert_eq!(mode.to_string().parse::<NodeMode>().unwrap(), *mode);
+            assert_eq!(
+                serde_json::to_string(mode).unwrap(),
+                format!("\"{}\"", mode)
+            );
+        }
+        assert!("read_only".parse::<NodeMode>().is_err());
+    }
+}
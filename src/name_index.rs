@@ -0,0 +1,119 @@
+//! Index of the file names of shared resources, for finding the resources with a file
+//! name containing some text without going through all of them.
+//!
+//! Names are lowercased and split into trigrams. A query looks up the resources having
+//! all of its trigrams and checks their names, shorter queries check all names.
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Default)]
+pub struct NameIndex {
+    /// Lowercased file names by resource hash.
+    names: HashMap<u128, Vec<String>>,
+    /// Resources by trigram of their names.
+    trigrams: HashMap<[char; 3], HashSet<u128>>,
+}
+
+fn trigrams(name: &str) -> Vec<[char; 3]> {
+    let chars: Vec<char> = name.chars().collect();
+    chars.windows(3).map(|w| [w[0], w[1], w[2]]).collect()
+}
+
+impl NameIndex {
+    /// Indexes the file names of a resource, replacing the ones indexed before.
+    pub fn insert(&mut self, hash: u128, names: impl IntoIterator<Item = String>) {
+        self.remove(hash);
+        let names: Vec<String> = names.into_iter().map(|name| name.to_lowercase()).collect();
+        for trigram in names.iter().flat_map(|name| trigrams(name)) {
+            self.trigrams.entry(trigram).or_default().insert(hash);
+        }
+        self.names.insert(hash, names);
+    }
+
+    pub fn remove(&mut self, hash: u128) {
+        let names = match self.names.remove(&hash) {
+            Some(names) => names,
+            None => return,
+        };
+        for trigram in names.iter().flat_map(|name| trigrams(name)) {
+            if let Entry::Occupied(mut hashes) = self.trigrams.entry(trigram) {
+                hashes.get_mut().remove(&hash);
+                if hashes.get().is_empty() {
+                    hashes.remove();
+                }
+            }
+        }
+    }
+
+    /// Resources with a file name containing `query`, ignoring case.
+    pub fn search(&self, query: &str) -> HashSet<u128> {
+        let query = query.to_lowercase();
+        let query_trigrams = trigrams(&query);
+        let postings: Option<Vec<&HashSet<u128>>> = query_trigrams
+            .iter()
+            .map(|trigram| self.trigrams.get(trigram))
+            .collect();
+        let candidates: Vec<u128> = match postings {
+            // A trigram no name has.
+            None => return HashSet::new(),
+            Some(postings) => match postings.iter().min_by_key(|hashes| hashes.len()) {
+                Some(smallest) => smallest
+                    .iter()
+                    .filter(|hash| postings.iter().all(|hashes| hashes.contains(hash)))
+                    .copied()
+                    .collect(),
+                None => self.names.keys().copied().collect(),
+            },
+        };
+        // Trigrams can match in different names or out of order.
+        candidates
+            .into_iter()
+            .filter(|hash| {
+                self.names[hash]
+                    .iter()
+                    .any(|name| name.contains(query.as_str()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let mut index = NameIndex::default();
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        index.insert(1, names(&["out/output_42.png", "out/log.txt"]));
+        index.insert(2, names(&["Output_7.PNG"]));
+        index.insert(3, names(&["42_tuptuo"]));
+        index.insert(4, names(&["abc", "bcd"]));
+
+        let found = |index: &NameIndex, query| {
+            let mut found: Vec<_> = index.search(query).into_iter().collect();
+            found.sort();
+            found
+        };
+        assert_eq!(found(&index, "output_42.png"), vec![1]);
+        assert_eq!(found(&index, "OUTPUT"), vec![1, 2]);
+        assert_eq!(found(&index, ".png"), vec![1, 2]);
+        assert_eq!(found(&index, "42"), vec![1, 3]);
+        assert_eq!(found(&index, ""), vec![1, 2, 3, 4]);
+        // Both trigrams are there, but not in one name.
+        assert!(found(&index, "abcd").is_empty());
+        assert!(found(&index, "missing").is_empty());
+
+        index.remove(1);
+        assert_eq!(found(&index, "output"), vec![2]);
+        for hash in 2..=4 {
+            index.remove(hash);
+        }
+        assert!(index.trigrams.is_empty() && index.names.is_empty());
+    }
+}
@@ -0,0 +1,473 @@
+//! Simulated network between nodes of a test.
+//!
+//! `Link` is a TCP proxy adding latency, a bandwidth cap and disconnects to the traffic
+//! passing through it. Disconnect points come from a seed, so failures repeat run to run.
+use crate::database::{self, Access, DatabaseManager, RegisterHash};
+use crate::filemap::{BundleFormat, FileMap, HashAlgorithm};
+use crate::user_report::UserReportHandle;
+use crate::{hasher, server};
+use actix::Addr;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Default)]
+pub struct Conditions {
+    /// Delay of every byte in each direction.
+    pub latency: Duration,
+    /// Bytes per second in each direction, unlimited if `None`.
+    pub bytes_per_sec: Option<u64>,
+    /// Connections are cut after a number of bytes in both directions taken from the
+    /// range, see `seed`.
+    pub disconnect_after: Option<Range<u64>>,
+    pub seed: u64,
+}
+
+impl Conditions {
+    /// Byte budget of each connection, in the order they are accepted.
+    fn budgets(&self) -> impl FnMut() -> Option<u64> {
+        let range = self.disconnect_after.clone();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(self.seed);
+        move || {
+            let range = range.clone()?;
+            Some(if range.start + 1 >= range.end {
+                range.start
+            } else {
+                rng.gen_range(range.start, range.end)
+            })
+        }
+    }
+}
+
+/// Proxy to `target` listening on `addr`, running until the system stops.
+pub struct Link {
+    pub addr: SocketAddr,
+}
+
+impl Link {
+    pub async fn start(target: SocketAddr, conditions: Conditions) -> Link {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut budgets = conditions.budgets();
+        actix::spawn(async move {
+            while let Ok((inbound, _)) = listener.accept().await {
+                let budget = budgets();
+                let conditions = conditions.clone();
+                actix::spawn(async move {
+                    if let Ok(outbound) = TcpStream::connect(target).await {
+                        forward(inbound, outbound, conditions, budget).await;
+                    }
+                });
+            }
+        });
+        Link { addr }
+    }
+}
+
+/// Passes traffic both ways until either side closes or the budget runs out, then closes
+/// both connections.
+async fn forward(a: TcpStream, b: TcpStream, conditions: Conditions, budget: Option<u64>) {
+    let budget = Rc::new(Cell::new(budget));
+    let (a_read, a_write) = a.into_split();
+    let (b_read, b_write) = b.into_split();
+    let there = pump(a_read, b_write, &conditions, &budget).boxed_local();
+    let back = pump(b_read, a_write, &conditions, &budget).boxed_local();
+    future::select(there, back).await;
+}
+
+async fn pump(
+    mut from: OwnedReadHalf,
+    mut to: OwnedWriteHalf,
+    conditions: &Conditions,
+    budget: &Cell<Option<u64>>,
+) {
+    let (tx, mut rx) = mpsc::unbounded::<(Instant, Vec<u8>)>();
+    let read = async move {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = match from.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            let n = match budget.get() {
+                Some(left) => {
+                    let n = n.min(left as usize);
+                    budget.set(Some(left - n as u64));
+                    n
+                }
+                None => n,
+            };
+            let due = Instant::now() + conditions.latency;
+            if n > 0 && tx.unbounded_send((due, buf[..n].to_vec())).is_err() {
+                return;
+            }
+            if budget.get() == Some(0) {
+                return;
+            }
+        }
+    };
+    // Latency is added per chunk, so chunks in flight overlap like on a real link.
+    let write = async {
+        while let Some((due, chunk)) = rx.next().await {
+            tokio::time::sleep_until(due).await;
+            if to.write_all(&chunk).await.is_err() {
+                return;
+            }
+            if let Some(rate) = conditions.bytes_per_sec {
+                let secs = chunk.len() as f64 / rate as f64;
+                tokio::time::sleep(Duration::from_secs_f64(secs)).await;
+            }
+        }
+    };
+    future::join(read, write).await;
+}
+
+/// Node sharing files in the running system.
+pub struct TestNode {
+    pub db: Addr<DatabaseManager>,
+    pub addr: SocketAddr,
+    hasher: Addr<hasher::Hasher>,
+}
+
+impl TestNode {
+    pub async fn start(dir: &Path) -> TestNode {
+        std::fs::create_dir_all(dir).unwrap();
+        let db = database::database_manager(&Some(dir.to_owned())).unwrap();
+        let addr = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        actix::spawn(server::new(db.clone(), addr).unwrap());
+        TestNode {
+            db,
+            addr,
+            hasher: hasher::start(1),
+        }
+    }
+
+    /// Shares the files under their file names like uploads, returns the resource hash.
+    pub async fn share(&self, files: Vec<PathBuf>) -> u128 {
+        let files = files.into_iter().map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (path, name)
+        });
+        let mut files: Vec<(FileMap, PathBuf)> =
+            hasher::hash_files(&self.hasher, files, HashAlgorithm::Blake3)
+                .await
+                .unwrap();
+        crate::filemap::sort_bundle(&mut files);
+        let (inline_data, inline_files) = database::pack_inline(&files).unwrap();
+        database::call(
+            &self.db,
+            RegisterHash {
+                files,
+                valid_to: None,
+                inline_data,
+                inline_files,
+                hash_algorithm: HashAlgorithm::Blake3,
+                access: Access::default(),
+                reporter: UserReportHandle::empty(),
+                bundle_format: BundleFormat::Merkle,
+                namespace: None,
+                metadata: Default::default(),
+            },
+        )
+        .await
+        .unwrap()
+        .hash
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::{Ask, GetBlock};
+    use crate::connection::GetInlineFiles;
+    use crate::download::{self, Peer};
+    use crate::filemap::BLOCK_SIZE;
+    use actix::System;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("hyperg-netsim-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_file(dir: &Path, name: &str, size: usize) -> PathBuf {
+        let path = dir.join(name);
+        let data: Vec<u8> = (0..size).map(|i| (i * 7 / 3) as u8).collect();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn get_block(hash: u128, block_nr: u32) -> GetBlock {
+        GetBlock {
+            hash,
+            file_nr: 0,
+            block_nr,
+        }
+    }
+
+    #[test]
+    fn test_disconnect_points() {
+        let conditions = Conditions {
+            disconnect_after: Some(1000..100_000),
+            seed: 7,
+            ..Conditions::default()
+        };
+        let (mut first, mut second) = (conditions.budgets(), conditions.budgets());
+        let points: Vec<_> = (0..8).map(|_| first()).collect();
+        assert_eq!(points, (0..8).map(|_| second()).collect::<Vec<_>>());
+        assert!(points
+            .iter()
+            .all(|point| (1000..100_000).contains(&point.unwrap())));
+        assert_eq!(Conditions::default().budgets()(), None);
+    }
+
+    #[test]
+    fn test_latency() {
+        let dir = test_dir("latency");
+        let sys = System::new();
+        sys.block_on(async {
+            let node = TestNode::start(&dir.join("server")).await;
+            let client = TestNode::start(&dir.join("client")).await;
+            let hash = node.share(vec![write_file(&dir, "a", 1000)]).await;
+            let latency = Duration::from_millis(50);
+            let link = Link::start(
+                node.addr,
+                Conditions {
+                    latency,
+                    ..Conditions::default()
+                },
+            )
+            .await;
+
+            let started = Instant::now();
+            let (connection, _) =
+                download::connect(client.db, vec![link.addr], UserReportHandle::empty())
+                    .await
+                    .map_err(|e| e[0].1.to_string())
+                    .unwrap();
+            connection
+                .send(Ask::new(hash, None))
+                .await
+                .unwrap()
+                .unwrap();
+            let block = connection.send(get_block(hash, 0)).await.unwrap().unwrap();
+            assert_eq!(block.bytes.len(), 1000);
+            // The ask and the block request each wait for an answer.
+            assert!(started.elapsed() >= latency * 4);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_disconnect_fails_requests() {
+        let dir = test_dir("disconnect");
+        let sys = System::new();
+        sys.block_on(async {
+            let node = TestNode::start(&dir.join("server")).await;
+            let client = TestNode::start(&dir.join("client")).await;
+            let hash = node
+                .share(vec![write_file(&dir, "a", BLOCK_SIZE * 2)])
+                .await;
+            let link = Link::start(
+                node.addr,
+                Conditions {
+                    disconnect_after: Some(BLOCK_SIZE as u64..BLOCK_SIZE as u64 + 1),
+                    ..Conditions::default()
+                },
+            )
+            .await;
+
+            let (connection, _) =
+                download::connect(client.db, vec![link.addr], UserReportHandle::empty())
+                    .await
+                    .map_err(|e| e[0].1.to_string())
+                    .unwrap();
+            connection
+                .send(Ask::new(hash, None))
+                .await
+                .unwrap()
+                .unwrap();
+            let blocks = future::join(
+                connection.send(get_block(hash, 0)),
+                connection.send(get_block(hash, 1)),
+            );
+            // Requests in flight fail when the connection is lost instead of hanging.
+            let (first, second) = tokio::time::timeout(Duration::from_secs(10), blocks)
+                .await
+                .expect("requests still pending after disconnect");
+            assert!(first.map_or(true, |r| r.is_err()) || second.map_or(true, |r| r.is_err()));
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_slow_peer_is_replaced() {
+        let dir = test_dir("switch");
+        let sys = System::new();
+        sys.block_on(async {
+            let path = write_file(&dir, "a", BLOCK_SIZE);
+            let slow = TestNode::start(&dir.join("slow")).await;
+            let fast = TestNode::start(&dir.join("fast")).await;
+            let client = TestNode::start(&dir.join("client")).await;
+            let hash = slow.share(vec![path.clone()]).await;
+            assert_eq!(fast.share(vec![path]).await, hash);
+            let slow_link = Link::start(
+                slow.addr,
+                Conditions {
+                    bytes_per_sec: Some(256 * 1024),
+                    ..Conditions::default()
+                },
+            )
+            .await;
+
+            let slow_peer = Peer::Direct(slow_link.addr);
+            let fast_peer = Peer::Direct(fast.addr);
+            let find = {
+                let db = client.db.clone();
+                move |peers| {
+                    download::find_peer(
+                        hash,
+                        Default::default(),
+                        db.clone(),
+                        peers,
+                        UserReportHandle::empty(),
+                    )
+                }
+            };
+            let (connection, _, peer) = find(vec![vec![slow_peer]]).await.unwrap();
+            assert_eq!(peer, slow_peer);
+            let source = download::BlockSource::new(
+                connection,
+                peer,
+                vec![vec![slow_peer], vec![fast_peer]],
+                Some(Duration::from_millis(200)),
+                find,
+                UserReportHandle::empty(),
+            );
+
+            let (block, peer) = source.get_block(get_block(hash, 0)).await.unwrap();
+            assert_eq!(block.bytes.len(), BLOCK_SIZE);
+            assert_eq!(peer, fast_peer);
+            assert_eq!(source.peer(), fast_peer);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_inline_files() {
+        let dir = test_dir("inline");
+        let sys = System::new();
+        sys.block_on(async {
+            let node = TestNode::start(&dir.join("server")).await;
+            let client = TestNode::start(&dir.join("client")).await;
+            let files = vec![
+                write_file(&dir, "a", 10),
+                write_file(&dir, "b", 1000),
+                write_file(&dir, "c", 150),
+            ];
+            let hash = node.share(files).await;
+
+            let (connection, _) =
+                download::connect(client.db, vec![node.addr], UserReportHandle::empty())
+                    .await
+                    .map_err(|e| e[0].1.to_string())
+                    .unwrap();
+            let reply = connection
+                .send(Ask::new(hash, None))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(reply.inline_files.len(), 2);
+            // "b" is served from disk, so the inline data is not sent with the reply.
+            assert!(reply.inline_data.is_none());
+            let inline = connection
+                .send(GetInlineFiles(hash))
+                .await
+                .unwrap()
+                .unwrap();
+            let sizes: Vec<_> = inline
+                .iter()
+                .map(|(file_nr, bytes)| (*file_nr, bytes.len()))
+                .collect();
+            assert_eq!(sizes, vec![(0, 10), (2, 150)]);
+            // Older peers request inline files block by block.
+            let block = connection.send(get_block(hash, 0)).await.unwrap().unwrap();
+            assert_eq!(block.bytes, inline[0].1);
+            let block = connection
+                .send(GetBlock {
+                    hash,
+                    file_nr: 2,
+                    block_nr: 0,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(block.bytes, inline[1].1);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_inline_data_in_ask_reply() {
+        let dir = test_dir("inline-reply");
+        crate::connection::set_inline_replies(true);
+        let sys = System::new();
+        sys.block_on(async {
+            let node = TestNode::start(&dir.join("server")).await;
+            let client = TestNode::start(&dir.join("client")).await;
+            let files = vec![write_file(&dir, "a", 10), write_file(&dir, "b", 150)];
+            let hash = node.share(files).await;
+            let latency = Duration::from_millis(100);
+            let link = Link::start(
+                node.addr,
+                Conditions {
+                    latency,
+                    ..Conditions::default()
+                },
+            )
+            .await;
+
+            let (connection, _) =
+                download::connect(client.db, vec![link.addr], UserReportHandle::empty())
+                    .await
+                    .map_err(|e| e[0].1.to_string())
+                    .unwrap();
+            let reply = connection
+                .send(Ask::new(hash, None))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(reply.inline_data.map(|data| data.len()), Some(160));
+            // Answered from the reply, a request would take a round trip.
+            let started = Instant::now();
+            let inline = connection
+                .send(GetInlineFiles(hash))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(started.elapsed() < latency);
+            let sizes: Vec<_> = inline
+                .iter()
+                .map(|(file_nr, bytes)| (*file_nr, bytes.len()))
+                .collect();
+            assert_eq!(sizes, vec![(0, 10), (1, 150)]);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,411 @@
+//! OpenAPI description of the RPC API served at `/openapi.json`.
+//!
+//! Schemas are generated from the request and response types in `command`, so they follow
+//! changes of those. Endpoints answering with ad hoc JSON are described as plain objects.
+use crate::command::{
+    AddressesResult, ArchiveQuery, AvailabilityResult, BlocklistResult, CleanupQuery, Command,
+    DownloadBatchResult, DownloadResult, ErrorResult, GcQuery, HashResult, IdResult, ListQuery,
+    ModeResult, NamespaceQuery, PrivacyResult, ProofQuery, RemoveQuery, ReplicateResult,
+    StatusResult, StreamQuery, UploadResult, UploadStatus, VersionResult,
+};
+use crate::version::PACKAGE_VERSION;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+
+/// Schema of the body of the command named `command`, without references, for errors of
+/// bodies not fitting it.
+pub fn command_schema(command: &str) -> Option<Value> {
+    command_schema_in(SchemaSettings::draft07(), command)
+}
+
+fn command_schema_in(mut settings: SchemaSettings, command: &str) -> Option<Value> {
+    settings.inline_subschemas = true;
+    let root = settings.into_generator().into_root_schema_for::<Command>();
+    root.schema
+        .subschemas?
+        .one_of?
+        .into_iter()
+        .filter_map(|schema| serde_json::to_value(schema).ok())
+        .find(|schema| schema["properties"]["command"]["enum"][0] == command)
+}
+
+/// Schema of `T`, a reference to `components/schemas` for named types.
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(gen.subschema_for::<T>()).unwrap_or_default()
+}
+
+/// Query parameters for the fields of the struct `T`.
+fn query<T: JsonSchema>(gen: &mut SchemaGenerator) -> Vec<Value> {
+    let object = match gen.root_schema_for::<T>().schema.object {
+        Some(object) => *object,
+        None => return Vec::new(),
+    };
+    let required = object.required;
+    object
+        .properties
+        .into_iter()
+        .map(|(name, schema)| {
+            json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(&name),
+                "schema": schema,
+            })
+        })
+        .collect()
+}
+
+/// `query` with the `{name}` path parameter in front.
+fn path_and_query<T: JsonSchema>(gen: &mut SchemaGenerator, name: &str) -> Vec<Value> {
+    let mut parameters = vec![json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": {"type": "string"},
+    })];
+    parameters.extend(query::<T>(gen));
+    parameters
+}
+
+/// Operation of a `/api/v2` route taking the fields of `command` in the body.
+fn command_route(summary: &str, command: &str, result: Value, error: &Value) -> Value {
+    let mut fields = command_schema_in(SchemaSettings::openapi3(), command).unwrap_or_default();
+    if let Some(properties) = fields["properties"].as_object_mut() {
+        properties.remove("command");
+    }
+    if let Some(required) = fields["required"].as_array_mut() {
+        required.retain(|name| name != "command");
+    }
+    json!({"post": {
+        "summary": summary,
+        "requestBody": {"required": true, "content": {"application/json": {"schema": fields}}},
+        "responses": {"200": json_ok(&result), "default": error},
+    }})
+}
+
+fn ok(content_type: &str, schema: &Value) -> Value {
+    json!({
+        "description": "OK",
+        "content": {content_type: {"schema": schema}},
+    })
+}
+
+fn json_ok(schema: &Value) -> Value {
+    ok("application/json", schema)
+}
+
+fn no_content() -> Value {
+    json!({"description": "Done"})
+}
+
+pub fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let object = json!({"type": "object"});
+    let text = json!({"type": "string"});
+    let binary = json!({"type": "string", "format": "binary"});
+    let error = json!({
+        "description": "Error",
+        "content": {"application/json": {"schema": schema::<ErrorResult>(&mut gen)}},
+    });
+    let not_found = json!({"description": "Not found"});
+    let command_results = json!({"oneOf": [
+        schema::<IdResult>(&mut gen),
+        schema::<AddressesResult>(&mut gen),
+        schema::<UploadResult>(&mut gen),
+        schema::<DownloadResult>(&mut gen),
+        schema::<DownloadBatchResult>(&mut gen),
+        schema::<HashResult>(&mut gen),
+        schema::<AvailabilityResult>(&mut gen),
+        schema::<ModeResult>(&mut gen),
+        schema::<PrivacyResult>(&mut gen),
+        schema::<BlocklistResult>(&mut gen),
+        schema::<ReplicateResult>(&mut gen),
+    ]});
+
+    let paths = json!({
+        "/api": {"post": {
+            "summary": "Runs a command, see COMMANDS.md",
+            "requestBody": {
+                "required": true,
+                "content": {"application/json": {"schema": schema::<Command>(&mut gen)}},
+            },
+            "responses": {"200": json_ok(&command_results), "default": error},
+        }},
+        "/healthz": {"get": {
+            "summary": "Liveness probe",
+            "responses": {"200": ok("text/plain", &text)},
+        }},
+        "/readyz": {"get": {
+            "summary": "Readiness probe",
+            "responses": {"200": ok("text/plain", &text), "503": ok("text/plain", &text)},
+        }},
+        "/status": {"get": {
+            "summary": "Instance status",
+            "responses": {"200": json_ok(&schema::<StatusResult>(&mut gen)), "default": error},
+        }},
+        "/stats": {"get": {
+            "summary": "Bytes served and fetched per user",
+            "responses": {"200": json_ok(&object), "default": error},
+        }},
+        "/version": {"get": {
+            "summary": "Package, build and protocol version",
+            "responses": {"200": json_ok(&schema::<VersionResult>(&mut gen))},
+        }},
+        "/openapi.json": {"get": {
+            "summary": "This document",
+            "responses": {"200": json_ok(&object)},
+        }},
+        "/config": {"get": {
+            "summary": "Effective configuration, as logged on start",
+            "responses": {"200": json_ok(&object)},
+        }},
+        "/traffic": {"get": {
+            "summary": "Bytes served this month and the monthly quota",
+            "responses": {"200": json_ok(&object), "default": error},
+        }},
+        "/errors": {"get": {
+            "summary": "Failed operations reported lately, oldest first",
+            "responses": {"200": json_ok(&json!({"type": "array", "items": object}))},
+        }},
+        "/ui": {"get": {
+            "summary": "Status page, served with --ui",
+            "responses": {"200": ok("text/html", &text), "404": not_found},
+        }},
+        "/connections": {"get": {
+            "summary": "Open transfer connections",
+            "responses": {"200": json_ok(&json!({"type": "array", "items": object}))},
+        }},
+        "/debug/actors": {"get": {
+            "summary": "Actor mailboxes, database queue, GC timing and pending requests per connection",
+            "responses": {"200": json_ok(&object)},
+        }},
+        "/resources": {"get": {
+            "summary": "Shared resources",
+            "parameters": query::<ListQuery>(&mut gen),
+            "responses": {"200": json_ok(&json!({"type": "array", "items": object})), "default": error},
+        }},
+        "/resources/{resourceId}": {
+            "get": {
+                "summary": "Shared resource with its serving stats",
+                "parameters": path_and_query::<NamespaceQuery>(&mut gen, "resourceId"),
+                "responses": {"200": json_ok(&object), "404": not_found, "default": error},
+            },
+            "delete": {
+                "summary": "Unshares a resource, soft deleted with `grace`",
+                "parameters": path_and_query::<RemoveQuery>(&mut gen, "resourceId"),
+                "responses": {"204": no_content(), "404": not_found, "default": error},
+            },
+        },
+        "/resources/{resourceId}/proof": {"get": {
+            "summary": "Merkle proof of a file, and of one of its blocks, in the resource hash",
+            "parameters": path_and_query::<ProofQuery>(&mut gen, "resourceId"),
+            "responses": {"200": json_ok(&object), "404": not_found, "default": error},
+        }},
+        "/resources/{resourceId}/restore": {"post": {
+            "summary": "Shares a soft deleted resource again",
+            "parameters": path_and_query::<NamespaceQuery>(&mut gen, "resourceId"),
+            "responses": {"204": no_content(), "404": not_found, "default": error},
+        }},
+        "/resources/{resourceId}/archive": {"get": {
+            "summary": "Resource files as a tar archive",
+            "parameters": path_and_query::<()>(&mut gen, "resourceId"),
+            "responses": {"200": ok("application/x-tar", &binary), "404": not_found, "default": error},
+        }},
+        "/resources/archive": {"post": {
+            "summary": "Shares the content of a tar or zip archive",
+            "parameters": query::<ArchiveQuery>(&mut gen),
+            "requestBody": {"required": true, "content": {"application/octet-stream": {"schema": binary}}},
+            "responses": {"200": json_ok(&schema::<UploadResult>(&mut gen)), "default": error},
+        }},
+        "/resources/stream": {"post": {
+            "summary": "Shares the request body as a single file",
+            "parameters": query::<StreamQuery>(&mut gen),
+            "requestBody": {"required": true, "content": {"application/octet-stream": {"schema": binary}}},
+            "responses": {"200": json_ok(&schema::<UploadResult>(&mut gen)), "default": error},
+        }},
+        "/resources/stream/{upload}": {"get": {
+            "summary": "Offset to continue an interrupted upload at",
+            "parameters": path_and_query::<()>(&mut gen, "upload"),
+            "responses": {"200": json_ok(&schema::<UploadStatus>(&mut gen)), "404": not_found, "default": error},
+        }},
+        "/artifacts/cleanup": {"post": {
+            "summary": "Removes files left by downloads and unfinished uploads",
+            "parameters": query::<CleanupQuery>(&mut gen),
+            "responses": {"200": json_ok(&object), "default": error},
+        }},
+        "/admin/gc": {"post": {
+            "summary": "Unshares expired resources now",
+            "parameters": query::<GcQuery>(&mut gen),
+            "responses": {"200": json_ok(&object), "default": error},
+        }},
+        "/admin/integrity": {"post": {
+            "summary": "Checks shared files and compacts the database directory",
+            "parameters": query::<CleanupQuery>(&mut gen),
+            "responses": {"200": json_ok(&object), "default": error},
+        }},
+    });
+
+    let mut paths = paths;
+    // The v2 routes share the handlers of the routes above.
+    for path in [
+        "/resources",
+        "/resources/{resourceId}",
+        "/resources/{resourceId}/proof",
+        "/resources/{resourceId}/restore",
+        "/resources/{resourceId}/archive",
+        "/resources/archive",
+        "/resources/stream",
+        "/resources/stream/{upload}",
+    ] {
+        paths[format!("/api/v2{}", path)] = paths[path].clone();
+    }
+    paths["/api/v2/peers"] = paths["/connections"].clone();
+    let v2_commands = [
+        (
+            "/node",
+            json!({"get": {
+                "summary": "Node id and version, like the `id` command",
+                "responses": {"200": json_ok(&schema::<IdResult>(&mut gen)), "default": error},
+            }}),
+        ),
+        (
+            "/resources",
+            command_route(
+                "Shares files, like the `upload` command",
+                "upload",
+                schema::<UploadResult>(&mut gen),
+                &error,
+            ),
+        ),
+        (
+            "/resources/hash",
+            command_route(
+                "Hash of files without sharing them, like the `hash` command",
+                "hash",
+                schema::<HashResult>(&mut gen),
+                &error,
+            ),
+        ),
+        (
+            "/transfers",
+            command_route(
+                "Downloads a resource, like the `download` command",
+                "download",
+                schema::<DownloadResult>(&mut gen),
+                &error,
+            ),
+        ),
+        (
+            "/transfers/batch",
+            command_route(
+                "Downloads several resources, like the `download_batch` command",
+                "download_batch",
+                schema::<DownloadBatchResult>(&mut gen),
+                &error,
+            ),
+        ),
+        (
+            "/replications",
+            command_route(
+                "Makes other nodes download a resource, like the `replicate` command",
+                "replicate",
+                schema::<ReplicateResult>(&mut gen),
+                &error,
+            ),
+        ),
+        (
+            "/peers/availability",
+            command_route(
+                "Asks peers for a resource, like the `check_availability` command",
+                "check_availability",
+                schema::<AvailabilityResult>(&mut gen),
+                &error,
+            ),
+        ),
+    ];
+    for (path, operations) in v2_commands {
+        let path = &mut paths[format!("/api/v2{}", path)];
+        match (path.as_object_mut(), operations) {
+            (Some(path), Value::Object(operations)) => path.extend(operations),
+            (_, operations) => *path = operations,
+        }
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {"title": "hyperg RPC", "version": PACKAGE_VERSION},
+        "paths": paths,
+        "components": {"schemas": gen.take_definitions()},
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn refs<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(object) => {
+                if let Some(Value::String(target)) = object.get("$ref") {
+                    found.push(target);
+                }
+                object.values().for_each(|value| refs(value, found));
+            }
+            Value::Array(values) => values.iter().for_each(|value| refs(value, found)),
+            _ => (),
+        }
+    }
+
+    #[test]
+    fn test_document() {
+        let document = document();
+        let schemas = &document["components"]["schemas"];
+        let mut found = Vec::new();
+        refs(&document, &mut found);
+        assert!(!found.is_empty());
+        for target in found {
+            let name = target.trim_start_matches("#/components/schemas/");
+            assert!(schemas.get(name).is_some(), "{} is not defined", target);
+        }
+
+        // Every command can be told apart by its tag.
+        let commands = serde_json::to_string(&schemas["Command"]).unwrap();
+        for tag in &[
+            "id",
+            "upload",
+            "download",
+            "replicate",
+            "check_availability",
+            "mode",
+        ] {
+            assert!(commands.contains(&format!("\"{}\"", tag)), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn test_v2_routes() {
+        let paths = &document()["paths"];
+        let resources = &paths["/api/v2/resources"];
+        assert!(resources.get("get").is_some() && resources.get("post").is_some());
+        let fields = &resources["post"]["requestBody"]["content"]["application/json"]["schema"];
+        assert!(fields["properties"].get("files").is_some());
+        assert!(fields["properties"].get("command").is_none());
+        for path in ["/api/v2/node", "/api/v2/transfers", "/api/v2/peers"] {
+            assert!(paths.get(path).is_some(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_command_schema() {
+        let schema = command_schema("download").unwrap();
+        assert!(schema["properties"].get("dest").is_some());
+        assert!(schema["properties"].get("size").is_none());
+        assert_eq!(schema["additionalProperties"], false);
+        let mut found = Vec::new();
+        refs(&schema, &mut found);
+        assert!(found.is_empty(), "{:?}", found);
+        assert!(command_schema("check_availability").is_some());
+        assert!(command_schema("dowload").is_none());
+    }
+}
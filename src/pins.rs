@@ -0,0 +1,179 @@
+//! Trust on first use pinning of peer node ids.
+//!
+//! The node id a peer proves, see `identity::prove`, is remembered for the address the
+//! connection was made to; a different id presented later is refused unless the download
+//! repins. Ids peers don't prove are never pinned. The proof is bound to the connection and
+//! authenticates the packets following it, so a node in the middle passing it on can't
+//! serve under the pinned id.
+use crate::codec::hash_to_hex;
+use crate::error::{Error, ProtocolError};
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+struct Pins {
+    path: PathBuf,
+    /// Hex node id by address
+    peers: HashMap<String, String>,
+}
+
+static PINS: OnceLock<Mutex<Option<Pins>>> = OnceLock::new();
+
+fn pins() -> std::sync::MutexGuard<'static, Option<Pins>> {
+    match PINS.get_or_init(Default::default).lock() {
+        Ok(pins) => pins,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Enables pinning, keeping the pins in `path`.
+pub fn enable(path: PathBuf) -> Result<(), Error> {
+    let peers = match fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    *pins() = Some(Pins { path, peers });
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    pins().is_some()
+}
+
+/// Pins the proven `node_id` for `addr` unless another id is pinned for it.
+///
+/// With `repin` the pinned id is replaced instead. Does nothing while pinning is disabled.
+pub fn check(addr: SocketAddr, node_id: u128, repin: bool) -> Result<(), ProtocolError> {
+    match pins().as_mut() {
+        Some(pins) => pins.check(addr, node_id, repin),
+        None => Ok(()),
+    }
+}
+
+/// Refuses the unproven `node_id` for `addr` when an id is pinned for it, without
+/// pinning it otherwise.
+pub fn check_unproven(addr: SocketAddr, node_id: u128) -> Result<(), ProtocolError> {
+    match pins().as_ref() {
+        Some(pins) => pins.check_unproven(addr, node_id),
+        None => Ok(()),
+    }
+}
+
+impl Pins {
+    fn check(&mut self, addr: SocketAddr, node_id: u128, repin: bool) -> Result<(), ProtocolError> {
+        let key = addr.to_string();
+        let seen = hash_to_hex(node_id);
+        match self.peers.get(&key) {
+            Some(pinned) if *pinned == seen => return Ok(()),
+            Some(pinned) if !repin => {
+                return Err(ProtocolError::PeerIdChanged {
+                    pinned: u128::from_str_radix(pinned, 16).unwrap_or_default(),
+                    seen: node_id,
+                })
+            }
+            Some(pinned) => log::warn!("repinned {} from {} to {}", addr, pinned, seen),
+            None => log::info!("pinned {} to {}", addr, seen),
+        }
+        self.peers.insert(key, seen);
+        if let Err(e) = self.save() {
+            log::error!("failed to save pinned peers: {}", e);
+        }
+        Ok(())
+    }
+
+    fn check_unproven(&self, addr: SocketAddr, node_id: u128) -> Result<(), ProtocolError> {
+        let pinned = match self.peers.get(&addr.to_string()) {
+            Some(pinned) => u128::from_str_radix(pinned, 16).unwrap_or_default(),
+            None => return Ok(()),
+        };
+        if pinned == node_id {
+            Err(ProtocolError::UnprovenPeerId(node_id))
+        } else {
+            Err(ProtocolError::PeerIdChanged {
+                pinned,
+                seen: node_id,
+            })
+        }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&self.peers)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::Identify;
+    use crate::identity;
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn test_check() {
+        let path = std::env::temp_dir().join(format!("hyperg-pins-{}.json", std::process::id()));
+        let addr: SocketAddr = "10.0.0.1:3282".parse().unwrap();
+        assert!(check(addr, 1, false).is_ok());
+
+        enable(path.clone()).unwrap();
+        assert!(check(addr, 1, false).is_ok());
+        assert!(check(addr, 1, false).is_ok());
+        match check(addr, 2, false) {
+            Err(ProtocolError::PeerIdChanged { pinned: 1, seen: 2 }) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(check(addr, 2, true).is_ok());
+        match check_unproven(addr, 2) {
+            Err(ProtocolError::UnprovenPeerId(2)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(check_unproven(addr, 3).is_err());
+        assert!(check_unproven("10.0.0.2:3282".parse().unwrap(), 3).is_ok());
+
+        // Pins survive a restart.
+        enable(path.clone()).unwrap();
+        assert!(check(addr, 1, false).is_err());
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_relayed_identity() {
+        let path = std::env::temp_dir().join(format!("hyperg-relayed-{}.json", std::process::id()));
+        let mut pins = Pins {
+            path: path.clone(),
+            peers: HashMap::new(),
+        };
+        let addr: SocketAddr = "10.0.0.3:3282".parse().unwrap();
+        let secret_key = identity::generate_key();
+        let key = SigningKey::from_bytes(&secret_key);
+        let node_id = identity::node_id(&secret_key);
+        let verifier_id = 9;
+
+        let (identify, secret) = identity::identify();
+        let (proof, _) =
+            identity::prove_with(&key, &identify, verifier_id, rand::random()).unwrap();
+        assert!(identity::verify_proof(&proof, &identify, secret, verifier_id, node_id).is_some());
+        assert!(pins.check(addr, node_id, false).is_ok());
+
+        // A node in the middle passes `Identify` on with its own key share, to learn the key
+        // the packets are tagged with.
+        let (identify, secret) = identity::identify();
+        let (relayed, _) = identity::identify();
+        let relayed = Identify {
+            nonce: identify.nonce,
+            ..relayed
+        };
+        let (proof, _) = identity::prove_with(&key, &relayed, verifier_id, rand::random()).unwrap();
+        assert!(identity::verify_proof(&proof, &identify, secret, verifier_id, node_id).is_none());
+        match pins.check_unproven(addr, node_id) {
+            Err(ProtocolError::UnprovenPeerId(id)) => assert_eq!(id, node_id),
+            r => panic!("unexpected {:?}", r),
+        }
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,300 @@
+//! Forwarding of transfer traffic for nodes that can not accept connections.
+//!
+//! A node behind NAT keeps a control connection to a relay (`RelayRegister`).
+//! A downloader asks the relay for the node (`RelayConnect`), the relay passes a
+//! token on the control connection (`RelayOffer`) and the node opens a new
+//! connection presenting it (`RelayAccept`). From then on the relay forwards
+//! packets between the two connections, starting with the downloader's `Hello`.
+use crate::codec::{RelayStatus, StCommand};
+use crate::connection::Connection;
+use crate::database::DatabaseManager;
+use crate::error::Error;
+use actix::prelude::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Time a registered node gets to accept a relay session.
+pub const RELAY_ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Concurrent sessions relayed to a single registered node.
+const MAX_SESSIONS_PER_PEER: usize = 8;
+
+/// Control connection to the relay is kept alive, checked and reopened this often.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy)]
+pub struct RelayConfig {
+    /// Max number of registered nodes.
+    pub max_peers: usize,
+    /// Block bytes forwarded per session in each direction.
+    pub quota: u64,
+}
+
+struct RegisteredPeer {
+    connection_id: usize,
+    control: Addr<Connection>,
+    sessions: usize,
+}
+
+struct PendingSession {
+    node_id: u128,
+    requester: Addr<Connection>,
+}
+
+#[derive(Default)]
+struct Registry {
+    config: Option<RelayConfig>,
+    peers: HashMap<u128, RegisteredPeer>,
+    pending: HashMap<u128, PendingSession>,
+}
+
+static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    match REGISTRY.get_or_init(Default::default).lock() {
+        Ok(registry) => registry,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Allows nodes to register at this node as a relay.
+pub fn enable(config: RelayConfig) {
+    registry().config = Some(config);
+}
+
+pub fn is_enabled() -> bool {
+    registry().config.is_some()
+}
+
+pub fn quota() -> u64 {
+    registry().config.map(|config| config.quota).unwrap_or(0)
+}
+
+/// Registers the node with the proven `node_id` over the control connection. A node
+/// registered over another connection can register again once that one is closed.
+pub fn register(node_id: u128, connection_id: usize, control: Addr<Connection>) -> RelayStatus {
+    let mut registry = registry();
+    let max_peers = match registry.config {
+        Some(config) => config.max_peers,
+        None => return RelayStatus::Disabled,
+    };
+    let sessions = match registry.peers.get(&node_id) {
+        Some(peer) if peer.connection_id != connection_id && peer.control.connected() => {
+            return RelayStatus::Registered
+        }
+        Some(peer) => peer.sessions,
+        None if registry.peers.len() >= max_peers => return RelayStatus::QuotaExceeded,
+        None => 0,
+    };
+    registry.peers.insert(
+        node_id,
+        RegisteredPeer {
+            connection_id,
+            control,
+            sessions,
+        },
+    );
+    RelayStatus::Ok
+}
+
+/// Drops the registration unless the node registered again over another connection.
+pub fn unregister(node_id: u128, connection_id: usize) {
+    let mut registry = registry();
+    if registry
+        .peers
+        .get(&node_id)
+        .map(|peer| peer.connection_id == connection_id)
+        .unwrap_or(false)
+    {
+        registry.peers.remove(&node_id);
+    }
+}
+
+/// Reserves a session with a registered node and offers it over the control connection.
+///
+/// Returns the session token.
+pub fn open_session(node_id: u128, requester: Addr<Connection>) -> Result<u128, RelayStatus> {
+    let mut registry = registry();
+    if registry.config.is_none() {
+        return Err(RelayStatus::Disabled);
+    }
+    let control = match registry.peers.get_mut(&node_id) {
+        Some(peer) if !peer.control.connected() => return Err(RelayStatus::UnknownPeer),
+        Some(peer) if peer.sessions >= MAX_SESSIONS_PER_PEER => {
+            return Err(RelayStatus::QuotaExceeded)
+        }
+        Some(peer) => {
+            peer.sessions += 1;
+            peer.control.clone()
+        }
+        None => return Err(RelayStatus::UnknownPeer),
+    };
+    let token = rand::random();
+    registry
+        .pending
+        .insert(token, PendingSession { node_id, requester });
+    control.do_send(Offer(token));
+    Ok(token)
+}
+
+/// Takes the pending session for a connection opened by `node_id`.
+pub fn accept(token: u128, node_id: u128) -> Option<Addr<Connection>> {
+    let mut registry = registry();
+    match registry.pending.get(&token) {
+        Some(pending) if pending.node_id == node_id => (),
+        _ => return None,
+    }
+    registry
+        .pending
+        .remove(&token)
+        .map(|pending| pending.requester)
+}
+
+/// Forgets a session not accepted in time, returns true if it was still pending.
+pub fn expire(token: u128) -> bool {
+    registry().pending.remove(&token).is_some()
+}
+
+pub fn close_session(node_id: u128) {
+    if let Some(peer) = registry().peers.get_mut(&node_id) {
+        peer.sessions = peer.sessions.saturating_sub(1);
+    }
+}
+
+/// Block payload counted against the relay quota.
+pub fn relayed_bytes(command: &StCommand) -> u64 {
+    match command {
+        StCommand::Block(block) => block.bytes.len() as u64,
+        StCommand::BlockPart(part) => part.bytes.len() as u64,
+        _ => 0,
+    }
+}
+
+/// Sent to a control connection to offer a session to the registered node.
+pub struct Offer(pub u128);
+
+impl Message for Offer {
+    type Result = ();
+}
+
+/// Binds a requester connection with the connection accepting its session.
+pub struct Pair {
+    pub peer: Addr<Connection>,
+    pub node_id: u128,
+}
+
+impl Message for Pair {
+    type Result = ();
+}
+
+/// Packet to be written by the other end of a relay session.
+pub struct Forward(pub StCommand);
+
+impl Message for Forward {
+    type Result = ();
+}
+
+/// The other end of a relay session is gone.
+pub struct Closed;
+
+impl Message for Closed {
+    type Result = ();
+}
+
+/// Opens the connection accepting a session offered by the relay.
+pub fn accept_offer(db: Addr<DatabaseManager>, relay: SocketAddr, token: u128) {
+    actix::spawn(async move {
+        let accepted = async {
+            let stream = TcpStream::connect(&relay).await?;
+            let connection = Connection::new(
+                db,
+                stream,
+                relay,
+                &crate::user_report::UserReportHandle::empty(),
+            )
+            .await?;
+            connection.send(crate::codec::RelayAccept { token }).await?
+        };
+        if let Err(e) = accepted.await {
+            log::error!("unable to accept session from relay {}: {}", relay, e)
+        }
+    });
+}
+
+/// Keeps this node registered at a relay.
+pub struct RelayClient {
+    db: Addr<DatabaseManager>,
+    relay: SocketAddr,
+    control: Option<Addr<Connection>>,
+    connecting: bool,
+}
+
+impl RelayClient {
+    pub fn start(db: Addr<DatabaseManager>, relay: SocketAddr) -> Addr<RelayClient> {
+        RelayClient {
+            db,
+            relay,
+            control: None,
+            connecting: false,
+        }
+        .start()
+    }
+
+    fn connect(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if self.connecting
+            || self
+                .control
+                .as_ref()
+                .map(|control| control.connected())
+                .unwrap_or(false)
+        {
+            return;
+        }
+        self.connecting = true;
+        let db = self.db.clone();
+        let relay = self.relay;
+
+        ctx.spawn(
+            async move {
+                let stream = TcpStream::connect(&relay).await?;
+                let control = Connection::new(
+                    db,
+                    stream,
+                    relay,
+                    &crate::user_report::UserReportHandle::empty(),
+                )
+                .await?;
+                control.send(crate::codec::RelayRegister).await??;
+                Ok(control)
+            }
+            .into_actor(self)
+            .map(move |r: Result<_, Error>, act, _ctx| {
+                act.connecting = false;
+                match r {
+                    Ok(control) => {
+                        log::info!("registering at relay {}", relay);
+                        act.control = Some(control)
+                    }
+                    Err(e) => log::error!("unable to connect relay {}: {}", relay, e),
+                }
+            }),
+        );
+    }
+}
+
+impl Actor for RelayClient {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect(ctx);
+        ctx.run_interval(RECONNECT_INTERVAL, |act, ctx| {
+            if let Some(control) = &act.control {
+                control.do_send(crate::codec::KeepAlive);
+            }
+            act.connect(ctx)
+        });
+    }
+}
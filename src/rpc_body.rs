@@ -0,0 +1,78 @@
+//! Checks of RPC request bodies enabled with `--rpc_check_body`, telling bodies cut short
+//! or followed by garbage on flaky local sockets apart from invalid commands.
+use crate::error::Error;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use sha2::digest::Digest;
+
+/// Hex SHA-256 of the body, checked when a request has it and added to answers of `/api`.
+pub const CHECKSUM_HEADER: &str = "x-content-sha256";
+
+pub fn checksum(body: &[u8]) -> String {
+    sha2::Sha256::digest(body)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Checks that `body` is all of the announced `content_length`, matches `checksum` when
+/// given and holds exactly one JSON value.
+pub fn check(
+    body: &[u8],
+    content_length: Option<usize>,
+    expected_checksum: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(expected) = content_length.filter(|&len| len != body.len()) {
+        return Err(Error::TruncatedBody {
+            received: body.len(),
+            expected: Some(expected),
+        });
+    }
+    if let Some(expected) = expected_checksum {
+        if !expected.eq_ignore_ascii_case(&checksum(body)) {
+            return Err(Error::BodyChecksum(body.len()));
+        }
+    }
+    let mut de = serde_json::Deserializer::from_slice(body);
+    match IgnoredAny::deserialize(&mut de) {
+        Err(e) if e.is_eof() => {
+            return Err(Error::TruncatedBody {
+                received: body.len(),
+                expected: None,
+            })
+        }
+        Err(e) => return Err(Error::InvalidJsonFormat(e)),
+        Ok(_) => (),
+    }
+    de.end().map_err(|_| Error::TrailingData(body.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    #[test]
+    fn test_check() {
+        let body = br#"{"command": "id"}"#;
+        assert!(check(body, Some(body.len()), Some(&checksum(body))).is_ok());
+        assert!(check(b" {}\n", None, None).is_ok());
+
+        let code = |r: Result<(), Error>| r.unwrap_err().code();
+        let truncated = ErrorCode::TruncatedBody as u16;
+        assert_eq!(code(check(&body[..10], None, None)), truncated);
+        assert_eq!(code(check(&body[..10], Some(body.len()), None)), truncated);
+        assert_eq!(
+            code(check(b"{\"command\": \"id\"}}", None, None)),
+            ErrorCode::TrailingData as u16
+        );
+        assert_eq!(
+            code(check(body, None, Some(&checksum(b"{}")))),
+            ErrorCode::BodyChecksum as u16
+        );
+        assert_eq!(
+            code(check(b"{\"command\" 1}", None, None)),
+            ErrorCode::InvalidArgument as u16
+        );
+    }
+}
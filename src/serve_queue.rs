@@ -0,0 +1,156 @@
+//! Fair serving of block requests across peers.
+//!
+//! Connections keep the block requests of their peer and ask the `ServeQueue` for a
+//! turn while they have requests and room in their write queue. Turns go round robin
+//! over peer addresses, so a peer opening many connections or requesting many blocks
+//! gets no more turns than others; in its turn a peer is served up to its weight of
+//! blocks from one of its connections.
+use crate::connection::Connection;
+use actix::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+/// Blocks served per turn to peers without a weight of their own.
+pub const DEFAULT_WEIGHT: usize = 1;
+
+static WEIGHTS: OnceLock<HashMap<IpAddr, usize>> = OnceLock::new();
+
+/// Sets the blocks served per turn to the given peer addresses.
+pub fn set_weights(weights: HashMap<IpAddr, usize>) {
+    let _ = WEIGHTS.set(weights);
+}
+
+fn weight(ip: &IpAddr) -> usize {
+    WEIGHTS
+        .get()
+        .and_then(|weights| weights.get(ip))
+        .copied()
+        .unwrap_or(DEFAULT_WEIGHT)
+}
+
+/// Order of turns: peers round robin, and the connections of a peer round robin.
+#[derive(Default)]
+struct Rounds {
+    peers: VecDeque<IpAddr>,
+    waiting: HashMap<IpAddr, VecDeque<usize>>,
+}
+
+impl Rounds {
+    fn push(&mut self, ip: IpAddr, connection_id: usize) {
+        let connections = self.waiting.entry(ip).or_default();
+        if connections.is_empty() {
+            self.peers.push_back(ip);
+        }
+        if !connections.contains(&connection_id) {
+            connections.push_back(connection_id);
+        }
+    }
+
+    fn next(&mut self) -> Option<(IpAddr, usize)> {
+        let ip = self.peers.pop_front()?;
+        let connections = self.waiting.get_mut(&ip)?;
+        let connection_id = connections.pop_front()?;
+        if connections.is_empty() {
+            self.waiting.remove(&ip);
+        } else {
+            self.peers.push_back(ip);
+        }
+        Some((ip, connection_id))
+    }
+}
+
+/// Gives turns to connections with block requests, one at a time.
+#[derive(Default)]
+pub struct ServeQueue {
+    rounds: Rounds,
+    connections: HashMap<usize, Addr<Connection>>,
+    serving: bool,
+}
+
+impl ServeQueue {
+    fn next_turn(&mut self, ctx: &mut <Self as Actor>::Context) {
+        while !self.serving {
+            let (ip, connection_id) = match self.rounds.next() {
+                Some(next) => next,
+                None => return,
+            };
+            let connection = match self.connections.remove(&connection_id) {
+                Some(connection) => connection,
+                None => continue,
+            };
+            self.serving = true;
+            // A connection closed meanwhile just ends its turn.
+            ctx.spawn(
+                connection
+                    .send(Serve {
+                        blocks: weight(&ip),
+                    })
+                    .into_actor(self)
+                    .map(|_, act, ctx| {
+                        act.serving = false;
+                        act.next_turn(ctx)
+                    }),
+            );
+        }
+    }
+}
+
+impl Actor for ServeQueue {
+    type Context = Context<Self>;
+}
+
+impl Supervised for ServeQueue {}
+
+impl SystemService for ServeQueue {}
+
+/// Asks for a turn, sent by connections with block requests and room to write.
+pub struct Ready {
+    pub connection_id: usize,
+    pub ip: IpAddr,
+    pub connection: Addr<Connection>,
+}
+
+impl Message for Ready {
+    type Result = ();
+}
+
+impl Handler<Ready> for ServeQueue {
+    type Result = ();
+
+    fn handle(&mut self, msg: Ready, ctx: &mut Self::Context) -> Self::Result {
+        self.rounds.push(msg.ip, msg.connection_id);
+        self.connections.insert(msg.connection_id, msg.connection);
+        self.next_turn(ctx)
+    }
+}
+
+/// Turn of a connection, it serves up to `blocks` of the requested blocks.
+pub struct Serve {
+    pub blocks: usize,
+}
+
+impl Message for Serve {
+    type Result = ();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rounds() {
+        let a: IpAddr = [10, 0, 0, 1].into();
+        let b: IpAddr = [10, 0, 0, 2].into();
+        let mut rounds = Rounds::default();
+        rounds.push(a, 1);
+        rounds.push(a, 2);
+        rounds.push(a, 3);
+        rounds.push(b, 4);
+        rounds.push(a, 1);
+
+        let order: Vec<_> = std::iter::from_fn(|| rounds.next()).collect();
+        assert_eq!(order, vec![(a, 1), (b, 4), (a, 2), (a, 3)]);
+        assert!(rounds.waiting.is_empty());
+    }
+}
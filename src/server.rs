@@ -1,34 +1,261 @@
 use crate::database::DatabaseManager;
 use actix::prelude::*;
-use actix_server::Io;
-use actix_service::service_fn;
+use actix_service::fn_service;
+use futures::prelude::*;
 
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use std::{io, net};
-use tokio_tcp::TcpStream;
+use tokio::net::TcpStream;
+
+/// Window `ConnectionLimits::rate` is counted over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Addresses tracked before the ones without connections, bans and recent
+/// accepts are dropped.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Limits on connections from a single IP address, see `set_limits`.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionLimits {
+    /// Connections accepted per minute, 0 for no limit. Exceeding it bans the address.
+    pub rate: usize,
+    /// Connections open at once, 0 for no limit. Further ones are refused.
+    pub concurrent: usize,
+    /// How long a banned address is refused.
+    pub ban: Duration,
+}
+
+/// Counters of the limiter, shown by `GET /status`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LimitStats {
+    /// Connections closed right after accept since start
+    pub refused: u64,
+    /// Bans issued since start
+    pub bans: u64,
+    /// Addresses banned now
+    pub banned: usize,
+}
+
+#[derive(Default)]
+struct Peer {
+    accepted: VecDeque<Instant>,
+    open: usize,
+    banned_until: Option<Instant>,
+}
+
+impl Peer {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    fn is_unused(&self, now: Instant) -> bool {
+        self.open == 0
+            && !self.is_banned(now)
+            && self
+                .accepted
+                .back()
+                .is_none_or(|&at| now.duration_since(at) >= RATE_WINDOW)
+    }
+}
+
+#[derive(Default)]
+struct Limiter {
+    limits: Option<ConnectionLimits>,
+    peers: HashMap<IpAddr, Peer>,
+    refused: u64,
+    bans: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Refusal {
+    Banned,
+    Rate,
+    Concurrent,
+}
+
+impl Limiter {
+    fn admit(&mut self, ip: IpAddr, now: Instant) -> Result<bool, Refusal> {
+        let limits = match self.limits {
+            Some(limits) => limits,
+            None => return Ok(false),
+        };
+        if self.peers.len() >= PRUNE_THRESHOLD {
+            self.peers.retain(|_, peer| !peer.is_unused(now));
+        }
+        let peer = self.peers.entry(ip).or_default();
+        let refusal = if peer.is_banned(now) {
+            Some(Refusal::Banned)
+        } else {
+            while peer
+                .accepted
+                .front()
+                .is_some_and(|&at| now.duration_since(at) >= RATE_WINDOW)
+            {
+                peer.accepted.pop_front();
+            }
+            if limits.rate > 0 && peer.accepted.len() >= limits.rate {
+                peer.banned_until = Some(now + limits.ban);
+                peer.accepted.clear();
+                self.bans += 1;
+                Some(Refusal::Rate)
+            } else if limits.concurrent > 0 && peer.open >= limits.concurrent {
+                Some(Refusal::Concurrent)
+            } else {
+                None
+            }
+        };
+        match refusal {
+            Some(refusal) => {
+                self.refused += 1;
+                Err(refusal)
+            }
+            None => {
+                peer.accepted.push_back(now);
+                peer.open += 1;
+                Ok(true)
+            }
+        }
+    }
+
+    fn release(&mut self, ip: IpAddr) {
+        if let Some(peer) = self.peers.get_mut(&ip) {
+            peer.open = peer.open.saturating_sub(1);
+        }
+    }
+
+    fn stats(&self, now: Instant) -> LimitStats {
+        LimitStats {
+            refused: self.refused,
+            bans: self.bans,
+            banned: self
+                .peers
+                .values()
+                .filter(|peer| peer.is_banned(now))
+                .count(),
+        }
+    }
+}
+
+static LIMITER: OnceLock<Mutex<Limiter>> = OnceLock::new();
+
+fn limiter() -> std::sync::MutexGuard<'static, Limiter> {
+    match LIMITER.get_or_init(Default::default).lock() {
+        Ok(limiter) => limiter,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Enables per address limits on accepted connections; without it every
+/// connection is accepted.
+pub fn set_limits(limits: ConnectionLimits) {
+    limiter().limits = Some(limits);
+}
+
+pub fn limit_stats() -> LimitStats {
+    limiter().stats(Instant::now())
+}
+
+/// Counts an accepted connection as open until dropped.
+pub struct Admission(Option<IpAddr>);
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if let Some(ip) = self.0 {
+            limiter().release(ip);
+        }
+    }
+}
+
+fn admit(ip: IpAddr) -> Option<Admission> {
+    match limiter().admit(ip, Instant::now()) {
+        Ok(counted) => Some(Admission(if counted { Some(ip) } else { None })),
+        Err(Refusal::Rate) => {
+            log::warn!("too many connections from {}, banned", ip);
+            None
+        }
+        Err(refusal) => {
+            log::debug!("refused connection from {}: {:?}", ip, refusal);
+            None
+        }
+    }
+}
 
 pub fn new(
     db: Addr<DatabaseManager>,
     addr: impl net::ToSocketAddrs,
+) -> io::Result<actix_server::Server> {
+    listen(db, net::TcpListener::bind(addr)?)
+}
+
+/// Transfer server accepting on a bound `listener`, e.g. to learn the port chosen for 0.
+pub fn listen(
+    db: Addr<DatabaseManager>,
+    listener: net::TcpListener,
 ) -> io::Result<actix_server::Server> {
     Ok(actix_server::Server::build()
-        .bind("gst", addr, move || {
+        .listen("gst", listener, move || {
             let db = db.clone();
-            service_fn(move |stream: Io<TcpStream>| {
-                let (tcp_stream, (), _) = stream.into_parts();
-                let peer_addr = tcp_stream.peer_addr()?;
-                log::info!("Connection from: {}", peer_addr);
-                let conn = crate::connection::Connection::new(
-                    db.clone(),
-                    tcp_stream,
-                    peer_addr,
-                    &crate::user_report::UserReportHandle::empty(),
-                );
-                Arbiter::spawn(
-                    conn.and_then(|_| Ok(()))
-                        .map_err(|e| log::error!("failed to initalize connection: {}", e)),
-                );
-                Ok::<_, io::Error>(())
+            fn_service(move |tcp_stream: TcpStream| {
+                let db = db.clone();
+                async move {
+                    let peer_addr = tcp_stream.peer_addr()?;
+                    let admission = match admit(peer_addr.ip()) {
+                        Some(admission) => admission,
+                        None => return Ok(()),
+                    };
+                    log::info!("Connection from: {}", peer_addr);
+                    let conn =
+                        crate::connection::Connection::accept(db, tcp_stream, peer_addr, admission);
+                    actix::spawn(conn.map(|r| {
+                        if let Err(e) = r {
+                            log::error!("failed to initalize connection: {}", e)
+                        }
+                    }));
+                    Ok::<_, io::Error>(())
+                }
             })
         })?
-        .start())
+        .system_exit()
+        .run())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_limiter() {
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let other: IpAddr = [10, 0, 0, 2].into();
+        let start = Instant::now();
+        let mut limiter = Limiter::default();
+        assert_eq!(limiter.admit(ip, start), Ok(false));
+
+        limiter.limits = Some(ConnectionLimits {
+            rate: 3,
+            concurrent: 2,
+            ban: Duration::from_secs(30),
+        });
+        assert_eq!(limiter.admit(ip, start), Ok(true));
+        assert_eq!(limiter.admit(ip, start), Ok(true));
+        assert_eq!(limiter.admit(ip, start), Err(Refusal::Concurrent));
+        limiter.release(ip);
+        assert_eq!(limiter.admit(ip, start), Ok(true));
+        limiter.release(ip);
+        assert_eq!(limiter.admit(ip, start), Err(Refusal::Rate));
+        assert_eq!(limiter.admit(other, start), Ok(true));
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.admit(ip, later), Err(Refusal::Banned));
+        let stats = limiter.stats(later);
+        assert_eq!((stats.refused, stats.bans, stats.banned), (3, 1, 1));
+
+        let after_ban = start + Duration::from_secs(31);
+        limiter.release(ip);
+        assert_eq!(limiter.admit(ip, after_ban), Ok(true));
+        assert_eq!(limiter.stats(after_ban).banned, 0);
+    }
 }
@@ -0,0 +1,90 @@
+//! What processes running the daemon (e.g. Golem from Python) can rely on at start: one
+//! JSON line on stdout once the node is ready or has failed to start, and exit codes
+//! telling the failures apart. Logs go to stderr and `--logfile` only.
+use serde::Serialize;
+use std::io::Write;
+use std::net::SocketAddr;
+
+/// Exit codes of failed starts, other failures exit with 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Invalid flags or `--config` file, or unusable files they name
+    Config = 2,
+    /// A transfer, RPC or gRPC address can't be listened on
+    Bind = 3,
+    /// The database can't be opened, e.g. its metadata is corrupt
+    Database = 4,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum Handshake {
+    /// Written once all servers listen and the database answers.
+    #[serde(rename_all = "camelCase")]
+    Ready {
+        version: String,
+        node_id: String,
+        pid: u32,
+        /// Addresses listened on, with the ports chosen for port 0
+        transfer: SocketAddr,
+        rpc: SocketAddr,
+        grpc: Option<SocketAddr>,
+    },
+    /// Written before exiting with `exit_code`.
+    #[serde(rename_all = "camelCase")]
+    Failed { exit_code: i32, error: String },
+}
+
+/// Writes the handshake line, ignoring a closed stdout.
+pub fn announce(handshake: &Handshake) {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let line = serde_json::to_string(handshake).unwrap_or_default();
+    let _ = writeln!(stdout, "{}", line).and_then(|_| stdout.flush());
+}
+
+/// Reports `error` on stderr and stdout and exits with `code`.
+pub fn fail(code: ExitCode, error: impl Into<String>) -> ! {
+    let error = error.into();
+    eprintln!("error: {}", error);
+    exit(code, error)
+}
+
+/// Reports `error` on stdout only and exits with `code`, for errors already explained on
+/// stderr.
+pub fn exit(code: ExitCode, error: impl Into<String>) -> ! {
+    announce(&Handshake::Failed {
+        exit_code: code as i32,
+        error: error.into(),
+    });
+    std::process::exit(code as i32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        let ready = Handshake::Ready {
+            version: "0.3.8".to_string(),
+            node_id: "4d9525183664edc6f7ff086d4f0b2d42".to_string(),
+            pid: 7,
+            transfer: "0.0.0.0:3282".parse().unwrap(),
+            rpc: "127.0.0.1:40123".parse().unwrap(),
+            grpc: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&ready).unwrap(),
+            r#"{"event":"ready","version":"0.3.8","nodeId":"4d9525183664edc6f7ff086d4f0b2d42","pid":7,"transfer":"0.0.0.0:3282","rpc":"127.0.0.1:40123","grpc":null}"#
+        );
+        let failed = Handshake::Failed {
+            exit_code: ExitCode::Bind as i32,
+            error: "address in use".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&failed).unwrap(),
+            r#"{"event":"failed","exitCode":3,"error":"address in use"}"#
+        );
+    }
+}
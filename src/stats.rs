@@ -0,0 +1,508 @@
+use crate::codec::hash_to_hex;
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bucket for traffic of resources shared or fetched without `user` info.
+const ANONYMOUS: &str = "anonymous";
+/// How often changed resource stats are written to disk.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Files in the database directory the stats are kept in.
+const RESOURCE_STATS_FILE: &str = "resource_stats.json";
+const TRAFFIC_FILE: &str = "traffic.json";
+/// Months of served traffic kept, the current one included.
+const TRAFFIC_MONTHS: usize = 12;
+/// Peers of a resource remembered, later ones are no longer counted as unique.
+const MAX_PEERS: usize = 1000;
+/// Resources broken down per user, the least recently transferred ones are dropped.
+const MAX_TRANSFERS: usize = 1000;
+
+/// Bytes served per month after which new asks are refused, 0 for no quota.
+static MONTHLY_QUOTA: AtomicU64 = AtomicU64::new(0);
+static QUOTA_EXCEEDED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Default, Serialize, Clone, Debug)]
+pub struct TransferStats {
+    pub served: u64,
+    pub fetched: u64,
+    /// `UserStats::updates` when last transferred
+    #[serde(skip)]
+    updated: u64,
+}
+
+#[derive(Default, Serialize, Clone, Debug)]
+pub struct UserStats {
+    pub served: u64,
+    pub fetched: u64,
+    /// Per resource hash breakdown of the last `MAX_TRANSFERS` resources
+    pub transfers: HashMap<String, TransferStats>,
+    #[serde(skip)]
+    updates: u64,
+}
+
+impl UserStats {
+    fn transfer(&mut self, hash: u128) -> &mut TransferStats {
+        let key = hash_to_hex(hash);
+        if !self.transfers.contains_key(&key) && self.transfers.len() >= MAX_TRANSFERS {
+            let oldest = self
+                .transfers
+                .iter()
+                .min_by_key(|(_, transfer)| transfer.updated)
+                .map(|(key, _)| key.clone())
+                .unwrap_or_default();
+            self.transfers.remove(&oldest);
+        }
+        self.updates += 1;
+        let transfer = self.transfers.entry(key).or_default();
+        transfer.updated = self.updates;
+        transfer
+    }
+}
+
+/// Serving stats of a shared resource.
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceStats {
+    pub asks: u64,
+    pub blocks_served: u64,
+    pub bytes_served: u64,
+    /// Seconds since the epoch of the last ask
+    pub last_requested: Option<u64>,
+    /// Hex node ids of the first `MAX_PEERS` peers that asked
+    pub peers: HashSet<String>,
+}
+
+/// Bytes served to peers per calendar month (UTC), keyed `YYYY-MM`.
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
+pub struct Traffic {
+    pub served: BTreeMap<String, u64>,
+}
+
+impl Traffic {
+    fn add(&mut self, month: String, bytes: u64) {
+        *self.served.entry(month).or_default() += bytes;
+        while self.served.len() > TRAFFIC_MONTHS {
+            let oldest = self.served.keys().next().cloned().unwrap_or_default();
+            self.served.remove(&oldest);
+        }
+    }
+
+    pub fn month_served(&self, month: &str) -> u64 {
+        self.served.get(month).copied().unwrap_or(0)
+    }
+}
+
+/// Month of `time` as `YYYY-MM` in UTC.
+pub fn month_of(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() / 86400)
+        .unwrap_or(0);
+    // Civil date from days since 1970-01-01, in eras of 400 years starting on March 1st.
+    let days = days + 719_468;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = days / 146_097 * 400 + year_of_era + u64::from(month <= 2);
+    format!("{:04}-{:02}", year, month)
+}
+
+/// Tracks bytes served and fetched per user id, serving stats per resource and
+/// traffic served per month.
+#[derive(Default)]
+pub struct StatsManager {
+    users: HashMap<String, UserStats>,
+    owners: HashMap<u128, String>,
+    resources: HashMap<u128, ResourceStats>,
+    traffic: Traffic,
+    /// Directory the resource stats and traffic are kept in, once persisted.
+    dir: Option<PathBuf>,
+    /// Stats changed since saved.
+    dirty: bool,
+}
+
+impl StatsManager {
+    fn user(&mut self, user_id: Option<String>) -> &mut UserStats {
+        self.users
+            .entry(user_id.unwrap_or_else(|| ANONYMOUS.to_string()))
+            .or_default()
+    }
+}
+
+impl StatsManager {
+    fn save(&mut self) {
+        let dir = match (&self.dir, self.dirty) {
+            (Some(dir), true) => dir,
+            _ => return,
+        };
+        let resources: HashMap<String, &ResourceStats> = self
+            .resources
+            .iter()
+            .map(|(&hash, stats)| (hash_to_hex(hash), stats))
+            .collect();
+        let result = write_json(&dir.join(RESOURCE_STATS_FILE), &resources)
+            .and_then(|()| write_json(&dir.join(TRAFFIC_FILE), &self.traffic));
+        match result {
+            Ok(()) => self.dirty = false,
+            Err(e) => log::error!("failed to save resource stats: {}", e),
+        }
+    }
+
+    /// Updates `quota_exceeded` for the current month.
+    fn check_quota(&self) {
+        let quota = MONTHLY_QUOTA.load(Ordering::Relaxed);
+        let exceeded =
+            quota > 0 && self.traffic.month_served(&month_of(SystemTime::now())) >= quota;
+        if QUOTA_EXCEEDED.swap(exceeded, Ordering::Relaxed) != exceeded {
+            if exceeded {
+                log::warn!("monthly quota of {} bytes served, refusing new asks", quota)
+            } else {
+                log::info!("monthly quota reset, answering asks again")
+            }
+        }
+    }
+}
+
+fn write_json(path: &Path, value: &impl Serialize) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let data = serde_json::to_vec(value)?;
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn read_json<T: serde::de::DeserializeOwned + Default>(path: &Path) -> T {
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            log::error!("invalid stats in {}: {}", path.display(), e);
+            T::default()
+        }),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => T::default(),
+        Err(e) => {
+            log::error!("unable to read {}: {}", path.display(), e);
+            T::default()
+        }
+    }
+}
+
+impl Actor for StatsManager {
+    type Context = Context<Self>;
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.save()
+    }
+}
+
+impl Supervised for StatsManager {}
+
+impl SystemService for StatsManager {}
+
+struct SetOwner {
+    hash: u128,
+    user_id: Option<String>,
+}
+
+impl Message for SetOwner {
+    type Result = ();
+}
+
+impl Handler<SetOwner> for StatsManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetOwner, _ctx: &mut Self::Context) -> Self::Result {
+        match msg.user_id {
+            Some(user_id) => {
+                self.owners.insert(msg.hash, user_id);
+            }
+            None => {
+                self.owners.remove(&msg.hash);
+            }
+        }
+    }
+}
+
+struct Served {
+    hash: u128,
+    bytes: u64,
+}
+
+impl Message for Served {
+    type Result = ();
+}
+
+impl Handler<Served> for StatsManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Served, _ctx: &mut Self::Context) -> Self::Result {
+        let owner = self.owners.get(&msg.hash).cloned();
+        let user = self.user(owner);
+        user.served += msg.bytes;
+        user.transfer(msg.hash).served += msg.bytes;
+        let resource = self.resources.entry(msg.hash).or_default();
+        resource.blocks_served += 1;
+        resource.bytes_served += msg.bytes;
+        self.traffic.add(month_of(SystemTime::now()), msg.bytes);
+        self.check_quota();
+        self.dirty = true;
+    }
+}
+
+struct Asked {
+    hash: u128,
+    peer_id: Option<u128>,
+}
+
+impl Message for Asked {
+    type Result = ();
+}
+
+impl Handler<Asked> for StatsManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Asked, _ctx: &mut Self::Context) -> Self::Result {
+        let resource = self.resources.entry(msg.hash).or_default();
+        resource.asks += 1;
+        resource.last_requested = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since_epoch| since_epoch.as_secs());
+        if let Some(peer_id) = msg.peer_id {
+            if resource.peers.len() < MAX_PEERS {
+                resource.peers.insert(hash_to_hex(peer_id));
+            }
+        }
+        self.dirty = true;
+    }
+}
+
+struct Forget(u128);
+
+impl Message for Forget {
+    type Result = ();
+}
+
+impl Handler<Forget> for StatsManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Forget, _ctx: &mut Self::Context) -> Self::Result {
+        self.owners.remove(&msg.0);
+        if self.resources.remove(&msg.0).is_some() {
+            self.dirty = true;
+        }
+    }
+}
+
+struct Persist(PathBuf);
+
+impl Message for Persist {
+    type Result = ();
+}
+
+impl Handler<Persist> for StatsManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Persist, ctx: &mut Self::Context) -> Self::Result {
+        let saved: HashMap<String, ResourceStats> = read_json(&msg.0.join(RESOURCE_STATS_FILE));
+        for (hash, stats) in saved {
+            if let Ok(hash) = u128::from_str_radix(&hash, 16) {
+                self.resources.entry(hash).or_insert(stats);
+            }
+        }
+        let traffic: Traffic = read_json(&msg.0.join(TRAFFIC_FILE));
+        for (month, bytes) in traffic.served {
+            self.traffic.add(month, bytes);
+        }
+        if self.dir.replace(msg.0).is_none() {
+            ctx.run_interval(SAVE_INTERVAL, |act, _ctx| {
+                act.check_quota();
+                act.save()
+            });
+        }
+        self.check_quota();
+    }
+}
+
+pub struct GetResourceStats(pub u128);
+
+impl Message for GetResourceStats {
+    type Result = Option<ResourceStats>;
+}
+
+impl Handler<GetResourceStats> for StatsManager {
+    type Result = MessageResult<GetResourceStats>;
+
+    fn handle(&mut self, msg: GetResourceStats, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.resources.get(&msg.0).cloned())
+    }
+}
+
+struct Fetched {
+    hash: u128,
+    user_id: Option<String>,
+    bytes: u64,
+}
+
+impl Message for Fetched {
+    type Result = ();
+}
+
+impl Handler<Fetched> for StatsManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Fetched, _ctx: &mut Self::Context) -> Self::Result {
+        let user = self.user(msg.user_id);
+        user.fetched += msg.bytes;
+        user.transfer(msg.hash).fetched += msg.bytes;
+    }
+}
+
+pub struct GetTraffic;
+
+impl Message for GetTraffic {
+    type Result = Traffic;
+}
+
+impl Handler<GetTraffic> for StatsManager {
+    type Result = MessageResult<GetTraffic>;
+
+    fn handle(&mut self, _msg: GetTraffic, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.traffic.clone())
+    }
+}
+
+pub struct GetStats;
+
+impl Message for GetStats {
+    type Result = HashMap<String, UserStats>;
+}
+
+impl Handler<GetStats> for StatsManager {
+    type Result = MessageResult<GetStats>;
+
+    fn handle(&mut self, _msg: GetStats, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.users.clone())
+    }
+}
+
+/// Attributes bytes served for `hash` to the user that shared it.
+pub fn set_owner(hash: u128, user_id: Option<String>) {
+    StatsManager::from_registry().do_send(SetOwner { hash, user_id })
+}
+
+pub fn served(hash: u128, bytes: usize) {
+    StatsManager::from_registry().do_send(Served {
+        hash,
+        bytes: bytes as u64,
+    })
+}
+
+/// Counts an ask for `hash` answered with the resource.
+pub fn asked(hash: u128, peer_id: Option<u128>) {
+    StatsManager::from_registry().do_send(Asked { hash, peer_id })
+}
+
+/// Drops the serving stats and owner of an unshared resource.
+pub fn forget(hash: u128) {
+    StatsManager::from_registry().do_send(Forget(hash))
+}
+
+/// Loads the serving stats of resources and the monthly traffic from files in `dir` and
+/// saves them there periodically.
+pub fn persist(dir: PathBuf) {
+    StatsManager::from_registry().do_send(Persist(dir))
+}
+
+/// Sets the bytes served per month after which asks for new transfers are refused.
+pub fn set_monthly_quota(bytes: u64) {
+    MONTHLY_QUOTA.store(bytes, Ordering::Relaxed);
+}
+
+pub fn monthly_quota() -> Option<u64> {
+    match MONTHLY_QUOTA.load(Ordering::Relaxed) {
+        0 => None,
+        quota => Some(quota),
+    }
+}
+
+/// Whether this month's traffic reached the quota, checked as blocks are served and
+/// every minute.
+pub fn quota_exceeded() -> bool {
+    QUOTA_EXCEEDED.load(Ordering::Relaxed)
+}
+
+pub fn traffic() -> Request<StatsManager, GetTraffic> {
+    StatsManager::from_registry().send(GetTraffic)
+}
+
+pub fn fetched(hash: u128, user_id: Option<String>, bytes: usize) {
+    StatsManager::from_registry().do_send(Fetched {
+        hash,
+        user_id,
+        bytes: bytes as u64,
+    })
+}
+
+pub fn get_stats() -> Request<StatsManager, GetStats> {
+    StatsManager::from_registry().send(GetStats)
+}
+
+pub fn resource_stats(hash: u128) -> Request<StatsManager, GetResourceStats> {
+    StatsManager::from_registry().send(GetResourceStats(hash))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_month_of() {
+        let at = |secs| month_of(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01");
+        // 2000-02-29 23:59:59 and the second after
+        assert_eq!(at(951_868_799), "2000-02");
+        assert_eq!(at(951_868_800), "2000-03");
+        // 2026-12-31 23:59:59 and the second after
+        assert_eq!(at(1_798_761_599), "2026-12");
+        assert_eq!(at(1_798_761_600), "2027-01");
+    }
+
+    #[test]
+    fn test_user_transfers() {
+        let mut user = UserStats::default();
+        for hash in 0..MAX_TRANSFERS as u128 {
+            user.transfer(hash).served += 1;
+        }
+        user.transfer(0).fetched += 1;
+        user.transfer(MAX_TRANSFERS as u128).served += 1;
+        assert_eq!(user.transfers.len(), MAX_TRANSFERS);
+        assert_eq!(user.transfers[&hash_to_hex(0)].fetched, 1);
+        assert!(!user.transfers.contains_key(&hash_to_hex(1)));
+        assert!(user
+            .transfers
+            .contains_key(&hash_to_hex(MAX_TRANSFERS as u128)));
+    }
+
+    #[test]
+    fn test_traffic_months() {
+        let mut traffic = Traffic::default();
+        for month in 1..=12 {
+            traffic.add(format!("2025-{:02}", month), month);
+        }
+        traffic.add("2026-01".into(), 5);
+        traffic.add("2026-01".into(), 5);
+        assert_eq!(traffic.served.len(), TRAFFIC_MONTHS);
+        assert_eq!(traffic.month_served("2025-01"), 0);
+        assert_eq!(traffic.month_served("2025-02"), 2);
+        assert_eq!(traffic.month_served("2026-01"), 10);
+    }
+}
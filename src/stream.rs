@@ -0,0 +1,238 @@
+//! Shares created from streamed request bodies.
+//!
+//! Each upload is written into its own directory under `streams` in the database
+//! directory. Uploads with a caller chosen id keep hashes of the blocks already
+//! stored in `<id>.json` next to the directory, so an interrupted upload can be
+//! continued from the last complete block. Files are encrypted if encryption
+//! at rest is enabled.
+use crate::encryption::StoredFile;
+use crate::error::Error;
+use crate::filemap::{BlockHasher, FileMap, HashAlgorithm, BLOCK_SIZE};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Progress of an interrupted upload.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadState {
+    pub name: String,
+    pub hash_algorithm: HashAlgorithm,
+    /// Hashes of blocks stored so far.
+    pub blocks: Vec<u128>,
+}
+
+impl UploadState {
+    /// Offset the upload continues at.
+    pub fn offset(&self) -> u64 {
+        self.blocks.len() as u64 * BLOCK_SIZE as u64
+    }
+}
+
+pub fn streams_dir(db_dir: &Path) -> PathBuf {
+    db_dir.join("streams")
+}
+
+/// File keeping `UploadState` of the upload stored in `dir`.
+pub fn state_path(dir: &Path) -> PathBuf {
+    dir.with_extension("json")
+}
+
+fn valid_upload_id(upload: &str) -> bool {
+    !upload.is_empty()
+        && upload.len() <= 64
+        && upload
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// State of the resumable upload, `None` if there is nothing to continue.
+pub fn upload_state(db_dir: &Path, upload: &str) -> Result<Option<UploadState>, Error> {
+    if !valid_upload_id(upload) {
+        return Err(Error::InvalidArgument(format!(
+            "invalid upload id: {}",
+            upload
+        )));
+    }
+    match fs::read(state_path(&streams_dir(db_dir).join(upload))) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub struct StreamUpload {
+    dir: PathBuf,
+    path: PathBuf,
+    file: StoredFile,
+    block_hasher: BlockHasher,
+    state: Option<UploadState>,
+}
+
+impl StreamUpload {
+    /// Starts a new upload, or continues `upload` from `offset`.
+    ///
+    /// Uploads without an id can not be continued.
+    pub fn open(
+        db_dir: &Path,
+        upload: Option<&str>,
+        name: Option<String>,
+        offset: u64,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<Self, Error> {
+        let mut state = match upload {
+            Some(upload) => upload_state(db_dir, upload)?,
+            None => None,
+        };
+        if let (Some(state), Some(name)) = (&state, &name) {
+            if state.name != *name {
+                return Err(Error::InvalidArgument(format!(
+                    "upload was started as {}",
+                    state.name
+                )));
+            }
+        }
+        let expected_offset = state.as_ref().map(UploadState::offset).unwrap_or(0);
+        if offset != expected_offset {
+            return Err(Error::InvalidArgument(format!(
+                "upload continues at offset {}",
+                expected_offset
+            )));
+        }
+
+        let name = match (&state, name) {
+            (Some(state), _) => state.name.clone(),
+            (None, Some(name)) => name,
+            (None, None) => "stream".to_string(),
+        };
+        if Path::new(&name).file_name() != Some(name.as_ref()) {
+            return Err(Error::InvalidArgument(format!(
+                "invalid file name: {}",
+                name
+            )));
+        }
+        let dir = streams_dir(db_dir).join(match upload {
+            Some(upload) => upload.to_string(),
+            None => crate::codec::hash_to_hex(rand::random()),
+        });
+        if state.is_none() && dir.exists() {
+            return Err(Error::InvalidArgument(format!(
+                "upload {} already completed",
+                upload.unwrap_or_default()
+            )));
+        }
+        let path = dir.join(&name);
+        fs::create_dir_all(&dir)?;
+        let mut file = StoredFile::open_append(&path)?;
+        // Drop the incomplete block written before the interruption.
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let block_hasher = match &state {
+            Some(state) => BlockHasher::resume(state.hash_algorithm, state.blocks.clone()),
+            None => BlockHasher::new(hash_algorithm),
+        };
+        if upload.is_some() && state.is_none() {
+            state = Some(UploadState {
+                name,
+                hash_algorithm,
+                blocks: Vec::new(),
+            });
+        }
+        let upload = StreamUpload {
+            dir,
+            path,
+            file,
+            block_hasher,
+            state,
+        };
+        upload.save_state()?;
+        Ok(upload)
+    }
+
+    /// Directory holding the upload.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Bytes stored so far, including the part of a previous upload.
+    pub fn size(&self) -> u64 {
+        self.block_hasher.file_size()
+    }
+
+    pub fn is_resumable(&self) -> bool {
+        self.state.is_some()
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.file.write_all(chunk)?;
+        self.block_hasher.update(chunk);
+
+        let stored = self.block_hasher.blocks();
+        let state = match &mut self.state {
+            Some(state) if state.blocks.len() < stored.len() => state,
+            _ => return Ok(()),
+        };
+        state.blocks = stored.to_vec();
+        // Blocks listed in the state have to be on disk.
+        self.file.sync_data()?;
+        self.save_state()
+    }
+
+    fn save_state(&self) -> Result<(), Error> {
+        if let Some(state) = &self.state {
+            let state_path = state_path(&self.dir);
+            let tmp_path = state_path.with_extension("tmp");
+            fs::write(&tmp_path, serde_json::to_vec(state)?)?;
+            fs::rename(&tmp_path, &state_path)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(FileMap, PathBuf), Error> {
+        self.file.sync_all()?;
+        if self.state.is_some() {
+            fs::remove_file(state_path(&self.dir))?;
+        }
+        let file_name = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok((self.block_hasher.finish(file_name.into()), self.path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resume_upload() {
+        let db_dir = std::env::temp_dir().join(format!("hyperg-stream-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&db_dir);
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+
+        let mut upload =
+            StreamUpload::open(&db_dir, Some("u1"), None, 0, HashAlgorithm::Blake3).unwrap();
+        upload.write(&data[..BLOCK_SIZE + 100]).unwrap();
+        drop(upload);
+
+        let offset = upload_state(&db_dir, "u1").unwrap().unwrap().offset();
+        assert_eq!(offset, BLOCK_SIZE as u64);
+        assert!(StreamUpload::open(&db_dir, Some("u1"), None, 0, HashAlgorithm::Blake3).is_err());
+
+        let mut upload =
+            StreamUpload::open(&db_dir, Some("u1"), None, offset, HashAlgorithm::Blake3).unwrap();
+        upload.write(&data[offset as usize..]).unwrap();
+        let (file_map, path) = upload.finish().unwrap();
+
+        let mut expected = BlockHasher::new(HashAlgorithm::Blake3);
+        expected.update(&data);
+        assert_eq!(file_map.blocks, expected.finish("stream".into()).blocks);
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(upload_state(&db_dir, "u1").unwrap().is_none());
+        fs::remove_dir_all(&db_dir).unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+//! Supervision of the actors running in `SyncArbiter` threads.
+//!
+//! A panic in a handler of a sync actor ends its thread and leaves every address of the
+//! actor closed. Messages wrapped in [`Guarded`] are handled with panics caught instead:
+//! the panic is reported, the actor is restarted in place by [`Restart::restart`] and the
+//! request fails with `Error::Panicked`.
+//!
+//! Handling also runs in an `actor` span below the span the message was sent from, and is
+//! counted in the [`mailboxes`] shown by `GET /debug/actors`.
+use crate::error::Error;
+use crate::user_report::UserReportHandle;
+use actix::dev::MessageResponse;
+use actix::prelude::*;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Sync actors restarted after a panic of a handler.
+pub trait Restart: Actor<Context = SyncContext<Self>> {
+    /// Name of the actor in logs, restart counts and mailboxes.
+    const NAME: &'static str;
+
+    /// Brings the state back to a consistent one, the handler may have left it half
+    /// updated.
+    fn restart(&mut self);
+}
+
+/// `M` handled with panics caught, see [`handle_guarded`].
+pub struct Guarded<M> {
+    msg: M,
+    span: tracing::Span,
+    sent: Instant,
+    _queued: Queued,
+}
+
+impl<M> Guarded<M> {
+    /// Wraps `msg` for actor `A`, to be handled in the current span.
+    pub fn new<A: Restart>(msg: M) -> Self {
+        Guarded {
+            msg,
+            span: tracing::Span::current(),
+            sent: Instant::now(),
+            _queued: Queued::new(A::NAME),
+        }
+    }
+}
+
+impl<M> Message for Guarded<M>
+where
+    M: Message,
+    M::Result: Send + 'static,
+{
+    type Result = Result<M::Result, Error>;
+}
+
+#[derive(Default)]
+struct Mailbox {
+    queued: usize,
+    handled: u64,
+    restarts: u64,
+    /// Messages being handled by id, with the time handling started.
+    handling: BTreeMap<u64, (&'static str, Instant)>,
+    longest: Option<(&'static str, Duration)>,
+}
+
+static MAILBOXES: Mutex<BTreeMap<&'static str, Mailbox>> = Mutex::new(BTreeMap::new());
+
+static NEXT_HANDLING_ID: AtomicU64 = AtomicU64::new(0);
+
+fn mailboxes_lock() -> MutexGuard<'static, BTreeMap<&'static str, Mailbox>> {
+    MAILBOXES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Counts a message in the queue of an actor until it is handled or dropped unhandled.
+struct Queued(&'static str);
+
+impl Queued {
+    fn new(actor: &'static str) -> Self {
+        mailboxes_lock().entry(actor).or_default().queued += 1;
+        Queued(actor)
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        if let Some(mailbox) = mailboxes_lock().get_mut(self.0) {
+            mailbox.queued -= 1;
+        }
+    }
+}
+
+/// Restarts after panics since start by actor name.
+pub fn restarts() -> BTreeMap<&'static str, u64> {
+    mailboxes_lock()
+        .iter()
+        .filter(|(_, mailbox)| mailbox.restarts > 0)
+        .map(|(&name, mailbox)| (name, mailbox.restarts))
+        .collect()
+}
+
+/// A message being handled by an actor.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HandlingInfo {
+    pub message: &'static str,
+    /// Milliseconds since handling started
+    pub elapsed_ms: u64,
+}
+
+/// Mailbox of a supervised actor, shared by all its threads.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MailboxInfo {
+    /// Messages sent and not handled yet
+    pub queued: usize,
+    pub handled: u64,
+    pub restarts: u64,
+    /// Messages being handled, the longest running first
+    pub handling: Vec<HandlingInfo>,
+    /// The message that took longest to handle since start
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longest: Option<HandlingInfo>,
+}
+
+/// Mailboxes of the actors that were sent a message since start, by actor name.
+pub fn mailboxes() -> BTreeMap<&'static str, MailboxInfo> {
+    let millis = |duration: Duration| duration.as_millis() as u64;
+    mailboxes_lock()
+        .iter()
+        .map(|(&name, mailbox)| {
+            let mut handling: Vec<_> = mailbox
+                .handling
+                .values()
+                .map(|&(message, started)| HandlingInfo {
+                    message,
+                    elapsed_ms: millis(started.elapsed()),
+                })
+                .collect();
+            handling.sort_by_key(|info| std::cmp::Reverse(info.elapsed_ms));
+            let info = MailboxInfo {
+                queued: mailbox.queued,
+                handled: mailbox.handled,
+                restarts: mailbox.restarts,
+                handling,
+                longest: mailbox.longest.map(|(message, took)| HandlingInfo {
+                    message,
+                    elapsed_ms: millis(took),
+                }),
+            };
+            (name, info)
+        })
+        .collect()
+}
+
+/// Name of a message type without its module path.
+fn message_name<M>() -> &'static str {
+    let name = std::any::type_name::<M>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// Handles `msg` by the `Handler<M>` of `act`, restarting it if the handler panics.
+pub fn handle_guarded<A, M>(
+    act: &mut A,
+    guarded: Guarded<M>,
+    ctx: &mut SyncContext<A>,
+) -> Result<M::Result, Error>
+where
+    A: Restart + Handler<M>,
+    M: Message + Send + 'static,
+    M::Result: Send + 'static,
+{
+    let Guarded {
+        msg,
+        span,
+        sent,
+        _queued: queued,
+    } = guarded;
+    let _span = tracing::debug_span!(
+        parent: &span,
+        "actor",
+        actor = A::NAME,
+        message = std::any::type_name::<M>(),
+        queued_us = sent.elapsed().as_micros() as u64,
+    )
+    .entered();
+    let message = message_name::<M>();
+    let id = NEXT_HANDLING_ID.fetch_add(1, Ordering::Relaxed);
+    let started = Instant::now();
+    drop(queued);
+    mailboxes_lock()
+        .entry(A::NAME)
+        .or_default()
+        .handling
+        .insert(id, (message, started));
+    let (tx, mut rx) = tokio::sync::oneshot::channel();
+    let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+        let response = <A as Handler<M>>::handle(act, msg, ctx);
+        response.handle(ctx, Some(tx));
+    }));
+    {
+        let took = started.elapsed();
+        let mut mailboxes = mailboxes_lock();
+        let mailbox = mailboxes.entry(A::NAME).or_default();
+        mailbox.handling.remove(&id);
+        mailbox.handled += 1;
+        if handled.is_err() {
+            mailbox.restarts += 1;
+        }
+        if mailbox.longest.is_none_or(|(_, longest)| took > longest) {
+            mailbox.longest = Some((message, took));
+        }
+    }
+    match handled {
+        // Responses of sync actors are sent before `handle` returns.
+        Ok(()) => rx.try_recv().map_err(|_| Error::ServiceFail(A::NAME)),
+        Err(payload) => {
+            let e = Error::Panicked(A::NAME, panic_message(payload.as_ref()));
+            log::error!("{}, restarting", e);
+            UserReportHandle::empty().emit_fail(&e);
+            act.restart();
+            Err(e)
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Counter(u32);
+
+    impl Actor for Counter {
+        type Context = SyncContext<Self>;
+    }
+
+    impl Restart for Counter {
+        const NAME: &'static str = "counter";
+
+        fn restart(&mut self) {
+            self.0 = 0;
+        }
+    }
+
+    struct Add(u32);
+
+    impl Message for Add {
+        type Result = u32;
+    }
+
+    impl Handler<Add> for Counter {
+        type Result = MessageResult<Add>;
+
+        fn handle(&mut self, msg: Add, _ctx: &mut Self::Context) -> Self::Result {
+            self.0 += 1;
+            assert!(msg.0 > 0, "nothing to add");
+            self.0 += msg.0 - 1;
+            MessageResult(self.0)
+        }
+    }
+
+    impl<M> Handler<Guarded<M>> for Counter
+    where
+        Self: Handler<M>,
+        M: Message + Send + 'static,
+        M::Result: Send + 'static,
+    {
+        type Result = Result<M::Result, Error>;
+
+        fn handle(&mut self, msg: Guarded<M>, ctx: &mut Self::Context) -> Self::Result {
+            handle_guarded(self, msg, ctx)
+        }
+    }
+
+    #[test]
+    fn test_restart() {
+        System::new().block_on(async {
+            let addr = SyncArbiter::start(1, || Counter(0));
+            assert_eq!(
+                addr.send(Guarded::new::<Counter>(Add(2)))
+                    .await
+                    .unwrap()
+                    .unwrap(),
+                2
+            );
+            match addr.send(Guarded::new::<Counter>(Add(0))).await.unwrap() {
+                Err(Error::Panicked("counter", message)) => assert_eq!(message, "nothing to add"),
+                other => panic!("unexpected {:?}", other.map_err(|e| e.to_string())),
+            }
+            assert_eq!(
+                addr.send(Guarded::new::<Counter>(Add(3)))
+                    .await
+                    .unwrap()
+                    .unwrap(),
+                3
+            );
+            assert_eq!(restarts()["counter"], 1);
+            let mailbox = &mailboxes()["counter"];
+            assert_eq!((mailbox.queued, mailbox.handled), (0, 3));
+            assert!(mailbox.handling.is_empty());
+            assert_eq!(mailbox.longest.as_ref().unwrap().message, "Add");
+        });
+    }
+}
@@ -0,0 +1,46 @@
+//! HTTPS for the RPC API.
+use crate::error::Error;
+use std::path::Path;
+
+#[cfg(feature = "with-tls")]
+pub type ServerConfig = rustls::ServerConfig;
+
+/// Builds without `with-tls` have no configuration to serve with.
+#[cfg(not(feature = "with-tls"))]
+pub enum ServerConfig {}
+
+/// Server configuration with the PEM certificate chain in `cert` and its private key
+/// (PKCS#8, RSA or EC) in `key`.
+#[cfg(feature = "with-tls")]
+pub fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig, Error> {
+    use rustls_pemfile::Item;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let certs: Vec<_> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(Error::Tls(format!("no certificate in {}", cert.display())));
+    }
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                Some(rustls::PrivateKey(key))
+            }
+            _ => None,
+        })
+        .ok_or_else(|| Error::Tls(format!("no private key in {}", key.display())))?;
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::Tls(e.to_string()))
+}
+
+#[cfg(not(feature = "with-tls"))]
+pub fn server_config(_cert: &Path, _key: &Path) -> Result<ServerConfig, Error> {
+    Err(Error::Tls("tls support is not compiled in".to_string()))
+}
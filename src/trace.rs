@@ -0,0 +1,287 @@
+//! Spans of the transfer pipeline, for finding where transfers spend their time.
+//!
+//! Downloads run in a `transfer` span, uploads in an `upload` span, connections in a
+//! `connection` span, and blocks fetched or served in `block` and `serve_block` spans
+//! below them. Requests handled by the database and hasher workers are `actor` spans of
+//! the span they were sent from, with the time they waited in the queue.
+//!
+//! Nothing is recorded unless `--trace_output` is given. A file gets a JSON line for every
+//! closed span with its parent, fields and busy and idle time; an `http://` url of an OTLP
+//! collector (Jaeger, Tempo, ...) gets the spans exported over OTLP/HTTP, which needs a
+//! `with-otlp` build.
+use crate::error::Error;
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{span, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Starts recording spans to `output`, a file or an OTLP collector url.
+pub fn init(output: &str) -> Result<(), Error> {
+    if output.starts_with("http://") || output.starts_with("https://") {
+        return otlp::init(output);
+    }
+    let file = OpenOptions::new().create(true).append(true).open(output)?;
+    tracing_subscriber::registry()
+        .with(SpanWriter::new(file))
+        .try_init()
+        .map_err(|e| Error::Trace(e.to_string()))
+}
+
+/// Sends the spans not exported yet, called before the daemon exits.
+pub fn shutdown() {
+    otlp::shutdown()
+}
+
+/// Writes closed spans as JSON lines, log lines stay in the log.
+///
+/// Span ids are reused once spans close, `parentId` is the id of a span open at the time.
+struct SpanWriter<W> {
+    out: Mutex<W>,
+}
+
+/// Fields and times of an open span, kept in its extensions.
+struct OpenSpan {
+    fields: Map<String, Value>,
+    start: SystemTime,
+    opened: Instant,
+    busy: Duration,
+    /// Enters not exited yet and the time of the first one.
+    entered: Option<(usize, Instant)>,
+}
+
+impl<W> SpanWriter<W> {
+    fn new(out: W) -> Self {
+        SpanWriter {
+            out: Mutex::new(out),
+        }
+    }
+}
+
+impl<S, W> Layer<S> for SpanWriter<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: Write + Send + 'static,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = Map::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            span.extensions_mut().insert(OpenSpan {
+                fields,
+                start: SystemTime::now(),
+                opened: Instant::now(),
+                busy: Duration::ZERO,
+                entered: None,
+            });
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() {
+                values.record(&mut FieldVisitor(&mut open.fields));
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() {
+                open.entered = match open.entered {
+                    Some((depth, since)) => Some((depth + 1, since)),
+                    None => Some((1, Instant::now())),
+                };
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() {
+                open.entered = match open.entered {
+                    Some((1, since)) => {
+                        open.busy += since.elapsed();
+                        None
+                    }
+                    Some((depth, since)) => Some((depth - 1, since)),
+                    None => None,
+                };
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let open = match span.extensions_mut().remove::<OpenSpan>() {
+            Some(open) => open,
+            None => return,
+        };
+        let mut scope: Vec<_> = span.scope().skip(1).map(|parent| parent.name()).collect();
+        scope.reverse();
+        let micros = |duration: Duration| duration.as_micros() as u64;
+        let mut line = match serde_json::to_vec(&json!({
+            "start": micros(open.start.duration_since(UNIX_EPOCH).unwrap_or_default()),
+            "name": span.name(),
+            "target": span.metadata().target(),
+            "id": id.into_u64(),
+            "parentId": span.parent().map(|parent| parent.id().into_u64()),
+            "scope": scope,
+            "busyUs": micros(open.busy),
+            "idleUs": micros(open.opened.elapsed().saturating_sub(open.busy)),
+            "fields": open.fields,
+        })) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        line.push(b'\n');
+        let mut out = match self.out.lock() {
+            Ok(out) => out,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // Spans are dropped rather than logged, a full disk would log for every block.
+        let _ = out.write_all(&line);
+    }
+}
+
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+#[cfg(feature = "with-otlp")]
+mod otlp {
+    use super::*;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use std::sync::OnceLock;
+    use tracing_subscriber::filter::filter_fn;
+
+    static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+    pub fn init(url: &str) -> Result<(), Error> {
+        let url = url.trim_end_matches('/');
+        // A collector url without a path gets the standard one for traces.
+        let endpoint = if url.ends_with("/v1/traces") {
+            url.to_string()
+        } else {
+            format!("{}/v1/traces", url)
+        };
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|e| Error::Trace(e.to_string()))?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("hyperg").build())
+            .build();
+        // Only spans are exported, log lines stay in the log.
+        let layer = tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("hyperg"))
+            .with_filter(filter_fn(|metadata| metadata.is_span()));
+        tracing_subscriber::registry()
+            .with(layer)
+            .try_init()
+            .map_err(|e| Error::Trace(e.to_string()))?;
+        let _ = PROVIDER.set(provider);
+        Ok(())
+    }
+
+    pub fn shutdown() {
+        if let Some(provider) = PROVIDER.get() {
+            if let Err(e) = provider.shutdown() {
+                log::warn!("unable to export the last spans: {}", e);
+            }
+        }
+    }
+}
+
+/// Builds without `with-otlp` only write spans to files.
+#[cfg(not(feature = "with-otlp"))]
+mod otlp {
+    use super::*;
+
+    pub fn init(_url: &str) -> Result<(), Error> {
+        Err(Error::Trace("otlp support is not compiled in".to_string()))
+    }
+
+    pub fn shutdown() {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{self, File};
+
+    #[test]
+    fn test_span_writer() {
+        let path = std::env::temp_dir().join(format!("hyperg-trace-{}", std::process::id()));
+        let subscriber =
+            tracing_subscriber::registry().with(SpanWriter::new(File::create(&path).unwrap()));
+        let (transfer_id, block_id) = tracing::subscriber::with_default(subscriber, || {
+            let transfer = tracing::info_span!("transfer", hash = "00");
+            let block = transfer.in_scope(|| tracing::debug_span!("block", block_nr = 1u32));
+            // Like spans of messages handled by other threads, with an explicit parent.
+            let actor = tracing::debug_span!(
+                parent: &block,
+                "actor",
+                queued_us = tracing::field::Empty
+            );
+            actor.record("queued_us", 5u64);
+            actor.in_scope(|| std::thread::sleep(Duration::from_millis(2)));
+            let ids = (transfer.id().unwrap(), block.id().unwrap());
+            drop((actor, block, transfer));
+            ids
+        });
+
+        let spans: Vec<Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let _ = fs::remove_file(&path);
+        assert_eq!(spans.len(), 3);
+        let actor = &spans[0];
+        assert_eq!(actor["name"], "actor");
+        assert_eq!(actor["parentId"], block_id.into_u64());
+        assert_eq!(actor["scope"], json!(["transfer", "block"]));
+        assert_eq!(actor["fields"]["queued_us"], 5);
+        assert!(actor["busyUs"].as_u64().unwrap() >= 2000);
+        assert_eq!(spans[1]["parentId"], transfer_id.into_u64());
+        assert_eq!(spans[1]["fields"]["block_nr"], 1);
+        assert_eq!(spans[2]["parentId"], Value::Null);
+        assert_eq!(spans[2]["fields"]["hash"], "00");
+    }
+}
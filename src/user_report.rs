@@ -1,49 +1,350 @@
 use crate::command::User;
-use futures::Future;
+use failure::{AsFail, Fail};
+use futures::prelude::*;
 use log::Level;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of errors kept for `recent_errors`.
+const RECENT_ERRORS: usize = 50;
+
+/// Event reporting backend, selected with `--telemetry`.
+#[derive(Clone, Debug)]
+pub enum Telemetry {
+    /// Events are dropped.
+    None,
+    /// Events are written to the application log.
+    Log,
+    /// Events are sent to sentry. `None` uses the built-in DSN.
+    #[cfg(feature = "with-sentry")]
+    Sentry(Option<String>),
+}
 
-#[cfg(feature = "with-sentry")]
-mod with_sentry {
-    use super::User;
-    use failure::AsFail;
-    use sentry::integrations::failure::FailureHubExt;
-    use sentry::{Hub, Level};
-    use serde::Serialize;
-    use std::error::Error;
-    use std::sync::Arc;
+impl Default for Telemetry {
+    #[cfg(feature = "with-sentry")]
+    fn default() -> Self {
+        Telemetry::Sentry(None)
+    }
 
-    const SENTRY_DSN: &str = "https://9a800ab4e1084d528c7dd2bfcf3e3a06@talkback.golem.network/7";
+    #[cfg(not(feature = "with-sentry"))]
+    fn default() -> Self {
+        Telemetry::None
+    }
+}
 
-    #[derive(Clone)]
-    pub struct UserReportHandle(Option<Arc<Hub>>);
+impl Telemetry {
+    /// Backend name as given to `--telemetry`, without the sentry DSN.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Telemetry::None => "none",
+            Telemetry::Log => "log",
+            #[cfg(feature = "with-sentry")]
+            Telemetry::Sentry(_) => "sentry",
+        }
+    }
+}
 
-    impl<UserRef: AsRef<User>> From<UserRef> for UserReportHandle {
-        fn from(u: UserRef) -> Self {
-            UserReportHandle(Some(new_hub(u.as_ref())))
+impl FromStr for Telemetry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Telemetry::None),
+            "log" => Ok(Telemetry::Log),
+            #[cfg(feature = "with-sentry")]
+            "sentry" => Ok(Telemetry::Sentry(None)),
+            #[cfg(feature = "with-sentry")]
+            s if s.starts_with("sentry:") => Ok(Telemetry::Sentry(Some(s[7..].to_string()))),
+            #[cfg(not(feature = "with-sentry"))]
+            s if s == "sentry" || s.starts_with("sentry:") => {
+                Err("sentry support is not compiled in".to_string())
+            }
+            _ => Err(format!(
+                "invalid telemetry backend: {} (expected none, log or sentry[:<dsn>])",
+                s
+            )),
         }
     }
+}
+
+/// What reports passed to the telemetry backend contain, selected with
+/// `--telemetry_privacy` and the `privacy` command.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Privacy {
+    /// Nothing is reported.
+    Off,
+    /// Errors only, with file paths and peer addresses scrubbed.
+    Errors,
+    /// Errors, warnings and breadcrumbs, with file paths and peer addresses scrubbed.
+    Breadcrumbs,
+    /// Everything as it is.
+    #[default]
+    Full,
+}
+
+impl fmt::Display for Privacy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Privacy::Off => "off",
+            Privacy::Errors => "errors",
+            Privacy::Breadcrumbs => "breadcrumbs",
+            Privacy::Full => "full",
+        })
+    }
+}
 
-    impl UserReportHandle {
-        #[inline]
-        pub fn start(user: &Option<User>) -> UserReportHandle {
-            UserReportHandle(user.as_ref().map(new_hub))
+impl FromStr for Privacy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Privacy::Off),
+            "errors" => Ok(Privacy::Errors),
+            "breadcrumbs" => Ok(Privacy::Breadcrumbs),
+            "full" => Ok(Privacy::Full),
+            _ => Err(format!(
+                "invalid privacy level: {} (expected off, errors, breadcrumbs or full)",
+                s
+            )),
         }
+    }
+}
 
-        pub fn empty() -> UserReportHandle {
-            UserReportHandle(None)
+static PRIVACY: AtomicU8 = AtomicU8::new(Privacy::Full as u8);
+
+pub fn privacy() -> Privacy {
+    match PRIVACY.load(Ordering::SeqCst) {
+        0 => Privacy::Off,
+        1 => Privacy::Errors,
+        2 => Privacy::Breadcrumbs,
+        _ => Privacy::Full,
+    }
+}
+
+/// Applies to reports of running operations as well.
+pub fn set_privacy(privacy: Privacy) {
+    if PRIVACY.swap(privacy as u8, Ordering::SeqCst) != privacy as u8 {
+        log::info!("telemetry privacy set to {}", privacy);
+    }
+}
+
+/// `message` with file paths replaced by `<path>` and IP addresses, `host:port` pairs
+/// and urls by `<addr>`. Paths are recognized by their start (`/`, `~/`, `./`, `../`, a
+/// drive letter) or a backslash, so relative paths and names with spaces may remain.
+pub fn scrub(message: &str) -> String {
+    message
+        .split(' ')
+        .map(scrub_word)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn scrub_word(word: &str) -> String {
+    let start = word.len() - word.trim_start_matches(['"', '\'', '(', '{', '<']).len();
+    let end = word
+        .trim_end_matches(['"', '\'', ')', '}', '>', ',', ';', ':', '.'])
+        .len();
+    if start >= end {
+        return word.to_string();
+    }
+    let core = &word[start..end];
+    let replacement = if is_path(core) {
+        "<path>"
+    } else if is_address(core) {
+        "<addr>"
+    } else {
+        return word.to_string();
+    };
+    format!("{}{}{}", &word[..start], replacement, &word[end..])
+}
+
+fn is_path(s: &str) -> bool {
+    let b = s.as_bytes();
+    let drive = b.len() > 2 && b[0].is_ascii_alphabetic() && b[1] == b':' && b"\\/".contains(&b[2]);
+    s.starts_with('/')
+        || s.starts_with("~/")
+        || s.starts_with("./")
+        || s.starts_with("../")
+        || drive
+        || s.contains('\\')
+}
+
+fn is_address(s: &str) -> bool {
+    if s.contains("://") || s.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    if s.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_ok()
+    {
+        return true;
+    }
+    match s.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty()
+                && !host.contains('/')
+                && host.contains('.')
+                && port.parse::<u16>().is_ok()
         }
+        None => false,
+    }
+}
 
-        pub fn new_context(&self) -> Self {
-            if let Some(hub) = &self.0 {
-                UserReportHandle(Some(Arc::new(Hub::new_from_top(hub))))
-            } else {
-                UserReportHandle(None)
+fn scrub_value(value: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        Value::String(s) => Value::String(scrub(&s)),
+        Value::Array(values) => Value::Array(values.into_iter().map(scrub_value).collect()),
+        Value::Object(values) => Value::Object(
+            values
+                .into_iter()
+                .map(|(key, value)| (key, scrub_value(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+/// Creates report scopes for users issuing commands.
+pub trait Backend: Send + Sync {
+    fn start(&self, user: &User) -> Option<Arc<dyn Scope>>;
+}
+
+/// Report scope of a single operation.
+pub trait Scope: Send + Sync {
+    fn new_context(&self) -> Arc<dyn Scope>;
+
+    fn capture_message(&self, message: &str, level: Level);
+
+    fn capture_fail(&self, fail: &dyn Fail);
+
+    fn add_breadcrumb(&self, level: Level, message: String);
+
+    fn annotate(&self, key: &str, value: serde_json::Value);
+}
+
+static BACKEND: OnceLock<Box<dyn Backend>> = OnceLock::new();
+
+struct NoneBackend;
+
+impl Backend for NoneBackend {
+    fn start(&self, _user: &User) -> Option<Arc<dyn Scope>> {
+        None
+    }
+}
+
+mod log_backend {
+    use super::{Backend, Scope};
+    use crate::command::User;
+    use failure::Fail;
+    use log::Level;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    pub struct LogBackend;
+
+    impl Backend for LogBackend {
+        fn start(&self, user: &User) -> Option<Arc<dyn Scope>> {
+            Some(Arc::new(LogScope {
+                user_id: user.id.clone(),
+                extra: Mutex::new(BTreeMap::new()),
+            }))
+        }
+    }
+
+    struct LogScope {
+        user_id: String,
+        extra: Mutex<BTreeMap<String, serde_json::Value>>,
+    }
+
+    impl LogScope {
+        fn extra(&self) -> String {
+            self.extra
+                .lock()
+                .map(|extra| serde_json::to_string(&*extra).unwrap_or_default())
+                .unwrap_or_default()
+        }
+    }
+
+    impl Scope for LogScope {
+        fn new_context(&self) -> Arc<dyn Scope> {
+            let extra = self
+                .extra
+                .lock()
+                .map(|extra| extra.clone())
+                .unwrap_or_default();
+            Arc::new(LogScope {
+                user_id: self.user_id.clone(),
+                extra: Mutex::new(extra),
+            })
+        }
+
+        fn capture_message(&self, message: &str, level: Level) {
+            log::log!(
+                level,
+                "[telemetry] user={} {} extra={}",
+                self.user_id,
+                message,
+                self.extra()
+            );
+        }
+
+        fn capture_fail(&self, fail: &dyn Fail) {
+            self.capture_message(&fail.to_string(), Level::Error)
+        }
+
+        fn add_breadcrumb(&self, level: Level, message: String) {
+            log::debug!(
+                "[telemetry] user={} breadcrumb {}: {}",
+                self.user_id,
+                level,
+                message
+            );
+        }
+
+        fn annotate(&self, key: &str, value: serde_json::Value) {
+            if let Ok(mut extra) = self.extra.lock() {
+                extra.insert(key.to_string(), value);
             }
         }
     }
+}
 
-    fn new_hub_clean(user: &User) -> Arc<Hub> {
+#[cfg(feature = "with-sentry")]
+mod sentry_backend {
+    use super::{privacy, Backend, Privacy, Scope};
+    use crate::command::User;
+    use failure::Fail;
+    use sentry::integrations::failure::FailureHubExt;
+    use sentry::Hub;
+    use std::sync::Arc;
+
+    pub const DEFAULT_DSN: &str =
+        "https://9a800ab4e1084d528c7dd2bfcf3e3a06@talkback.golem.network/7";
+
+    pub struct SentryBackend {
+        pub dsn: String,
+    }
+
+    impl Backend for SentryBackend {
+        fn start(&self, user: &User) -> Option<Arc<dyn Scope>> {
+            Some(Arc::new(SentryScope(new_hub(&self.dsn, user))))
+        }
+    }
+
+    fn new_hub_clean(dsn: &str, user: &User) -> Arc<Hub> {
         use sentry::*;
 
         let client = sentry::Hub::with(|h| h.client());
@@ -55,11 +356,10 @@ mod with_sentry {
         log::info!("new client!");
         let release = Some(crate::version::PACKAGE_VERSION.into());
         let environment = Some(user.env.to_str());
-        let node_id = user.id.clone();
 
         let client: Arc<Client> = Arc::new(
             (
-                SENTRY_DSN,
+                dsn,
                 ClientOptions {
                     release,
                     environment,
@@ -69,28 +369,23 @@ mod with_sentry {
                 .into(),
         );
 
-        sentry::Hub::with(|h| {
-            h.bind_client(Some(client.clone()));
-            h.configure_scope(|s| {
-                s.set_user(Some(sentry::protocol::User {
-                    id: Some(node_id),
-                    ..sentry::protocol::User::default()
-                }))
-            })
-        });
+        sentry::Hub::with(|h| h.bind_client(Some(client.clone())));
 
         Arc::new(Hub::new_from_top(Hub::current()))
     }
 
-    pub fn new_hub(user: &User) -> Arc<Hub> {
-        let h = new_hub_clean(user);
+    fn new_hub(dsn: &str, user: &User) -> Arc<Hub> {
+        let h = new_hub_clean(dsn, user);
 
         h.configure_scope(|s| {
-            s.set_user(Some(sentry::User {
-                id: Some(user.id.clone().into()),
-                username: user.node_name.as_ref().and_then(|n| n.clone().into()),
-                ..sentry::User::default()
-            }));
+            // The node id and name identify the user, they are only sent unscrubbed.
+            if privacy() == Privacy::Full {
+                s.set_user(Some(sentry::User {
+                    id: Some(user.id.clone().into()),
+                    username: user.node_name.as_ref().and_then(|n| n.clone().into()),
+                    ..sentry::User::default()
+                }));
+            }
 
             if let Some(ref gv) = &user.golem_version {
                 s.set_tag("golem_version".into(), gv.to_string());
@@ -109,48 +404,32 @@ mod with_sentry {
         }
     }
 
-    impl UserReportHandle {
-        pub fn emit_error(&self, stage: &'static str, error: &(dyn Error + 'static)) {
-            log::error!("failed processing {}: {}", stage, error);
-            if let Some(hub) = &self.0 {
-                hub.capture_message(
-                    &format!("failed processing {}: {}", stage, error),
-                    Level::Error,
-                );
-            }
+    struct SentryScope(Arc<Hub>);
+
+    impl Scope for SentryScope {
+        fn new_context(&self) -> Arc<dyn Scope> {
+            Arc::new(SentryScope(Arc::new(Hub::new_from_top(&self.0))))
         }
 
-        pub fn emit_fail(&self, e: &impl AsFail) {
-            if let Some(hub) = &self.0 {
-                let _ = hub.capture_fail(e.as_fail());
-            }
+        fn capture_message(&self, message: &str, level: log::Level) {
+            let _uid = self.0.capture_message(message, map_log_level(level));
         }
 
-        pub fn emit_warn(&self, message: String) {
-            if let Some(hub) = &self.0 {
-                let _uid = hub.capture_message(&message, Level::Warning);
-            }
+        fn capture_fail(&self, fail: &dyn Fail) {
+            let _uid = self.0.capture_fail(fail);
         }
 
-        pub fn add_breadcrumb<MessageFactory: FnOnce() -> String>(
-            &self,
-            level: log::Level,
-            message_factory: MessageFactory,
-        ) {
-            if let Some(hub) = &self.0 {
-                let mut b = sentry::protocol::Breadcrumb::default();
-                b.message = Some(message_factory());
-                b.level = map_log_level(level);
-                hub.add_breadcrumb(b);
-            }
+        fn add_breadcrumb(&self, level: log::Level, message: String) {
+            let mut b = sentry::protocol::Breadcrumb::default();
+            b.message = Some(message);
+            b.level = map_log_level(level);
+            self.0.add_breadcrumb(b);
         }
 
-        pub fn annotate(&self, key: &str, value: &impl Serialize) {
-            if let Some(hub) = &self.0 {
-                hub.configure_scope(|s| {
-                    s.set_extra(key, serde_json::to_value(value).unwrap());
-                })
-            }
+        fn annotate(&self, key: &str, value: serde_json::Value) {
+            self.0.configure_scope(|s| {
+                s.set_extra(key, value);
+            })
         }
     }
 
@@ -159,78 +438,208 @@ mod with_sentry {
     }
 }
 
-#[cfg(not(feature = "with-sentry"))]
-mod without_sentry {
-    use super::User;
-    use failure::AsFail;
-    use log::Level;
-    use serde::Serialize;
-    use std::error::Error;
+/// Installs the telemetry backend. Must be called once at startup.
+pub fn init(telemetry: &Telemetry) {
+    let backend: Box<dyn Backend> = match telemetry {
+        Telemetry::None => Box::new(NoneBackend),
+        Telemetry::Log => Box::new(log_backend::LogBackend),
+        #[cfg(feature = "with-sentry")]
+        Telemetry::Sentry(dsn) => {
+            sentry_backend::init();
+            Box::new(sentry_backend::SentryBackend {
+                dsn: dsn
+                    .clone()
+                    .unwrap_or_else(|| sentry_backend::DEFAULT_DSN.to_string()),
+            })
+        }
+    };
+    if BACKEND.set(backend).is_err() {
+        log::warn!("telemetry backend already initialized");
+    }
+}
 
-    #[derive(Clone)]
-    pub struct UserReportHandle {}
+/// `message` scrubbed unless the privacy level is `Full`.
+fn scrubbed(message: String) -> String {
+    if privacy() == Privacy::Full {
+        message
+    } else {
+        scrub(&message)
+    }
+}
 
-    impl<UserRef: AsRef<User>> From<UserRef> for UserReportHandle {
-        fn from(_u: UserRef) -> Self {
-            UserReportHandle {}
-        }
+fn backend() -> &'static dyn Backend {
+    BACKEND.get_or_init(|| Box::new(NoneBackend)).as_ref()
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecentError {
+    /// Seconds since the epoch
+    pub time: u64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+static ERRORS: OnceLock<Mutex<VecDeque<RecentError>>> = OnceLock::new();
+
+fn errors() -> std::sync::MutexGuard<'static, VecDeque<RecentError>> {
+    match ERRORS.get_or_init(Default::default).lock() {
+        Ok(errors) => errors,
+        Err(poisoned) => poisoned.into_inner(),
     }
+}
 
-    impl UserReportHandle {
-        #[inline]
-        pub fn start(_user: &Option<User>) -> UserReportHandle {
-            UserReportHandle {}
-        }
+/// Failed RPC operations and transfers reported lately, oldest first, with any telemetry.
+pub fn recent_errors() -> Vec<RecentError> {
+    errors().iter().cloned().collect()
+}
+
+#[derive(Clone)]
+pub struct UserReportHandle {
+    scope: Option<Arc<dyn Scope>>,
+    /// Correlation id of the RPC request the operation serves.
+    request_id: Option<Arc<str>>,
+}
 
-        #[inline]
-        pub fn empty() -> UserReportHandle {
-            UserReportHandle {}
+impl<UserRef: AsRef<User>> From<UserRef> for UserReportHandle {
+    fn from(u: UserRef) -> Self {
+        UserReportHandle {
+            scope: backend().start(u.as_ref()),
+            request_id: None,
         }
+    }
+}
 
-        pub fn new_context(&self) -> Self {
-            UserReportHandle::empty()
+impl UserReportHandle {
+    #[inline]
+    pub fn start(user: &Option<User>) -> UserReportHandle {
+        UserReportHandle {
+            scope: user.as_ref().and_then(|user| backend().start(user)),
+            request_id: None,
         }
     }
 
-    impl UserReportHandle {
-        pub fn emit_error(&self, stage: &'static str, error: &(dyn Error + 'static)) {
-            log::error!("failed processing {}: {}", stage, error);
+    #[inline]
+    pub fn empty() -> UserReportHandle {
+        UserReportHandle {
+            scope: None,
+            request_id: None,
         }
+    }
 
-        pub fn emit_fail(&self, _e: &impl AsFail) {}
+    /// Tags reports and log lines of the operation with the id of the RPC request.
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.annotate("request_id", &request_id);
+        self.request_id = Some(request_id.into());
+        self
+    }
 
-        pub fn emit_warn(&self, _message: String) {}
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_ref().map(AsRef::as_ref)
+    }
 
-        #[inline]
-        pub fn add_breadcrumb<MessageFactory: FnOnce() -> String>(
-            &self,
-            _level: Level,
-            _message_factory: MessageFactory,
-        ) {
+    pub fn new_context(&self) -> Self {
+        UserReportHandle {
+            scope: self.scope.as_ref().map(|scope| scope.new_context()),
+            request_id: self.request_id.clone(),
+        }
+    }
 
+    pub fn emit_error(&self, stage: &'static str, error: &(dyn Error + 'static)) {
+        self.remember(format!("failed processing {}: {}", stage, error));
+        match &self.request_id {
+            Some(request_id) => {
+                log::error!("[{}] failed processing {}: {}", request_id, stage, error)
+            }
+            None => log::error!("failed processing {}: {}", stage, error),
+        }
+        if let Some(scope) = self.scope(Privacy::Errors) {
+            scope.capture_message(
+                &scrubbed(format!("failed processing {}: {}", stage, error)),
+                Level::Error,
+            );
         }
+    }
 
-        pub fn annotate(&self, _key: &str, _value: &impl Serialize) {}
+    pub fn emit_fail(&self, e: &impl AsFail) {
+        self.remember(e.as_fail().to_string());
+        match self.scope(Privacy::Errors) {
+            Some(scope) if privacy() == Privacy::Full => scope.capture_fail(e.as_fail()),
+            Some(scope) => scope.capture_message(&scrub(&e.as_fail().to_string()), Level::Error),
+            None => (),
+        }
     }
 
-    pub fn init() {}
-}
+    /// The scope when reports need at most the `required` privacy level.
+    fn scope(&self, required: Privacy) -> Option<&Arc<dyn Scope>> {
+        self.scope.as_ref().filter(|_| privacy() >= required)
+    }
 
-#[cfg(feature = "with-sentry")]
-pub use with_sentry::{init, UserReportHandle};
+    fn remember(&self, message: String) {
+        let mut errors = errors();
+        if errors.len() >= RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or_default(),
+            message,
+            request_id: self.request_id.as_ref().map(|id| id.to_string()),
+        });
+    }
 
-#[cfg(not(feature = "with-sentry"))]
-pub use without_sentry::{init, UserReportHandle};
+    pub fn emit_warn(&self, message: String) {
+        if let Some(scope) = self.scope(Privacy::Breadcrumbs) {
+            scope.capture_message(&scrubbed(message), Level::Warn);
+        }
+    }
 
-impl UserReportHandle {
+    /// Breadcrumbs of RPC requests are also logged at debug level.
     #[inline]
-    pub fn wrap_future<F: Future>(
+    pub fn add_breadcrumb<MessageFactory: FnOnce() -> String>(
+        &self,
+        level: Level,
+        message_factory: MessageFactory,
+    ) {
+        let logged = self.request_id.is_some() && log::log_enabled!(Level::Debug);
+        let scope = self.scope(Privacy::Breadcrumbs);
+        if scope.is_none() && !logged {
+            return;
+        }
+        let message = message_factory();
+        if let (true, Some(request_id)) = (logged, &self.request_id) {
+            log::debug!("[{}] {}", request_id, message);
+        }
+        if let Some(scope) = scope {
+            scope.add_breadcrumb(level, scrubbed(message));
+        }
+    }
+
+    pub fn annotate(&self, key: &str, value: &impl Serialize) {
+        if let Some(scope) = self.scope(Privacy::Errors) {
+            let value = serde_json::to_value(value).unwrap_or(serde_json::Value::Null);
+            scope.annotate(
+                key,
+                if privacy() == Privacy::Full {
+                    value
+                } else {
+                    scrub_value(value)
+                },
+            );
+        }
+    }
+
+    #[inline]
+    pub fn wrap_future<T, E, F: Future<Output = Result<T, E>>>(
         &self,
         stage: &'static str,
         f: F,
-    ) -> impl Future<Item = F::Item, Error = F::Error>
+    ) -> impl Future<Output = Result<T, E>>
     where
-        F::Error: Error + 'static,
+        E: Error + 'static,
     {
         let reporter = self.clone();
 
@@ -250,3 +659,37 @@ impl UserReportHandle {
         self.add_breadcrumb(Level::Error, message_factory);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scrub() {
+        assert_eq!(
+            scrub("unable to open \"/home/user/a.txt\": denied"),
+            "unable to open \"<path>\": denied"
+        );
+        assert_eq!(
+            scrub("failed to serve 00ff to 10.0.0.1:3282: lost"),
+            "failed to serve 00ff to <addr>: lost"
+        );
+        assert_eq!(
+            scrub("peers [::1]:3282, example.com:3282 and http://x/y"),
+            "peers <addr>, <addr> and <addr>"
+        );
+        assert_eq!(scrub("C:\\data\\a.txt and a.txt"), "<path> and a.txt");
+        assert_eq!(
+            scrub_value(serde_json::json!(["upload", ["./a"], {"TCP": ["1.2.3.4", 3282]}])),
+            serde_json::json!(["upload", ["<path>"], {"TCP": ["<addr>", 3282]}])
+        );
+        for privacy in &[
+            Privacy::Off,
+            Privacy::Errors,
+            Privacy::Breadcrumbs,
+            Privacy::Full,
+        ] {
+            assert_eq!(privacy.to_string().parse::<Privacy>().unwrap(), *privacy);
+        }
+    }
+}
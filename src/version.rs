@@ -1,3 +1,4 @@
+use crate::command::BuildInfo;
 use log::info;
 
 pub static PACKAGE_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -21,3 +22,33 @@ pub fn startup_log() {
         _ => (),
     }
 }
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        commit: TRAVIS_COMMIT.map(Into::into),
+        build_number: TRAVIS_BUILD_NUMBER.map(Into::into),
+        tag: TRAVIS_TAG.map(Into::into),
+        os: TRAVIS_OS_NAME.map(Into::into),
+    }
+}
+
+/// Optional features the binary was built with.
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "with-sentry") {
+        features.push("sentry");
+    }
+    if cfg!(feature = "with-mmap") {
+        features.push("mmap");
+    }
+    if cfg!(feature = "with-mdns") {
+        features.push("mdns");
+    }
+    if cfg!(feature = "with-tls") {
+        features.push("tls");
+    }
+    if cfg!(feature = "with-grpc") {
+        features.push("grpc");
+    }
+    features
+}
@@ -0,0 +1,186 @@
+use crate::database::{self, Access, DatabaseManager, RegisterHash, RemoveHash};
+use crate::error::Error;
+use crate::file_name::FileName;
+use crate::filemap::{BundleFormat, HashAlgorithm};
+use crate::hasher::{self, Hasher};
+use crate::user_report::UserReportHandle;
+use actix::prelude::*;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Time a file has to stay unchanged before it is (re)shared.
+const DEBOUNCE_DELAY: Duration = Duration::from_secs(2);
+
+/// Shares files dropped into a directory for as long as they stay there.
+pub struct DirWatcher {
+    dir: PathBuf,
+    db: Addr<DatabaseManager>,
+    hasher: Addr<Hasher>,
+    hash_algorithm: HashAlgorithm,
+    shares: HashMap<PathBuf, u128>,
+}
+
+impl DirWatcher {
+    fn share(&mut self, path: PathBuf, ctx: &mut <Self as Actor>::Context) {
+        if !path.is_file() {
+            return;
+        }
+        let file_name = match path.file_name() {
+            Some(file_name) => FileName::from_os_str(file_name),
+            None => return,
+        };
+        let db = self.db.clone();
+        let hash_algorithm = self.hash_algorithm;
+
+        let hash_files = hasher::hash_files(
+            &self.hasher,
+            vec![(path.clone(), file_name)],
+            hash_algorithm,
+        );
+
+        async move {
+            let files = hash_files.await?;
+            database::request(
+                &db,
+                RegisterHash {
+                    files,
+                    valid_to: None,
+                    inline_data: Vec::new(),
+                    inline_files: Vec::new(),
+                    hash_algorithm,
+                    access: Access::default(),
+                    reporter: UserReportHandle::empty(),
+                    bundle_format: BundleFormat::Merkle,
+                    namespace: None,
+                    metadata: Default::default(),
+                },
+            )
+            .await?
+            .map(|registration| registration.hash)
+        }
+        .into_actor(self)
+        .map(move |r: Result<u128, Error>, act, _ctx| match r {
+            Ok(hash) => {
+                log::info!("watch share {:032x} {}", hash, path.display());
+                if let Some(prev_hash) = act.shares.insert(path, hash) {
+                    if prev_hash != hash {
+                        act.release(prev_hash);
+                    }
+                }
+            }
+            Err(e) => log::error!("failed to share {}: {}", path.display(), e),
+        })
+        .spawn(ctx);
+    }
+
+    fn unshare(&mut self, path: &Path) {
+        if let Some(hash) = self.shares.remove(path) {
+            log::info!("watch unshare {:032x} {}", hash, path.display());
+            self.release(hash);
+        }
+    }
+
+    /// Removes `hash` unless another watched file has the same content.
+    fn release(&self, hash: u128) {
+        if !self.shares.values().any(|&h| h == hash) {
+            database::notify(&self.db, RemoveHash(hash));
+        }
+    }
+
+    fn rescan(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let gone: Vec<PathBuf> = self
+            .shares
+            .keys()
+            .filter(|path| !path.is_file())
+            .cloned()
+            .collect();
+        for path in gone {
+            self.unshare(&path);
+        }
+
+        match fs::read_dir(&self.dir) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    if !self.shares.contains_key(&path) {
+                        self.share(path, ctx);
+                    }
+                }
+            }
+            Err(e) => log::error!("failed to scan {}: {}", self.dir.display(), e),
+        }
+    }
+}
+
+impl Actor for DirWatcher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        log::info!("watching {}", self.dir.display());
+        self.rescan(ctx)
+    }
+}
+
+struct FsEvent(DebouncedEvent);
+
+impl Message for FsEvent {
+    type Result = ();
+}
+
+impl Handler<FsEvent> for DirWatcher {
+    type Result = ();
+
+    fn handle(&mut self, msg: FsEvent, ctx: &mut Self::Context) -> Self::Result {
+        match msg.0 {
+            DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => self.share(path, ctx),
+            DebouncedEvent::Remove(path) => self.unshare(&path),
+            DebouncedEvent::Rename(from, to) => {
+                self.unshare(&from);
+                if to.parent() == Some(self.dir.as_path()) {
+                    self.share(to, ctx)
+                }
+            }
+            DebouncedEvent::Rescan => self.rescan(ctx),
+            DebouncedEvent::Error(e, path) => {
+                log::error!("watch error on {:?}: {}", path, e);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Starts sharing the content of `dir`.
+pub fn start(
+    dir: &Path,
+    db: Addr<DatabaseManager>,
+    hasher: Addr<Hasher>,
+    hash_algorithm: HashAlgorithm,
+) -> Result<Addr<DirWatcher>, Error> {
+    let dir = dir.canonicalize()?;
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE_DELAY)?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    let addr = DirWatcher {
+        dir,
+        db,
+        hasher,
+        hash_algorithm,
+        shares: HashMap::new(),
+    }
+    .start();
+
+    let events = addr.clone();
+    std::thread::spawn(move || {
+        let _watcher = watcher;
+        for event in rx {
+            events.do_send(FsEvent(event));
+        }
+    });
+
+    Ok(addr)
+}
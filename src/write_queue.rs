@@ -0,0 +1,198 @@
+use bytes::BytesMut;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::codec::Encoder;
+
+/// Number of bytes encoded for a connection but not yet written to the socket.
+#[derive(Clone)]
+pub struct WriteQueue(Rc<WriteQueueInner>);
+
+struct WriteQueueInner {
+    queued: Cell<usize>,
+    written: Cell<u64>,
+    low_watermark: usize,
+    high_watermark: usize,
+    drain_waker: RefCell<Option<Waker>>,
+}
+
+impl WriteQueue {
+    pub fn new(low_watermark: usize, high_watermark: usize) -> Self {
+        WriteQueue(Rc::new(WriteQueueInner {
+            queued: Cell::new(0),
+            written: Cell::new(0),
+            low_watermark,
+            high_watermark,
+            drain_waker: RefCell::new(None),
+        }))
+    }
+
+    #[inline]
+    pub fn queued(&self) -> usize {
+        self.0.queued.get()
+    }
+
+    /// Total bytes written to the socket.
+    #[inline]
+    pub fn written(&self) -> u64 {
+        self.0.written.get()
+    }
+
+    /// True if new writes should wait until the queue drains.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.queued() > self.0.high_watermark
+    }
+
+    fn push(&self, n: usize) {
+        self.0.queued.set(self.queued() + n);
+    }
+
+    fn consume(&self, n: usize) {
+        self.0.written.set(self.written() + n as u64);
+        let queued = self.queued().saturating_sub(n);
+        self.0.queued.set(queued);
+        if queued <= self.0.low_watermark {
+            if let Some(waker) = self.0.drain_waker.borrow_mut().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Resolves once queued bytes drop to the low watermark.
+    pub fn drained(&self) -> Drained {
+        Drained(self.clone())
+    }
+}
+
+pub struct Drained(WriteQueue);
+
+impl Future for Drained {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let inner = &(self.0).0;
+        if inner.queued.get() <= inner.low_watermark {
+            Poll::Ready(())
+        } else {
+            *inner.drain_waker.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Encoder adding size of every encoded frame to the queue.
+pub struct QueuedEncoder<E> {
+    encoder: E,
+    queue: WriteQueue,
+}
+
+impl<E> QueuedEncoder<E> {
+    pub fn new(encoder: E, queue: WriteQueue) -> Self {
+        QueuedEncoder { encoder, queue }
+    }
+}
+
+impl<I, E: Encoder<I>> Encoder<I> for QueuedEncoder<E> {
+    type Error = E::Error;
+
+    fn encode(&mut self, item: I, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let before = dst.len();
+        let result = self.encoder.encode(item, dst);
+        self.queue.push(dst.len().saturating_sub(before));
+        result
+    }
+}
+
+/// Writer removing bytes written to the socket from the queue.
+pub struct CountingWrite<W> {
+    inner: W,
+    queue: WriteQueue,
+}
+
+impl<W> CountingWrite<W> {
+    pub fn new(inner: W, queue: WriteQueue) -> Self {
+        CountingWrite { inner, queue }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWrite<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.queue.consume(n);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reader counting bytes read from the socket.
+pub struct CountingRead<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R> CountingRead<R> {
+    pub fn new(inner: R, count: Rc<Cell<u64>>) -> Self {
+        CountingRead { inner, count }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingRead<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        self.count.set(self.count.get() + n as u64);
+        poll
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::{StCodec, StCommand};
+    use futures::executor::block_on;
+    use futures::FutureExt;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_queue_accounting() {
+        let queue = WriteQueue::new(10, 100);
+        let mut encoder = QueuedEncoder::new(StCodec::default(), queue.clone());
+        let mut buf = BytesMut::new();
+        encoder
+            .encode(StCommand::block(1, 0, 0, vec![0u8; 200]), &mut buf)
+            .unwrap();
+        assert_eq!(queue.queued(), buf.len());
+        assert!(queue.is_full());
+
+        let mut w = CountingWrite::new(Vec::new(), queue.clone());
+        block_on(w.write_all(&buf[..150])).unwrap();
+        assert!(!queue.is_full());
+        block_on(w.write_all(&buf[150..])).unwrap();
+        assert_eq!(queue.queued(), 0);
+        assert_eq!(queue.written(), buf.len() as u64);
+        assert_eq!(queue.drained().now_or_never(), Some(()));
+    }
+}